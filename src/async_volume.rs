@@ -0,0 +1,240 @@
+use crate::constants::READ_STEP;
+use crate::error::VolumeError;
+use crate::index::ObjectList;
+use crate::shared::SharedVolume;
+use crate::volume::BigFileVolume;
+use crate::UUID;
+use std::future::Future;
+use std::panic::AssertUnwindSafe;
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::sync::{mpsc, oneshot};
+
+// How many buffers of READ_STEP bytes can be on their way between the
+// runtime and the thread doing the I/O and the encoding.
+const BUFFERS: usize = 4;
+
+// A call holds a file record and blocks on its stack, like the main thread
+// of the command line does.
+const CALL_STACK_BYTES: usize = 16 << 20;
+
+// Calls that run at once unless told otherwise; the rest wait their turn.
+const DEFAULT_THREADS: usize = 16;
+
+type Call = Box<dyn FnOnce() + Send>;
+
+// A volume for async code. Calls run the blocking volume on a fixed set of
+// threads of its own, so neither disk and network I/O nor the Reed-Solomon
+// math ever stall the runtime; data crosses over in buffers of a chunk's
+// worth. Calls run side by side on a shared volume, as many at once as
+// there are threads. A call whose future is neither awaited nor dropped
+// keeps its thread.
+#[derive(Clone)]
+pub struct AsyncVolume {
+    volume: SharedVolume,
+    calls: Sender<Call>,
+}
+
+impl AsyncVolume {
+    pub fn new(volume: BigFileVolume) -> AsyncVolume {
+        AsyncVolume::with_threads(volume, DEFAULT_THREADS)
+    }
+
+    // The threads go once the last clone of the volume does. Calls fail
+    // with WorkerFailed if none of them could be started.
+    pub fn with_threads(volume: BigFileVolume, threads: usize) -> AsyncVolume {
+        let (calls, queue) = channel();
+        let queue = Arc::new(Mutex::new(queue));
+        for _ in 0..threads.max(1) {
+            let queue = queue.clone();
+            let _ = std::thread::Builder::new()
+                .stack_size(CALL_STACK_BYTES)
+                .spawn(move || work(&queue));
+        }
+        AsyncVolume {
+            volume: SharedVolume::new(volume),
+            calls,
+        }
+    }
+
+    // Stores what `reader` holds as the current version of `name`. If the
+    // future is dropped before the reader is exhausted, nothing is stored.
+    pub async fn put<R>(&self, name: &str, mut reader: R) -> Result<UUID, VolumeError>
+    where
+        R: AsyncRead + Unpin,
+    {
+        let (sender, receiver) = mpsc::channel(BUFFERS);
+        let name = name.to_owned();
+        let stored = self.blocking(move |volume| {
+            let mut incoming = Incoming {
+                receiver,
+                buf: Vec::new(),
+                at: 0,
+                ended: false,
+            };
+            volume.put(&name, &mut incoming)
+        });
+
+        // An empty buffer marks the end, so that one that never comes tells
+        // a dropped put apart from a finished one.
+        loop {
+            let mut buf = vec![0; READ_STEP];
+            let read = reader.read(&mut buf[..]).await.map(|n| {
+                buf.truncate(n);
+                buf
+            });
+            let done = !matches!(read, Ok(ref buf) if !buf.is_empty());
+            if sender.send(read).await.is_err() || done {
+                break;
+            }
+        }
+        drop(sender);
+        stored.await
+    }
+
+    // Writes the current version of `name` to `writer`.
+    pub async fn get<W>(&self, name: &str, writer: W) -> Result<(), VolumeError>
+    where
+        W: AsyncWrite + Unpin,
+    {
+        let name = name.to_owned();
+        self.write_out(writer, move |volume, outgoing| volume.get(&name, outgoing))
+            .await
+    }
+
+    // Writes the `len` bytes of the current version of `name` from
+    // `offset` on, or as many of them as it holds.
+    pub async fn read_range<W>(
+        &self,
+        name: &str,
+        offset: u64,
+        len: u64,
+        writer: W,
+    ) -> Result<(), VolumeError>
+    where
+        W: AsyncWrite + Unpin,
+    {
+        let name = name.to_owned();
+        self.write_out(writer, move |volume, outgoing| {
+            volume.read_range(&name, offset, len, outgoing)
+        })
+        .await
+    }
+
+    pub async fn delete(&self, name: &str) -> Result<(), VolumeError> {
+        let name = name.to_owned();
+        self.blocking(move |volume| volume.delete(&name)).await
+    }
+
+    pub async fn list(
+        &self,
+        prefix: &str,
+        delimiter: Option<&str>,
+        start_after: Option<&str>,
+        limit: usize,
+    ) -> Result<ObjectList, VolumeError> {
+        let prefix = prefix.to_owned();
+        let delimiter = delimiter.map(|d| d.to_owned());
+        let start_after = start_after.map(|s| s.to_owned());
+        self.blocking(move |volume| {
+            volume.list(&prefix, delimiter.as_deref(), start_after.as_deref(), limit)
+        })
+        .await
+    }
+
+    // Queues `call` on the volume's threads right away, not once the
+    // result is awaited.
+    fn blocking<F, T>(&self, call: F) -> impl Future<Output = Result<T, VolumeError>>
+    where
+        F: FnOnce(&SharedVolume) -> Result<T, VolumeError> + Send + 'static,
+        T: Send + 'static,
+    {
+        let volume = self.volume.clone();
+        let (sender, receiver) = oneshot::channel();
+        let queued = self.calls.send(Box::new(move || {
+            let _ = sender.send(call(&volume));
+        }));
+        async move {
+            queued.map_err(|_| VolumeError::WorkerFailed)?;
+            receiver.await.map_err(|_| VolumeError::WorkerFailed)?
+        }
+    }
+
+    // Runs `call` on one of the volume's threads, copying what it writes to `writer`
+    // here as it comes.
+    async fn write_out<W, F>(&self, mut writer: W, call: F) -> Result<(), VolumeError>
+    where
+        W: AsyncWrite + Unpin,
+        F: FnOnce(&SharedVolume, &mut Outgoing) -> Result<(), VolumeError> + Send + 'static,
+    {
+        let (sender, mut receiver) = mpsc::channel::<Vec<u8>>(BUFFERS);
+        let written = self.blocking(move |volume| call(volume, &mut Outgoing { sender }));
+        while let Some(buf) = receiver.recv().await {
+            writer
+                .write_all(&buf[..])
+                .await
+                .map_err(VolumeError::IoError)?;
+        }
+        written.await?;
+        writer.flush().await.map_err(VolumeError::IoError)
+    }
+}
+
+// Runs the calls queued on the volume until it goes. One that panics only
+// fails itself.
+fn work(queue: &Mutex<Receiver<Call>>) {
+    loop {
+        let call = match queue.lock().unwrap().recv() {
+            Ok(call) => call,
+            Err(_) => return,
+        };
+        let _ = std::panic::catch_unwind(AssertUnwindSafe(call));
+    }
+}
+
+// The reading end of a put, on the blocking side.
+struct Incoming {
+    receiver: mpsc::Receiver<std::io::Result<Vec<u8>>>,
+    buf: Vec<u8>,
+    at: usize,
+    ended: bool,
+}
+
+impl std::io::Read for Incoming {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if self.at == self.buf.len() {
+            if self.ended {
+                return Ok(0);
+            }
+            self.buf = match self.receiver.blocking_recv() {
+                Some(read) => read?,
+                None => return Err(std::io::ErrorKind::UnexpectedEof.into()),
+            };
+            self.at = 0;
+            self.ended = self.buf.is_empty();
+        }
+        let n = buf.len().min(self.buf.len() - self.at);
+        buf[..n].copy_from_slice(&self.buf[self.at..self.at + n]);
+        self.at += n;
+        Ok(n)
+    }
+}
+
+// The writing end of a get, on the blocking side.
+struct Outgoing {
+    sender: mpsc::Sender<Vec<u8>>,
+}
+
+impl std::io::Write for Outgoing {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.sender
+            .blocking_send(buf.to_vec())
+            .map_err(|_| std::io::Error::from(std::io::ErrorKind::BrokenPipe))?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
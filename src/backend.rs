@@ -0,0 +1,365 @@
+use crate::block::Block;
+use crate::chunk::Chunk;
+use crate::constants::{BLOCKS, PARITY};
+use crate::error::VolumeError;
+use crate::index::{IndexEntry, ObjectList};
+use crate::label::ChunkLabel;
+use crate::placement::Placement;
+use crate::redundant_file::RedundantFile;
+use crate::snapshot::{Snapshot, SnapshotSummary};
+use crate::store::BlockStores;
+use crate::volume_manager::FileVolumeManager;
+use crate::UUID;
+use std::time::Duration;
+
+// A new chunk with room taken for it, as `reserve_chunk` leaves it for
+// `write_reserved`: which store is to hold which of its blocks, and where.
+pub struct Reserved {
+    pub label: ChunkLabel,
+    pub chunk: Chunk,
+    pub slots: Vec<(usize, u64, Vec<Block>)>,
+}
+
+// Where a volume keeps its chunks. A backend only stores what it is given;
+// encoding, decoding and rebuilding blocks are left to the volume. Nothing
+// saved has to last until `sync_data`, and no allocation or free until
+// `sync_metadata`.
+pub trait BlockStore: Send + Sync {
+    // The volume the chunks belong to.
+    fn uuid(&self) -> UUID;
+
+    // Stores a new chunk: its label, its header and its blocks.
+    fn save_chunk(
+        &mut self,
+        label: ChunkLabel,
+        chunk: Chunk,
+        blocks: Vec<Block>,
+    ) -> Result<(), VolumeError>;
+    fn save_chunk_header(&mut self, chunk: Chunk) -> Result<(), VolumeError>;
+    fn load_chunk(&self, id: UUID) -> Result<Chunk, VolumeError>;
+
+    // Saves a new chunk in two steps, for writers to store theirs side by
+    // side: `reserve_chunk` places it and takes room for it, which needs the
+    // store to itself, and `write_reserved` writes it there, alongside other
+    // writes and reads. Nothing may be freed in between, or the room could
+    // go to another chunk. Backends that can't tell the steps apart save
+    // the chunk whole and reserve nothing.
+    fn reserve_chunk(
+        &mut self,
+        label: ChunkLabel,
+        chunk: Chunk,
+        blocks: Vec<Block>,
+    ) -> Result<Option<Reserved>, VolumeError> {
+        self.save_chunk(label, chunk, blocks)?;
+        Ok(None)
+    }
+    fn write_reserved(&self, _reserved: Reserved) -> Result<(), VolumeError> {
+        Ok(())
+    }
+
+    // The label of chunk `id`, or None if it has none that parses.
+    fn load_label(&self, id: UUID) -> Result<Option<ChunkLabel>, VolumeError>;
+    fn save_label(&mut self, label: ChunkLabel) -> Result<(), VolumeError>;
+    fn load_block(&self, chunk: UUID, n: usize) -> Result<Block, VolumeError>;
+
+    // Every block of chunk `id`, None for those that couldn't be read.
+    // Backends that can read them all at once may stop as soon as there
+    // are enough to rebuild the chunk.
+    fn load_blocks(&self, chunk: UUID) -> Result<Vec<Option<Block>>, VolumeError> {
+        Ok((0..BLOCKS + PARITY)
+            .map(|n| self.load_block(chunk, n).ok())
+            .collect())
+    }
+    fn save_block(&mut self, chunk: UUID, n: usize, block: Block) -> Result<(), VolumeError>;
+    fn contains(&self, id: UUID) -> bool;
+    fn ids(&self) -> Vec<UUID>;
+    fn free_chunk(&mut self, id: UUID) -> Result<(), VolumeError>;
+    fn sync_metadata(&mut self) -> Result<(), VolumeError>;
+    fn sync_data(&mut self) -> Result<(), VolumeError>;
+
+    // Backends that don't spread chunks over several stores have no use for
+    // a placement policy.
+    fn set_policy(&mut self, _policy: Box<dyn Placement>) {}
+
+    // Nor have those that read blocks one at a time any use for a hedge
+    // delay.
+    fn set_hedge(&mut self, _delay: Duration) {}
+}
+
+// Where a volume keeps its file records, its index of names and its
+// snapshots. Changes are kept in memory until `sync_metadata`, or until
+// `journal_commit` while a write is open, and a write that never committed
+// leaves its chunk ids in `rollback` for the volume to free.
+pub trait MetadataStore: Send + Sync {
+    fn uuid(&self) -> UUID;
+
+    fn journal_begin(&mut self, id: UUID, chunks: &[UUID]) -> Result<(), VolumeError>;
+
+    // Adds `chunks` to those of the open write, for a write that only
+    // learns its chunks as it goes. They have to be in the journal before
+    // they are stored. From then on the write counts as committed once
+    // object `id` is in the index, which lets writes to several objects
+    // share the journal.
+    fn journal_extend(&mut self, id: UUID, chunks: &[UUID]) -> Result<(), VolumeError>;
+    fn journal_commit(&mut self) -> Result<(), VolumeError>;
+    fn rollback(&self) -> &[UUID];
+    fn finish_rollback(&mut self) -> Result<(), VolumeError>;
+
+    // Drops whatever changed since the last commit, as after a failed
+    // write.
+    fn reload(&mut self) -> Result<(), VolumeError>;
+    fn sync_metadata(&mut self) -> Result<(), VolumeError>;
+
+    // Saves the record of a file, in a new slot unless it already has one.
+    fn save_file(&mut self, file: RedundantFile) -> Result<(), VolumeError>;
+    fn load_file(&self, id: UUID) -> Result<RedundantFile, VolumeError>;
+    fn contains_file(&self, id: UUID) -> bool;
+    fn free_file(&mut self, id: UUID) -> Result<(), VolumeError>;
+
+    fn versioning(&self) -> bool;
+    fn set_versioning(&mut self, enabled: bool) -> Result<(), VolumeError>;
+    fn index_put(&mut self, entry: IndexEntry) -> Result<Vec<IndexEntry>, VolumeError>;
+    fn index_restore(&mut self, entry: IndexEntry) -> Result<(), VolumeError>;
+    fn index_get(&self, name: &str) -> Option<IndexEntry>;
+    fn index_get_version(&self, name: &str, id: UUID) -> Option<IndexEntry>;
+    fn index_entries(&self) -> Vec<IndexEntry>;
+    fn index_remove_all(&mut self, name: &str) -> Result<Vec<IndexEntry>, VolumeError>;
+    fn index_remove_version(
+        &mut self,
+        name: &str,
+        id: UUID,
+    ) -> Result<Option<IndexEntry>, VolumeError>;
+    fn list(
+        &self,
+        prefix: &str,
+        delimiter: Option<&str>,
+        start_after: Option<&str>,
+        limit: usize,
+    ) -> Result<ObjectList, VolumeError>;
+    fn list_versions(
+        &self,
+        prefix: &str,
+        start_after: Option<&str>,
+        limit: usize,
+    ) -> Result<ObjectList, VolumeError>;
+
+    fn create_snapshot(&mut self, name: &str, created: u64) -> Result<(), VolumeError>;
+    fn delete_snapshot(&mut self, name: &str) -> Result<Snapshot, VolumeError>;
+    fn snapshots(&self) -> Vec<SnapshotSummary>;
+    fn snapshot(&self, name: &str) -> Result<&Snapshot, VolumeError>;
+
+    // Whether the live index or any snapshot still uses file `id`.
+    fn referenced(&self, id: UUID) -> bool;
+}
+
+// The block file, or the block stores of a volume spread over several.
+impl BlockStore for BlockStores {
+    fn uuid(&self) -> UUID {
+        self.super_block().uuid()
+    }
+
+    fn save_chunk(
+        &mut self,
+        label: ChunkLabel,
+        chunk: Chunk,
+        blocks: Vec<Block>,
+    ) -> Result<(), VolumeError> {
+        BlockStores::save_chunk(self, label, chunk, blocks)
+    }
+
+    fn save_chunk_header(&mut self, chunk: Chunk) -> Result<(), VolumeError> {
+        BlockStores::save_chunk_header(self, chunk)
+    }
+
+    fn reserve_chunk(
+        &mut self,
+        label: ChunkLabel,
+        chunk: Chunk,
+        blocks: Vec<Block>,
+    ) -> Result<Option<Reserved>, VolumeError> {
+        BlockStores::reserve_chunk(self, label, chunk, blocks).map(Some)
+    }
+
+    fn write_reserved(&self, reserved: Reserved) -> Result<(), VolumeError> {
+        BlockStores::write_reserved(self, reserved)
+    }
+
+    fn load_chunk(&self, id: UUID) -> Result<Chunk, VolumeError> {
+        BlockStores::load_chunk(self, id)
+    }
+
+    fn load_label(&self, id: UUID) -> Result<Option<ChunkLabel>, VolumeError> {
+        BlockStores::load_label(self, id)
+    }
+
+    fn save_label(&mut self, label: ChunkLabel) -> Result<(), VolumeError> {
+        BlockStores::save_label(self, label)
+    }
+
+    fn load_block(&self, chunk: UUID, n: usize) -> Result<Block, VolumeError> {
+        BlockStores::load_block(self, chunk, n)
+    }
+
+    fn save_block(&mut self, chunk: UUID, n: usize, block: Block) -> Result<(), VolumeError> {
+        BlockStores::save_block(self, chunk, n, block)
+    }
+
+    fn contains(&self, id: UUID) -> bool {
+        BlockStores::contains(self, id)
+    }
+
+    fn ids(&self) -> Vec<UUID> {
+        BlockStores::ids(self)
+    }
+
+    fn free_chunk(&mut self, id: UUID) -> Result<(), VolumeError> {
+        BlockStores::free_file(self, id)
+    }
+
+    fn sync_metadata(&mut self) -> Result<(), VolumeError> {
+        BlockStores::sync_metadata(self)
+    }
+
+    fn sync_data(&mut self) -> Result<(), VolumeError> {
+        BlockStores::sync_data(self)
+    }
+
+    fn set_policy(&mut self, policy: Box<dyn Placement>) {
+        BlockStores::set_policy(self, policy)
+    }
+}
+
+// The metadata file.
+impl MetadataStore for FileVolumeManager {
+    fn uuid(&self) -> UUID {
+        self.super_block().uuid()
+    }
+
+    fn journal_begin(&mut self, id: UUID, chunks: &[UUID]) -> Result<(), VolumeError> {
+        FileVolumeManager::journal_begin(self, id, chunks)
+    }
+
+    fn journal_extend(&mut self, id: UUID, chunks: &[UUID]) -> Result<(), VolumeError> {
+        FileVolumeManager::journal_extend(self, id, chunks)
+    }
+
+    fn journal_commit(&mut self) -> Result<(), VolumeError> {
+        FileVolumeManager::journal_commit(self)
+    }
+
+    fn rollback(&self) -> &[UUID] {
+        FileVolumeManager::rollback(self)
+    }
+
+    fn finish_rollback(&mut self) -> Result<(), VolumeError> {
+        FileVolumeManager::finish_rollback(self)
+    }
+
+    fn reload(&mut self) -> Result<(), VolumeError> {
+        *self = FileVolumeManager::open_metadata(self.path())?;
+        Ok(())
+    }
+
+    fn sync_metadata(&mut self) -> Result<(), VolumeError> {
+        FileVolumeManager::sync_metadata(self)
+    }
+
+    fn save_file(&mut self, file: RedundantFile) -> Result<(), VolumeError> {
+        let pos = match self.find(file.id) {
+            Some(pos) => pos,
+            None => self.allocate_file(file.id)?,
+        };
+        FileVolumeManager::save_file(self, pos, file)
+    }
+
+    fn load_file(&self, id: UUID) -> Result<RedundantFile, VolumeError> {
+        FileVolumeManager::load_file(self, id)
+    }
+
+    fn contains_file(&self, id: UUID) -> bool {
+        self.find(id).is_some()
+    }
+
+    fn free_file(&mut self, id: UUID) -> Result<(), VolumeError> {
+        FileVolumeManager::free_file(self, id)
+    }
+
+    fn versioning(&self) -> bool {
+        FileVolumeManager::versioning(self)
+    }
+
+    fn set_versioning(&mut self, enabled: bool) -> Result<(), VolumeError> {
+        FileVolumeManager::set_versioning(self, enabled)
+    }
+
+    fn index_put(&mut self, entry: IndexEntry) -> Result<Vec<IndexEntry>, VolumeError> {
+        FileVolumeManager::index_put(self, entry)
+    }
+
+    fn index_restore(&mut self, entry: IndexEntry) -> Result<(), VolumeError> {
+        FileVolumeManager::index_restore(self, entry)
+    }
+
+    fn index_get(&self, name: &str) -> Option<IndexEntry> {
+        FileVolumeManager::index_get(self, name)
+    }
+
+    fn index_get_version(&self, name: &str, id: UUID) -> Option<IndexEntry> {
+        FileVolumeManager::index_get_version(self, name, id)
+    }
+
+    fn index_entries(&self) -> Vec<IndexEntry> {
+        FileVolumeManager::index_entries(self)
+    }
+
+    fn index_remove_all(&mut self, name: &str) -> Result<Vec<IndexEntry>, VolumeError> {
+        FileVolumeManager::index_remove_all(self, name)
+    }
+
+    fn index_remove_version(
+        &mut self,
+        name: &str,
+        id: UUID,
+    ) -> Result<Option<IndexEntry>, VolumeError> {
+        FileVolumeManager::index_remove_version(self, name, id)
+    }
+
+    fn list(
+        &self,
+        prefix: &str,
+        delimiter: Option<&str>,
+        start_after: Option<&str>,
+        limit: usize,
+    ) -> Result<ObjectList, VolumeError> {
+        FileVolumeManager::list(self, prefix, delimiter, start_after, limit)
+    }
+
+    fn list_versions(
+        &self,
+        prefix: &str,
+        start_after: Option<&str>,
+        limit: usize,
+    ) -> Result<ObjectList, VolumeError> {
+        FileVolumeManager::list_versions(self, prefix, start_after, limit)
+    }
+
+    fn create_snapshot(&mut self, name: &str, created: u64) -> Result<(), VolumeError> {
+        FileVolumeManager::create_snapshot(self, name, created)
+    }
+
+    fn delete_snapshot(&mut self, name: &str) -> Result<Snapshot, VolumeError> {
+        FileVolumeManager::delete_snapshot(self, name)
+    }
+
+    fn snapshots(&self) -> Vec<SnapshotSummary> {
+        FileVolumeManager::snapshots(self)
+    }
+
+    fn snapshot(&self, name: &str) -> Result<&Snapshot, VolumeError> {
+        FileVolumeManager::snapshot(self, name)
+    }
+
+    fn referenced(&self, id: UUID) -> bool {
+        FileVolumeManager::referenced(self, id)
+    }
+}
@@ -47,6 +47,9 @@ where
     D: Deserializer<'de>,
 {
     let array: &[u8] = Deserialize::deserialize(data)?;
+    if array.len() > BLOCK_SIZE {
+        return Err(serde::de::Error::invalid_length(array.len(), &"a block"));
+    }
     let mut buf = [0u8; BLOCK_SIZE];
     for i in 0..array.len() {
         buf[i] = array[i];
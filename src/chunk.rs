@@ -6,6 +6,7 @@ use crate::serde::Serializer;
 
 use crate::constants::{BLOCKS, BLOCK_SIZE, PARITY, READ_STEP};
 use crate::error::{RedundantFileError, VolumeError};
+use crate::label::LABEL_BYTES;
 use crate::uuid::Uuid;
 use crate::volume::Volume;
 use crate::UUID;
@@ -66,11 +67,37 @@ pub fn chunk_block_serialize(chunk: &Chunk, blocks: &Vec<Block>) -> Vec<u8> {
 }
 
 impl Chunk {
+    // Bytes taken by a chunk in the block file: its label, its header and
+    // then its blocks.
     pub fn size() -> usize {
-        let chunk_size = bincode::serialized_size(&Chunk::default()).unwrap() as usize;
+        let chunk_size = Chunk::header_size();
         let block_size = bincode::serialized_size(&Block::default()).unwrap() as usize;
 
-        chunk_size + block_size * (BLOCKS + PARITY)
+        LABEL_BYTES + chunk_size + block_size * (BLOCKS + PARITY)
+    }
+
+    // Bytes taken by one block of a chunk in a store of a sharded volume,
+    // which keeps the label and header with every block.
+    pub fn shard_size() -> usize {
+        LABEL_BYTES + Chunk::header_size() + Block::size() as usize
+    }
+
+    pub fn header_size() -> usize {
+        bincode::serialized_size(&Chunk::default()).unwrap() as usize
+    }
+
+    // Whether a header read back can be that of chunk `id`. Headers in the
+    // block file carry no checksum, so this is all that catches damage.
+    pub fn is_sound(&self, id: u128) -> bool {
+        self.id == id
+            && self.chunk_n == BLOCKS
+            && self.parity_n == PARITY
+            && self.chunk_size <= READ_STEP
+    }
+
+    // Where block `n` starts, counted from the start of the chunk's slot.
+    pub fn block_offset(n: usize) -> u64 {
+        (LABEL_BYTES + Chunk::header_size()) as u64 + (n as u64) * Block::size()
     }
 
     pub fn rebuild<T, W>(id: UUID, data_manager: &T, writer: &mut W) -> Result<(), VolumeError>
@@ -79,8 +106,7 @@ impl Chunk {
         W: std::io::Write,
     {
         let chunk: Box<Chunk> = data_manager.get_chunk(id)?;
-        chunk.inner_rebuild(data_manager, writer);
-        Ok(())
+        chunk.inner_rebuild(data_manager, writer)
     }
 
     pub fn inner_rebuild<T, W>(&self, data_manager: &T, writer: &mut W) -> Result<(), VolumeError>
@@ -88,19 +114,31 @@ impl Chunk {
         T: Volume,
         W: std::io::Write,
     {
-        let blocks: Vec<Box<Block>> = self
-            .blocks
-            .iter()
-            .map(|b| match data_manager.get_block(*b) {
-                Ok(data) => data,
-                Err(err) => {
-                    println!("{} missing block", *b);
-                    Box::new(Block::empty())
-                }
-            })
-            .collect();
+        let data = self.decode(self.load_blocks(data_manager))?;
 
-        let data = Chunk::rebuild_data(
+        writer.write_all(&data[..]).map_err(VolumeError::IoError)?;
+
+        Ok(())
+    }
+
+    // The blocks of the chunk as `data_manager` has them, and empty ones in
+    // place of those it couldn't read.
+    pub fn load_blocks<T>(&self, data_manager: &T) -> Vec<Box<Block>>
+    where
+        T: Volume,
+    {
+        // Too few of them is for decode to report.
+        data_manager
+            .get_chunk_blocks(self)
+            .into_iter()
+            .map(|b| b.unwrap_or_else(|| Box::new(Block::empty())))
+            .collect()
+    }
+
+    // The data of the chunk, rebuilt from `blocks` and checked against its
+    // hash.
+    pub fn decode(&self, blocks: Vec<Box<Block>>) -> Result<Vec<u8>, VolumeError> {
+        Chunk::rebuild_data(
             self.position,
             self.chunk_size,
             self.chunk_n,
@@ -108,11 +146,7 @@ impl Chunk {
             self.hash,
             blocks,
         )
-        .unwrap();
-
-        writer.write(&data[..]);
-
-        Ok(())
+        .map_err(VolumeError::FileError)
     }
 
     #[allow(clippy::needless_range_loop)]
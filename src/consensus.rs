@@ -0,0 +1,1345 @@
+use crate::crc32c::crc32c;
+use crate::directory::{read_file, seal, unseal, write_file};
+use crate::error::{ErrorLog, VolumeError};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+pub const TERM_MAGIC: [u8; 8] = *b"OGGRAFTT";
+pub const LOG_MAGIC: [u8; 8] = *b"OGGRAFTL";
+pub const SNAPSHOT_MAGIC: [u8; 8] = *b"OGGRAFTS";
+const LOG_HEADER_BYTES: usize = 8 + 8 + 8 + 4;
+
+// Messages are sent as their length and then bincode; none gets near this.
+pub const MAX_MESSAGE_BYTES: usize = 1 << 30;
+
+const HEARTBEAT: Duration = Duration::from_millis(50);
+const TICK: Duration = Duration::from_millis(10);
+const ELECTION_MS: (u64, u64) = (300, 600);
+const PEER_TIMEOUT: Duration = Duration::from_millis(200);
+const PROPOSE_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Entries sent to a peer at once, so that one far behind catches up in
+// steps of a bounded size.
+const MAX_BATCH: usize = 64;
+
+// Applied entries the log keeps before they are replaced by a snapshot of
+// the machine, which is what bounds the log.
+const SNAPSHOT_EVERY: u64 = 1024;
+
+// What a replica keeps a copy of. Entries are applied in the order the
+// log has them, the same on every replica, and have to change the machine
+// the same way everywhere.
+pub trait StateMachine: Send + 'static {
+    fn apply(&mut self, entry: &[u8]) -> Result<(), VolumeError>;
+
+    // All the machine holds, for a client to start from. It is also what
+    // the log is compacted into.
+    fn image(&self) -> Vec<u8>;
+
+    // Makes the machine what `image` says, as a snapshot has it.
+    fn restore(&mut self, image: &[u8]) -> Result<(), VolumeError>;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Role {
+    Follower,
+    Candidate,
+    Leader,
+}
+
+// What replicas say to each other, as Raft has it, and what clients ask of
+// them.
+#[derive(Debug, Serialize, Deserialize)]
+pub enum Message {
+    RequestVote {
+        term: u64,
+        candidate: usize,
+        last_index: u64,
+        last_term: u64,
+    },
+    Vote {
+        term: u64,
+        granted: bool,
+    },
+    Append {
+        term: u64,
+        leader: usize,
+        prev_index: u64,
+        prev_term: u64,
+        entries: Vec<(u64, Vec<u8>)>,
+        commit: u64,
+    },
+    Appended {
+        term: u64,
+        success: bool,
+        last: u64,
+    },
+    // What a leader sends a replica that is missing entries it no longer
+    // has in its log: the machine as of entry `index`.
+    InstallSnapshot {
+        term: u64,
+        leader: usize,
+        index: u64,
+        last_term: u64,
+        image: Vec<u8>,
+    },
+
+    // Appends `entry` if nothing but the empty entries of new leaders came
+    // after `base`, where the client saw the machine, and answers once it is
+    // committed.
+    Propose {
+        base: u64,
+        entry: Vec<u8>,
+    },
+    Proposed {
+        index: u64,
+    },
+    Fetch,
+    Image {
+        applied: u64,
+        image: Vec<u8>,
+    },
+    Status,
+    Replica {
+        id: usize,
+        role: Role,
+        term: u64,
+        leader: Option<usize>,
+        last: u64,
+        commit: u64,
+    },
+    NotLeader {
+        leader: Option<usize>,
+    },
+    Conflict {
+        last: u64,
+    },
+    Failed(String),
+}
+
+pub fn send(mut stream: &TcpStream, message: &Message) -> Result<(), VolumeError> {
+    let bytes = bincode::serialize(message).map_err(|_| VolumeError::BadFrame)?;
+    let mut buf = (bytes.len() as u32).to_le_bytes().to_vec();
+    buf.extend(bytes);
+    stream.write_all(&buf[..]).map_err(VolumeError::IoError)
+}
+
+pub fn receive(mut stream: &TcpStream) -> Result<Message, VolumeError> {
+    let mut len = [0u8; 4];
+    stream.read_exact(&mut len).map_err(VolumeError::IoError)?;
+    let len = u32::from_le_bytes(len) as usize;
+    if len > MAX_MESSAGE_BYTES {
+        return Err(VolumeError::BadFrame);
+    }
+    let mut buf = vec![0u8; len];
+    stream
+        .read_exact(&mut buf[..])
+        .map_err(VolumeError::IoError)?;
+    bincode::deserialize(&buf[..]).map_err(|_| VolumeError::BadFrame)
+}
+
+// Sends `message` to the replica at `addr` over a connection of its own
+// and waits up to `timeout` for the answer.
+pub fn call(addr: &str, message: &Message, timeout: Duration) -> Result<Message, VolumeError> {
+    let mut err = VolumeError::NoDataFound;
+    for addr in addr.to_socket_addrs().map_err(VolumeError::IoError)? {
+        let stream = match TcpStream::connect_timeout(&addr, timeout) {
+            Ok(stream) => stream,
+            Err(e) => {
+                err = VolumeError::IoError(e);
+                continue;
+            }
+        };
+        stream.set_nodelay(true).map_err(VolumeError::IoError)?;
+        stream
+            .set_read_timeout(Some(timeout))
+            .map_err(VolumeError::IoError)?;
+        stream
+            .set_write_timeout(Some(timeout))
+            .map_err(VolumeError::IoError)?;
+        send(&stream, message)?;
+        return receive(&stream);
+    }
+    Err(err)
+}
+
+// The log as kept on disk: a header with the index and term of the last
+// entry a snapshot took the place of, and then one record after the other,
+// each its term, its length, the entry and a CRC of all three. A record
+// that doesn't check out ends the log; it was being written when the
+// replica stopped.
+struct Log {
+    path: PathBuf,
+    file: File,
+    base: u64,
+    base_term: u64,
+    entries: Vec<(u64, Vec<u8>)>,
+    offsets: Vec<u64>,
+}
+
+impl Log {
+    fn open(path: &Path) -> Result<Log, VolumeError> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)
+            .map_err(VolumeError::IoError)?;
+        let mut bytes = Vec::new();
+        file.read_to_end(&mut bytes).map_err(VolumeError::IoError)?;
+        if bytes.is_empty() {
+            file.write_all(&log_header(0, 0))
+                .map_err(VolumeError::IoError)?;
+            file.sync_data().map_err(VolumeError::IoError)?;
+            bytes = log_header(0, 0);
+        }
+        let header = &bytes[..LOG_HEADER_BYTES.min(bytes.len())];
+        let (base, base_term) = parse_log_header(header).ok_or(VolumeError::CorruptMetadata(0))?;
+        let mut log = Log {
+            path: path.to_owned(),
+            file,
+            base,
+            base_term,
+            entries: Vec::new(),
+            offsets: Vec::new(),
+        };
+        let mut at = LOG_HEADER_BYTES;
+        while at + 16 <= bytes.len() {
+            let mut buf = [0u8; 8];
+            buf.copy_from_slice(&bytes[at..at + 8]);
+            let term = u64::from_le_bytes(buf);
+            let mut buf = [0u8; 4];
+            buf.copy_from_slice(&bytes[at + 8..at + 12]);
+            let len = u32::from_le_bytes(buf) as usize;
+            if at + 16 + len > bytes.len() {
+                break;
+            }
+            buf.copy_from_slice(&bytes[at + 12 + len..at + 16 + len]);
+            if crc32c(&bytes[at..at + 12 + len]) != u32::from_le_bytes(buf) {
+                break;
+            }
+            log.offsets.push(at as u64);
+            log.entries
+                .push((term, bytes[at + 12..at + 12 + len].to_vec()));
+            at += 16 + len;
+        }
+        log.file.set_len(at as u64).map_err(VolumeError::IoError)?;
+        Ok(log)
+    }
+
+    fn last(&self) -> u64 {
+        self.base + self.entries.len() as u64
+    }
+
+    // The term of entry `index`, counted from 1; 0 before the first, and
+    // before the base, whose entries are gone.
+    fn term(&self, index: u64) -> u64 {
+        match index {
+            n if n == self.base => self.base_term,
+            n if n < self.base => 0,
+            n => self
+                .entries
+                .get((n - self.base) as usize - 1)
+                .map(|e| e.0)
+                .unwrap_or(0),
+        }
+    }
+
+    // Entry `index`, which has to be past the base.
+    fn entry(&self, index: u64) -> &[u8] {
+        &self.entries[(index - self.base) as usize - 1].1[..]
+    }
+
+    // The entries after `index`, which can't be before the base.
+    fn since(&self, index: u64) -> &[(u64, Vec<u8>)] {
+        &self.entries[(index - self.base) as usize..]
+    }
+
+    // Drops every entry after `last`, which can't be before the base.
+    fn truncate(&mut self, last: u64) -> Result<(), VolumeError> {
+        let kept = (last - self.base) as usize;
+        let end = match self.offsets.get(kept) {
+            Some(end) => *end,
+            None => self
+                .file
+                .seek(SeekFrom::End(0))
+                .map_err(VolumeError::IoError)?,
+        };
+        self.entries.truncate(kept);
+        self.offsets.truncate(kept);
+        self.file.set_len(end).map_err(VolumeError::IoError)?;
+        self.file
+            .seek(SeekFrom::Start(end))
+            .map_err(VolumeError::IoError)?;
+        Ok(())
+    }
+
+    // Appends the entries and makes them durable.
+    fn append(&mut self, entries: Vec<(u64, Vec<u8>)>) -> Result<(), VolumeError> {
+        let mut at = self
+            .file
+            .seek(SeekFrom::End(0))
+            .map_err(VolumeError::IoError)?;
+        let mut buf = Vec::new();
+        for (term, entry) in entries {
+            let start = buf.len();
+            buf.extend_from_slice(&term.to_le_bytes());
+            buf.extend_from_slice(&(entry.len() as u32).to_le_bytes());
+            buf.extend_from_slice(&entry[..]);
+            let crc = crc32c(&buf[start..]);
+            buf.extend_from_slice(&crc.to_le_bytes());
+            self.offsets.push(at);
+            at += (buf.len() - start) as u64;
+            self.entries.push((term, entry));
+        }
+        self.file
+            .write_all(&buf[..])
+            .map_err(VolumeError::IoError)?;
+        self.file.sync_data().map_err(VolumeError::IoError)
+    }
+
+    // Makes entry `index` of `term`, which a snapshot now holds, the base.
+    // The entries after it stay if the log has that entry; otherwise they
+    // can't be the ones that followed it, and go too. The log is written
+    // anew next to the old one and then takes its place.
+    fn compact(&mut self, index: u64, term: u64) -> Result<(), VolumeError> {
+        let kept = match index <= self.last() && self.term(index) == term {
+            true => self.since(index.max(self.base)).to_vec(),
+            false => Vec::new(),
+        };
+        let mut bytes = log_header(index, term);
+        let mut offsets = Vec::new();
+        for (term, entry) in kept.iter() {
+            offsets.push(bytes.len() as u64);
+            let start = bytes.len();
+            bytes.extend_from_slice(&term.to_le_bytes());
+            bytes.extend_from_slice(&(entry.len() as u32).to_le_bytes());
+            bytes.extend_from_slice(&entry[..]);
+            let crc = crc32c(&bytes[start..]);
+            bytes.extend_from_slice(&crc.to_le_bytes());
+        }
+        let mut dirty = HashSet::new();
+        write_file(&self.path, &bytes[..], &mut dirty)?;
+        crate::directory::sync_dirs(&mut dirty)?;
+        self.file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&self.path)
+            .map_err(VolumeError::IoError)?;
+        self.base = index;
+        self.base_term = term;
+        self.entries = kept;
+        self.offsets = offsets;
+        Ok(())
+    }
+}
+
+fn log_header(base: u64, base_term: u64) -> Vec<u8> {
+    let mut header = LOG_MAGIC.to_vec();
+    header.extend_from_slice(&base.to_le_bytes());
+    header.extend_from_slice(&base_term.to_le_bytes());
+    let crc = crc32c(&header[..]);
+    header.extend_from_slice(&crc.to_le_bytes());
+    header
+}
+
+fn parse_log_header(header: &[u8]) -> Option<(u64, u64)> {
+    if header.len() < LOG_HEADER_BYTES || header[..8] != LOG_MAGIC {
+        return None;
+    }
+    let mut buf = [0u8; 4];
+    buf.copy_from_slice(&header[24..28]);
+    if crc32c(&header[..24]) != u32::from_le_bytes(buf) {
+        return None;
+    }
+    let mut buf = [0u8; 8];
+    buf.copy_from_slice(&header[8..16]);
+    let base = u64::from_le_bytes(buf);
+    buf.copy_from_slice(&header[16..24]);
+    Some((base, u64::from_le_bytes(buf)))
+}
+
+// One replica of a machine, kept the same as the others by Raft: a leader
+// is elected by a majority, appends what clients propose to its log and
+// has it copied to the others; an entry a majority has is committed and
+// applied everywhere. A leader that goes away is replaced by whichever
+// replica times out first with a log as long as a majority's.
+struct Raft<S> {
+    id: usize,
+    peers: Vec<String>,
+    dir: PathBuf,
+    term: u64,
+    voted_for: Option<usize>,
+    log: Log,
+    commit: u64,
+    applied: u64,
+    role: Role,
+    leader: Option<usize>,
+    votes: HashSet<usize>,
+    next: Vec<u64>,
+    matched: Vec<u64>,
+    deadline: Instant,
+    heartbeat: Instant,
+    heard: Vec<Instant>,
+    hurry: bool,
+    machine: S,
+    errors: ErrorLog,
+}
+
+// Messages for peers, and the peers to be sent the snapshot.
+type Outgoing = (Vec<(usize, Message)>, Vec<usize>);
+
+impl<S: StateMachine> Raft<S> {
+    fn open(
+        id: usize,
+        peers: Vec<String>,
+        dir: &Path,
+        machine: S,
+        errors: ErrorLog,
+    ) -> Result<Raft<S>, VolumeError> {
+        std::fs::create_dir_all(dir).map_err(VolumeError::IoError)?;
+        let (term, voted_for) = match read_file(&dir.join("term")) {
+            Ok(bytes) => {
+                let state = unseal(&TERM_MAGIC, &bytes[..])
+                    .filter(|s| s.len() == 16)
+                    .ok_or(VolumeError::CorruptSuperBlock)?;
+                let mut buf = [0u8; 8];
+                buf.copy_from_slice(&state[..8]);
+                let term = u64::from_le_bytes(buf);
+                buf.copy_from_slice(&state[8..]);
+                let voted_for = match u64::from_le_bytes(buf) {
+                    u64::MAX => None,
+                    n => Some(n as usize),
+                };
+                (term, voted_for)
+            }
+            Err(VolumeError::NoDataFound) => (0, None),
+            Err(err) => return Err(err),
+        };
+        let mut machine = machine;
+        let mut log = Log::open(&dir.join("log"))?;
+        let applied = match read_snapshot(dir)? {
+            Some((index, term, image)) => {
+                machine.restore(&image[..])?;
+                // The snapshot lasts before the log is compacted.
+                if log.base < index {
+                    log.compact(index, term)?;
+                }
+                index
+            }
+            None => 0,
+        };
+        if log.base > applied {
+            return Err(VolumeError::CorruptMetadata(log.base));
+        }
+        let count = peers.len();
+        Ok(Raft {
+            id,
+            peers,
+            dir: dir.to_owned(),
+            term,
+            voted_for,
+            log,
+            commit: applied,
+            applied,
+            role: Role::Follower,
+            leader: None,
+            votes: HashSet::new(),
+            next: vec![1; count],
+            matched: vec![0; count],
+            deadline: Instant::now() + election_timeout(),
+            heartbeat: Instant::now(),
+            heard: vec![Instant::now(); count],
+            hurry: false,
+            machine,
+            errors,
+        })
+    }
+
+    // The term and vote have to last before anyone is told of them.
+    fn persist(&self) -> Result<(), VolumeError> {
+        let mut state = self.term.to_le_bytes().to_vec();
+        let voted_for = self.voted_for.map(|v| v as u64).unwrap_or(u64::MAX);
+        state.extend_from_slice(&voted_for.to_le_bytes());
+        let mut dirty = HashSet::new();
+        write_file(
+            &self.dir.join("term"),
+            &seal(&TERM_MAGIC, &state[..])[..],
+            &mut dirty,
+        )?;
+        crate::directory::sync_dirs(&mut dirty)
+    }
+
+    fn majority(&self) -> usize {
+        self.peers.len() / 2 + 1
+    }
+
+    fn follow(&mut self, term: u64, leader: Option<usize>) -> Result<(), VolumeError> {
+        if term > self.term {
+            self.term = term;
+            self.voted_for = None;
+            self.persist()?;
+        }
+        self.role = Role::Follower;
+        self.leader = leader;
+        self.deadline = Instant::now() + election_timeout();
+        Ok(())
+    }
+
+    fn apply(&mut self) -> Result<(), VolumeError> {
+        while self.applied < self.commit {
+            self.applied += 1;
+            let entry = self.log.entry(self.applied);
+            // Empty entries are the ones new leaders start their term with.
+            // Ones the machine can't apply are skipped on every replica alike.
+            if entry.is_empty() {
+                continue;
+            }
+            if let Err(err) = self.machine.apply(entry) {
+                (self.errors)(&format!("log entry {}", self.applied), &err);
+            }
+        }
+        if self.applied - self.log.base >= SNAPSHOT_EVERY {
+            self.snapshot()?;
+        }
+        Ok(())
+    }
+
+    // Replaces the applied entries of the log with the machine they made.
+    fn snapshot(&mut self) -> Result<(), VolumeError> {
+        let term = self.log.term(self.applied);
+        write_snapshot(&self.dir, self.applied, term, &self.machine.image())?;
+        self.log.compact(self.applied, term)
+    }
+
+    // Takes the leader's snapshot in place of the entries up to `index`,
+    // unless they are applied already.
+    fn install(
+        &mut self,
+        term: u64,
+        leader: usize,
+        index: u64,
+        last_term: u64,
+        image: Vec<u8>,
+    ) -> Result<Message, VolumeError> {
+        if term < self.term {
+            return Ok(Message::Appended {
+                term: self.term,
+                success: false,
+                last: self.log.last(),
+            });
+        }
+        self.follow(term, Some(leader))?;
+        if index > self.applied {
+            self.machine.restore(&image[..])?;
+            write_snapshot(&self.dir, index, last_term, &image[..])?;
+            self.log.compact(index, last_term)?;
+            self.commit = index;
+            self.applied = index;
+        }
+        Ok(Message::Appended {
+            term: self.term,
+            success: true,
+            last: index,
+        })
+    }
+
+    fn request_vote(
+        &mut self,
+        term: u64,
+        candidate: usize,
+        last_index: u64,
+        last_term: u64,
+    ) -> Result<Message, VolumeError> {
+        if term > self.term {
+            self.follow(term, None)?;
+        }
+        let ours = (self.log.term(self.log.last()), self.log.last());
+        let granted = term == self.term
+            && self.voted_for.is_none_or(|v| v == candidate)
+            && (last_term, last_index) >= ours;
+        if granted {
+            self.voted_for = Some(candidate);
+            self.persist()?;
+            self.deadline = Instant::now() + election_timeout();
+        }
+        Ok(Message::Vote {
+            term: self.term,
+            granted,
+        })
+    }
+
+    fn append(
+        &mut self,
+        term: u64,
+        leader: usize,
+        prev_index: u64,
+        prev_term: u64,
+        entries: Vec<(u64, Vec<u8>)>,
+        commit: u64,
+    ) -> Result<Message, VolumeError> {
+        if term < self.term {
+            return Ok(Message::Appended {
+                term: self.term,
+                success: false,
+                last: self.log.last(),
+            });
+        }
+        self.follow(term, Some(leader))?;
+        let last = prev_index + entries.len() as u64;
+        // Entries up to the base are committed, and so the leader's too.
+        let (prev_index, prev_term, entries) = match prev_index < self.log.base {
+            true => {
+                let skip = (self.log.base - prev_index).min(entries.len() as u64);
+                let entries = entries.into_iter().skip(skip as usize).collect();
+                (prev_index + skip, self.log.term(prev_index + skip), entries)
+            }
+            false => (prev_index, prev_term, entries),
+        };
+        if prev_index > self.log.last() || self.log.term(prev_index) != prev_term {
+            return Ok(Message::Appended {
+                term: self.term,
+                success: false,
+                last: self.log.last().min(prev_index.saturating_sub(1)),
+            });
+        }
+        let mut new = Vec::new();
+        for (n, entry) in entries.into_iter().enumerate() {
+            let index = prev_index + 1 + n as u64;
+            if !new.is_empty() || index > self.log.last() {
+                new.push(entry);
+            } else if self.log.term(index) != entry.0 {
+                self.log.truncate(index - 1)?;
+                new.push(entry);
+            }
+        }
+        if !new.is_empty() {
+            self.log.append(new)?;
+        }
+        if commit > self.commit {
+            self.commit = commit.min(last).max(self.commit);
+            self.apply()?;
+        }
+        Ok(Message::Appended {
+            term: self.term,
+            success: true,
+            last,
+        })
+    }
+
+    // What the replica has to send now: heartbeats and entries if it
+    // leads, votes requests if it has waited too long for a leader. Peers
+    // missing entries the log no longer has come apart, to be sent the
+    // snapshot that took their place.
+    fn outgoing(&mut self) -> Result<Outgoing, VolumeError> {
+        self.check_lease();
+        let now = Instant::now();
+        let id = self.id;
+        let others = (0..self.peers.len()).filter(move |p| *p != id);
+        match self.role {
+            Role::Leader if self.hurry || now >= self.heartbeat => {
+                self.hurry = false;
+                self.heartbeat = now + HEARTBEAT;
+                let mut messages = Vec::new();
+                let mut behind = Vec::new();
+                for p in others {
+                    let prev_index = self.next[p] - 1;
+                    if prev_index < self.log.base {
+                        behind.push(p);
+                        continue;
+                    }
+                    let entries = self
+                        .log
+                        .since(prev_index)
+                        .iter()
+                        .take(MAX_BATCH)
+                        .cloned()
+                        .collect();
+                    let message = Message::Append {
+                        term: self.term,
+                        leader: self.id,
+                        prev_index,
+                        prev_term: self.log.term(prev_index),
+                        entries,
+                        commit: self.commit,
+                    };
+                    messages.push((p, message));
+                }
+                Ok((messages, behind))
+            }
+            Role::Follower | Role::Candidate if now >= self.deadline => {
+                self.term += 1;
+                self.role = Role::Candidate;
+                self.leader = None;
+                self.voted_for = Some(self.id);
+                self.votes = std::iter::once(self.id).collect();
+                self.persist()?;
+                self.deadline = now + election_timeout();
+                let last_index = self.log.last();
+                let last_term = self.log.term(last_index);
+                let term = self.term;
+                let messages = others
+                    .map(|p| {
+                        let message = Message::RequestVote {
+                            term,
+                            candidate: id,
+                            last_index,
+                            last_term,
+                        };
+                        (p, message)
+                    })
+                    .collect();
+                // Alone, a replica is its own majority.
+                self.count_votes()?;
+                Ok((messages, Vec::new()))
+            }
+            _ => Ok((Vec::new(), Vec::new())),
+        }
+    }
+
+    fn count_votes(&mut self) -> Result<(), VolumeError> {
+        if self.role != Role::Candidate || self.votes.len() < self.majority() {
+            return Ok(());
+        }
+        self.role = Role::Leader;
+        self.leader = Some(self.id);
+        self.next = vec![self.log.last() + 1; self.peers.len()];
+        self.matched = vec![0; self.peers.len()];
+        self.heard = vec![Instant::now(); self.peers.len()];
+        // Entries of earlier terms only commit along with one of this term.
+        self.log.append(vec![(self.term, Vec::new())])?;
+        self.advance()?;
+        self.hurry = true;
+        Ok(())
+    }
+
+    // A leader that hasn't heard from a majority for as long as the shortest
+    // election timeout may have been replaced already, and stops answering
+    // clients as one.
+    fn check_lease(&mut self) {
+        if self.role != Role::Leader {
+            return;
+        }
+        let lease = Duration::from_millis(ELECTION_MS.0);
+        let heard = (0..self.peers.len())
+            .filter(|p| *p == self.id || self.heard[*p].elapsed() < lease)
+            .count();
+        if heard < self.majority() {
+            self.role = Role::Follower;
+            self.leader = None;
+            self.deadline = Instant::now() + election_timeout();
+        }
+    }
+
+    // Commits what a majority has, as long as it is of this term.
+    fn advance(&mut self) -> Result<(), VolumeError> {
+        let mut matched = self.matched.clone();
+        matched[self.id] = self.log.last();
+        matched.sort_unstable_by(|a, b| b.cmp(a));
+        let commit = matched[self.majority() - 1];
+        if commit > self.commit && self.log.term(commit) == self.term {
+            self.commit = commit;
+            self.apply()?;
+        }
+        Ok(())
+    }
+
+    fn reply(&mut self, peer: usize, reply: Message) -> Result<(), VolumeError> {
+        match reply {
+            Message::Vote { term, .. } | Message::Appended { term, .. } if term > self.term => {
+                self.follow(term, None)
+            }
+            Message::Vote { term, granted } => {
+                if granted && term == self.term {
+                    self.votes.insert(peer);
+                    self.count_votes()?;
+                }
+                Ok(())
+            }
+            Message::Appended {
+                term,
+                success,
+                last,
+            } if term == self.term && self.role == Role::Leader => {
+                self.heard[peer] = Instant::now();
+                if success {
+                    self.matched[peer] = self.matched[peer].max(last);
+                    self.next[peer] = self.matched[peer] + 1;
+                    if self.next[peer] <= self.log.last() {
+                        self.hurry = true;
+                    }
+                    self.advance()?;
+                } else {
+                    self.next[peer] = (last + 1).min(self.next[peer] - 1).max(1);
+                    self.hurry = true;
+                }
+                Ok(())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    // Answers what another replica sent.
+    fn answer(&mut self, message: Message) -> Result<Message, VolumeError> {
+        match message {
+            Message::RequestVote {
+                term,
+                candidate,
+                last_index,
+                last_term,
+            } => self.request_vote(term, candidate, last_index, last_term),
+            Message::Append {
+                term,
+                leader,
+                prev_index,
+                prev_term,
+                entries,
+                commit,
+            } => self.append(term, leader, prev_index, prev_term, entries, commit),
+            Message::InstallSnapshot {
+                term,
+                leader,
+                index,
+                last_term,
+                image,
+            } => self.install(term, leader, index, last_term, image),
+            _ => Err(VolumeError::BadFrame),
+        }
+    }
+
+    fn status(&self) -> Message {
+        Message::Replica {
+            id: self.id,
+            role: self.role,
+            term: self.term,
+            leader: self.leader,
+            last: self.log.last(),
+            commit: self.commit,
+        }
+    }
+}
+
+// The snapshot in `dir`: the index and term of the last entry it holds,
+// and the machine as of then.
+fn read_snapshot(dir: &Path) -> Result<Option<(u64, u64, Vec<u8>)>, VolumeError> {
+    let bytes = match read_file(&dir.join("snapshot")) {
+        Ok(bytes) => bytes,
+        Err(VolumeError::NoDataFound) => return Ok(None),
+        Err(err) => return Err(err),
+    };
+    let snapshot = unseal(&SNAPSHOT_MAGIC, &bytes[..]).ok_or(VolumeError::CorruptSuperBlock)?;
+    bincode::deserialize(snapshot)
+        .map(Some)
+        .map_err(|_| VolumeError::CorruptSuperBlock)
+}
+
+// The snapshot in `dir`, as leader `leader` of term `term` sends it to each
+// of the peers `behind`.
+fn snapshots_for(
+    dir: &Path,
+    term: u64,
+    leader: usize,
+    behind: &[usize],
+) -> Result<Vec<(usize, Message)>, VolumeError> {
+    if behind.is_empty() {
+        return Ok(Vec::new());
+    }
+    let (index, last_term, image) = read_snapshot(dir)?.ok_or(VolumeError::NoDataFound)?;
+    Ok(behind
+        .iter()
+        .map(|p| {
+            let message = Message::InstallSnapshot {
+                term,
+                leader,
+                index,
+                last_term,
+                image: image.clone(),
+            };
+            (*p, message)
+        })
+        .collect())
+}
+
+fn write_snapshot(dir: &Path, index: u64, term: u64, image: &[u8]) -> Result<(), VolumeError> {
+    let snapshot = bincode::serialize(&(index, term, image))
+        .map_err(|err| VolumeError::IoError(std::io::Error::other(err)))?;
+    let mut dirty = HashSet::new();
+    write_file(
+        &dir.join("snapshot"),
+        &seal(&SNAPSHOT_MAGIC, &snapshot[..])[..],
+        &mut dirty,
+    )?;
+    crate::directory::sync_dirs(&mut dirty)
+}
+
+fn election_timeout() -> Duration {
+    Duration::from_millis(rand::thread_rng().gen_range(ELECTION_MS.0, ELECTION_MS.1))
+}
+
+struct Replica<S> {
+    raft: Mutex<Raft<S>>,
+    changed: Condvar,
+}
+
+impl<S: StateMachine> Replica<S> {
+    fn lock(&self) -> MutexGuard<'_, Raft<S>> {
+        self.raft.lock().unwrap()
+    }
+
+    // Waits until `done` holds, the replica stops leading or time runs out.
+    fn wait_leading<'a>(
+        &'a self,
+        mut raft: MutexGuard<'a, Raft<S>>,
+        term: u64,
+        until: Instant,
+        done: &dyn Fn(&Raft<S>) -> bool,
+    ) -> (MutexGuard<'a, Raft<S>>, Option<Message>) {
+        loop {
+            if raft.role != Role::Leader || raft.term != term {
+                let leader = raft.leader;
+                return (raft, Some(Message::NotLeader { leader }));
+            }
+            if done(&raft) {
+                return (raft, None);
+            }
+            let now = Instant::now();
+            if now >= until {
+                return (raft, Some(Message::Failed("timed out".to_owned())));
+            }
+            raft = self.changed.wait_timeout(raft, until - now).unwrap().0;
+        }
+    }
+
+    fn propose(&self, base: u64, entry: Vec<u8>) -> Result<Message, VolumeError> {
+        let mut raft = self.lock();
+        raft.check_lease();
+        if raft.role != Role::Leader {
+            return Ok(Message::NotLeader {
+                leader: raft.leader,
+            });
+        }
+        let term = raft.term;
+        let until = Instant::now() + PROPOSE_TIMEOUT;
+        let (mut raft, failed) =
+            self.wait_leading(raft, term, until, &|r| r.applied == r.log.last());
+        if let Some(failed) = failed {
+            return Ok(failed);
+        }
+        let last = raft.log.last();
+        if base > last
+            || base < raft.log.base
+            || raft.log.since(base).iter().any(|e| !e.1.is_empty())
+        {
+            return Ok(Message::Conflict {
+                last: raft.log.last(),
+            });
+        }
+        raft.log.append(vec![(term, entry)])?;
+        let index = raft.log.last();
+        raft.advance()?;
+        raft.hurry = true;
+        self.changed.notify_all();
+        let (raft, failed) = self.wait_leading(raft, term, until, &|r| r.commit >= index);
+        match failed {
+            Some(failed) => Ok(failed),
+            None if raft.log.term(index) == term => Ok(Message::Proposed { index }),
+            None => Ok(Message::Failed("lost to another leader".to_owned())),
+        }
+    }
+
+    fn fetch(&self) -> Message {
+        let mut raft = self.lock();
+        raft.check_lease();
+        if raft.role != Role::Leader {
+            return Message::NotLeader {
+                leader: raft.leader,
+            };
+        }
+        let term = raft.term;
+        let until = Instant::now() + PROPOSE_TIMEOUT;
+        let (raft, failed) = self.wait_leading(raft, term, until, &|r| r.applied == r.log.last());
+        match failed {
+            Some(failed) => failed,
+            None => Message::Image {
+                applied: raft.applied,
+                image: raft.machine.image(),
+            },
+        }
+    }
+
+    fn handle(&self, message: Message) -> Result<Message, VolumeError> {
+        let reply = match message {
+            Message::Propose { base, entry } => self.propose(base, entry)?,
+            Message::Fetch => self.fetch(),
+            Message::Status => self.lock().status(),
+            message => self.lock().answer(message)?,
+        };
+        self.changed.notify_all();
+        Ok(reply)
+    }
+
+    // Sends what has to be sent, waits for the answers and acts on them,
+    // for as long as the replica runs. A snapshot can be big, so it is read
+    // once the replica is unlocked.
+    fn tick(&self) -> Result<(), VolumeError> {
+        loop {
+            let (peers, dir, term, id, (mut outgoing, behind)) = {
+                let mut raft = self.lock();
+                if !raft.hurry {
+                    raft = self.changed.wait_timeout(raft, TICK).unwrap().0;
+                }
+                let outgoing = raft.outgoing()?;
+                (
+                    raft.peers.clone(),
+                    raft.dir.clone(),
+                    raft.term,
+                    raft.id,
+                    outgoing,
+                )
+            };
+            outgoing.extend(snapshots_for(&dir, term, id, &behind)?);
+            if outgoing.is_empty() {
+                continue;
+            }
+            let replies: Vec<(usize, Message)> = std::thread::scope(|s| {
+                let calls: Vec<_> = outgoing
+                    .iter()
+                    .map(|(p, message)| {
+                        let addr = &peers[*p];
+                        s.spawn(move || (*p, call(addr, message, PEER_TIMEOUT)))
+                    })
+                    .collect();
+                calls
+                    .into_iter()
+                    .filter_map(|c| c.join().ok())
+                    .filter_map(|(p, reply)| reply.ok().map(|r| (p, r)))
+                    .collect()
+            });
+            let mut raft = self.lock();
+            for (p, reply) in replies {
+                raft.reply(p, reply)?;
+            }
+            drop(raft);
+            self.changed.notify_all();
+        }
+    }
+}
+
+// Asks the replica at `addr` what it is doing.
+pub fn status(addr: &str) -> Result<Message, VolumeError> {
+    call(addr, &Message::Status, PEER_TIMEOUT)
+}
+
+// Runs replica `id` of those at `peers`, keeping its log and vote in `dir`
+// and answering on `listener`, until something fails. Entries the machine
+// can't apply are reported to `errors` and skipped.
+pub fn serve<S: StateMachine>(
+    id: usize,
+    peers: Vec<String>,
+    dir: &Path,
+    machine: S,
+    listener: TcpListener,
+    errors: ErrorLog,
+) -> Result<(), VolumeError> {
+    if id >= peers.len() {
+        return Err(VolumeError::StoreMismatch(id.to_string()));
+    }
+    let replica = Arc::new(Replica {
+        raft: Mutex::new(Raft::open(id, peers, dir, machine, errors)?),
+        changed: Condvar::new(),
+    });
+    // Whichever of the ticker and the listener fails first stops the
+    // replica.
+    let (stopped, stop) = std::sync::mpsc::channel();
+    let ticker = replica.clone();
+    let ticker_stopped = stopped.clone();
+    std::thread::spawn(move || {
+        let _ = ticker_stopped.send(ticker.tick());
+    });
+    std::thread::spawn(move || {
+        let _ = stopped.send(accept(listener, replica));
+    });
+    stop.recv().unwrap_or(Err(VolumeError::WorkerFailed))
+}
+
+// Answers whoever connects to the replica, each connection in a thread of
+// its own, until the listener fails.
+fn accept<S: StateMachine>(
+    listener: TcpListener,
+    replica: Arc<Replica<S>>,
+) -> Result<(), VolumeError> {
+    for stream in listener.incoming() {
+        let stream = stream.map_err(VolumeError::IoError)?;
+        let replica = replica.clone();
+        std::thread::spawn(move || {
+            while let Ok(message) = receive(&stream) {
+                let reply = match replica.handle(message) {
+                    Ok(reply) => reply,
+                    Err(VolumeError::BadFrame) => break,
+                    Err(err) => Message::Failed(format!("{:?}", err)),
+                };
+                if send(&stream, &reply).is_err() {
+                    break;
+                }
+            }
+        });
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{snapshots_for, Log, Raft, Role, StateMachine, SNAPSHOT_EVERY};
+    use crate::error::VolumeError;
+    use crate::test_util::Scratch;
+    use std::path::{Path, PathBuf};
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+
+    // Keeps every entry applied, one after the other.
+    #[derive(Default)]
+    struct Tally(Vec<u8>);
+
+    impl StateMachine for Tally {
+        fn apply(&mut self, entry: &[u8]) -> Result<(), VolumeError> {
+            self.0.extend_from_slice(entry);
+            Ok(())
+        }
+
+        fn image(&self) -> Vec<u8> {
+            self.0.clone()
+        }
+
+        fn restore(&mut self, image: &[u8]) -> Result<(), VolumeError> {
+            self.0 = image.to_vec();
+            Ok(())
+        }
+    }
+
+    // Replicas that talk to each other only when a test has them, and stand
+    // for election only when told to.
+    fn replicas(scratch: &Scratch, count: usize) -> Vec<Raft<Tally>> {
+        let peers: Vec<String> = (0..count).map(|n| n.to_string()).collect();
+        let mut replicas: Vec<Raft<Tally>> = (0..count)
+            .map(|n| {
+                let dir = PathBuf::from(scratch.path(&format!("replica{}", n)));
+                let errors = Arc::new(|_: &str, _: &VolumeError| {});
+                Raft::open(n, peers.clone(), &dir, Tally::default(), errors).unwrap()
+            })
+            .collect();
+        calm(&mut replicas);
+        replicas
+    }
+
+    fn calm(replicas: &mut [Raft<Tally>]) {
+        for raft in replicas.iter_mut() {
+            raft.deadline = Instant::now() + Duration::from_secs(3600);
+        }
+    }
+
+    // Has every replica that is up send what it has to, and the replicas it
+    // goes to that are up answer.
+    fn round(replicas: &mut [Raft<Tally>], up: &[bool]) {
+        for n in 0..replicas.len() {
+            if !up[n] {
+                continue;
+            }
+            replicas[n].hurry = true;
+            let (mut messages, behind) = replicas[n].outgoing().unwrap();
+            let (term, dir) = (replicas[n].term, replicas[n].dir.clone());
+            messages.extend(snapshots_for(&dir, term, n, &behind).unwrap());
+            for (p, message) in messages {
+                if up[p] {
+                    let reply = replicas[p].answer(message).unwrap();
+                    replicas[n].reply(p, reply).unwrap();
+                }
+            }
+        }
+        calm(replicas);
+    }
+
+    fn elect(replicas: &mut [Raft<Tally>], up: &[bool], candidate: usize) {
+        replicas[candidate].deadline = Instant::now();
+        round(replicas, up);
+    }
+
+    fn propose(raft: &mut Raft<Tally>, entry: &[u8]) {
+        let term = raft.term;
+        raft.log.append(vec![(term, entry.to_vec())]).unwrap();
+        raft.advance().unwrap();
+    }
+
+    fn leaders(replicas: &[Raft<Tally>], up: &[bool]) -> Vec<usize> {
+        (0..replicas.len())
+            .filter(|n| up[*n] && replicas[*n].role == Role::Leader)
+            .collect()
+    }
+
+    #[test]
+    fn a_candidate_a_majority_votes_for_leads() {
+        let scratch = Scratch::new();
+        let mut replicas = replicas(&scratch, 3);
+        let up = [true, true, true];
+        elect(&mut replicas, &up, 1);
+        assert_eq!(leaders(&replicas, &up), vec![1]);
+        round(&mut replicas, &up);
+        assert!(replicas.iter().all(|r| r.leader == Some(1) && r.term == 1));
+
+        // Without a majority, nobody leads.
+        let mut replicas = self::replicas(&Scratch::new(), 3);
+        let alone = [true, false, false];
+        elect(&mut replicas, &alone, 0);
+        assert_eq!(replicas[0].role, Role::Candidate);
+        assert!(leaders(&replicas, &alone).is_empty());
+    }
+
+    #[test]
+    fn a_new_leader_keeps_every_committed_entry() {
+        let scratch = Scratch::new();
+        let mut replicas = replicas(&scratch, 3);
+        let mut up = [true, true, true];
+        elect(&mut replicas, &up, 0);
+        propose(&mut replicas[0], b"a");
+        propose(&mut replicas[0], b"b");
+        round(&mut replicas, &up);
+        round(&mut replicas, &up);
+        assert!(replicas.iter().all(|r| r.machine.0 == b"ab"));
+
+        // The leader takes an entry nobody else gets and goes away.
+        propose(&mut replicas[0], b"x");
+        up[0] = false;
+
+        // Both that are left have every committed entry, so either can win.
+        elect(&mut replicas, &up, 2);
+        assert_eq!(leaders(&replicas, &up), vec![2]);
+        propose(&mut replicas[2], b"c");
+        round(&mut replicas, &up);
+        round(&mut replicas, &up);
+        assert_eq!(replicas[1].machine.0, b"abc");
+        assert_eq!(replicas[2].machine.0, b"abc");
+
+        // The old leader comes back, follows and drops the entry that
+        // never committed.
+        up[0] = true;
+        round(&mut replicas, &up);
+        round(&mut replicas, &up);
+        round(&mut replicas, &up);
+        assert_eq!(replicas[0].role, Role::Follower);
+        assert_eq!(replicas[0].leader, Some(2));
+        assert_eq!(replicas[0].machine.0, b"abc");
+        assert_eq!(replicas[0].log.last(), replicas[2].log.last());
+    }
+
+    #[test]
+    fn a_replica_with_a_shorter_log_is_not_elected() {
+        let scratch = Scratch::new();
+        let mut replicas = replicas(&scratch, 3);
+        let mut up = [true, true, true];
+        elect(&mut replicas, &up, 0);
+        up[2] = false;
+        propose(&mut replicas[0], b"a");
+        round(&mut replicas, &up);
+        assert_eq!(replicas[0].commit, replicas[0].log.last());
+
+        up[0] = false;
+        up[2] = true;
+        elect(&mut replicas, &up, 2);
+        assert!(leaders(&replicas, &up).is_empty());
+        elect(&mut replicas, &up, 1);
+        assert_eq!(leaders(&replicas, &up), vec![1]);
+        for _ in 0..5 {
+            round(&mut replicas, &up);
+        }
+        assert_eq!(replicas[2].machine.0, b"a");
+    }
+
+    #[test]
+    fn a_replica_far_behind_catches_up_from_the_snapshot() {
+        let scratch = Scratch::new();
+        let mut replicas = replicas(&scratch, 3);
+        let mut up = [true, true, false];
+        elect(&mut replicas, &up, 0);
+        let entries: Vec<(u64, Vec<u8>)> = (0..SNAPSHOT_EVERY + 100)
+            .map(|n| (replicas[0].term, vec![n as u8]))
+            .collect();
+        replicas[0].log.append(entries).unwrap();
+        while replicas[1].applied < replicas[0].log.last() {
+            round(&mut replicas, &up);
+        }
+        assert!(replicas[0].log.base > 0);
+
+        up[2] = true;
+        for _ in 0..10 {
+            round(&mut replicas, &up);
+        }
+        assert_eq!(replicas[2].applied, replicas[0].applied);
+        assert_eq!(replicas[2].machine.0, replicas[0].machine.0);
+        assert_eq!(replicas[2].log.last(), replicas[0].log.last());
+    }
+
+    fn entries(terms: &[u64]) -> Vec<(u64, Vec<u8>)> {
+        terms.iter().map(|t| (*t, vec![*t as u8; 3])).collect()
+    }
+
+    fn logged(path: &Path, terms: &[u64]) -> Log {
+        let mut log = Log::open(path).unwrap();
+        log.append(entries(terms)).unwrap();
+        log
+    }
+
+    #[test]
+    fn log_reads_back_after_truncation() {
+        let scratch = Scratch::new();
+        let path = PathBuf::from(scratch.path("log"));
+        let mut log = logged(&path, &[1, 1, 2, 3]);
+        log.truncate(2).unwrap();
+        log.append(entries(&[4])).unwrap();
+        drop(log);
+
+        let log = Log::open(&path).unwrap();
+        assert_eq!(log.last(), 3);
+        assert_eq!(log.term(3), 4);
+        assert_eq!(log.since(0), &entries(&[1, 1, 4])[..]);
+    }
+
+    #[test]
+    fn compaction_keeps_the_entries_after_the_snapshot() {
+        let scratch = Scratch::new();
+        let path = PathBuf::from(scratch.path("log"));
+        let mut log = logged(&path, &[1, 1, 2, 2, 3]);
+        log.compact(3, 2).unwrap();
+        log.append(entries(&[3])).unwrap();
+        drop(log);
+
+        let mut log = Log::open(&path).unwrap();
+        assert_eq!((log.base, log.base_term, log.last()), (3, 2, 6));
+        assert_eq!(log.term(3), 2);
+        assert_eq!(log.term(4), 2);
+        assert_eq!(log.entry(5), &[3u8; 3][..]);
+        assert_eq!(log.since(3), &entries(&[2, 3, 3])[..]);
+        log.truncate(4).unwrap();
+        assert_eq!(log.last(), 4);
+    }
+
+    #[test]
+    fn compaction_past_a_different_entry_drops_the_rest() {
+        let scratch = Scratch::new();
+        let path = PathBuf::from(scratch.path("log"));
+        let mut log = logged(&path, &[1, 1, 2]);
+        log.compact(2, 5).unwrap();
+        assert_eq!((log.base, log.last()), (2, 2));
+        log.compact(10, 6).unwrap();
+        drop(log);
+
+        let log = Log::open(&path).unwrap();
+        assert_eq!((log.base, log.base_term, log.last()), (10, 6, 10));
+    }
+}
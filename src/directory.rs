@@ -0,0 +1,727 @@
+use crate::backend::{BlockStore, MetadataStore};
+use crate::block::Block;
+use crate::chunk::Chunk;
+use crate::crc32c::crc32c;
+use crate::data_encoding::HEXLOWER;
+use crate::error::VolumeError;
+use crate::index::{IndexEntry, IndexPage, ObjectIndex, ObjectList, INDEX_PAGE_BYTES};
+use crate::label::{ChunkLabel, LABEL_BYTES};
+use crate::redundant_file::RedundantFile;
+use crate::snapshot::{Snapshot, SnapshotSummary};
+use crate::volume::BigFileVolume;
+use crate::UUID;
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+pub const STORE_MAGIC: [u8; 8] = *b"OGGDIRST";
+pub const CHUNK_MAGIC: [u8; 8] = *b"OGGDIRCH";
+pub const VOLUME_MAGIC: [u8; 8] = *b"OGGDIRVL";
+pub const INDEX_MAGIC: [u8; 8] = *b"OGGDIRIX";
+pub const FILE_MAGIC: [u8; 8] = *b"OGGDIRFL";
+pub const SNAPSHOT_MAGIC: [u8; 8] = *b"OGGDIRSN";
+pub const JOURNAL_MAGIC: [u8; 8] = *b"OGGDIRJN";
+
+const VERSIONING: u64 = 1;
+
+// Opens the volume kept as files under `root`, creating it if there is
+// nothing there yet.
+pub fn open(root: &str) -> Result<BigFileVolume, VolumeError> {
+    let root = Path::new(root);
+    let meta_data = match root.join("volume").exists() {
+        true => Some(DirMetadataStore::open(root)?),
+        false => None,
+    };
+    let blocks = match root.join("store").exists() {
+        true => Some(DirBlockStore::open(root)?),
+        false => None,
+    };
+    let uuid = meta_data
+        .as_ref()
+        .map(MetadataStore::uuid)
+        .or(blocks.as_ref().map(BlockStore::uuid))
+        .unwrap_or_else(|| uuid::Uuid::new_v4().as_u128());
+    let meta_data = match meta_data {
+        Some(meta_data) => meta_data,
+        None => DirMetadataStore::init(root, uuid)?,
+    };
+    let blocks = match blocks {
+        Some(blocks) => blocks,
+        None => DirBlockStore::init(root, uuid)?,
+    };
+    BigFileVolume::with_backends(Box::new(meta_data), Box::new(blocks))
+}
+
+// Every file the backends write is `magic`, what it holds and a CRC of both.
+pub(crate) fn seal(magic: &[u8; 8], payload: &[u8]) -> Vec<u8> {
+    let mut buf = magic.to_vec();
+    buf.extend_from_slice(payload);
+    let crc = crc32c(&buf[..]);
+    buf.extend_from_slice(&crc.to_le_bytes());
+    buf
+}
+
+pub(crate) fn unseal<'a>(magic: &[u8; 8], bytes: &'a [u8]) -> Option<&'a [u8]> {
+    if bytes.len() < 12 || bytes[..8] != magic[..] {
+        return None;
+    }
+    let (payload, crc) = bytes.split_at(bytes.len() - 4);
+    let mut buf = [0u8; 4];
+    buf.copy_from_slice(crc);
+    match crc32c(payload) == u32::from_le_bytes(buf) {
+        true => Some(&payload[8..]),
+        false => None,
+    }
+}
+
+fn u64_at(bytes: &[u8], at: usize) -> u64 {
+    let mut buf = [0u8; 8];
+    buf.copy_from_slice(&bytes[at..at + 8]);
+    u64::from_le_bytes(buf)
+}
+
+pub(crate) fn u128_at(bytes: &[u8], at: usize) -> u128 {
+    let mut buf = [0u8; 16];
+    buf.copy_from_slice(&bytes[at..at + 16]);
+    u128::from_le_bytes(buf)
+}
+
+// Where the file named after `id` goes under `dir`: two levels down,
+// picked by a hash of the id, so that no directory gets too big and
+// subtrees can be put on other mount points.
+pub(crate) fn hashed(dir: &Path, id: UUID) -> PathBuf {
+    let hash = crc32c(&id.to_le_bytes());
+    dir.join(format!("{:02x}", hash >> 24))
+        .join(format!("{:02x}", (hash >> 16) & 0xff))
+        .join(format!("{:032x}", id))
+}
+
+// The ids of the files in a tree laid out by `hashed`.
+pub(crate) fn hashed_ids(dir: &Path) -> Vec<UUID> {
+    let entries = |dir: &Path| -> Vec<PathBuf> {
+        match fs::read_dir(dir) {
+            Ok(entries) => entries.flatten().map(|e| e.path()).collect(),
+            Err(_) => Vec::new(),
+        }
+    };
+    let mut ids = Vec::new();
+    for first in entries(dir) {
+        for second in entries(&first) {
+            for file in entries(&second) {
+                let name = file.file_name().and_then(|n| n.to_str()).unwrap_or("");
+                if name.len() == 32 {
+                    if let Ok(id) = u128::from_str_radix(name, 16) {
+                        ids.push(id);
+                    }
+                }
+            }
+        }
+    }
+    ids
+}
+
+pub(crate) fn read_file(path: &Path) -> Result<Vec<u8>, VolumeError> {
+    match fs::read(path) {
+        Ok(bytes) => Ok(bytes),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Err(VolumeError::NoDataFound),
+        Err(err) => Err(VolumeError::IoError(err)),
+    }
+}
+
+// Replaces the file at `path` as a whole: `bytes` go to a temporary file
+// that is made durable and then renamed over it. The rename itself lasts
+// once the directory is synced; the directory is added to `dirty` for that.
+pub(crate) fn write_file(
+    path: &Path,
+    bytes: &[u8],
+    dirty: &mut HashSet<PathBuf>,
+) -> Result<(), VolumeError> {
+    let dir = path.parent().unwrap_or(Path::new("."));
+    fs::create_dir_all(dir).map_err(VolumeError::IoError)?;
+    let tmp = path.with_extension("tmp");
+    let mut file = File::create(&tmp).map_err(VolumeError::IoError)?;
+    file.write_all(bytes).map_err(VolumeError::IoError)?;
+    file.sync_all().map_err(VolumeError::IoError)?;
+    fs::rename(&tmp, path).map_err(VolumeError::IoError)?;
+    dirty.insert(dir.to_owned());
+    Ok(())
+}
+
+pub(crate) fn remove_file(path: &Path, dirty: &mut HashSet<PathBuf>) -> Result<(), VolumeError> {
+    match fs::remove_file(path) {
+        Ok(()) => {
+            dirty.insert(path.parent().unwrap_or(Path::new(".")).to_owned());
+            Ok(())
+        }
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Err(VolumeError::NoDataFound),
+        Err(err) => Err(VolumeError::IoError(err)),
+    }
+}
+
+pub(crate) fn sync_dirs(dirty: &mut HashSet<PathBuf>) -> Result<(), VolumeError> {
+    for dir in dirty.drain() {
+        File::open(&dir)
+            .and_then(|d| d.sync_all())
+            .map_err(VolumeError::IoError)?;
+    }
+    Ok(())
+}
+
+// Chunks kept as files, easy to look at, copy with rsync or spread over
+// several disks: every block in a file of its own under blocks/, and the
+// label and header of every chunk in a small file under chunks/. A chunk
+// is there as long as its file under chunks/ is; its blocks are written
+// first and removed last.
+pub struct DirBlockStore {
+    root: PathBuf,
+    uuid: UUID,
+    dirty: HashSet<PathBuf>,
+}
+
+impl DirBlockStore {
+    pub fn init(root: &Path, uuid: UUID) -> Result<DirBlockStore, VolumeError> {
+        let mut dirty = HashSet::new();
+        write_file(
+            &root.join("store"),
+            &seal(&STORE_MAGIC, &uuid.to_le_bytes()),
+            &mut dirty,
+        )?;
+        sync_dirs(&mut dirty)?;
+        DirBlockStore::open(root)
+    }
+
+    pub fn open(root: &Path) -> Result<DirBlockStore, VolumeError> {
+        let bytes = read_file(&root.join("store"))?;
+        let header = unseal(&STORE_MAGIC, &bytes[..]).ok_or(VolumeError::CorruptSuperBlock)?;
+        if header.len() != 16 {
+            return Err(VolumeError::CorruptSuperBlock);
+        }
+        Ok(DirBlockStore {
+            root: root.to_owned(),
+            uuid: u128_at(header, 0),
+            dirty: HashSet::new(),
+        })
+    }
+
+    fn chunk_path(&self, id: UUID) -> PathBuf {
+        hashed(&self.root.join("chunks"), id)
+    }
+
+    fn block_path(&self, id: UUID) -> PathBuf {
+        hashed(&self.root.join("blocks"), id)
+    }
+
+    // The label, as it was written, and the header of chunk `id`.
+    fn read_chunk(&self, id: UUID) -> Result<(Vec<u8>, Chunk), VolumeError> {
+        let bytes = read_file(&self.chunk_path(id))?;
+        let payload = unseal(&CHUNK_MAGIC, &bytes[..])
+            .filter(|p| p.len() >= LABEL_BYTES)
+            .ok_or(VolumeError::CorruptChunk(id))?;
+        match bincode::deserialize::<Chunk>(&payload[LABEL_BYTES..]) {
+            Ok(chunk) if chunk.is_sound(id) => Ok((payload[..LABEL_BYTES].to_vec(), chunk)),
+            _ => Err(VolumeError::CorruptChunk(id)),
+        }
+    }
+
+    fn write_chunk(&mut self, label: Vec<u8>, chunk: Chunk) -> Result<(), VolumeError> {
+        let mut payload = label;
+        payload.extend(bincode::serialize(&chunk).unwrap());
+        let path = self.chunk_path(chunk.id);
+        write_file(&path, &seal(&CHUNK_MAGIC, &payload[..]), &mut self.dirty)
+    }
+
+    fn write_block(&mut self, block: &Block) -> Result<(), VolumeError> {
+        let path = self.block_path(block.id);
+        let bytes = bincode::serialize(block).unwrap();
+        write_file(&path, &bytes[..], &mut self.dirty)
+    }
+}
+
+impl BlockStore for DirBlockStore {
+    fn uuid(&self) -> UUID {
+        self.uuid
+    }
+
+    fn save_chunk(
+        &mut self,
+        label: ChunkLabel,
+        chunk: Chunk,
+        blocks: Vec<Block>,
+    ) -> Result<(), VolumeError> {
+        for block in blocks.iter() {
+            self.write_block(block)?;
+        }
+        self.write_chunk(label.into(), chunk)
+    }
+
+    fn save_chunk_header(&mut self, chunk: Chunk) -> Result<(), VolumeError> {
+        let (label, _) = self.read_chunk(chunk.id)?;
+        self.write_chunk(label, chunk)
+    }
+
+    fn load_chunk(&self, id: UUID) -> Result<Chunk, VolumeError> {
+        Ok(self.read_chunk(id)?.1)
+    }
+
+    fn load_label(&self, id: UUID) -> Result<Option<ChunkLabel>, VolumeError> {
+        Ok(ChunkLabel::parse(&self.read_chunk(id)?.0[..]))
+    }
+
+    fn save_label(&mut self, label: ChunkLabel) -> Result<(), VolumeError> {
+        let (_, chunk) = self.read_chunk(label.chunk)?;
+        self.write_chunk(label.into(), chunk)
+    }
+
+    fn load_block(&self, chunk: UUID, n: usize) -> Result<Block, VolumeError> {
+        let chunk = self.load_chunk(chunk)?;
+        let id = *chunk.blocks.get(n).ok_or(VolumeError::NoDataFound)?;
+        let bytes = read_file(&self.block_path(id))?;
+        bincode::deserialize(&bytes[..]).map_err(|_| VolumeError::CorruptBlock(chunk.id, n))
+    }
+
+    fn save_block(&mut self, chunk: UUID, n: usize, block: Block) -> Result<(), VolumeError> {
+        let chunk = self.load_chunk(chunk)?;
+        if chunk.blocks.get(n) != Some(&block.id) {
+            return Err(VolumeError::BlockMismatch(chunk.id, n));
+        }
+        self.write_block(&block)
+    }
+
+    fn contains(&self, id: UUID) -> bool {
+        self.chunk_path(id).exists()
+    }
+
+    fn ids(&self) -> Vec<UUID> {
+        hashed_ids(&self.root.join("chunks"))
+    }
+
+    // A chunk whose header is lost leaves its blocks behind.
+    fn free_chunk(&mut self, id: UUID) -> Result<(), VolumeError> {
+        let blocks = self.read_chunk(id).map(|(_, c)| c.blocks).ok();
+        remove_file(&self.chunk_path(id), &mut self.dirty)?;
+        for block in blocks.iter().flatten().filter(|b| **b != 0) {
+            match remove_file(&self.block_path(*block), &mut self.dirty) {
+                Ok(()) | Err(VolumeError::NoDataFound) => {}
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(())
+    }
+
+    fn sync_metadata(&mut self) -> Result<(), VolumeError> {
+        sync_dirs(&mut self.dirty)
+    }
+
+    fn sync_data(&mut self) -> Result<(), VolumeError> {
+        sync_dirs(&mut self.dirty)
+    }
+}
+
+// File records, the index and snapshots kept as files next to a
+// DirBlockStore, or anywhere else. Every file record is a file under
+// files/, every snapshot a file under snapshots/ and the index one file.
+// Changes stay in memory until they are synced, and the index is written
+// last: a write whose file is in the index on disk has committed, even if
+// its journal file is still there.
+pub struct DirMetadataStore {
+    root: PathBuf,
+    uuid: UUID,
+    flags: u64,
+    sequence: u64,
+    index: ObjectIndex,
+    snapshots: Vec<Snapshot>,
+    files: HashMap<UUID, Option<RedundantFile>>,
+    journal: Option<(UUID, Vec<UUID>)>,
+    rollback: Vec<UUID>,
+    dirty: HashSet<PathBuf>,
+}
+
+impl DirMetadataStore {
+    pub fn init(root: &Path, uuid: UUID) -> Result<DirMetadataStore, VolumeError> {
+        let mut meta_data = DirMetadataStore {
+            root: root.to_owned(),
+            uuid,
+            flags: 0,
+            sequence: 0,
+            index: ObjectIndex::new(vec![IndexPage::default()]),
+            snapshots: Vec::new(),
+            files: HashMap::new(),
+            journal: None,
+            rollback: Vec::new(),
+            dirty: HashSet::new(),
+        };
+        meta_data.commit()?;
+        Ok(meta_data)
+    }
+
+    pub fn open(root: &Path) -> Result<DirMetadataStore, VolumeError> {
+        let bytes = read_file(&root.join("volume"))?;
+        let header = unseal(&VOLUME_MAGIC, &bytes[..]).ok_or(VolumeError::CorruptSuperBlock)?;
+        if header.len() != 32 {
+            return Err(VolumeError::CorruptSuperBlock);
+        }
+        let bytes = read_file(&root.join("index"))?;
+        let index = decode_index(unseal(&INDEX_MAGIC, &bytes[..]))
+            .ok_or(VolumeError::CorruptMetadata(0))?;
+
+        let mut snapshots = Vec::new();
+        if let Ok(entries) = fs::read_dir(root.join("snapshots")) {
+            for entry in entries.flatten() {
+                let name = entry.file_name();
+                let name = match HEXLOWER.decode(name.to_string_lossy().as_bytes()) {
+                    Ok(name) => String::from_utf8_lossy(&name[..]).into_owned(),
+                    Err(_) => continue,
+                };
+                let bytes = read_file(&entry.path())?;
+                let payload = unseal(&SNAPSHOT_MAGIC, &bytes[..])
+                    .filter(|p| p.len() >= 8)
+                    .ok_or(VolumeError::CorruptMetadata(0))?;
+                snapshots.push(Snapshot {
+                    name,
+                    created: u64_at(payload, 0),
+                    index_start: 0,
+                    index: decode_index(Some(&payload[8..]))
+                        .ok_or(VolumeError::CorruptMetadata(0))?,
+                });
+            }
+        }
+        snapshots.sort_by_key(|s| (s.created, s.name.clone()));
+
+        // A journal left behind by a write that never reached the index
+        // names the chunks it stored, to be freed.
+        let mut rollback = Vec::new();
+        if let Ok(bytes) = read_file(&root.join("journal")) {
+            let journal = unseal(&JOURNAL_MAGIC, &bytes[..])
+                .filter(|j| j.len() >= 16 && (j.len() - 16) % 16 == 0)
+                .ok_or(VolumeError::CorruptMetadata(0))?;
+            let file = u128_at(journal, 0);
+            if !index.iter().any(|e| e.id == file) {
+                rollback = (16..journal.len())
+                    .step_by(16)
+                    .map(|at| u128_at(journal, at))
+                    .collect();
+            }
+        }
+
+        let mut meta_data = DirMetadataStore {
+            root: root.to_owned(),
+            uuid: u128_at(header, 0),
+            flags: u64_at(header, 16),
+            sequence: u64_at(header, 24),
+            index,
+            snapshots,
+            files: HashMap::new(),
+            journal: None,
+            rollback,
+            dirty: HashSet::new(),
+        };
+        if meta_data.rollback.is_empty() {
+            meta_data.finish_rollback()?;
+        }
+        Ok(meta_data)
+    }
+
+    fn write_journal(&mut self, id: UUID, chunks: Vec<UUID>) -> Result<(), VolumeError> {
+        let mut journal = id.to_le_bytes().to_vec();
+        for chunk in chunks.iter() {
+            journal.extend_from_slice(&chunk.to_le_bytes());
+        }
+        write_file(
+            &self.root.join("journal"),
+            &seal(&JOURNAL_MAGIC, &journal[..]),
+            &mut self.dirty,
+        )?;
+        sync_dirs(&mut self.dirty)?;
+        self.journal = Some((id, chunks));
+        Ok(())
+    }
+
+    fn file_path(&self, id: UUID) -> PathBuf {
+        hashed(&self.root.join("files"), id)
+    }
+
+    fn snapshot_path(&self, name: &str) -> PathBuf {
+        self.root
+            .join("snapshots")
+            .join(HEXLOWER.encode(name.as_bytes()))
+    }
+
+    // Writes out every change: file records and snapshots first, then the
+    // header and last the index.
+    fn commit(&mut self) -> Result<(), VolumeError> {
+        let files: Vec<(UUID, Option<RedundantFile>)> = self.files.drain().collect();
+        for (id, file) in files {
+            let path = self.file_path(id);
+            match file {
+                Some(file) => {
+                    let bytes = seal(&FILE_MAGIC, &bincode::serialize(&file).unwrap()[..]);
+                    write_file(&path, &bytes[..], &mut self.dirty)?
+                }
+                None => match remove_file(&path, &mut self.dirty) {
+                    Ok(()) | Err(VolumeError::NoDataFound) => {}
+                    Err(err) => return Err(err),
+                },
+            }
+        }
+
+        let kept: HashSet<PathBuf> = self
+            .snapshots
+            .iter()
+            .map(|s| self.snapshot_path(&s.name))
+            .collect();
+        for snapshot in self.snapshots.iter() {
+            let path = self.snapshot_path(&snapshot.name);
+            if !path.exists() {
+                let mut payload = snapshot.created.to_le_bytes().to_vec();
+                payload.extend(encode_index(&snapshot.index));
+                write_file(&path, &seal(&SNAPSHOT_MAGIC, &payload[..]), &mut self.dirty)?;
+            }
+        }
+        if let Ok(entries) = fs::read_dir(self.root.join("snapshots")) {
+            for path in entries.flatten().map(|e| e.path()) {
+                if !kept.contains(&path) {
+                    remove_file(&path, &mut self.dirty)?;
+                }
+            }
+        }
+
+        let mut header = self.uuid.to_le_bytes().to_vec();
+        header.extend_from_slice(&self.flags.to_le_bytes());
+        header.extend_from_slice(&self.sequence.to_le_bytes());
+        write_file(
+            &self.root.join("volume"),
+            &seal(&VOLUME_MAGIC, &header[..]),
+            &mut self.dirty,
+        )?;
+        sync_dirs(&mut self.dirty)?;
+        write_file(
+            &self.root.join("index"),
+            &seal(&INDEX_MAGIC, &encode_index(&self.index)[..]),
+            &mut self.dirty,
+        )?;
+        sync_dirs(&mut self.dirty)
+    }
+}
+
+pub(crate) fn encode_index(index: &ObjectIndex) -> Vec<u8> {
+    let mut buf = Vec::new();
+    for page in index.pages().iter() {
+        let mut page = page.clone();
+        page.set_next_index_page(0);
+        buf.extend(Vec::<u8>::from(page));
+    }
+    buf
+}
+
+pub(crate) fn decode_index(bytes: Option<&[u8]>) -> Option<ObjectIndex> {
+    let bytes = bytes?;
+    if bytes.len() % INDEX_PAGE_BYTES != 0 {
+        return None;
+    }
+    let mut pages: Vec<IndexPage> = bytes
+        .chunks(INDEX_PAGE_BYTES)
+        .map(|page| {
+            let mut buf = [0u8; INDEX_PAGE_BYTES];
+            buf.copy_from_slice(page);
+            IndexPage::from(buf)
+        })
+        .collect();
+    if pages.is_empty() {
+        pages.push(IndexPage::default());
+    }
+    Some(ObjectIndex::new(pages))
+}
+
+impl MetadataStore for DirMetadataStore {
+    fn uuid(&self) -> UUID {
+        self.uuid
+    }
+
+    fn journal_begin(&mut self, id: UUID, chunks: &[UUID]) -> Result<(), VolumeError> {
+        if self.journal.is_some() {
+            return Err(VolumeError::PendingJournal);
+        }
+        self.write_journal(id, chunks.to_vec())
+    }
+
+    fn journal_extend(&mut self, id: UUID, chunks: &[UUID]) -> Result<(), VolumeError> {
+        let (_, mut journal) = self.journal.clone().ok_or(VolumeError::NoJournal)?;
+        journal.extend_from_slice(chunks);
+        self.write_journal(id, journal)
+    }
+
+    fn journal_commit(&mut self) -> Result<(), VolumeError> {
+        self.journal.take().ok_or(VolumeError::NoJournal)?;
+        self.commit()?;
+        self.finish_rollback()
+    }
+
+    fn rollback(&self) -> &[UUID] {
+        &self.rollback[..]
+    }
+
+    fn finish_rollback(&mut self) -> Result<(), VolumeError> {
+        match remove_file(&self.root.join("journal"), &mut self.dirty) {
+            Ok(()) | Err(VolumeError::NoDataFound) => {}
+            Err(err) => return Err(err),
+        }
+        self.rollback.clear();
+        sync_dirs(&mut self.dirty)
+    }
+
+    fn reload(&mut self) -> Result<(), VolumeError> {
+        *self = DirMetadataStore::open(&self.root)?;
+        Ok(())
+    }
+
+    fn sync_metadata(&mut self) -> Result<(), VolumeError> {
+        match self.journal {
+            Some(_) => Ok(()),
+            None => self.commit(),
+        }
+    }
+
+    fn save_file(&mut self, file: RedundantFile) -> Result<(), VolumeError> {
+        self.files.insert(file.id, Some(file));
+        Ok(())
+    }
+
+    fn load_file(&self, id: UUID) -> Result<RedundantFile, VolumeError> {
+        if let Some(file) = self.files.get(&id) {
+            return file.clone().ok_or(VolumeError::NoDataFound);
+        }
+        let bytes = read_file(&self.file_path(id))?;
+        let payload = unseal(&FILE_MAGIC, &bytes[..]).ok_or(VolumeError::CorruptFile(id))?;
+        bincode::deserialize(payload).map_err(|_| VolumeError::CorruptFile(id))
+    }
+
+    fn contains_file(&self, id: UUID) -> bool {
+        match self.files.get(&id) {
+            Some(file) => file.is_some(),
+            None => self.file_path(id).exists(),
+        }
+    }
+
+    fn free_file(&mut self, id: UUID) -> Result<(), VolumeError> {
+        if !self.contains_file(id) {
+            return Err(VolumeError::NoDataFound);
+        }
+        self.files.insert(id, None);
+        Ok(())
+    }
+
+    fn versioning(&self) -> bool {
+        self.flags & VERSIONING != 0
+    }
+
+    fn set_versioning(&mut self, enabled: bool) -> Result<(), VolumeError> {
+        match enabled {
+            true => self.flags |= VERSIONING,
+            false => self.flags &= !VERSIONING,
+        }
+        Ok(())
+    }
+
+    fn index_put(&mut self, mut entry: IndexEntry) -> Result<Vec<IndexEntry>, VolumeError> {
+        self.sequence += 1;
+        entry.version = self.sequence;
+        let displaced = match self.index.get(entry.key()).map(|e| e.id) {
+            Some(id) if !self.versioning() => self
+                .index
+                .remove_version(entry.key(), id, &mut |_| {})
+                .into_iter()
+                .collect(),
+            _ => Vec::new(),
+        };
+        self.index.insert(entry, &mut || 0);
+        Ok(displaced)
+    }
+
+    fn index_restore(&mut self, mut entry: IndexEntry) -> Result<(), VolumeError> {
+        self.sequence += 1;
+        entry.version = self.sequence;
+        self.index.insert(entry, &mut || 0);
+        Ok(())
+    }
+
+    fn index_get(&self, name: &str) -> Option<IndexEntry> {
+        self.index.get(name.as_bytes()).cloned()
+    }
+
+    fn index_get_version(&self, name: &str, id: UUID) -> Option<IndexEntry> {
+        self.index.get_version(name.as_bytes(), id).cloned()
+    }
+
+    fn index_entries(&self) -> Vec<IndexEntry> {
+        self.index.iter().cloned().collect()
+    }
+
+    fn index_remove_all(&mut self, name: &str) -> Result<Vec<IndexEntry>, VolumeError> {
+        Ok(self.index.remove_all(name.as_bytes(), &mut |_| {}))
+    }
+
+    fn index_remove_version(
+        &mut self,
+        name: &str,
+        id: UUID,
+    ) -> Result<Option<IndexEntry>, VolumeError> {
+        Ok(self.index.remove_version(name.as_bytes(), id, &mut |_| {}))
+    }
+
+    fn list(
+        &self,
+        prefix: &str,
+        delimiter: Option<&str>,
+        start_after: Option<&str>,
+        limit: usize,
+    ) -> Result<ObjectList, VolumeError> {
+        Ok(self.index.list(prefix, delimiter, start_after, limit))
+    }
+
+    fn list_versions(
+        &self,
+        prefix: &str,
+        start_after: Option<&str>,
+        limit: usize,
+    ) -> Result<ObjectList, VolumeError> {
+        Ok(self.index.list_versions(prefix, start_after, limit))
+    }
+
+    fn create_snapshot(&mut self, name: &str, created: u64) -> Result<(), VolumeError> {
+        if self.snapshots.iter().any(|s| s.name == name) {
+            return Err(VolumeError::SnapshotExists(name.to_owned()));
+        }
+        self.snapshots.push(Snapshot {
+            name: name.to_owned(),
+            created,
+            index_start: 0,
+            index: self.index.clone(),
+        });
+        Ok(())
+    }
+
+    fn delete_snapshot(&mut self, name: &str) -> Result<Snapshot, VolumeError> {
+        let pos = self
+            .snapshots
+            .iter()
+            .position(|s| s.name == name)
+            .ok_or_else(|| VolumeError::NoSuchSnapshot(name.to_owned()))?;
+        Ok(self.snapshots.remove(pos))
+    }
+
+    fn snapshots(&self) -> Vec<SnapshotSummary> {
+        self.snapshots.iter().map(|s| s.summary()).collect()
+    }
+
+    fn snapshot(&self, name: &str) -> Result<&Snapshot, VolumeError> {
+        self.snapshots
+            .iter()
+            .find(|s| s.name == name)
+            .ok_or_else(|| VolumeError::NoSuchSnapshot(name.to_owned()))
+    }
+
+    fn referenced(&self, id: UUID) -> bool {
+        self.index.iter().any(|e| e.id == id) || self.snapshots.iter().any(|s| s.references(id))
+    }
+}
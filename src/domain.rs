@@ -0,0 +1,92 @@
+use crate::crc32c::crc32c;
+use crate::error::VolumeError;
+use crate::volume_manager::FileVolumeManager;
+
+pub const DOMAIN_MAGIC: [u8; 8] = *b"OGGDOMAN";
+pub const DOMAIN_PAGE_BYTES: usize = 4096;
+
+// Where a block store sits among the things that fail together, from the
+// widest down, such as "rack1/host3/sdb". Stores sharing a prefix go down
+// together when what the prefix names does. A store with no domain only
+// shares its own fate.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub struct FailureDomain {
+    levels: Vec<String>,
+}
+
+impl FailureDomain {
+    pub fn parse(domain: &str) -> FailureDomain {
+        FailureDomain {
+            levels: domain
+                .split('/')
+                .filter(|l| !l.is_empty())
+                .map(|l| l.to_owned())
+                .collect(),
+        }
+    }
+
+    pub fn depth(&self) -> usize {
+        self.levels.len()
+    }
+
+    // The domain `depth` levels down from the widest, if it goes that deep.
+    pub fn prefix(&self, depth: usize) -> Option<&[String]> {
+        self.levels.get(..depth)
+    }
+
+    // Parses the domain page written after the superblock of a store.
+    pub fn decode(bytes: &[u8]) -> Option<FailureDomain> {
+        if bytes.len() < DOMAIN_PAGE_BYTES || bytes[0..8] != DOMAIN_MAGIC {
+            return None;
+        }
+        let mut buf = [0u8; 4];
+        buf.copy_from_slice(&bytes[DOMAIN_PAGE_BYTES - 4..DOMAIN_PAGE_BYTES]);
+        if crc32c(&bytes[..DOMAIN_PAGE_BYTES - 4]) != u32::from_le_bytes(buf) {
+            return None;
+        }
+        buf.copy_from_slice(&bytes[8..12]);
+        let len = u32::from_le_bytes(buf) as usize;
+        let name = bytes.get(12..12 + len)?;
+        Some(FailureDomain::parse(std::str::from_utf8(name).ok()?))
+    }
+
+    pub fn encode(&self) -> Result<Vec<u8>, VolumeError> {
+        let name = self.to_string();
+        if 12 + name.len() > DOMAIN_PAGE_BYTES - 4 {
+            return Err(VolumeError::DomainTooLong(name.len()));
+        }
+        let mut buf = DOMAIN_MAGIC.to_vec();
+        buf.extend_from_slice(&(name.len() as u32).to_le_bytes());
+        buf.extend_from_slice(name.as_bytes());
+        buf.resize(DOMAIN_PAGE_BYTES - 4, 0);
+        let crc = crc32c(&buf[..]);
+        buf.extend_from_slice(&crc.to_le_bytes());
+        Ok(buf)
+    }
+}
+
+impl std::fmt::Display for FailureDomain {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.levels.join("/"))
+    }
+}
+
+// Splits a store given as PATH@DOMAIN into its path and failure domain.
+pub fn split_domain(store: &str) -> (String, FailureDomain) {
+    match store.rsplit_once('@') {
+        Some((path, domain)) => (path.to_owned(), FailureDomain::parse(domain)),
+        None => (store.to_owned(), FailureDomain::default()),
+    }
+}
+
+// Reports the failure domain of the block store at `path`, first setting
+// it to `domain` if given. Only blocks written after the change are placed
+// by the new domain.
+pub fn domain(path: &str, domain: Option<FailureDomain>) -> Result<FailureDomain, VolumeError> {
+    let mut store = FileVolumeManager::open_blockdata(path)?;
+    if let Some(domain) = domain {
+        store.save_domain(&domain)?;
+        store.sync_data()?;
+    }
+    Ok(store.load_domain()?.unwrap_or_default())
+}
@@ -5,6 +5,9 @@ pub enum RedundantFileError {
     RecostructError(reed_solomon_erasure::Error),
     JSONError(serde_json::Error),
     NoDataFound,
+    FileTooLarge(usize),
+    NameTooLong(usize),
+    NulInName,
 }
 
 #[derive(Debug)]
@@ -14,5 +17,47 @@ pub enum BlockError {}
 pub enum VolumeError {
     GeneralError,
     NoDataFound,
+    NotOpen,
+    NoJournal,
+    CorruptFile(u128),
+    CorruptChunk(u128),
+    CorruptBlock(u128, usize),
+    WorkerFailed,
+    BlockMismatch(u128, usize),
+    DomainTooLong(usize),
     IoError(std::io::Error),
+    FileError(RedundantFileError),
+    NoSuchUpload(u128),
+    BadPartNumber(u32),
+    NoSuchSnapshot(String),
+    SnapshotExists(String),
+    BadStream,
+    BadMagic,
+    CorruptSuperBlock,
+    UnsupportedVersion(u32),
+    WrongVolumeKind(u32),
+    GeometryMismatch,
+    VolumeMismatch(u128, u128),
+    MixedLayouts,
+    CorruptMetadata(u64),
+    VolumeFull,
+    TooSmall(u64),
+    PendingJournal,
+    TooFewStores(usize),
+    StoreMismatch(String),
+    TooManyMissing(usize),
+    StoreUnavailable(usize),
+    NoPlacement,
+    StoreNotEmpty(usize),
+    BadFrame,
+    BadQuorum(usize),
+    NoQuorum(usize, usize),
+    NoLeader,
+    StaleMetadata,
+    BadLogEntry,
+    CommitFailed,
 }
+
+// Where servers report what goes wrong with no caller to return it to:
+// what it happened to, and the error.
+pub type ErrorLog = std::sync::Arc<dyn Fn(&str, &VolumeError) + Send + Sync>;
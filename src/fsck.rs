@@ -0,0 +1,994 @@
+use crate::block::Block;
+use crate::chunk::Chunk;
+use crate::constants::{BLOCKS, PARITY};
+use crate::domain::{FailureDomain, DOMAIN_PAGE_BYTES};
+use crate::error::VolumeError;
+use crate::index::{IndexEntry, IndexPage, INDEX_PAGE_BYTES};
+use crate::journal::read_journal;
+use crate::label::{ChunkLabel, LABEL_BYTES};
+use crate::mirror::VolumeFile;
+use crate::placement::fits;
+use crate::redundant_file::RedundantFile;
+use crate::reed_solomon_erasure::galois_8::ReedSolomon;
+use crate::snapshot::{SnapshotPage, SNAPSHOT_PAGE_BYTES};
+use crate::store::BlockStores;
+use crate::volume_manager::{
+    has_domain, vector_region, FileVector, FileVolumeManager, SuperBlock, BLOCK_VOLUME,
+    FILE_VECTOR_BYTES, METADATA_VOLUME, SHARD_VOLUME, SUPER_BLOCK_AREA, SUPER_BLOCK_BYTES,
+    SUPER_BLOCK_COPIES,
+};
+use crate::UUID;
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Problem {
+    BadSuperBlock {
+        path: String,
+        reason: String,
+    },
+    BadSuperBlockCopy {
+        path: String,
+        pos: u64,
+    },
+    SizeMismatch {
+        path: String,
+        recorded: u64,
+        actual: u64,
+    },
+    VolumeMismatch {
+        metadata: UUID,
+        blocks: UUID,
+    },
+    MissingStore {
+        path: String,
+    },
+    BadPage {
+        path: String,
+        pos: u64,
+        reason: &'static str,
+    },
+    BadSlot {
+        path: String,
+        pos: u64,
+        id: UUID,
+    },
+    Overlap {
+        path: String,
+        pos: u64,
+        first: String,
+        second: String,
+    },
+    DuplicateId {
+        path: String,
+        id: UUID,
+    },
+    BadMirror {
+        pos: u64,
+    },
+    PendingJournal {
+        committed: bool,
+    },
+    BadFile {
+        id: UUID,
+    },
+    MissingFile {
+        name: String,
+        id: UUID,
+        snapshot: Option<String>,
+    },
+    MissingChunk {
+        file: UUID,
+        chunk: UUID,
+    },
+    BadChunk {
+        chunk: UUID,
+    },
+    BadLabel {
+        chunk: UUID,
+    },
+    Misplaced {
+        chunk: UUID,
+    },
+    BadBlock {
+        chunk: UUID,
+        n: usize,
+    },
+    LostChunk {
+        chunk: UUID,
+        bad: usize,
+    },
+    OrphanFile {
+        id: UUID,
+    },
+    OrphanChunk {
+        chunk: UUID,
+    },
+    AbandonedPart {
+        upload: UUID,
+        chunk: UUID,
+    },
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Report {
+    pub files: usize,
+    pub chunks: usize,
+    pub problems: Vec<Problem>,
+    pub fixed: Vec<Problem>,
+}
+
+impl Problem {
+    // Damage to the layout itself: nothing can be written to such a volume
+    // without risking what is still intact.
+    pub fn is_structural(&self) -> bool {
+        matches!(
+            self,
+            Problem::BadSuperBlock { .. }
+                | Problem::VolumeMismatch { .. }
+                | Problem::BadPage { .. }
+                | Problem::BadSlot { .. }
+                | Problem::Overlap { .. }
+                | Problem::DuplicateId { .. }
+        )
+    }
+}
+
+impl std::fmt::Display for Problem {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Problem::BadSuperBlockCopy { path, pos } => {
+                write!(f, "{}: superblock copy at {} is damaged", path, pos)
+            }
+            Problem::SizeMismatch {
+                path,
+                recorded,
+                actual,
+            } => write!(
+                f,
+                "{}: superblock says {} bytes but the file has {}",
+                path, recorded, actual
+            ),
+            Problem::VolumeMismatch { metadata, blocks } => write!(
+                f,
+                "metadata belongs to volume {:032x} but blocks to {:032x}",
+                metadata, blocks
+            ),
+            Problem::MissingStore { path } => write!(f, "{}: block store is missing", path),
+            Problem::BadSuperBlock { path, reason } => {
+                write!(f, "{}: bad superblock: {}", path, reason)
+            }
+            Problem::BadPage { path, pos, reason } => {
+                write!(f, "{}: bad page at {}: {}", path, pos, reason)
+            }
+            Problem::BadSlot { path, pos, id } => {
+                write!(
+                    f,
+                    "{}: {:032x} has a slot at {} outside its vector",
+                    path, id, pos
+                )
+            }
+            Problem::Overlap {
+                path,
+                pos,
+                first,
+                second,
+            } => write!(f, "{}: {} overlaps {} at {}", path, second, first, pos),
+            Problem::DuplicateId { path, id } => {
+                write!(f, "{}: {:032x} is allocated twice", path, id)
+            }
+            Problem::BadMirror { pos } => {
+                write!(f, "metadata sector at {} has a damaged copy", pos)
+            }
+            Problem::PendingJournal { committed: true } => {
+                write!(f, "journal holds a committed write")
+            }
+            Problem::PendingJournal { committed: false } => {
+                write!(f, "journal holds an interrupted write")
+            }
+            Problem::BadFile { id } => write!(f, "file {:032x} is unreadable", id),
+            Problem::MissingFile { name, id, snapshot } => match snapshot {
+                Some(snapshot) => write!(
+                    f,
+                    "{} ({:032x}) in snapshot {} has no file record",
+                    name, id, snapshot
+                ),
+                None => write!(f, "{} ({:032x}) has no file record", name, id),
+            },
+            Problem::MissingChunk { file, chunk } => {
+                write!(
+                    f,
+                    "file {:032x} references missing chunk {:032x}",
+                    file, chunk
+                )
+            }
+            Problem::BadChunk { chunk } => write!(f, "chunk {:032x} has a bad header", chunk),
+            Problem::BadLabel { chunk } => {
+                write!(f, "chunk {:032x} has a missing or stale label", chunk)
+            }
+            Problem::Misplaced { chunk } => write!(
+                f,
+                "chunk {:032x} has more blocks in one failure domain than it can lose",
+                chunk
+            ),
+            Problem::BadBlock { chunk, n } => {
+                write!(f, "chunk {:032x} block {} is corrupt", chunk, n)
+            }
+            Problem::LostChunk { chunk, bad } => write!(
+                f,
+                "chunk {:032x} has {} corrupt blocks and cannot be rebuilt",
+                chunk, bad
+            ),
+            Problem::OrphanFile { id } => write!(f, "file {:032x} is not in any index", id),
+            Problem::OrphanChunk { chunk } => write!(f, "chunk {:032x} belongs to no file", chunk),
+            Problem::AbandonedPart { upload, chunk } => write!(
+                f,
+                "chunk {:032x} is part of upload {:032x}, which never completed",
+                chunk, upload
+            ),
+        }
+    }
+}
+
+impl Report {
+    pub fn is_clean(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
+// Everything found while walking one of the two files.
+struct Walk {
+    path: String,
+    len: u64,
+    file: VolumeFile,
+    regions: Vec<(u64, u64, String)>,
+    slots: HashMap<UUID, u64>,
+    problems: Vec<Problem>,
+}
+
+impl Walk {
+    fn open(path: &str, mirrored: bool) -> Result<Walk, VolumeError> {
+        let file = File::open(path).map_err(VolumeError::IoError)?;
+        let file = match mirrored {
+            true => VolumeFile::mirrored(file),
+            false => VolumeFile::plain(file),
+        };
+        let len = file.end()?;
+        Ok(Walk {
+            path: path.to_owned(),
+            len,
+            file,
+            regions: Vec::new(),
+            slots: HashMap::new(),
+            problems: Vec::new(),
+        })
+    }
+
+    fn read(&self, pos: u64, buf: &mut [u8]) -> bool {
+        pos.checked_add(buf.len() as u64)
+            .is_some_and(|end| end <= self.len)
+            && self.file.read_at(pos, buf).is_ok()
+    }
+
+    fn bad_page(&mut self, pos: u64, reason: &'static str) {
+        self.problems.push(Problem::BadPage {
+            path: self.path.clone(),
+            pos,
+            reason,
+        });
+    }
+
+    // Every copy has to be intact and identical; the first good one is used.
+    fn super_block(&mut self, kind: u32, slot_size: usize) -> Option<SuperBlock> {
+        let mut found: Option<(SuperBlock, Vec<u8>)> = None;
+        let mut error = None;
+        let mut damaged = Vec::new();
+        for pos in SUPER_BLOCK_COPIES.iter() {
+            let mut buf = [0u8; SUPER_BLOCK_BYTES];
+            let parsed = match self.read(*pos, &mut buf) {
+                true => SuperBlock::parse(buf).and_then(|sb| sb.check(kind, slot_size).map(|_| sb)),
+                false => Err(VolumeError::NoDataFound),
+            };
+            match (parsed, found.as_ref()) {
+                (Ok(sb), None) => found = Some((sb, buf.to_vec())),
+                (Ok(_), Some((_, first))) if first[..] != buf[..] => damaged.push(*pos),
+                (Ok(_), Some(_)) => {}
+                (Err(err), _) => {
+                    damaged.push(*pos);
+                    error = error.or(Some(err));
+                }
+            }
+            self.regions
+                .push((*pos, SUPER_BLOCK_BYTES as u64, "superblock".to_owned()));
+        }
+        let sb = match found {
+            Some((sb, _)) => sb,
+            None => {
+                self.problems.push(Problem::BadSuperBlock {
+                    path: self.path.clone(),
+                    reason: format!("{:?}", error.unwrap_or(VolumeError::NoDataFound)),
+                });
+                return None;
+            }
+        };
+        for pos in damaged {
+            self.problems.push(Problem::BadSuperBlockCopy {
+                path: self.path.clone(),
+                pos,
+            });
+        }
+
+        let reason = if sb.file_vector_start < SUPER_BLOCK_AREA || sb.file_vector_start >= self.len
+        {
+            Some("first vector page out of range")
+        } else if sb.next_free > self.len || sb.next_free < sb.file_vector_start {
+            Some("high-water mark out of range")
+        } else {
+            None
+        };
+        if let Some(reason) = reason {
+            self.problems.push(Problem::BadSuperBlock {
+                path: self.path.clone(),
+                reason: reason.to_owned(),
+            });
+            return None;
+        }
+        let actual = self.file.size().unwrap_or(self.len);
+        if sb.file_size != actual {
+            self.problems.push(Problem::SizeMismatch {
+                path: self.path.clone(),
+                recorded: sb.file_size,
+                actual,
+            });
+        }
+        Some(sb)
+    }
+
+    // Follows a chain of fixed-size pages, stopping at the first one that is
+    // out of range or already visited.
+    fn chain(
+        &mut self,
+        start: u64,
+        size: usize,
+        what: &str,
+        next: &dyn Fn(&[u8]) -> u64,
+    ) -> Vec<(u64, Vec<u8>)> {
+        let mut pages = Vec::new();
+        let mut visited = HashSet::new();
+        let mut pos = start;
+        while pos != 0 {
+            if !visited.insert(pos) {
+                self.bad_page(pos, "page chain loops");
+                break;
+            }
+            let mut buf = vec![0u8; size];
+            if pos < SUPER_BLOCK_AREA || !self.read(pos, &mut buf[..]) {
+                self.bad_page(pos, "page out of range");
+                break;
+            }
+            self.regions.push((pos, size as u64, what.to_owned()));
+            let following = next(&buf[..]);
+            pages.push((pos, buf));
+            pos = following;
+        }
+        pages
+    }
+
+    // Walks the vector pages and records every allocated slot.
+    fn vectors(&mut self, start: u64, slot_size: usize) {
+        let pages = self.chain(start, FILE_VECTOR_BYTES, "vector page", &|b| {
+            let mut buf = [0u8; FILE_VECTOR_BYTES];
+            buf.copy_from_slice(b);
+            FileVector::from(buf).next_file_vector
+        });
+        for (pos, bytes) in pages {
+            let mut buf = [0u8; FILE_VECTOR_BYTES];
+            buf.copy_from_slice(&bytes[..]);
+            let vector: FileVector = buf.into();
+            self.regions.pop();
+            self.regions
+                .push((pos, vector_region(slot_size), "vector page".to_owned()));
+            for (n, (slot, id)) in vector.entries.iter().enumerate() {
+                if *id == 0 {
+                    continue;
+                }
+                let expected = pos + (FILE_VECTOR_BYTES + n * slot_size) as u64;
+                if *slot != expected || slot.saturating_add(slot_size as u64) > self.len {
+                    self.problems.push(Problem::BadSlot {
+                        path: self.path.clone(),
+                        pos: *slot,
+                        id: *id,
+                    });
+                    continue;
+                }
+                if self.slots.insert(*id, *slot).is_some() {
+                    self.problems.push(Problem::DuplicateId {
+                        path: self.path.clone(),
+                        id: *id,
+                    });
+                }
+            }
+        }
+    }
+
+    fn index(&mut self, start: u64, what: &str) -> Vec<IndexEntry> {
+        let pages = self.chain(start, INDEX_PAGE_BYTES, what, &|b| {
+            let mut buf = [0u8; INDEX_PAGE_BYTES];
+            buf.copy_from_slice(b);
+            IndexPage::from(buf).next_index_page()
+        });
+        pages
+            .into_iter()
+            .flat_map(|(_, bytes)| {
+                let mut buf = [0u8; INDEX_PAGE_BYTES];
+                buf.copy_from_slice(&bytes[..]);
+                IndexPage::from(buf).entries().to_vec()
+            })
+            .collect()
+    }
+
+    fn overlaps(&mut self) {
+        self.regions.sort_by_key(|r| r.0);
+        let mut end = 0;
+        let mut owner = String::new();
+        for (pos, len, what) in self.regions.iter() {
+            if *pos < end {
+                self.problems.push(Problem::Overlap {
+                    path: self.path.clone(),
+                    pos: *pos,
+                    first: owner.clone(),
+                    second: what.clone(),
+                });
+            }
+            if pos + len > end {
+                end = pos + len;
+                owner = what.clone();
+            }
+        }
+    }
+}
+
+struct Scan {
+    report: Report,
+    files: HashMap<UUID, Vec<UUID>>,
+    repairs: Vec<(UUID, usize, Block)>,
+    labels: Vec<ChunkLabel>,
+}
+
+fn scan(meta_data: &str, stores: &[String]) -> Result<Scan, VolumeError> {
+    let mut report = Report::default();
+    let mut meta = Walk::open(meta_data, true)?;
+
+    // Metadata: superblock, journal, vectors, index, snapshots, free pages.
+    let mut live = Vec::new();
+    let mut snapshot_entries: Vec<(String, IndexEntry)> = Vec::new();
+    let meta_sb = meta.super_block(METADATA_VOLUME, RedundantFile::size());
+    if let Some(sb) = meta_sb {
+        for pos in meta.file.damaged(sb.next_free)? {
+            report.problems.push(Problem::BadMirror { pos });
+        }
+        if sb.journal_start != 0 {
+            if sb.journal_start.saturating_add(sb.journal_size) > meta.len {
+                meta.bad_page(sb.journal_start, "journal out of range");
+            } else {
+                meta.regions
+                    .push((sb.journal_start, sb.journal_size, "journal".to_owned()));
+                let record = read_journal(&meta.file, sb.journal_start)?;
+                if !record.is_empty() {
+                    report.problems.push(Problem::PendingJournal {
+                        committed: record.committed,
+                    });
+                }
+            }
+        }
+        meta.vectors(sb.file_vector_start, RedundantFile::size());
+        live = meta.index(sb.index_start, "index page");
+
+        let pages = meta.chain(
+            sb.snapshot_start,
+            SNAPSHOT_PAGE_BYTES,
+            "snapshot page",
+            &|b| {
+                let mut buf = [0u8; SNAPSHOT_PAGE_BYTES];
+                buf.copy_from_slice(b);
+                SnapshotPage::from(buf).next_snapshot_page
+            },
+        );
+        for (_, bytes) in pages {
+            let mut buf = [0u8; SNAPSHOT_PAGE_BYTES];
+            buf.copy_from_slice(&bytes[..]);
+            for entry in SnapshotPage::from(buf).entries {
+                let what = format!("snapshot {} index page", entry.name());
+                for e in meta.index(entry.index_start, &what) {
+                    snapshot_entries.push((entry.name(), e));
+                }
+            }
+        }
+
+        meta.chain(sb.free_page_start, 8, "free page", &|b| {
+            let mut buf = [0u8; 8];
+            buf.copy_from_slice(b);
+            u64::from_le_bytes(buf)
+        });
+        // Free pages are tracked by their link only, but own a whole page.
+        for region in meta.regions.iter_mut().filter(|r| r.2 == "free page") {
+            region.1 = INDEX_PAGE_BYTES as u64;
+        }
+        meta.overlaps();
+    }
+    // Orphans can only be told apart when every file record was found.
+    let complete = !meta.problems.iter().any(|p| p.is_structural());
+
+    // Block stores: superblock and chunk vectors. A volume spread over
+    // several stores gets each in the place its superblock says it has.
+    let sharded = stores.len() > 1;
+    let (kind, slot_size) = match sharded {
+        true => (SHARD_VOLUME, Chunk::shard_size()),
+        false => (BLOCK_VOLUME, Chunk::size()),
+    };
+    report.problems.append(&mut meta.problems);
+    let mut blocks: Vec<Option<Walk>> = stores.iter().map(|_| None).collect();
+    let mut domains = vec![FailureDomain::default(); stores.len()];
+    let mut missing = Vec::new();
+    for path in stores.iter() {
+        if sharded && !std::path::Path::new(path).exists() {
+            missing.push(path.clone());
+            continue;
+        }
+        let mut walk = Walk::open(path, false)?;
+        let sb = walk.super_block(kind, slot_size);
+        let n = sb.map(|sb| sb.store()).unwrap_or(0);
+        let fits = sb.is_some_and(|sb| {
+            sb.stores() == if sharded { stores.len() } else { 0 }
+                && n < stores.len()
+                && blocks[n].is_none()
+        });
+        if let (Some(sb), false) = (sb, fits) {
+            walk.problems.push(Problem::BadSuperBlock {
+                path: path.clone(),
+                reason: format!("store {} of {} doesn't fit this volume", n, sb.stores()),
+            });
+        }
+        if let (Some(sb), true) = (sb, fits) {
+            if has_domain(&sb) {
+                let mut buf = vec![0u8; DOMAIN_PAGE_BYTES];
+                let domain = match walk.read(SUPER_BLOCK_AREA, &mut buf[..]) {
+                    true => FailureDomain::decode(&buf[..]),
+                    false => None,
+                };
+                match domain {
+                    Some(domain) => domains[n] = domain,
+                    None => walk.bad_page(SUPER_BLOCK_AREA, "bad failure domain"),
+                }
+                walk.regions.push((
+                    SUPER_BLOCK_AREA,
+                    DOMAIN_PAGE_BYTES as u64,
+                    "domain page".to_owned(),
+                ));
+            }
+            walk.vectors(sb.file_vector_start, slot_size);
+            walk.overlaps();
+            if let Some(meta_sb) = meta_sb.filter(|m| m.uuid() != sb.uuid()) {
+                report.problems.push(Problem::VolumeMismatch {
+                    metadata: meta_sb.uuid(),
+                    blocks: sb.uuid(),
+                });
+            }
+        }
+        report.problems.append(&mut walk.problems);
+        if fits {
+            blocks[n] = Some(walk);
+        }
+    }
+    for path in missing {
+        report.problems.push(Problem::MissingStore { path });
+    }
+    let holds = |id: &UUID| blocks.iter().flatten().any(|w| w.slots.contains_key(id));
+
+    // File records and the chunks they reference.
+    let mut files: HashMap<UUID, Vec<UUID>> = HashMap::new();
+    let mut owners: HashMap<UUID, Vec<ChunkLabel>> = HashMap::new();
+    let mut slots: Vec<(UUID, u64)> = meta.slots.iter().map(|(k, v)| (*k, *v)).collect();
+    slots.sort_by_key(|s| s.1);
+    for (id, pos) in slots {
+        let mut buf = vec![0u8; RedundantFile::size()];
+        let file: Option<RedundantFile> = if meta.read(pos, &mut buf[..]) {
+            bincode::deserialize(&buf[..]).ok()
+        } else {
+            None
+        };
+        match file {
+            Some(file) if file.id == id => {
+                let count = file.chunk_ids().len() as u32;
+                for (n, chunk) in file.chunk_ids().into_iter().enumerate() {
+                    if !holds(&chunk) {
+                        report
+                            .problems
+                            .push(Problem::MissingChunk { file: id, chunk });
+                    }
+                    let owner = ChunkLabel::new(&Chunk::empty(), &[])
+                        .owned(id, &file.name, n as u32, count);
+                    owners.entry(chunk).or_default().push(owner);
+                }
+                files.insert(id, file.chunk_ids());
+            }
+            _ => report.problems.push(Problem::BadFile { id }),
+        }
+    }
+    report.files = meta.slots.len();
+
+    let mut referenced: HashSet<UUID> = HashSet::new();
+    let entries = live
+        .iter()
+        .map(|e| (None, e))
+        .chain(snapshot_entries.iter().map(|(s, e)| (Some(s.clone()), e)));
+    for (snapshot, entry) in entries {
+        if entry.is_delete_marker() {
+            continue;
+        }
+        referenced.insert(entry.id);
+        if !meta.slots.contains_key(&entry.id) {
+            report.problems.push(Problem::MissingFile {
+                name: String::from_utf8_lossy(entry.key()).into_owned(),
+                id: entry.id,
+                snapshot,
+            });
+        }
+    }
+    let mut orphans: Vec<UUID> = meta
+        .slots
+        .keys()
+        .filter(|id| !referenced.contains(id))
+        .cloned()
+        .collect();
+    orphans.sort();
+    for id in orphans.into_iter().filter(|_| complete) {
+        report.problems.push(Problem::OrphanFile { id });
+    }
+
+    // Chunks: header, blocks and their CRCs. In a sharded volume every store
+    // holding a block of the chunk has its own copy of the label and header,
+    // and the label says which store has which block.
+    let used: HashSet<UUID> = files.values().flat_map(|c| c.iter().cloned()).collect();
+    let mut slots: Vec<(usize, u64, UUID)> = Vec::new();
+    for (n, walk) in blocks.iter().enumerate() {
+        if let Some(walk) = walk {
+            slots.extend(walk.slots.iter().map(|(id, pos)| (n, *pos, *id)));
+        }
+    }
+    slots.sort();
+    let mut seen = HashSet::new();
+    slots.retain(|s| seen.insert(s.2));
+    let domains: Vec<&FailureDomain> = domains.iter().collect();
+    let mut repairs = Vec::new();
+    let mut labels = Vec::new();
+    for (_, _, id) in slots.iter().cloned() {
+        let holders: Vec<(usize, u64, &Walk)> = blocks
+            .iter()
+            .enumerate()
+            .filter_map(|(n, w)| w.as_ref().map(|w| (n, w)))
+            .filter_map(|(n, w)| w.slots.get(&id).map(|pos| (n, *pos, w)))
+            .collect();
+        let mut label: Option<ChunkLabel> = None;
+        let mut stale = false;
+        let mut chunk: Option<Chunk> = None;
+        for (_, pos, walk) in holders.iter() {
+            let mut buf = vec![0u8; LABEL_BYTES];
+            let parsed = match walk.read(*pos, &mut buf[..]) {
+                true => ChunkLabel::parse(&buf[..]),
+                false => None,
+            };
+            match (parsed, label) {
+                (None, _) => stale = true,
+                (Some(l), Some(first)) if Vec::<u8>::from(l) != Vec::<u8>::from(first) => {
+                    stale = true
+                }
+                (Some(l), _) => label = label.or(Some(l)),
+            }
+            let mut buf = vec![0u8; Chunk::header_size()];
+            if chunk.is_none() && walk.read(pos + LABEL_BYTES as u64, &mut buf[..]) {
+                chunk = bincode::deserialize::<Chunk>(&buf[..])
+                    .ok()
+                    .filter(|chunk| chunk.is_sound(id));
+            }
+        }
+        // Uploads are kept as file records outside the index, which report
+        // as orphans. Volumes written before that kept them only while open,
+        // leaving the parts of one still open without a file; their labels
+        // name the upload and no chunk count.
+        if complete && !used.contains(&id) {
+            match label.filter(|l| l.chunk == id && l.file != 0 && l.count == 0) {
+                Some(l) => report.problems.push(Problem::AbandonedPart {
+                    upload: l.file,
+                    chunk: id,
+                }),
+                None => report.problems.push(Problem::OrphanChunk { chunk: id }),
+            }
+        }
+        let chunk = match chunk {
+            Some(chunk) => chunk,
+            None => {
+                report.problems.push(Problem::BadChunk { chunk: id });
+                continue;
+            }
+        };
+
+        // Without a label to say where its blocks are, they are looked for
+        // in every store holding the chunk.
+        let placement: Option<Vec<usize>> = match sharded {
+            true => label
+                .filter(|l| l.chunk == id)
+                .map(|l| l.stores.iter().map(|s| *s as usize).collect::<Vec<usize>>())
+                .filter(|p| p.iter().all(|s| *s < stores.len())),
+            false => Some(vec![0; BLOCKS + PARITY]),
+        };
+        let read_block = |walk: &Walk, pos: u64, at: usize| -> Option<Block> {
+            let mut buf = vec![0u8; Block::size() as usize];
+            match walk.read(pos + Chunk::block_offset(at), &mut buf[..]) {
+                true => bincode::deserialize(&buf[..]).ok(),
+                false => None,
+            }
+        };
+
+        // Blocks in a missing store are lost too, but only a store that is
+        // there can have them put back.
+        let mut shards: Vec<Option<Vec<u8>>> = Vec::new();
+        let mut found = vec![None; chunk.blocks.len()];
+        let mut absent = HashSet::new();
+        for n in 0..chunk.blocks.len() {
+            let at = if sharded { 0 } else { n };
+            let block = match placement.as_ref().map(|p| p[n]) {
+                Some(store) => match blocks[store].as_ref() {
+                    Some(walk) => walk
+                        .slots
+                        .get(&id)
+                        .and_then(|pos| read_block(walk, *pos, at)),
+                    None => {
+                        absent.insert(n);
+                        None
+                    }
+                },
+                None => holders
+                    .iter()
+                    .filter_map(|(store, pos, walk)| {
+                        read_block(walk, *pos, at).map(|b| (*store, b))
+                    })
+                    .find(|(_, b)| b.position == n && b.id == chunk.blocks[n])
+                    .map(|(store, b)| {
+                        found[n] = Some(store);
+                        b
+                    }),
+            };
+            shards.push(
+                block
+                    .filter(|b| b.id == chunk.blocks[n])
+                    .and_then(|b| b.inner_data_as_vec()),
+            );
+        }
+        let placement = placement.or_else(|| found.iter().cloned().collect());
+        if let (true, Some(placement)) = (sharded, placement.as_ref()) {
+            if !fits(&domains, placement, chunk.parity_n) {
+                report.problems.push(Problem::Misplaced { chunk: id });
+            }
+        }
+        let bad: Vec<usize> = (0..shards.len()).filter(|n| shards[*n].is_none()).collect();
+        if bad.len() > chunk.parity_n {
+            report.problems.push(Problem::LostChunk {
+                chunk: id,
+                bad: bad.len(),
+            });
+            continue;
+        }
+        if !bad.is_empty() {
+            let rebuilt = ReedSolomon::new(chunk.chunk_n, chunk.parity_n)
+                .ok()
+                .and_then(|r| r.reconstruct(&mut shards).ok());
+            for n in bad.into_iter().filter(|n| !absent.contains(n)) {
+                report.problems.push(Problem::BadBlock { chunk: id, n });
+                if let (Some(()), Some(data)) = (rebuilt, shards[n].as_ref()) {
+                    let mut block = Block::empty();
+                    block.id = chunk.blocks[n];
+                    block.position = n;
+                    block.data.copy_from_slice(&data[..]);
+                    block.crc = crate::crc32c::crc32c(&block.data);
+                    repairs.push((id, n, block));
+                }
+            }
+        }
+
+        // The label has to name one of the files using the chunk, at the
+        // place that file uses it. A chunk no file uses may be unowned.
+        let owners = owners.get(&id).map(|o| &o[..]).unwrap_or(&[]);
+        let good = !stale
+            && label.is_some_and(|l| {
+                l.chunk == id
+                    && (owners.is_empty()
+                        || owners.iter().any(|o| {
+                            o.file == l.file && o.position == l.position && o.count == l.count
+                        }))
+            });
+        if !good {
+            report.problems.push(Problem::BadLabel { chunk: id });
+            let mut fixed = ChunkLabel::new(&chunk, &[]);
+            for (n, shard) in shards.iter().enumerate() {
+                fixed.crcs[n] = shard
+                    .as_ref()
+                    .map(|d| crate::crc32c::crc32c(&d[..]))
+                    .unwrap_or(0);
+            }
+            if let Some(o) = owners.first() {
+                fixed = fixed.owned(o.file, o.key(), o.position, o.count);
+            }
+            // A label has to say where the blocks are; if that isn't known
+            // it can't be written.
+            if let Some(placement) = placement.as_ref() {
+                for (n, store) in placement.iter().enumerate() {
+                    fixed.stores[n] = *store as u16;
+                }
+                labels.push(fixed);
+            }
+        }
+    }
+    report.chunks = slots.len();
+
+    Ok(Scan {
+        report,
+        files,
+        repairs,
+        labels,
+    })
+}
+
+// Checks a volume without writing to it.
+pub fn check(meta_data: &str, stores: &[String]) -> Result<Report, VolumeError> {
+    Ok(scan(meta_data, stores)?.report)
+}
+
+// Checks a volume and fixes what can be fixed without losing data: finishes
+// or rolls back an interrupted write, rewrites damaged metadata copies from
+// their mirror, corrupt blocks from the rest of their chunk and stale chunk
+// labels from the file records, records the actual size of each file and
+// releases records nothing refers to. Index entries without a file record
+// are dropped from the live index. A missing block store is created empty
+// and gets its blocks rebuilt from the other stores. A volume with
+// structural damage is left untouched.
+pub fn repair(meta_data: &str, stores: &[String]) -> Result<Report, VolumeError> {
+    let mut scan = scan(meta_data, stores)?;
+    if scan.report.problems.iter().any(|p| p.is_structural()) {
+        return Ok(scan.report);
+    }
+
+    let mut fixed = Vec::new();
+    let missing: Vec<Problem> = scan
+        .report
+        .problems
+        .iter()
+        .filter(|p| matches!(p, Problem::MissingStore { .. }))
+        .cloned()
+        .collect();
+    if !missing.is_empty() {
+        let mut blocks = BlockStores::open(stores)?;
+        for n in blocks.missing() {
+            blocks.replace(n, &FailureDomain::default())?;
+        }
+        fixed.extend(missing);
+        scan = self::scan(meta_data, stores)?;
+    }
+
+    // Opening replays a committed write; the chunks of an interrupted one
+    // are freed here. Everything else is judged on the volume as it is after.
+    if let Some(pending) = scan
+        .report
+        .problems
+        .iter()
+        .find(|p| matches!(p, Problem::PendingJournal { .. }))
+        .cloned()
+    {
+        let mut meta = FileVolumeManager::open_metadata(meta_data)?;
+        let mut blocks = BlockStores::open(stores)?;
+        for chunk in meta.rollback().to_vec() {
+            match blocks.free_file(chunk) {
+                Ok(()) | Err(VolumeError::NoDataFound) => {}
+                Err(err) => return Err(err),
+            }
+        }
+        blocks.sync_metadata()?;
+        blocks.sync_data()?;
+        meta.finish_rollback()?;
+        fixed.push(pending);
+        scan = self::scan(meta_data, stores)?;
+    }
+
+    let mut meta = FileVolumeManager::open_metadata(meta_data)?;
+    let mut blocks = BlockStores::open(stores)?;
+    let unreadable = scan
+        .report
+        .problems
+        .iter()
+        .any(|p| matches!(p, Problem::BadFile { .. }));
+    let mut users: HashMap<UUID, usize> = HashMap::new();
+    for chunk in scan.files.values().flat_map(|c| c.iter()) {
+        *users.entry(*chunk).or_insert(0) += 1;
+    }
+
+    // Blocks are put back where the chunk's label says, so labels go first.
+    scan.report
+        .problems
+        .sort_by_key(|p| !matches!(p, Problem::BadLabel { .. }));
+    let mut remaining = Vec::new();
+    for problem in scan.report.problems.drain(..) {
+        let done = match &problem {
+            Problem::BadSuperBlockCopy { path, .. } => {
+                match blocks.store_mut(path) {
+                    Some(store) if path != meta_data => store.write_super_blocks()?,
+                    _ => meta.write_super_blocks()?,
+                }
+                true
+            }
+            Problem::BadMirror { pos } => {
+                meta.rewrite_sector(*pos)?;
+                true
+            }
+            // Opening the files took their size; syncing records it.
+            Problem::SizeMismatch { .. } => true,
+            Problem::BadBlock { chunk, n } => {
+                match scan.repairs.iter().find(|r| r.0 == *chunk && r.1 == *n) {
+                    Some((chunk, n, block)) => {
+                        blocks.save_block(*chunk, *n, *block)?;
+                        true
+                    }
+                    None => false,
+                }
+            }
+            Problem::BadLabel { chunk } => match scan.labels.iter().find(|l| l.chunk == *chunk) {
+                Some(label) => {
+                    blocks.save_label(*label)?;
+                    true
+                }
+                None => false,
+            },
+            Problem::MissingFile {
+                name,
+                id,
+                snapshot: None,
+            } => {
+                meta.index_remove_version(name, *id)?;
+                true
+            }
+            // The chunks go with the record unless another file shares them.
+            Problem::OrphanFile { id } if scan.files.contains_key(id) => {
+                for chunk in scan.files[id].iter() {
+                    let count = users.get_mut(chunk).unwrap();
+                    *count -= 1;
+                    if *count == 0 && blocks.contains(*chunk) {
+                        blocks.free_file(*chunk)?;
+                    }
+                }
+                meta.free_file(*id)?;
+                true
+            }
+            // A chunk of an unreadable file would look orphaned too.
+            Problem::OrphanChunk { chunk } | Problem::AbandonedPart { chunk, .. }
+                if !unreadable =>
+            {
+                if blocks.contains(*chunk) {
+                    blocks.free_file(*chunk)?;
+                }
+                true
+            }
+            _ => false,
+        };
+        if done {
+            fixed.push(problem);
+        } else {
+            remaining.push(problem);
+        }
+    }
+    blocks.sync_metadata()?;
+    blocks.sync_data()?;
+    meta.sync_metadata()?;
+
+    scan.report.problems = remaining;
+    scan.report.fixed = fixed;
+    Ok(scan.report)
+}
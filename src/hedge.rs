@@ -0,0 +1,197 @@
+use crate::block::Block;
+use crate::crc32c::crc32c;
+use crate::error::VolumeError;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+// A fetch holds one block, and deserializes it, on its stack.
+const FETCH_STACK_BYTES: usize = 4 << 20;
+
+// What a shard fetch came to: None if it was never started, or was still
+// running when enough shards were in.
+pub type Shard = Option<Result<Box<Block>, VolumeError>>;
+
+type Hook = Box<dyn FnOnce() + Send>;
+
+// Tells fetches still running that their shard is no longer wanted. A fetch
+// blocked on I/O registers a hook that unblocks it, like shutting down its
+// connection.
+#[derive(Default)]
+pub struct Cancel {
+    cancelled: AtomicBool,
+    hooks: Mutex<Vec<Hook>>,
+}
+
+impl Cancel {
+    pub fn cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+    }
+
+    // Runs `hook` once the fetches are cancelled, or now if they already
+    // are.
+    pub fn on_cancel(&self, hook: Hook) {
+        let mut hooks = self.hooks.lock().unwrap();
+        if self.cancelled() {
+            drop(hooks);
+            hook();
+            return;
+        }
+        hooks.push(hook);
+    }
+
+    fn cancel(&self) {
+        let hooks: Vec<Hook> = {
+            let mut hooks = self.hooks.lock().unwrap();
+            self.cancelled.store(true, Ordering::SeqCst);
+            hooks.drain(..).collect()
+        };
+        for hook in hooks {
+            hook();
+        }
+    }
+}
+
+// A block that reads back as written.
+fn whole(block: &Block) -> bool {
+    block.id != 0 && crc32c(&block.data) == block.crc
+}
+
+// Fetches shards 0..shards of a chunk, each on a thread of its own, until
+// `wanted` of them are in whole, and cancels the rest. The first `wanted`
+// are started at once, the data blocks, and another for every one that
+// fails; when `hedge` goes by without a shard coming in, one more is
+// started to race the slow ones. A hedge of zero starts them all at once.
+pub fn fetch<F>(shards: usize, wanted: usize, hedge: Duration, fetch: F) -> Vec<Shard>
+where
+    F: Fn(usize, &Cancel) -> Result<Block, VolumeError> + Send + Sync + 'static,
+{
+    let fetch = Arc::new(fetch);
+    let cancel = Arc::new(Cancel::default());
+    let (sender, receiver) = channel();
+    let mut results: Vec<Shard> = (0..shards).map(|_| None).collect();
+    let start = |n: usize, sender: &Sender<_>| {
+        let fetch = fetch.clone();
+        let cancel = cancel.clone();
+        let done = sender.clone();
+        let spawned = std::thread::Builder::new()
+            .stack_size(FETCH_STACK_BYTES)
+            .spawn(move || {
+                if cancel.cancelled() {
+                    return;
+                }
+                let result = fetch(n, &cancel).map(Box::new);
+                let _ = done.send((n, result));
+            });
+        if let Err(err) = spawned {
+            let _ = sender.send((n, Err(VolumeError::IoError(err))));
+        }
+    };
+
+    let mut started = match hedge.as_nanos() {
+        0 => shards,
+        _ => wanted.min(shards),
+    };
+    for n in 0..started {
+        start(n, &sender);
+    }
+    let mut running = started;
+    let mut whole_shards = 0;
+    let mut hedge_at = Instant::now() + hedge;
+    while whole_shards < wanted && running > 0 {
+        let received = match started < shards {
+            true => receiver.recv_timeout(hedge_at.saturating_duration_since(Instant::now())),
+            false => receiver.recv().map_err(|_| RecvTimeoutError::Disconnected),
+        };
+        match received {
+            Ok((n, result)) => {
+                running -= 1;
+                match result {
+                    Ok(block) if whole(&block) => {
+                        whole_shards += 1;
+                        results[n] = Some(Ok(block));
+                        hedge_at = Instant::now() + hedge;
+                        continue;
+                    }
+                    Ok(_) => results[n] = Some(Err(VolumeError::NoDataFound)),
+                    Err(err) => results[n] = Some(Err(err)),
+                }
+            }
+            Err(RecvTimeoutError::Timeout) => hedge_at = Instant::now() + hedge,
+            Err(RecvTimeoutError::Disconnected) => break,
+        }
+        if started < shards {
+            start(started, &sender);
+            started += 1;
+            running += 1;
+        }
+    }
+    cancel.cancel();
+    results
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{fetch, Shard};
+    use crate::block::Block;
+    use crate::error::VolumeError;
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+
+    fn whole(shards: &[Shard]) -> Vec<usize> {
+        (0..shards.len())
+            .filter(|n| matches!(shards[*n], Some(Ok(_))))
+            .collect()
+    }
+
+    #[test]
+    fn returns_once_enough_shards_are_in() {
+        let started = Arc::new(AtomicUsize::new(0));
+        let counted = started.clone();
+        let begin = Instant::now();
+        let shards = fetch(8, 4, Duration::from_secs(10), move |n, _| {
+            counted.fetch_add(1, Ordering::SeqCst);
+            Ok(*Block::parity(n))
+        });
+        assert!(begin.elapsed() < Duration::from_secs(5));
+        assert_eq!(whole(&shards), vec![0, 1, 2, 3]);
+        assert_eq!(started.load(Ordering::SeqCst), 4);
+    }
+
+    #[test]
+    fn hedge_races_a_slow_shard() {
+        let stopped = Arc::new(AtomicBool::new(false));
+        let slow = stopped.clone();
+        let begin = Instant::now();
+        let shards = fetch(8, 4, Duration::from_millis(50), move |n, cancel| {
+            if n == 0 {
+                while !cancel.cancelled() {
+                    std::thread::sleep(Duration::from_millis(5));
+                }
+                slow.store(true, Ordering::SeqCst);
+                return Err(VolumeError::NoDataFound);
+            }
+            Ok(*Block::parity(n))
+        });
+        assert!(begin.elapsed() < Duration::from_secs(5));
+        assert_eq!(whole(&shards), vec![1, 2, 3, 4]);
+        assert!(shards[0].is_none());
+        let waited = Instant::now();
+        while !stopped.load(Ordering::SeqCst) {
+            assert!(waited.elapsed() < Duration::from_secs(5));
+            std::thread::sleep(Duration::from_millis(5));
+        }
+    }
+
+    #[test]
+    fn gives_up_once_too_many_shards_failed() {
+        let shards = fetch(8, 4, Duration::from_secs(10), |n, _| match n {
+            0..=4 => Err(VolumeError::NoDataFound),
+            _ => Ok(*Block::parity(n)),
+        });
+        assert_eq!(whole(&shards), vec![5, 6, 7]);
+        assert!(shards.iter().all(|s| s.is_some()));
+    }
+}
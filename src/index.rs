@@ -0,0 +1,650 @@
+use crate::constants::FILENAME_SIZE;
+use crate::UUID;
+use std::cmp::Ordering;
+
+pub const INDEX_PAGE_ENTRIES: usize = 16;
+pub const INDEX_ENTRY_BYTES: usize = FILENAME_SIZE + 16 + 8 + 8 + 8;
+pub const INDEX_PAGE_BYTES: usize = 8 + INDEX_ENTRY_BYTES * INDEX_PAGE_ENTRIES + 8;
+
+pub const DELETE_MARKER: u64 = 1;
+
+// Entries are kept sorted by name and, within a name, newest version first,
+// so the first entry of a name is always its current state.
+#[derive(Copy, Clone)]
+pub struct IndexEntry {
+    pub name: [u8; FILENAME_SIZE],
+    pub id: UUID,
+    pub size: u64,
+    pub version: u64,
+    pub flags: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ObjectSummary {
+    pub name: String,
+    pub id: UUID,
+    pub size: u64,
+    pub version: u64,
+    pub is_latest: bool,
+    pub delete_marker: bool,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ObjectList {
+    pub objects: Vec<ObjectSummary>,
+    pub common_prefixes: Vec<String>,
+    pub truncated: bool,
+    pub next_start_after: Option<String>,
+}
+
+#[derive(Clone)]
+pub struct IndexPage {
+    entries: Vec<IndexEntry>,
+    next_index_page: u64,
+}
+
+#[derive(Debug, Clone)]
+pub struct ObjectIndex {
+    pages: Vec<IndexPage>,
+}
+
+impl IndexEntry {
+    pub fn new(name: &[u8], id: UUID, size: u64) -> IndexEntry {
+        let mut buf = [0u8; FILENAME_SIZE];
+        let len = std::cmp::min(name.len(), FILENAME_SIZE);
+        buf[..len].copy_from_slice(&name[..len]);
+        IndexEntry {
+            name: buf,
+            id,
+            size,
+            version: 0,
+            flags: 0,
+        }
+    }
+
+    pub fn delete_marker(name: &[u8]) -> IndexEntry {
+        let mut entry = IndexEntry::new(name, uuid::Uuid::new_v4().as_u128(), 0);
+        entry.flags = DELETE_MARKER;
+        entry
+    }
+
+    pub fn is_delete_marker(&self) -> bool {
+        self.flags & DELETE_MARKER != 0
+    }
+
+    fn order(&self, key: &[u8], version: u64) -> Ordering {
+        self.key().cmp(key).then_with(|| version.cmp(&self.version))
+    }
+
+    pub fn key(&self) -> &[u8] {
+        let len = self
+            .name
+            .iter()
+            .position(|b| *b == 0)
+            .unwrap_or(FILENAME_SIZE);
+        &self.name[..len]
+    }
+
+    pub fn summary(&self, is_latest: bool) -> ObjectSummary {
+        ObjectSummary {
+            name: String::from_utf8_lossy(self.key()).into_owned(),
+            id: self.id,
+            size: self.size,
+            version: self.version,
+            is_latest,
+            delete_marker: self.is_delete_marker(),
+        }
+    }
+}
+
+impl std::fmt::Debug for IndexEntry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("IndexEntry")
+            .field("name", &String::from_utf8_lossy(self.key()))
+            .field("id", &self.id)
+            .field("size", &self.size)
+            .field("version", &self.version)
+            .field("flags", &self.flags)
+            .finish()
+    }
+}
+
+impl std::fmt::Debug for IndexPage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("IndexPage")
+            .field("entries", &self.entries)
+            .field("next_index_page", &self.next_index_page)
+            .finish()
+    }
+}
+
+impl Default for IndexPage {
+    fn default() -> Self {
+        IndexPage {
+            entries: Vec::with_capacity(INDEX_PAGE_ENTRIES),
+            next_index_page: 0,
+        }
+    }
+}
+
+impl From<IndexPage> for Vec<u8> {
+    fn from(page: IndexPage) -> Vec<u8> {
+        let mut buf: Vec<u8> = Vec::with_capacity(INDEX_PAGE_BYTES);
+
+        buf.extend_from_slice(&(page.entries.len() as u64).to_le_bytes());
+        for n in 0..INDEX_PAGE_ENTRIES {
+            match page.entries.get(n) {
+                Some(e) => {
+                    buf.extend_from_slice(&e.name);
+                    buf.extend_from_slice(&e.id.to_le_bytes());
+                    buf.extend_from_slice(&e.size.to_le_bytes());
+                    buf.extend_from_slice(&e.version.to_le_bytes());
+                    buf.extend_from_slice(&e.flags.to_le_bytes());
+                }
+                None => buf.extend_from_slice(&[0u8; INDEX_ENTRY_BYTES]),
+            }
+        }
+        buf.extend_from_slice(&page.next_index_page.to_le_bytes());
+
+        buf
+    }
+}
+
+impl From<[u8; INDEX_PAGE_BYTES]> for IndexPage {
+    fn from(bytes: [u8; INDEX_PAGE_BYTES]) -> Self {
+        let mut buf = [0u8; 8];
+        buf.copy_from_slice(&bytes[0..8]);
+        let count = std::cmp::min(u64::from_le_bytes(buf) as usize, INDEX_PAGE_ENTRIES);
+
+        let mut entries = Vec::with_capacity(INDEX_PAGE_ENTRIES);
+        let mut i = 8;
+        for _ in 0..count {
+            let mut name = [0u8; FILENAME_SIZE];
+            name.copy_from_slice(&bytes[i..i + FILENAME_SIZE]);
+
+            let mut buf = [0u8; 16];
+            buf.copy_from_slice(&bytes[i + FILENAME_SIZE..i + FILENAME_SIZE + 16]);
+            let id = u128::from_le_bytes(buf);
+
+            let mut fields = [0u64; 3];
+            for (n, field) in fields.iter_mut().enumerate() {
+                let at = i + FILENAME_SIZE + 16 + n * 8;
+                let mut buf = [0u8; 8];
+                buf.copy_from_slice(&bytes[at..at + 8]);
+                *field = u64::from_le_bytes(buf);
+            }
+
+            entries.push(IndexEntry {
+                name,
+                id,
+                size: fields[0],
+                version: fields[1],
+                flags: fields[2],
+            });
+            i += INDEX_ENTRY_BYTES;
+        }
+
+        let mut buf = [0u8; 8];
+        buf.copy_from_slice(&bytes[INDEX_PAGE_BYTES - 8..INDEX_PAGE_BYTES]);
+        IndexPage {
+            entries,
+            next_index_page: u64::from_le_bytes(buf),
+        }
+    }
+}
+
+impl IndexPage {
+    pub fn next_index_page(&self) -> u64 {
+        self.next_index_page
+    }
+
+    pub fn entries(&self) -> &[IndexEntry] {
+        &self.entries
+    }
+
+    pub fn set_next_index_page(&mut self, next: u64) {
+        self.next_index_page = next;
+    }
+}
+
+impl ObjectIndex {
+    pub fn new(pages: Vec<IndexPage>) -> ObjectIndex {
+        ObjectIndex { pages }
+    }
+
+    pub fn pages(&self) -> &[IndexPage] {
+        &self.pages
+    }
+
+    pub fn len(&self) -> usize {
+        self.pages.iter().map(|p| p.entries.len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &IndexEntry> {
+        self.pages.iter().flat_map(|p| p.entries.iter())
+    }
+
+    // Pages are sorted as a whole, so only the last page starting at or
+    // before (`key`, `version`) can hold it; empty pages are skipped.
+    fn page_for(&self, key: &[u8], version: u64) -> usize {
+        let mut page = 0;
+        for (n, p) in self.pages.iter().enumerate() {
+            match p.entries.first() {
+                Some(first) if first.order(key, version) != Ordering::Greater => page = n,
+                Some(_) => break,
+                None => {}
+            }
+        }
+        page
+    }
+
+    // Position of the first entry not ordered before (`key`, `version`).
+    fn lower_bound(&self, key: &[u8], version: u64) -> (usize, usize) {
+        let page = self.page_for(key, version);
+        let pos = match self.pages[page]
+            .entries
+            .binary_search_by(|e| e.order(key, version))
+        {
+            Ok(pos) => pos,
+            Err(pos) => pos,
+        };
+        (page, pos)
+    }
+
+    fn iter_from(&self, page: usize, pos: usize) -> impl Iterator<Item = &IndexEntry> {
+        self.pages[page].entries[pos..]
+            .iter()
+            .chain(self.pages[page + 1..].iter().flat_map(|p| p.entries.iter()))
+    }
+
+    pub(crate) fn versions(&self, key: &[u8]) -> impl Iterator<Item = &IndexEntry> {
+        let (page, pos) = self.lower_bound(key, u64::MAX);
+        let key = key.to_vec();
+        self.iter_from(page, pos)
+            .take_while(move |e| e.key() == &key[..])
+    }
+
+    // Current version of `key`, which may be a delete marker.
+    pub fn get(&self, key: &[u8]) -> Option<&IndexEntry> {
+        self.versions(key).next()
+    }
+
+    pub fn get_version(&self, key: &[u8], id: UUID) -> Option<&IndexEntry> {
+        self.versions(key).find(|e| e.id == id)
+    }
+
+    // Inserts `entry`, replacing one with the same name and version. When the
+    // target page is full it is split in two and the new half is placed at
+    // the offset returned by `allocate`.
+    pub fn insert(
+        &mut self,
+        entry: IndexEntry,
+        allocate: &mut dyn FnMut() -> u64,
+    ) -> Option<IndexEntry> {
+        let n = self.page_for(entry.key(), entry.version);
+        let page = &mut self.pages[n];
+        match page
+            .entries
+            .binary_search_by(|e| e.order(entry.key(), entry.version))
+        {
+            Ok(pos) => Some(std::mem::replace(&mut page.entries[pos], entry)),
+            Err(pos) => {
+                page.entries.insert(pos, entry);
+                if page.entries.len() > INDEX_PAGE_ENTRIES {
+                    let upper = page.entries.split_off(page.entries.len() / 2);
+                    let new_page = IndexPage {
+                        entries: upper,
+                        next_index_page: page.next_index_page,
+                    };
+                    page.next_index_page = allocate();
+                    self.pages.insert(n + 1, new_page);
+                }
+                None
+            }
+        }
+    }
+
+    // Removes every version of `key`. Pages left empty are unlinked from the
+    // chain and their offsets handed to `free`.
+    pub fn remove_all(&mut self, key: &[u8], free: &mut dyn FnMut(u64)) -> Vec<IndexEntry> {
+        let mut removed = Vec::new();
+        for page in self.pages.iter_mut() {
+            let (gone, kept): (Vec<IndexEntry>, Vec<IndexEntry>) =
+                page.entries.drain(..).partition(|e| e.key() == key);
+            page.entries = kept;
+            removed.extend(gone);
+        }
+        self.prune(free);
+        removed
+    }
+
+    pub fn remove_version(
+        &mut self,
+        key: &[u8],
+        id: UUID,
+        free: &mut dyn FnMut(u64),
+    ) -> Option<IndexEntry> {
+        for page in self.pages.iter_mut() {
+            if let Some(pos) = page
+                .entries
+                .iter()
+                .position(|e| e.key() == key && e.id == id)
+            {
+                let removed = page.entries.remove(pos);
+                self.prune(free);
+                return Some(removed);
+            }
+        }
+        None
+    }
+
+    // Unlinks empty pages, handing their offsets to `free`. The first page
+    // is where the chain starts, so it stays, taking the entries of the
+    // first page that has any.
+    fn prune(&mut self, free: &mut dyn FnMut(u64)) {
+        if self.pages[0].entries.is_empty() {
+            if let Some(n) = self.pages.iter().position(|p| !p.entries.is_empty()) {
+                self.pages[0].entries = std::mem::take(&mut self.pages[n].entries);
+            }
+        }
+        let mut n = 1;
+        while n < self.pages.len() {
+            if !self.pages[n].entries.is_empty() {
+                n += 1;
+                continue;
+            }
+            let page = self.pages.remove(n);
+            free(self.pages[n - 1].next_index_page);
+            self.pages[n - 1].next_index_page = page.next_index_page;
+        }
+    }
+
+    pub fn list(
+        &self,
+        prefix: &str,
+        delimiter: Option<&str>,
+        start_after: Option<&str>,
+        limit: usize,
+    ) -> ObjectList {
+        self.scan(prefix, delimiter, start_after, limit, false)
+    }
+
+    // Like `list`, but returns every version and delete marker instead of
+    // only the current state of each name.
+    pub fn list_versions(
+        &self,
+        prefix: &str,
+        start_after: Option<&str>,
+        limit: usize,
+    ) -> ObjectList {
+        self.scan(prefix, None, start_after, limit, true)
+    }
+
+    fn scan(
+        &self,
+        prefix: &str,
+        delimiter: Option<&str>,
+        start_after: Option<&str>,
+        limit: usize,
+        all_versions: bool,
+    ) -> ObjectList {
+        let prefix = prefix.as_bytes();
+        let start_after = start_after.unwrap_or("").as_bytes();
+        let delimiter = delimiter.filter(|d| !d.is_empty()).map(|d| d.as_bytes());
+        let mut ret = ObjectList::default();
+        let mut count = 0;
+
+        let from = if start_after > prefix {
+            start_after
+        } else {
+            prefix
+        };
+        let (page, pos) = self.lower_bound(from, u64::MAX);
+        let entries = self
+            .iter_from(page, pos)
+            .skip_while(|e| e.key() < prefix || e.key() <= start_after);
+
+        let mut previous: Option<&[u8]> = None;
+        for entry in entries {
+            let key = entry.key();
+            if !key.starts_with(prefix) {
+                break;
+            }
+            let is_latest = previous != Some(key);
+            previous = Some(key);
+            if !all_versions && (!is_latest || entry.is_delete_marker()) {
+                continue;
+            }
+
+            let common = delimiter.and_then(|d| {
+                find(&key[prefix.len()..], d).map(|at| &key[..prefix.len() + at + d.len()])
+            });
+            let last_key = match common {
+                Some(common) => {
+                    let common = String::from_utf8_lossy(common).into_owned();
+                    if ret.common_prefixes.last() == Some(&common) {
+                        continue;
+                    }
+                    if common.as_bytes() <= start_after {
+                        continue;
+                    }
+                    if count == limit {
+                        ret.truncated = true;
+                        break;
+                    }
+                    ret.common_prefixes.push(common.clone());
+                    common
+                }
+                None => {
+                    // Versions of one name are never split across pages of
+                    // results, as `start_after` can only resume at a name.
+                    if count >= limit && is_latest {
+                        ret.truncated = true;
+                        break;
+                    }
+                    let summary = entry.summary(is_latest);
+                    let name = summary.name.clone();
+                    ret.objects.push(summary);
+                    name
+                }
+            };
+            count += 1;
+            ret.next_start_after = Some(last_key);
+        }
+        if !ret.truncated {
+            ret.next_start_after = None;
+        }
+
+        ret
+    }
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|w| w == needle)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{IndexEntry, ObjectIndex, INDEX_PAGE_ENTRIES};
+    use crate::index::IndexPage;
+
+    // An index of `names`, each with one version, and the page offsets its
+    // inserts allocated, counting up from 1.
+    fn index(names: &[&str]) -> (ObjectIndex, u64) {
+        let mut index = ObjectIndex::new(vec![IndexPage::default()]);
+        let mut next = 0;
+        for (n, name) in names.iter().enumerate() {
+            put(&mut index, &mut next, name, n as u64 + 1, false);
+        }
+        (index, next)
+    }
+
+    fn put(index: &mut ObjectIndex, next: &mut u64, name: &str, version: u64, marker: bool) {
+        let mut entry = match marker {
+            true => IndexEntry::delete_marker(name.as_bytes()),
+            false => IndexEntry::new(name.as_bytes(), version as u128, 10),
+        };
+        entry.version = version;
+        index.insert(entry, &mut || {
+            *next += 1;
+            *next
+        });
+    }
+
+    fn names(list: &super::ObjectList) -> Vec<&str> {
+        list.objects.iter().map(|o| &o.name[..]).collect()
+    }
+
+    // The offsets of every page but the first, which the superblock points
+    // at.
+    fn chain(index: &ObjectIndex) -> Vec<u64> {
+        index
+            .pages()
+            .iter()
+            .map(|p| p.next_index_page())
+            .filter(|n| *n != 0)
+            .collect()
+    }
+
+    #[test]
+    fn lists_by_prefix_and_delimiter() {
+        let (index, _) = index(&["a/1", "a/2", "a/b/3", "ab", "b/1"]);
+        let top = index.list("a", Some("/"), None, 100);
+        assert_eq!(names(&top), vec!["ab"]);
+        assert_eq!(top.common_prefixes, vec!["a/".to_owned()]);
+        assert!(!top.truncated);
+
+        let inner = index.list("a/", Some("/"), None, 100);
+        assert_eq!(names(&inner), vec!["a/1", "a/2"]);
+        assert_eq!(inner.common_prefixes, vec!["a/b/".to_owned()]);
+
+        let flat = index.list("a/", None, None, 100);
+        assert_eq!(names(&flat), vec!["a/1", "a/2", "a/b/3"]);
+
+        let after = index.list("", Some("/"), Some("a/"), 100);
+        assert_eq!(names(&after), vec!["ab"]);
+        assert_eq!(after.common_prefixes, vec!["b/".to_owned()]);
+    }
+
+    #[test]
+    fn pages_through_every_name_once() {
+        let all: Vec<String> = (0..3 * INDEX_PAGE_ENTRIES + 5)
+            .map(|n| format!("name{:03}", n))
+            .collect();
+        let refs: Vec<&str> = all.iter().map(|n| &n[..]).collect();
+        let (index, _) = index(&refs);
+        assert!(index.pages().len() > 1);
+
+        let mut seen = Vec::new();
+        let mut start_after = None;
+        loop {
+            let page = index.list("name", None, start_after.as_deref(), 7);
+            assert!(page.objects.len() <= 7);
+            seen.extend(page.objects.iter().map(|o| o.name.clone()));
+            if !page.truncated {
+                assert!(page.next_start_after.is_none());
+                break;
+            }
+            start_after = page.next_start_after;
+        }
+        assert_eq!(seen, all);
+        assert!(index.list("name", None, None, 0).truncated);
+        assert!(index.list("other", None, None, 10).objects.is_empty());
+    }
+
+    #[test]
+    fn delete_markers_hide_a_name_but_keep_its_versions() {
+        let mut index = ObjectIndex::new(vec![IndexPage::default()]);
+        let mut next = 0;
+        put(&mut index, &mut next, "x", 1, false);
+        put(&mut index, &mut next, "x", 2, false);
+        put(&mut index, &mut next, "y", 3, false);
+        put(&mut index, &mut next, "x", 4, true);
+
+        assert!(index.get(b"x").unwrap().is_delete_marker());
+        assert_eq!(names(&index.list("", None, None, 10)), vec!["y"]);
+
+        let versions = index.list_versions("", None, 10);
+        assert_eq!(names(&versions), vec!["x", "x", "x", "y"]);
+        let latest: Vec<bool> = versions.objects.iter().map(|o| o.is_latest).collect();
+        assert_eq!(latest, vec![true, false, false, true]);
+        assert!(versions.objects[0].delete_marker);
+        assert_eq!(versions.objects[1].version, 2);
+        assert_eq!(versions.objects[2].version, 1);
+
+        // The versions of one name are never split between pages.
+        let first = index.list_versions("", None, 1);
+        assert_eq!(names(&first), vec!["x", "x", "x"]);
+        assert!(first.truncated);
+        assert_eq!(first.next_start_after.as_deref(), Some("x"));
+        let rest = index.list_versions("", Some("x"), 1);
+        assert_eq!(names(&rest), vec!["y"]);
+        assert!(!rest.truncated);
+    }
+
+    #[test]
+    fn removing_every_name_frees_every_page_but_the_first() {
+        let all: Vec<String> = (0..4 * INDEX_PAGE_ENTRIES)
+            .map(|n| format!("n{:03}", n))
+            .collect();
+        let refs: Vec<&str> = all.iter().map(|n| &n[..]).collect();
+        let (mut index, _) = index(&refs);
+        let mut pages = chain(&index);
+        assert!(!pages.is_empty());
+
+        let mut freed = Vec::new();
+        for name in refs.iter() {
+            assert_eq!(
+                index
+                    .remove_all(name.as_bytes(), &mut |p| freed.push(p))
+                    .len(),
+                1
+            );
+        }
+        assert!(index.is_empty());
+        assert_eq!(index.pages().len(), 1);
+        assert_eq!(index.pages()[0].next_index_page(), 0);
+        freed.sort();
+        pages.sort();
+        assert_eq!(freed, pages);
+    }
+
+    #[test]
+    fn pages_emptied_in_the_middle_are_unlinked() {
+        let all: Vec<String> = (0..4 * INDEX_PAGE_ENTRIES)
+            .map(|n| format!("n{:03}", n))
+            .collect();
+        let refs: Vec<&str> = all.iter().map(|n| &n[..]).collect();
+        let (mut index, _) = index(&refs);
+        let before = chain(&index);
+
+        let mut freed = Vec::new();
+        for (n, name) in refs.iter().enumerate() {
+            if (INDEX_PAGE_ENTRIES..3 * INDEX_PAGE_ENTRIES).contains(&n) {
+                let id = index.get(name.as_bytes()).unwrap().id;
+                let free = &mut |p| freed.push(p);
+                assert!(index.remove_version(name.as_bytes(), id, free).is_some());
+            }
+        }
+        assert!(!freed.is_empty());
+        assert!(index.pages().iter().all(|p| !p.entries().is_empty()));
+        let mut after = chain(&index);
+        after.extend(freed.iter());
+        after.sort();
+        let mut before = before;
+        before.sort();
+        assert_eq!(after, before);
+
+        let left: Vec<&[u8]> = index.iter().map(|e| e.key()).collect();
+        assert_eq!(left.len(), 2 * INDEX_PAGE_ENTRIES);
+        assert_eq!(left[0], b"n000");
+        assert_eq!(
+            left[INDEX_PAGE_ENTRIES],
+            format!("n{:03}", 3 * INDEX_PAGE_ENTRIES).as_bytes()
+        );
+    }
+}
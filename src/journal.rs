@@ -0,0 +1,172 @@
+use crate::crc32c::crc32c;
+use crate::error::VolumeError;
+use crate::mirror::VolumeFile;
+use crate::UUID;
+use serde::{Deserialize, Serialize};
+
+pub const JOURNAL_MAGIC: [u8; 8] = *b"OGGJRNL\0";
+pub const JOURNAL_HEADER_BYTES: u64 = 8 + 8 + 4;
+pub const JOURNAL_BYTES: u64 = 1024 * 1024;
+
+// What the metadata volume is in the middle of. An object write first
+// records its intent (the file and chunk ids it is about to allocate); its
+// metadata pages are only written in place once the same record, now holding
+// their new images, has been made durable with `committed` set.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct JournalRecord {
+    pub file: UUID,
+    pub chunks: Vec<UUID>,
+    pub pages: Vec<(u64, Vec<u8>)>,
+    pub committed: bool,
+}
+
+impl JournalRecord {
+    pub fn intent(file: UUID, chunks: &[UUID]) -> JournalRecord {
+        JournalRecord {
+            file,
+            chunks: chunks.to_vec(),
+            pages: Vec::new(),
+            committed: false,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.file == 0 && self.chunks.is_empty() && self.pages.is_empty()
+    }
+
+    pub fn size(&self) -> u64 {
+        JOURNAL_HEADER_BYTES + bincode::serialized_size(self).unwrap()
+    }
+}
+
+// The journal region starts with the magic, the length of the record and its
+// crc. A record that doesn't check out was never made durable, so it is
+// treated as an empty journal.
+pub fn read_journal(file: &VolumeFile, start: u64) -> Result<JournalRecord, VolumeError> {
+    if start == 0 {
+        return Ok(JournalRecord::default());
+    }
+    let mut header = [0u8; JOURNAL_HEADER_BYTES as usize];
+    file.read_at(start, &mut header)?;
+    if header[0..8] != JOURNAL_MAGIC {
+        return Ok(JournalRecord::default());
+    }
+
+    let mut buf = [0u8; 8];
+    buf.copy_from_slice(&header[8..16]);
+    let len = u64::from_le_bytes(buf);
+    let mut buf = [0u8; 4];
+    buf.copy_from_slice(&header[16..20]);
+    let crc = u32::from_le_bytes(buf);
+    if len == 0 || len > JOURNAL_BYTES * 1024 {
+        return Ok(JournalRecord::default());
+    }
+
+    let mut payload = vec![0u8; len as usize];
+    if file
+        .read_at(start + JOURNAL_HEADER_BYTES, &mut payload[..])
+        .is_err()
+        || crc32c(&payload[..]) != crc
+    {
+        return Ok(JournalRecord::default());
+    }
+    Ok(bincode::deserialize(&payload[..]).unwrap_or_default())
+}
+
+// Writes `record` at `start` and waits for it to reach the disk.
+pub fn write_journal(
+    file: &mut VolumeFile,
+    start: u64,
+    record: &JournalRecord,
+) -> Result<(), VolumeError> {
+    let mut bytes = Vec::new();
+    bytes.extend_from_slice(&JOURNAL_MAGIC);
+    if record.is_empty() {
+        bytes.extend_from_slice(&0u64.to_le_bytes());
+        bytes.extend_from_slice(&0u32.to_le_bytes());
+    } else {
+        let payload = bincode::serialize(record)
+            .map_err(|err| VolumeError::IoError(std::io::Error::other(err)))?;
+        bytes.extend_from_slice(&(payload.len() as u64).to_le_bytes());
+        bytes.extend_from_slice(&crc32c(&payload[..]).to_le_bytes());
+        bytes.extend_from_slice(&payload[..]);
+    }
+    file.write_at(start, &bytes[..])?;
+    file.sync_data()
+}
+
+// Writes the page images of a committed record in place.
+pub fn replay_journal(file: &mut VolumeFile, record: &JournalRecord) -> Result<(), VolumeError> {
+    for (pos, bytes) in record.pages.iter() {
+        file.write_at(*pos, &bytes[..])?;
+    }
+    file.sync_data()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{read_journal, replay_journal, write_journal, JournalRecord, JOURNAL_HEADER_BYTES};
+    use crate::mirror::VolumeFile;
+    use crate::test_util::Scratch;
+    use std::fs::OpenOptions;
+
+    fn file(scratch: &Scratch) -> VolumeFile {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(scratch.path("journal"))
+            .unwrap();
+        VolumeFile::plain(file)
+    }
+
+    fn record() -> JournalRecord {
+        JournalRecord {
+            pages: vec![(0, vec![1, 2, 3]), (10, vec![4; 5])],
+            committed: true,
+            ..JournalRecord::intent(7, &[8, 9])
+        }
+    }
+
+    #[test]
+    fn record_reads_back_as_written() {
+        let scratch = Scratch::new();
+        let mut file = file(&scratch);
+        write_journal(&mut file, 100, &record()).unwrap();
+        let read = read_journal(&file, 100).unwrap();
+        assert_eq!(read.file, 7);
+        assert_eq!(read.chunks, vec![8, 9]);
+        assert_eq!(read.pages, record().pages);
+        assert!(read.committed);
+
+        write_journal(&mut file, 100, &JournalRecord::default()).unwrap();
+        assert!(read_journal(&file, 100).unwrap().is_empty());
+    }
+
+    #[test]
+    fn torn_record_reads_as_empty() {
+        let scratch = Scratch::new();
+        let mut file = file(&scratch);
+        write_journal(&mut file, 100, &record()).unwrap();
+        let at = 100 + JOURNAL_HEADER_BYTES + 3;
+        let mut byte = [0u8];
+        file.read_at(at, &mut byte).unwrap();
+        file.write_at(at, &[byte[0] ^ 0xff]).unwrap();
+        assert!(read_journal(&file, 100).unwrap().is_empty());
+        assert!(read_journal(&file, 0).unwrap().is_empty());
+    }
+
+    #[test]
+    fn replay_writes_the_pages_in_place() {
+        let scratch = Scratch::new();
+        let mut file = file(&scratch);
+        file.write_at(0, &[0u8; 20]).unwrap();
+        replay_journal(&mut file, &record()).unwrap();
+        let mut buf = [0u8; 20];
+        file.read_at(0, &mut buf).unwrap();
+        assert_eq!(buf[..3], [1, 2, 3]);
+        assert_eq!(buf[3..10], [0; 7]);
+        assert_eq!(buf[10..15], [4; 5]);
+    }
+}
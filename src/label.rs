@@ -0,0 +1,171 @@
+use crate::block::Block;
+use crate::chunk::Chunk;
+use crate::constants::{BLOCKS, BLOCK_SIZE, FILENAME_SIZE, PARITY};
+use crate::crc32c::crc32c;
+use crate::UUID;
+
+pub const LABEL_MAGIC: [u8; 8] = *b"OGGCHUNK";
+pub const LABEL_BYTES: usize = 512;
+
+// Written in front of every chunk in the block file. It says which object
+// the chunk belongs to and where in it, so the objects can be pieced back
+// together from the block file alone if the metadata file is lost. A chunk
+// stored before its object exists, like a part of a multipart upload, is
+// labelled again once the object is complete. In a volume spread over
+// several block stores it also says which store holds each block.
+#[derive(Debug, Clone, Copy)]
+pub struct ChunkLabel {
+    pub file: UUID,
+    pub chunk: UUID,
+    pub position: u32,
+    pub count: u32,
+    pub written: u64,
+    pub name: [u8; FILENAME_SIZE],
+    pub blocks: u32,
+    pub parity: u32,
+    pub block_size: u32,
+    pub chunk_size: u32,
+    pub hash: u32,
+    pub crcs: [u32; BLOCKS + PARITY],
+    pub stores: [u16; BLOCKS + PARITY],
+}
+
+impl ChunkLabel {
+    // A label naming no object yet.
+    pub fn new(chunk: &Chunk, blocks: &[Block]) -> ChunkLabel {
+        let mut crcs = [0u32; BLOCKS + PARITY];
+        for b in blocks.iter().filter(|b| b.position < BLOCKS + PARITY) {
+            crcs[b.position] = b.crc;
+        }
+        ChunkLabel {
+            file: 0,
+            chunk: chunk.id,
+            position: chunk.position,
+            count: 0,
+            written: 0,
+            name: [0u8; FILENAME_SIZE],
+            blocks: chunk.chunk_n as u32,
+            parity: chunk.parity_n as u32,
+            block_size: BLOCK_SIZE as u32,
+            chunk_size: chunk.chunk_size as u32,
+            hash: chunk.hash,
+            crcs,
+            stores: [0u16; BLOCKS + PARITY],
+        }
+    }
+
+    // The same label as chunk `position` of the `count` making up `file`.
+    pub fn owned(mut self, file: UUID, name: &[u8], position: u32, count: u32) -> ChunkLabel {
+        let len = name
+            .iter()
+            .position(|b| *b == 0)
+            .unwrap_or(name.len())
+            .min(FILENAME_SIZE);
+        self.file = file;
+        self.name = [0u8; FILENAME_SIZE];
+        self.name[..len].copy_from_slice(&name[..len]);
+        self.position = position;
+        self.count = count;
+        self.written = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_nanos() as u64)
+            .unwrap_or(0);
+        self
+    }
+
+    pub fn key(&self) -> &[u8] {
+        let len = self
+            .name
+            .iter()
+            .position(|b| *b == 0)
+            .unwrap_or(FILENAME_SIZE);
+        &self.name[..len]
+    }
+
+    // Parses a label as read from disk; anything without the magic or with
+    // a bad crc isn't one.
+    pub fn parse(bytes: &[u8]) -> Option<ChunkLabel> {
+        if bytes.len() < LABEL_BYTES || bytes[0..8] != LABEL_MAGIC {
+            return None;
+        }
+        let u16_at = |i: usize| {
+            let mut buf = [0u8; 2];
+            buf.copy_from_slice(&bytes[i..i + 2]);
+            u16::from_le_bytes(buf)
+        };
+        let u32_at = |i: usize| {
+            let mut buf = [0u8; 4];
+            buf.copy_from_slice(&bytes[i..i + 4]);
+            u32::from_le_bytes(buf)
+        };
+        let u64_at = |i: usize| {
+            let mut buf = [0u8; 8];
+            buf.copy_from_slice(&bytes[i..i + 8]);
+            u64::from_le_bytes(buf)
+        };
+        let u128_at = |i: usize| {
+            let mut buf = [0u8; 16];
+            buf.copy_from_slice(&bytes[i..i + 16]);
+            u128::from_le_bytes(buf)
+        };
+        if crc32c(&bytes[..LABEL_BYTES - 4]) != u32_at(LABEL_BYTES - 4) {
+            return None;
+        }
+
+        let mut name = [0u8; FILENAME_SIZE];
+        name.copy_from_slice(&bytes[64..64 + FILENAME_SIZE]);
+        let mut crcs = [0u32; BLOCKS + PARITY];
+        for (n, crc) in crcs.iter_mut().enumerate() {
+            *crc = u32_at(64 + FILENAME_SIZE + n * 4);
+        }
+        let mut stores = [0u16; BLOCKS + PARITY];
+        for (n, store) in stores.iter_mut().enumerate() {
+            *store = u16_at(76 + FILENAME_SIZE + (BLOCKS + PARITY) * 4 + n * 2);
+        }
+        Some(ChunkLabel {
+            file: u128_at(8),
+            chunk: u128_at(24),
+            position: u32_at(40),
+            count: u32_at(44),
+            written: u64_at(48),
+            name,
+            blocks: u32_at(56),
+            parity: u32_at(60),
+            block_size: u32_at(64 + FILENAME_SIZE + (BLOCKS + PARITY) * 4),
+            chunk_size: u32_at(68 + FILENAME_SIZE + (BLOCKS + PARITY) * 4),
+            hash: u32_at(72 + FILENAME_SIZE + (BLOCKS + PARITY) * 4),
+            crcs,
+            stores,
+        })
+    }
+}
+
+impl From<ChunkLabel> for Vec<u8> {
+    fn from(label: ChunkLabel) -> Vec<u8> {
+        let mut buf: Vec<u8> = Vec::with_capacity(LABEL_BYTES);
+
+        buf.extend_from_slice(&LABEL_MAGIC);
+        buf.extend_from_slice(&label.file.to_le_bytes());
+        buf.extend_from_slice(&label.chunk.to_le_bytes());
+        buf.extend_from_slice(&label.position.to_le_bytes());
+        buf.extend_from_slice(&label.count.to_le_bytes());
+        buf.extend_from_slice(&label.written.to_le_bytes());
+        buf.extend_from_slice(&label.blocks.to_le_bytes());
+        buf.extend_from_slice(&label.parity.to_le_bytes());
+        buf.extend_from_slice(&label.name);
+        for crc in label.crcs.iter() {
+            buf.extend_from_slice(&crc.to_le_bytes());
+        }
+        buf.extend_from_slice(&label.block_size.to_le_bytes());
+        buf.extend_from_slice(&label.chunk_size.to_le_bytes());
+        buf.extend_from_slice(&label.hash.to_le_bytes());
+        for store in label.stores.iter() {
+            buf.extend_from_slice(&store.to_le_bytes());
+        }
+        buf.resize(LABEL_BYTES - 4, 0);
+        let crc = crc32c(&buf[..]);
+        buf.extend_from_slice(&crc.to_le_bytes());
+
+        buf
+    }
+}
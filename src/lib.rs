@@ -9,11 +9,38 @@ extern crate serde_derive;
 extern crate serde_json;
 
 extern crate uuid;
+pub mod async_volume;
+pub mod backend;
 pub mod block;
 pub mod chunk;
+pub mod consensus;
 pub mod constants;
+pub mod directory;
+pub mod domain;
 pub mod error;
+pub mod fsck;
+pub mod hedge;
+pub mod index;
+pub mod journal;
+pub mod label;
+pub mod memory;
+pub mod metad;
+pub mod mirror;
+pub mod multipart;
+pub mod net;
+pub mod pipeline;
+pub mod placement;
+pub mod rebalance;
+pub mod recover;
 pub mod redundant_file;
+pub mod resize;
+pub mod shared;
+pub mod snapshot;
+pub mod store;
+pub mod stream;
+#[cfg(test)]
+mod test_util;
+pub mod upgrade;
 pub mod volume;
 pub mod volume_manager;
 
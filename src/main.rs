@@ -1,10 +1,84 @@
 extern crate clap;
 use clap::{App, Arg};
 
+use oggetto::backend::BlockStore;
+use oggetto::consensus::{self, Message};
+use oggetto::directory::{self, DirBlockStore};
+use oggetto::domain::{domain, split_domain, FailureDomain};
+use oggetto::error::{ErrorLog, VolumeError};
+use oggetto::fsck::{check, repair};
+use oggetto::metad;
+use oggetto::net::{self, NetBlockStores};
+use oggetto::pipeline::Pipeline;
+use oggetto::rebalance::{attach, decommission, rebalance, Progress};
+use oggetto::recover::recover;
+use oggetto::resize::resize;
+use oggetto::store::BlockStores;
+use oggetto::stream::{receive, send};
+use oggetto::upgrade::{upgrade, upgrade_in_place, Layout};
 use oggetto::volume::{BigFileVolume, Volume};
 
 fn main() {
     let matches = App::new("Oggetto")
+        .arg(
+            Arg::with_name("store")
+                .long("store")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .value_name("PATH[@DOMAIN]")
+                .help("block store to use instead of block.bin; give several to spread the volume over them, each in the failure domain, like rack/host/disk, it is created in"),
+        )
+        .arg(
+            Arg::with_name("dir")
+                .long("dir")
+                .takes_value(true)
+                .value_name("PATH")
+                .conflicts_with("store")
+                .help("keep the volume as a tree of small files in this directory instead of volume.bin and block.bin"),
+        )
+        .arg(
+            Arg::with_name("node")
+                .long("node")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .value_name("ADDR[@DOMAIN]")
+                .conflicts_with_all(&["store", "dir"])
+                .help("block daemon to keep the blocks on instead of block.bin; give one for every block and parity block at least, each in the failure domain it is created in"),
+        )
+        .arg(
+            Arg::with_name("quorum")
+                .long("quorum")
+                .takes_value(true)
+                .value_name("BLOCKS")
+                .requires("node")
+                .help("blocks of a chunk that have to reach their daemons for a write to succeed; the rest wait for daemons that are down"),
+        )
+        .arg(
+            Arg::with_name("hedge")
+                .long("hedge")
+                .takes_value(true)
+                .value_name("MS")
+                .requires("node")
+                .help("milliseconds a read waits on a slow daemon before asking another for one more block of the chunk; 0 asks all of them at once"),
+        )
+        .arg(
+            Arg::with_name("workers")
+                .long("workers")
+                .takes_value(true)
+                .value_name("N")
+                .help("threads encoding or decoding chunks at once; defaults to one per core, up to 8"),
+        )
+        .arg(
+            Arg::with_name("meta")
+                .long("meta")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .value_name("ADDR")
+                .help("replica of the metadata service to keep the metadata on instead of volume.bin; give all three or five, in the same order as to metad"),
+        )
         .subcommand(
             App::new("write").arg(
                 Arg::with_name("FILE")
@@ -14,19 +88,365 @@ fn main() {
             ),
         )
         .subcommand(
-            App::new("read").arg(
-                Arg::with_name("FILE")
+            App::new("read")
+                .arg(
+                    Arg::with_name("FILE")
+                        .index(1)
+                        .required(true)
+                        .help("file to read"),
+                )
+                .arg(
+                    Arg::with_name("version")
+                        .long("version")
+                        .takes_value(true)
+                        .help("read this version instead of the current one"),
+                )
+                .arg(
+                    Arg::with_name("snapshot")
+                        .long("snapshot")
+                        .takes_value(true)
+                        .conflicts_with("version")
+                        .help("read the file as it was in this snapshot"),
+                ),
+        )
+        .subcommand(
+            App::new("ls")
+                .arg(
+                    Arg::with_name("PREFIX")
+                        .index(1)
+                        .help("only list objects whose name starts with this prefix"),
+                )
+                .arg(
+                    Arg::with_name("delimiter")
+                        .long("delimiter")
+                        .short("d")
+                        .takes_value(true)
+                        .help("group names sharing a prefix up to this delimiter"),
+                )
+                .arg(
+                    Arg::with_name("start-after")
+                        .long("start-after")
+                        .takes_value(true)
+                        .help("only list names sorting after this one"),
+                )
+                .arg(
+                    Arg::with_name("limit")
+                        .long("limit")
+                        .takes_value(true)
+                        .default_value("1000")
+                        .help("maximum number of entries to list"),
+                )
+                .arg(
+                    Arg::with_name("versions")
+                        .long("versions")
+                        .help("list every version and delete marker"),
+                )
+                .arg(
+                    Arg::with_name("snapshot")
+                        .long("snapshot")
+                        .takes_value(true)
+                        .conflicts_with("versions")
+                        .help("list the objects as they were in this snapshot"),
+                ),
+        )
+        .subcommand(
+            App::new("rm")
+                .arg(
+                    Arg::with_name("FILE")
+                        .index(1)
+                        .required(true)
+                        .help("object to delete"),
+                )
+                .arg(
+                    Arg::with_name("version")
+                        .long("version")
+                        .takes_value(true)
+                        .help("permanently delete this version"),
+                ),
+        )
+        .subcommand(
+            App::new("versioning").arg(
+                Arg::with_name("STATE")
                     .index(1)
-                    .required(true)
-                    .help("file to read"),
+                    .possible_values(&["on", "off"])
+                    .help("turn versioning on or off; prints the state if omitted"),
+            ),
+        )
+        .subcommand(
+            App::new("snapshot")
+                .subcommand(
+                    App::new("create").arg(
+                        Arg::with_name("NAME")
+                            .index(1)
+                            .required(true)
+                            .help("name of the new snapshot"),
+                    ),
+                )
+                .subcommand(App::new("list"))
+                .subcommand(
+                    App::new("delete").arg(
+                        Arg::with_name("NAME")
+                            .index(1)
+                            .required(true)
+                            .help("snapshot to delete"),
+                    ),
+                ),
+        )
+        .subcommand(
+            App::new("send")
+                .arg(
+                    Arg::with_name("snapshot")
+                        .long("snapshot")
+                        .takes_value(true)
+                        .help("send this snapshot instead of the live volume"),
+                )
+                .arg(
+                    Arg::with_name("from")
+                        .long("from")
+                        .takes_value(true)
+                        .requires("snapshot")
+                        .help("only send what changed since this snapshot"),
+                )
+                .arg(
+                    Arg::with_name("output")
+                        .long("output")
+                        .short("o")
+                        .takes_value(true)
+                        .help("write the stream to this file instead of stdout"),
+                ),
+        )
+        .subcommand(
+            App::new("receive").arg(
+                Arg::with_name("input")
+                    .long("input")
+                    .short("i")
+                    .takes_value(true)
+                    .help("read the stream from this file instead of stdin"),
+            ),
+        )
+        .subcommand(
+            App::new("fsck")
+                .arg(
+                    Arg::with_name("VOLUME")
+                        .index(1)
+                        .default_value("volume.bin")
+                        .help("metadata file of the volume"),
+                )
+                .arg(
+                    Arg::with_name("BLOCKS")
+                        .index(2)
+                        .default_value("block.bin")
+                        .help("block file of the volume"),
+                )
+                .arg(
+                    Arg::with_name("fix")
+                        .long("fix")
+                        .help("fix the problems that can be fixed without losing data"),
+                ),
+        )
+        .subcommand(
+            App::new("upgrade")
+                .arg(
+                    Arg::with_name("VOLUME")
+                        .index(1)
+                        .default_value("volume.bin")
+                        .help("metadata file of the volume"),
+                )
+                .arg(
+                    Arg::with_name("BLOCKS")
+                        .index(2)
+                        .default_value("block.bin")
+                        .help("block file of the volume"),
+                )
+                .arg(
+                    Arg::with_name("output")
+                        .long("output")
+                        .takes_value(true)
+                        .number_of_values(2)
+                        .value_names(&["VOLUME", "BLOCKS"])
+                        .help("write the upgraded volume to these new files instead of in place"),
+                ),
+        )
+        .subcommand(
+            App::new("recover")
+                .arg(
+                    Arg::with_name("BLOCKS")
+                        .index(1)
+                        .default_value("block.bin")
+                        .help("block file to recover the objects from"),
+                )
+                .arg(
+                    Arg::with_name("VOLUME")
+                        .index(2)
+                        .default_value("volume.bin")
+                        .help("new metadata file to write; must not exist"),
+                ),
+        )
+        .subcommand(
+            App::new("blockd")
+                .arg(
+                    Arg::with_name("DIR")
+                        .index(1)
+                        .required(true)
+                        .help("directory to keep the blocks in"),
+                )
+                .arg(
+                    Arg::with_name("listen")
+                        .long("listen")
+                        .takes_value(true)
+                        .value_name("ADDR")
+                        .default_value("127.0.0.1:7070")
+                        .help("address to serve the blocks on"),
+                ),
+        )
+        .subcommand(
+            App::new("metad")
+                .arg(
+                    Arg::with_name("DIR")
+                        .index(1)
+                        .required(true)
+                        .help("directory to keep the log of this replica in"),
+                )
+                .arg(
+                    Arg::with_name("id")
+                        .long("id")
+                        .takes_value(true)
+                        .required(true)
+                        .value_name("N")
+                        .help("which of the peers this replica is, from 0; it listens on that address"),
+                )
+                .arg(
+                    Arg::with_name("peer")
+                        .long("peer")
+                        .takes_value(true)
+                        .required(true)
+                        .multiple(true)
+                        .number_of_values(1)
+                        .value_name("ADDR")
+                        .help("address of every replica of the service, this one included, in the same order on all of them"),
+                ),
+        )
+        .subcommand(App::new("replicas"))
+        .subcommand(
+            App::new("domain")
+                .arg(
+                    Arg::with_name("STORE")
+                        .index(1)
+                        .required(true)
+                        .help("block store of a volume spread over several"),
+                )
+                .arg(
+                    Arg::with_name("DOMAIN")
+                        .index(2)
+                        .help("failure domain to move the store to, like rack/host/disk"),
+                ),
+        )
+        .subcommand(
+            App::new("rebalance").arg(
+                Arg::with_name("rate")
+                    .long("rate")
+                    .takes_value(true)
+                    .value_name("SIZE")
+                    .help("move at most this many bytes a second"),
             ),
         )
+        .subcommand(
+            App::new("attach")
+                .arg(
+                    Arg::with_name("STORE")
+                        .index(1)
+                        .required(true)
+                        .value_name("PATH[@DOMAIN]")
+                        .help("new block store to add to the volume"),
+                )
+                .arg(
+                    Arg::with_name("rate")
+                        .long("rate")
+                        .takes_value(true)
+                        .value_name("SIZE")
+                        .help("move at most this many bytes a second"),
+                ),
+        )
+        .subcommand(
+            App::new("decommission")
+                .arg(
+                    Arg::with_name("STORE")
+                        .index(1)
+                        .required(true)
+                        .help("block store to drain and take out of the volume"),
+                )
+                .arg(
+                    Arg::with_name("rate")
+                        .long("rate")
+                        .takes_value(true)
+                        .value_name("SIZE")
+                        .help("move at most this many bytes a second"),
+                ),
+        )
+        .subcommand(
+            App::new("resize")
+                .arg(
+                    Arg::with_name("FILE")
+                        .index(1)
+                        .default_value("block.bin")
+                        .help("metadata or block file to resize"),
+                )
+                .arg(
+                    Arg::with_name("SIZE")
+                        .index(2)
+                        .help("new size of the file, in bytes or with a K, M, G or T suffix"),
+                )
+                .arg(
+                    Arg::with_name("max")
+                        .long("max")
+                        .takes_value(true)
+                        .value_name("SIZE")
+                        .help("size the file may grow to on its own; 0 lifts the limit"),
+                ),
+        )
         .get_matches();
+    let stores: Option<Vec<String>> = matches
+        .values_of("store")
+        .map(|paths| paths.map(|p| p.to_owned()).collect());
+    let dir = matches.value_of("dir").map(|d| d.to_owned());
+    let nodes: Option<Vec<String>> = matches
+        .values_of("node")
+        .map(|addrs| addrs.map(|a| a.to_owned()).collect());
+    let quorum = match matches.value_of("quorum").map(|q| q.parse::<usize>()) {
+        Some(Ok(quorum)) => quorum,
+        Some(Err(_)) => {
+            println!("invalid quorum");
+            return;
+        }
+        None => net::DEFAULT_QUORUM,
+    };
+    let hedge = match matches.value_of("hedge").map(|h| h.parse::<u64>()) {
+        Some(Ok(hedge)) => Some(std::time::Duration::from_millis(hedge)),
+        Some(Err(_)) => {
+            println!("invalid hedge delay");
+            return;
+        }
+        None => None,
+    };
+    let pipeline = match matches.value_of("workers").map(|w| w.parse::<usize>()) {
+        Some(Ok(workers)) if workers > 0 => Some(Pipeline::new(workers, 2 * workers)),
+        Some(_) => {
+            println!("invalid number of workers");
+            return;
+        }
+        None => None,
+    };
+    let meta: Option<Vec<String>> = matches
+        .values_of("meta")
+        .map(|addrs| addrs.map(|a| a.to_owned()).collect());
     if let Some(ref matches) = matches.subcommand_matches("write") {
         match matches.value_of("FILE") {
             Some(input) => {
-                let mut volume = BigFileVolume::init("volume.bin", "block.bin");
-                let _id = volume.destruct_from_file(input).unwrap();
+                let mut volume = open_volume(&stores, &dir, &nodes, &meta, quorum, hedge, pipeline);
+                if let Err(err) = volume.destruct_from_file(input) {
+                    println!("cannot write {}: {:?}", input, err);
+                    std::process::exit(1);
+                }
             }
             None => {
                 println!("no file specified");
@@ -35,10 +455,531 @@ fn main() {
     }
     if let Some(ref matches) = matches.subcommand_matches("read") {
         match matches.value_of("FILE") {
-            Some(_input) => {}
+            Some(input) => {
+                let mut volume = open_volume(&stores, &dir, &nodes, &meta, quorum, hedge, pipeline);
+                let id = match (matches.value_of("version"), matches.value_of("snapshot")) {
+                    (Some(version), _) => match parse_id(version) {
+                        Some(version) => volume.lookup_version(input, version),
+                        None => {
+                            println!("invalid version");
+                            return;
+                        }
+                    },
+                    (None, Some(snapshot)) => volume.lookup_snapshot(snapshot, input),
+                    (None, None) => volume.lookup(input),
+                };
+                // The object goes to stdout, so what went wrong can't.
+                let read = id.and_then(|id| volume.restruct(id, &mut std::io::stdout()));
+                if let Err(err) = read {
+                    eprintln!("cannot read {}: {:?}", input, err);
+                    std::process::exit(1);
+                }
+            }
             None => {
                 println!("no file specified");
             }
         }
     }
+    if let Some(matches) = matches.subcommand_matches("ls") {
+        let limit = match matches.value_of("limit").unwrap().parse::<usize>() {
+            Ok(limit) => limit,
+            Err(_) => {
+                println!("invalid limit");
+                return;
+            }
+        };
+        let volume = open_volume(&stores, &dir, &nodes, &meta, quorum, hedge, pipeline);
+        let prefix = matches.value_of("PREFIX").unwrap_or("");
+        let listing = if matches.is_present("versions") {
+            volume.list_versions(prefix, matches.value_of("start-after"), limit)
+        } else if let Some(snapshot) = matches.value_of("snapshot") {
+            volume.list_snapshot(
+                snapshot,
+                prefix,
+                matches.value_of("delimiter"),
+                matches.value_of("start-after"),
+                limit,
+            )
+        } else {
+            volume.list(
+                prefix,
+                matches.value_of("delimiter"),
+                matches.value_of("start-after"),
+                limit,
+            )
+        };
+        let listing = match listing {
+            Ok(listing) => listing,
+            Err(err) => {
+                println!("cannot list {}: {:?}", prefix, err);
+                std::process::exit(1);
+            }
+        };
+        for prefix in listing.common_prefixes.iter() {
+            println!("{:>32} {:>12} {}", "PRE", "", prefix);
+        }
+        for object in listing.objects.iter() {
+            let state = match (object.delete_marker, object.is_latest) {
+                (true, _) => " (delete marker)",
+                (false, false) => " (noncurrent)",
+                (false, true) => "",
+            };
+            println!(
+                "{:032x} {:>12} {}{}",
+                object.id, object.size, object.name, state
+            );
+        }
+        if let Some(next) = listing.next_start_after {
+            println!("truncated, continue with --start-after {}", next);
+        }
+    }
+    if let Some(matches) = matches.subcommand_matches("rm") {
+        let name = matches.value_of("FILE").unwrap();
+        let mut volume = open_volume(&stores, &dir, &nodes, &meta, quorum, hedge, pipeline);
+        let deleted = match matches.value_of("version") {
+            Some(version) => match parse_id(version) {
+                Some(version) => volume.delete_version(name, version),
+                None => {
+                    println!("invalid version");
+                    return;
+                }
+            },
+            None => volume.delete(name),
+        };
+        if let Err(err) = deleted {
+            println!("cannot delete {}: {:?}", name, err);
+        }
+    }
+    if let Some(matches) = matches.subcommand_matches("versioning") {
+        let mut volume = open_volume(&stores, &dir, &nodes, &meta, quorum, hedge, pipeline);
+        match matches.value_of("STATE") {
+            Some(state) => {
+                if let Err(err) = volume.set_versioning(state == "on") {
+                    println!("cannot turn versioning {}: {:?}", state, err);
+                    std::process::exit(1);
+                }
+            }
+            None => println!("{}", if volume.versioning() { "on" } else { "off" }),
+        }
+    }
+    if let Some(matches) = matches.subcommand_matches("snapshot") {
+        let mut volume = open_volume(&stores, &dir, &nodes, &meta, quorum, hedge, pipeline);
+        let done = match matches.subcommand() {
+            ("create", Some(matches)) => volume.create_snapshot(matches.value_of("NAME").unwrap()),
+            ("delete", Some(matches)) => volume.delete_snapshot(matches.value_of("NAME").unwrap()),
+            _ => {
+                for snapshot in volume.list_snapshots() {
+                    println!(
+                        "{:>12} {:>8} {}",
+                        snapshot.created, snapshot.objects, snapshot.name
+                    );
+                }
+                Ok(())
+            }
+        };
+        if let Err(err) = done {
+            println!("snapshot failed: {:?}", err);
+        }
+    }
+    if let Some(matches) = matches.subcommand_matches("send") {
+        let volume = open_volume(&stores, &dir, &nodes, &meta, quorum, hedge, pipeline);
+        let from = matches.value_of("from");
+        let snapshot = matches.value_of("snapshot");
+        let sent = match matches.value_of("output") {
+            Some(output) => match std::fs::File::create(output) {
+                Ok(file) => send(&volume, from, snapshot, &mut std::io::BufWriter::new(file)),
+                Err(err) => {
+                    eprintln!("cannot create {}: {}", output, err);
+                    return;
+                }
+            },
+            None => send(&volume, from, snapshot, &mut std::io::stdout().lock()),
+        };
+        match sent {
+            Ok(stats) => eprintln!("sent {:?}", stats),
+            Err(err) => eprintln!("send failed: {:?}", err),
+        }
+    }
+    if let Some(matches) = matches.subcommand_matches("receive") {
+        let mut volume = open_volume(&stores, &dir, &nodes, &meta, quorum, hedge, pipeline);
+        let received = match matches.value_of("input") {
+            Some(input) => match std::fs::File::open(input) {
+                Ok(file) => receive(&mut volume, &mut std::io::BufReader::new(file)),
+                Err(err) => {
+                    eprintln!("cannot open {}: {}", input, err);
+                    return;
+                }
+            },
+            None => receive(&mut volume, &mut std::io::stdin().lock()),
+        };
+        match received {
+            Ok(stats) => eprintln!("received {:?}", stats),
+            Err(err) => eprintln!("receive failed: {:?}", err),
+        }
+    }
+    if let Some(matches) = matches.subcommand_matches("fsck") {
+        let volume = matches.value_of("VOLUME").unwrap();
+        let blocks = block_stores(&stores, matches.value_of("BLOCKS").unwrap());
+        let checked = if matches.is_present("fix") {
+            repair(volume, &blocks)
+        } else {
+            check(volume, &blocks)
+        };
+        let report = match checked {
+            Ok(report) => report,
+            Err(err) => {
+                println!("cannot check {} {}: {:?}", volume, blocks.join(" "), err);
+                std::process::exit(2);
+            }
+        };
+        for problem in report.fixed.iter() {
+            println!("fixed: {}", problem);
+        }
+        for problem in report.problems.iter() {
+            println!("{}", problem);
+        }
+        println!(
+            "{} files, {} chunks, {} problems, {} fixed",
+            report.files,
+            report.chunks,
+            report.problems.len(),
+            report.fixed.len()
+        );
+        if !report.is_clean() {
+            std::process::exit(1);
+        }
+    }
+    if let Some(matches) = matches.subcommand_matches("upgrade") {
+        let volume = matches.value_of("VOLUME").unwrap();
+        let blocks = matches.value_of("BLOCKS").unwrap();
+        let upgraded = match matches.values_of("output") {
+            Some(output) => {
+                let output: Vec<&str> = output.collect();
+                upgrade(volume, blocks, output[0], output[1])
+            }
+            None => upgrade_in_place(volume, blocks),
+        };
+        let stats = match upgraded {
+            Ok(stats) => stats,
+            Err(err) => {
+                println!("cannot upgrade {} {}: {:?}", volume, blocks, err);
+                std::process::exit(1);
+            }
+        };
+        if stats.layout == Layout::Current {
+            println!("{} {} is already in the current format", volume, blocks);
+            return;
+        }
+        println!(
+            "upgraded {} layout: {} objects, {} files, {} chunks",
+            stats.layout, stats.objects, stats.files, stats.chunks
+        );
+        if stats.missing_files > 0 || stats.missing_chunks > 0 {
+            println!(
+                "{} files and {} chunks were already missing",
+                stats.missing_files, stats.missing_chunks
+            );
+        }
+    }
+    if let Some(matches) = matches.subcommand_matches("recover") {
+        let blocks = block_stores(&stores, matches.value_of("BLOCKS").unwrap());
+        let volume = matches.value_of("VOLUME").unwrap();
+        let recovered = match recover(&blocks, volume) {
+            Ok(recovered) => recovered,
+            Err(err) => {
+                println!(
+                    "cannot recover {} into {}: {:?}",
+                    blocks.join(" "),
+                    volume,
+                    err
+                );
+                std::process::exit(1);
+            }
+        };
+        for (id, name) in recovered.incomplete.iter() {
+            println!("{} ({:032x}) is missing chunks", name, id);
+        }
+        println!(
+            "{} objects, {} chunks recovered; {} unlabeled and {} unowned chunks skipped",
+            recovered.objects, recovered.chunks, recovered.unlabeled, recovered.unowned
+        );
+    }
+    if let Some(matches) = matches.subcommand_matches("blockd") {
+        let dir = matches.value_of("DIR").unwrap();
+        let addr = matches.value_of("listen").unwrap();
+        let served = std::net::TcpListener::bind(addr)
+            .map_err(VolumeError::IoError)
+            .and_then(|listener| {
+                eprintln!("serving {} on {}", dir, addr);
+                net::serve(listener, std::path::Path::new(dir), stderr_log())
+            });
+        if let Err(err) = served {
+            println!("cannot serve {} on {}: {:?}", dir, addr, err);
+            std::process::exit(1);
+        }
+    }
+    if let Some(matches) = matches.subcommand_matches("metad") {
+        let dir = matches.value_of("DIR").unwrap();
+        let peers: Vec<String> = matches
+            .values_of("peer")
+            .unwrap()
+            .map(|p| p.to_owned())
+            .collect();
+        let id = match matches.value_of("id").unwrap().parse::<usize>() {
+            Ok(id) if id < peers.len() => id,
+            _ => {
+                println!("invalid id");
+                return;
+            }
+        };
+        let served = std::net::TcpListener::bind(&peers[id])
+            .map_err(VolumeError::IoError)
+            .and_then(|listener| {
+                eprintln!("replica {} of {} serving on {}", id, peers.len(), peers[id]);
+                metad::serve(
+                    id,
+                    peers.clone(),
+                    std::path::Path::new(dir),
+                    listener,
+                    stderr_log(),
+                )
+            });
+        if let Err(err) = served {
+            println!("cannot serve {} on {}: {:?}", dir, peers[id], err);
+            std::process::exit(1);
+        }
+    }
+    if matches.subcommand_matches("replicas").is_some() {
+        for addr in meta.iter().flatten() {
+            match consensus::status(addr) {
+                Ok(Message::Replica {
+                    id,
+                    role,
+                    term,
+                    leader,
+                    last,
+                    commit,
+                }) => {
+                    let leader = leader.map(|l| l.to_string()).unwrap_or("none".to_owned());
+                    println!(
+                        "{}: replica {}, {:?} in term {}, leader {}, {} entries, {} committed",
+                        addr, id, role, term, leader, last, commit
+                    );
+                }
+                Ok(_) => println!("{}: not a replica", addr),
+                Err(err) => println!("{}: down: {:?}", addr, err),
+            }
+        }
+    }
+    if let Some(matches) = matches.subcommand_matches("domain") {
+        let store = matches.value_of("STORE").unwrap();
+        match domain(store, matches.value_of("DOMAIN").map(FailureDomain::parse)) {
+            Ok(domain) => println!("{}: {}", store, domain),
+            Err(err) => {
+                println!("cannot label {}: {:?}", store, err);
+                std::process::exit(1);
+            }
+        }
+    }
+    for command in ["rebalance", "attach", "decommission"] {
+        let matches = match matches.subcommand_matches(command) {
+            Some(matches) => matches,
+            None => continue,
+        };
+        let rate = match matches.value_of("rate").map(parse_size) {
+            Some(None) => {
+                println!("invalid rate");
+                return;
+            }
+            rate => rate.flatten(),
+        };
+        let blocks = block_stores(&stores, "block.bin");
+        let mut shown = std::time::Instant::now();
+        let mut report = |progress: &Progress| {
+            if shown.elapsed().as_secs() >= 1 {
+                eprintln!(
+                    "{}/{} chunks, {} blocks moved",
+                    progress.checked, progress.chunks, progress.moved
+                );
+                shown = std::time::Instant::now();
+            }
+        };
+        let store = matches.value_of("STORE").map(split_domain);
+        let done = match (command, store.as_ref()) {
+            ("attach", Some((path, domain))) => attach(&blocks, path, domain, rate, &mut report),
+            ("decommission", Some((path, _))) => decommission(&blocks, path, rate, &mut report),
+            _ => rebalance(&blocks, rate, &mut report),
+        };
+        let progress = match done {
+            Ok(progress) => progress,
+            Err(err) => {
+                println!("cannot {} {}: {:?}", command, blocks.join(" "), err);
+                std::process::exit(1);
+            }
+        };
+        println!(
+            "{} chunks, {} blocks moved, {} rebuilt, {} left on missing stores, {} chunks failed",
+            progress.chunks, progress.moved, progress.rebuilt, progress.skipped, progress.failed
+        );
+        if progress.skipped > 0 {
+            println!(
+                "fsck --fix puts back the blocks on missing stores, decommission moves them elsewhere"
+            );
+        }
+        if progress.failed > 0 {
+            println!("fsck can tell what is wrong with the chunks that failed");
+        }
+        if progress.skipped > 0 || progress.failed > 0 {
+            std::process::exit(1);
+        }
+        if let ("decommission", Some((path, _))) = (command, store.as_ref()) {
+            println!("{} is drained and no longer part of the volume", path);
+        }
+    }
+    if let Some(matches) = matches.subcommand_matches("resize") {
+        let file = matches.value_of("FILE").unwrap();
+        let size = matches.value_of("SIZE").map(parse_size);
+        let max_size = matches.value_of("max").map(parse_size);
+        let (size, max_size) = match (size, max_size) {
+            (Some(None), _) | (_, Some(None)) => {
+                println!("invalid size");
+                return;
+            }
+            (size, max_size) => (size.flatten(), max_size.flatten()),
+        };
+        let usage = match resize(file, size, max_size) {
+            Ok(usage) => usage,
+            Err(err) => {
+                println!("cannot resize {}: {:?}", file, err);
+                std::process::exit(1);
+            }
+        };
+        let limit = match usage.max_size {
+            0 => "grows as needed".to_owned(),
+            max => format!("grows up to {} bytes", max),
+        };
+        println!(
+            "{}: {} bytes, {} in use, {}",
+            file, usage.size, usage.used, limit
+        );
+    }
+}
+
+fn open_volume(
+    stores: &Option<Vec<String>>,
+    dir: &Option<String>,
+    nodes: &Option<Vec<String>>,
+    meta: &Option<Vec<String>>,
+    quorum: usize,
+    hedge: Option<std::time::Duration>,
+    pipeline: Option<Pipeline>,
+) -> BigFileVolume {
+    let volume = match (dir, nodes) {
+        _ if meta.is_some() => metad::open(meta.as_ref().unwrap(), |uuid| {
+            open_blocks(stores, dir, nodes, quorum, uuid)
+        }),
+        (Some(dir), _) => directory::open(dir),
+        (_, Some(nodes)) => {
+            let (addrs, domains): (Vec<String>, Vec<FailureDomain>) =
+                nodes.iter().map(|n| split_domain(n)).unzip();
+            net::open("volume.bin", &addrs, &domains, quorum, stderr_log())
+        }
+        _ => {
+            let (paths, domains): (Vec<String>, Vec<FailureDomain>) = match stores {
+                Some(stores) => stores.iter().map(|s| split_domain(s)).unzip(),
+                None => (vec!["block.bin".to_owned()], Vec::new()),
+            };
+            BigFileVolume::try_init_stores("volume.bin", &paths, &domains)
+        }
+    };
+    match volume {
+        Ok(mut volume) => {
+            if let Some(hedge) = hedge {
+                volume.set_hedge(hedge);
+            }
+            if let Some(pipeline) = pipeline {
+                volume.set_pipeline(pipeline);
+            }
+            volume
+        }
+        Err(err) => {
+            eprintln!("cannot open volume: {:?}", err);
+            std::process::exit(1);
+        }
+    }
+}
+
+// What goes wrong in the background goes to stderr.
+fn stderr_log() -> ErrorLog {
+    std::sync::Arc::new(|what, err| eprintln!("{}: {:?}", what, err))
+}
+
+// Opens the blocks of a volume whose metadata the service keeps, wherever
+// the other options say they are, or creates them for volume `uuid`, or a
+// new one.
+fn open_blocks(
+    stores: &Option<Vec<String>>,
+    dir: &Option<String>,
+    nodes: &Option<Vec<String>>,
+    quorum: usize,
+    uuid: Option<u128>,
+) -> Result<Box<dyn BlockStore>, VolumeError> {
+    let uuid = uuid.unwrap_or_else(|| uuid::Uuid::new_v4().as_u128());
+    match (dir, nodes) {
+        (Some(dir), _) => {
+            let root = std::path::Path::new(dir);
+            match root.join("store").exists() {
+                true => Ok(Box::new(DirBlockStore::open(root)?)),
+                false => Ok(Box::new(DirBlockStore::init(root, uuid)?)),
+            }
+        }
+        (_, Some(nodes)) => {
+            let (addrs, domains): (Vec<String>, Vec<FailureDomain>) =
+                nodes.iter().map(|n| split_domain(n)).unzip();
+            let hints = std::path::Path::new("volume.hints");
+            let mut blocks = match NetBlockStores::open(&addrs, hints) {
+                Err(VolumeError::NoDataFound) => {
+                    NetBlockStores::init(&addrs, &domains, uuid, hints)?
+                }
+                blocks => blocks?,
+            };
+            blocks.set_quorum(quorum)?;
+            blocks.set_log(stderr_log());
+            Ok(Box::new(blocks))
+        }
+        _ => {
+            let (paths, domains): (Vec<String>, Vec<FailureDomain>) = match stores {
+                Some(stores) => stores.iter().map(|s| split_domain(s)).unzip(),
+                None => (vec!["block.bin".to_owned()], Vec::new()),
+            };
+            match BlockStores::exists(&paths) {
+                true => Ok(Box::new(BlockStores::open(&paths)?)),
+                false => Ok(Box::new(BlockStores::init(&paths, &domains, uuid)?)),
+            }
+        }
+    }
+}
+
+// The paths of the block stores given with --store, or else the one block
+// file.
+fn block_stores(stores: &Option<Vec<String>>, block_file: &str) -> Vec<String> {
+    match stores {
+        Some(stores) => stores.iter().map(|s| split_domain(s).0).collect(),
+        None => vec![block_file.to_owned()],
+    }
+}
+
+fn parse_size(size: &str) -> Option<u64> {
+    let (digits, unit) = match size.char_indices().last()? {
+        (n, 'K') | (n, 'k') => (&size[..n], 1 << 10),
+        (n, 'M') | (n, 'm') => (&size[..n], 1 << 20),
+        (n, 'G') | (n, 'g') => (&size[..n], 1 << 30),
+        (n, 'T') | (n, 't') => (&size[..n], 1 << 40),
+        _ => (size, 1),
+    };
+    digits.parse::<u64>().ok()?.checked_mul(unit)
+}
+
+fn parse_id(id: &str) -> Option<u128> {
+    u128::from_str_radix(id, 16).ok()
 }
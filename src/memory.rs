@@ -0,0 +1,309 @@
+use crate::block::Block;
+use crate::chunk::Chunk;
+use crate::error::VolumeError;
+use crate::index::{IndexEntry, IndexPage, ObjectIndex, ObjectList};
+use crate::redundant_file::RedundantFile;
+use crate::volume::{BigFileVolumeHashMap, Volume};
+use crate::UUID;
+
+// A volume kept entirely in memory, for tests and as a cache. Objects are
+// encoded and rebuilt as on any other volume, and blocks can be dropped or
+// corrupted on purpose to watch a chunk being reconstructed without them.
+// Writing a name again replaces what was there; there are no versions.
+pub struct MemoryVolume {
+    files: BigFileVolumeHashMap<RedundantFile>,
+    chunks: BigFileVolumeHashMap<Chunk>,
+    blocks: BigFileVolumeHashMap<Block>,
+    index: ObjectIndex,
+}
+
+impl Default for MemoryVolume {
+    fn default() -> Self {
+        MemoryVolume::new()
+    }
+}
+
+impl MemoryVolume {
+    pub fn new() -> MemoryVolume {
+        MemoryVolume {
+            files: BigFileVolumeHashMap::new(),
+            chunks: BigFileVolumeHashMap::new(),
+            blocks: BigFileVolumeHashMap::new(),
+            index: ObjectIndex::new(vec![IndexPage::default()]),
+        }
+    }
+
+    pub fn destruct<T>(&mut self, name: &str, reader: &mut T) -> Result<UUID, VolumeError>
+    where
+        T: std::io::Read + Send,
+    {
+        let (file, chunks, blocks) =
+            RedundantFile::destruct(name, reader).map_err(VolumeError::FileError)?;
+        for chunk in chunks.iter() {
+            self.chunks.insert(chunk.id, *chunk);
+        }
+        for block in blocks.iter() {
+            self.blocks.insert(block.id, *block);
+        }
+        let size = chunks.iter().map(|c| c.chunk_size as u64).sum();
+        let id = file.id;
+        self.files.insert(id, *file);
+
+        let displaced = self.index.remove_all(name.as_bytes(), &mut |_| {});
+        self.index
+            .insert(IndexEntry::new(name.as_bytes(), id, size), &mut || 0);
+        for entry in displaced {
+            self.free_file(entry.id);
+        }
+        Ok(id)
+    }
+
+    pub fn restruct<T>(&self, id: UUID, writer: &mut T) -> Result<(), VolumeError>
+    where
+        T: std::io::Write + Send,
+    {
+        RedundantFile::rebuild(id, self, writer)?;
+        writer.flush().map_err(VolumeError::IoError)
+    }
+
+    pub fn lookup(&self, name: &str) -> Result<UUID, VolumeError> {
+        match self.index.get(name.as_bytes()) {
+            Some(entry) => Ok(entry.id),
+            None => Err(VolumeError::NoDataFound),
+        }
+    }
+
+    // Loses block `id`, as a disk would. Returns it, to be put back with
+    // `restore_block`.
+    pub fn drop_block(&mut self, id: UUID) -> Option<Block> {
+        self.blocks.remove(&id)
+    }
+
+    pub fn restore_block(&mut self, block: Block) {
+        self.blocks.insert(block.id, block);
+    }
+
+    // Flips the bits of byte `at` of block `id` and leaves its CRC as it
+    // was, so the block reads back but fails its check. Returns whether
+    // there was such a block.
+    pub fn corrupt_block(&mut self, id: UUID, at: usize) -> bool {
+        match self.blocks.get_mut(&id) {
+            Some(block) if at < block.data.len() => {
+                block.data[at] ^= 0xff;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    // Drops the file and everything it is made of.
+    fn free_file(&mut self, id: UUID) {
+        let file = match self.files.remove(&id) {
+            Some(file) => file,
+            None => return,
+        };
+        for chunk in file.chunk_ids() {
+            if let Some(chunk) = self.chunks.remove(&chunk) {
+                for block in chunk.blocks.iter() {
+                    self.blocks.remove(block);
+                }
+            }
+        }
+    }
+}
+
+impl Volume for MemoryVolume {
+    fn get_redundant_file(&self, id: UUID) -> Result<Box<RedundantFile>, VolumeError> {
+        match self.files.get(&id) {
+            Some(file) => Ok(Box::new(file.clone())),
+            None => Err(VolumeError::NoDataFound),
+        }
+    }
+
+    fn get_chunk(&self, id: UUID) -> Result<Box<Chunk>, VolumeError> {
+        match self.chunks.get(&id) {
+            Some(chunk) => Ok(Box::new(*chunk)),
+            None => Err(VolumeError::NoDataFound),
+        }
+    }
+
+    fn get_block(&self, id: UUID) -> Result<Box<Block>, VolumeError> {
+        match self.blocks.get(&id) {
+            Some(block) => Ok(Box::new(*block)),
+            None => Err(VolumeError::NoDataFound),
+        }
+    }
+
+    fn destruct_from_file(&mut self, file_name: &str) -> Result<UUID, VolumeError> {
+        let mut file = std::fs::File::open(file_name).map_err(VolumeError::IoError)?;
+
+        self.destruct(file_name, &mut file)
+    }
+
+    fn restruct_to_file(&mut self, id: UUID, file_name: &str) -> Result<(), VolumeError> {
+        let mut file = std::fs::File::create(file_name).map_err(VolumeError::IoError)?;
+
+        self.restruct(id, &mut file)
+    }
+
+    fn delete(&mut self, name: &str) -> Result<(), VolumeError> {
+        let removed = self.index.remove_all(name.as_bytes(), &mut |_| {});
+        if removed.is_empty() {
+            return Err(VolumeError::NoDataFound);
+        }
+        for entry in removed {
+            self.free_file(entry.id);
+        }
+        Ok(())
+    }
+
+    fn list(
+        &self,
+        prefix: &str,
+        delimiter: Option<&str>,
+        start_after: Option<&str>,
+        limit: usize,
+    ) -> Result<ObjectList, VolumeError> {
+        Ok(self.index.list(prefix, delimiter, start_after, limit))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::MemoryVolume;
+    use crate::constants::{BLOCKS, PARITY, READ_STEP};
+    use crate::test_util::with_stack;
+    use crate::volume::Volume;
+    use crate::UUID;
+
+    fn data(len: usize) -> Vec<u8> {
+        (0..len).map(|n| (n * 7 + n / 251) as u8).collect()
+    }
+
+    fn stored(data: &[u8]) -> (MemoryVolume, UUID) {
+        let mut volume = MemoryVolume::new();
+        let id = volume.destruct("object", &mut &data[..]).unwrap();
+        (volume, id)
+    }
+
+    // The blocks of every chunk of the file, in chunk order.
+    fn blocks(volume: &MemoryVolume, id: UUID) -> Vec<Vec<UUID>> {
+        let file = volume.get_redundant_file(id).unwrap();
+        file.chunk_ids()
+            .iter()
+            .map(|c| volume.get_chunk(*c).unwrap().blocks.to_vec())
+            .collect()
+    }
+
+    fn read(volume: &MemoryVolume, id: UUID) -> Result<Vec<u8>, crate::error::VolumeError> {
+        let mut out = Vec::new();
+        volume.restruct(id, &mut out).map(|_| out)
+    }
+
+    #[test]
+    fn reads_back_what_was_written() {
+        with_stack(|| {
+            let data = data(READ_STEP * 2 + 1000);
+            let (volume, id) = stored(&data);
+            assert_eq!(volume.lookup("object").unwrap(), id);
+            assert_eq!(read(&volume, id).unwrap(), data);
+        });
+    }
+
+    #[test]
+    fn rebuilds_with_up_to_parity_blocks_dropped() {
+        with_stack(|| {
+            let data = data(READ_STEP + 1000);
+            for lost in 1..=PARITY {
+                let (mut volume, id) = stored(&data);
+                for chunk in blocks(&volume, id) {
+                    // Data blocks first, as the hardest case.
+                    for block in chunk.iter().take(lost) {
+                        assert!(volume.drop_block(*block).is_some());
+                    }
+                }
+                assert_eq!(read(&volume, id).unwrap(), data, "{} dropped", lost);
+            }
+        });
+    }
+
+    #[test]
+    fn rebuilds_with_up_to_parity_blocks_corrupted() {
+        with_stack(|| {
+            let data = data(READ_STEP + 1000);
+            for lost in 1..=PARITY {
+                let (mut volume, id) = stored(&data);
+                for chunk in blocks(&volume, id) {
+                    // Half of them data blocks, the rest parity.
+                    for block in chunk.iter().skip(BLOCKS + PARITY - (lost - lost / 2)) {
+                        assert!(volume.corrupt_block(*block, 0));
+                    }
+                    for block in chunk.iter().take(lost / 2) {
+                        assert!(volume.corrupt_block(*block, 100));
+                    }
+                }
+                assert_eq!(read(&volume, id).unwrap(), data, "{} corrupted", lost);
+            }
+        });
+    }
+
+    #[test]
+    fn rebuilds_with_dropped_and_corrupted_blocks_mixed() {
+        with_stack(|| {
+            let data = data(READ_STEP / 2);
+            let (mut volume, id) = stored(&data);
+            let chunk = &blocks(&volume, id)[0];
+            for block in chunk.iter().take(PARITY / 2) {
+                volume.drop_block(*block);
+            }
+            for block in chunk.iter().skip(BLOCKS).take(PARITY - PARITY / 2) {
+                volume.corrupt_block(*block, 1);
+            }
+            assert_eq!(read(&volume, id).unwrap(), data);
+        });
+    }
+
+    #[test]
+    fn fails_cleanly_with_more_than_parity_blocks_dropped() {
+        with_stack(|| {
+            let data = data(READ_STEP / 2);
+            let (mut volume, id) = stored(&data);
+            let chunk = &blocks(&volume, id)[0];
+            let dropped: Vec<_> = chunk
+                .iter()
+                .take(PARITY + 1)
+                .map(|b| volume.drop_block(*b).unwrap())
+                .collect();
+            assert!(read(&volume, id).is_err());
+
+            // Putting one back is enough again.
+            volume.restore_block(dropped[0]);
+            assert_eq!(read(&volume, id).unwrap(), data);
+        });
+    }
+
+    #[test]
+    fn fails_cleanly_with_more_than_parity_blocks_corrupted() {
+        with_stack(|| {
+            let data = data(READ_STEP / 2);
+            let (mut volume, id) = stored(&data);
+            for block in blocks(&volume, id)[0].iter().take(PARITY + 1) {
+                volume.corrupt_block(*block, 0);
+            }
+            assert!(read(&volume, id).is_err());
+        });
+    }
+
+    #[test]
+    fn writing_a_name_again_replaces_it() {
+        with_stack(|| {
+            let (mut volume, first) = stored(&data(1000));
+            let second = volume.destruct("object", &mut &[1u8, 2, 3][..]).unwrap();
+            assert_eq!(volume.lookup("object").unwrap(), second);
+            assert!(volume.get_redundant_file(first).is_err());
+            assert_eq!(read(&volume, second).unwrap(), vec![1, 2, 3]);
+            volume.delete("object").unwrap();
+            assert!(volume.lookup("object").is_err());
+        });
+    }
+}
@@ -0,0 +1,837 @@
+use crate::backend::{BlockStore, MetadataStore};
+use crate::consensus::{self, Message, StateMachine};
+use crate::directory::{decode_index, encode_index};
+use crate::error::{ErrorLog, VolumeError};
+use crate::index::{IndexEntry, IndexPage, ObjectIndex, ObjectList};
+use crate::redundant_file::RedundantFile;
+use crate::snapshot::{Snapshot, SnapshotSummary};
+use crate::volume::BigFileVolume;
+use crate::UUID;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::net::TcpListener;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+const VERSIONING: u64 = 1;
+
+// Entries whose tags the state keeps, for a client that lost track of its
+// proposal to find out whether it went in.
+const RECENT: usize = 256;
+
+// How long a client keeps looking for a leader before giving up, and waits
+// for one to answer; a proposal can take the replica up to 5s.
+const LEADER_TIMEOUT: Duration = Duration::from_secs(5);
+const CALL_TIMEOUT: Duration = Duration::from_secs(6);
+const RETRY: Duration = Duration::from_millis(50);
+// How long a client keeps at a commit through changes of leader and the
+// writes of other clients.
+const COMMIT_TIMEOUT: Duration = Duration::from_secs(15);
+
+// Runs replica `id` of the metadata service at `peers`, keeping its log in
+// `dir`. Entries of the log that don't parse are reported to `errors`.
+pub fn serve(
+    id: usize,
+    peers: Vec<String>,
+    dir: &Path,
+    listener: TcpListener,
+    errors: ErrorLog,
+) -> Result<(), VolumeError> {
+    consensus::serve(id, peers, dir, MetadataState::default(), listener, errors)
+}
+
+// Opens the volume whose metadata the service at `replicas` keeps. `blocks`
+// opens its blocks, given the uuid of the volume, or None if the service
+// holds none yet; then the volume becomes the one the blocks belong to.
+pub fn open<F>(replicas: &[String], blocks: F) -> Result<BigFileVolume, VolumeError>
+where
+    F: FnOnce(Option<UUID>) -> Result<Box<dyn BlockStore>, VolumeError>,
+{
+    let mut meta_data = ReplicatedMetadataStore::open(replicas)?;
+    let uuid = match meta_data.uuid() {
+        0 => None,
+        uuid => Some(uuid),
+    };
+    let blocks = blocks(uuid)?;
+    if uuid.is_none() {
+        meta_data.run(Command::Init(blocks.uuid()));
+        meta_data.commit()?;
+    }
+    BigFileVolume::with_backends(Box::new(meta_data), blocks)
+}
+
+// An index entry as it travels in a command.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Entry {
+    name: Vec<u8>,
+    id: UUID,
+    size: u64,
+    version: u64,
+    flags: u64,
+}
+
+impl From<&IndexEntry> for Entry {
+    fn from(entry: &IndexEntry) -> Entry {
+        Entry {
+            name: entry.key().to_vec(),
+            id: entry.id,
+            size: entry.size,
+            version: entry.version,
+            flags: entry.flags,
+        }
+    }
+}
+
+impl From<&Entry> for IndexEntry {
+    fn from(entry: &Entry) -> IndexEntry {
+        let mut index_entry = IndexEntry::new(&entry.name[..], entry.id, entry.size);
+        index_entry.version = entry.version;
+        index_entry.flags = entry.flags;
+        index_entry
+    }
+}
+
+// One change to the metadata. Every replica applies them in the same order
+// to the same state, so they end up with the same versions and the same
+// index.
+#[derive(Serialize, Deserialize)]
+pub enum Command {
+    Init(UUID),
+    JournalBegin(UUID, Vec<UUID>),
+    JournalEnd,
+    SaveFile(RedundantFile),
+    FreeFile(UUID),
+    SetVersioning(bool),
+    IndexPut(Entry),
+    IndexRestore(Entry),
+    IndexRemoveAll(Vec<u8>),
+    IndexRemoveVersion(Vec<u8>, UUID),
+    CreateSnapshot(String, u64),
+    DeleteSnapshot(String),
+    // Comes first in every entry a client proposes, the same each time it
+    // proposes it again.
+    Tag(UUID),
+}
+
+// All the metadata, as a replica sends it to a client.
+#[derive(Serialize, Deserialize)]
+struct Image {
+    uuid: UUID,
+    flags: u64,
+    sequence: u64,
+    index: Vec<u8>,
+    snapshots: Vec<(String, u64, Vec<u8>)>,
+    files: Vec<RedundantFile>,
+    journal: Option<(UUID, Vec<UUID>)>,
+    recent: Vec<UUID>,
+}
+
+// The metadata of a volume, kept in memory by every replica and by the
+// client. A uuid of 0 is a service no volume has been given yet.
+pub struct MetadataState {
+    uuid: UUID,
+    flags: u64,
+    sequence: u64,
+    index: ObjectIndex,
+    snapshots: Vec<Snapshot>,
+    files: HashMap<UUID, RedundantFile>,
+    journal: Option<(UUID, Vec<UUID>)>,
+    recent: VecDeque<UUID>,
+}
+
+impl Default for MetadataState {
+    fn default() -> Self {
+        MetadataState {
+            uuid: 0,
+            flags: 0,
+            sequence: 0,
+            index: ObjectIndex::new(vec![IndexPage::default()]),
+            snapshots: Vec::new(),
+            files: HashMap::new(),
+            journal: None,
+            recent: VecDeque::new(),
+        }
+    }
+}
+
+impl MetadataState {
+    fn decode(bytes: &[u8]) -> Result<MetadataState, VolumeError> {
+        let image: Image = bincode::deserialize(bytes).map_err(|_| VolumeError::BadFrame)?;
+        let mut snapshots = Vec::new();
+        for (name, created, index) in image.snapshots {
+            snapshots.push(Snapshot {
+                name,
+                created,
+                index_start: 0,
+                index: decode_index(Some(&index[..])).ok_or(VolumeError::BadFrame)?,
+            });
+        }
+        Ok(MetadataState {
+            uuid: image.uuid,
+            flags: image.flags,
+            sequence: image.sequence,
+            index: decode_index(Some(&image.index[..])).ok_or(VolumeError::BadFrame)?,
+            snapshots,
+            files: image.files.into_iter().map(|f| (f.id, f)).collect(),
+            journal: image.journal,
+            recent: image.recent.into_iter().collect(),
+        })
+    }
+
+    fn versioning(&self) -> bool {
+        self.flags & VERSIONING != 0
+    }
+
+    fn index_put(&mut self, mut entry: IndexEntry) -> Vec<IndexEntry> {
+        self.sequence += 1;
+        entry.version = self.sequence;
+        let displaced = match self.index.get(entry.key()).map(|e| e.id) {
+            Some(id) if !self.versioning() => self
+                .index
+                .remove_version(entry.key(), id, &mut |_| {})
+                .into_iter()
+                .collect(),
+            _ => Vec::new(),
+        };
+        self.index.insert(entry, &mut || 0);
+        displaced
+    }
+
+    fn index_restore(&mut self, mut entry: IndexEntry) {
+        self.sequence += 1;
+        entry.version = self.sequence;
+        self.index.insert(entry, &mut || 0);
+    }
+
+    fn create_snapshot(&mut self, name: &str, created: u64) -> Result<(), VolumeError> {
+        if self.snapshots.iter().any(|s| s.name == name) {
+            return Err(VolumeError::SnapshotExists(name.to_owned()));
+        }
+        self.snapshots.push(Snapshot {
+            name: name.to_owned(),
+            created,
+            index_start: 0,
+            index: self.index.clone(),
+        });
+        Ok(())
+    }
+
+    fn delete_snapshot(&mut self, name: &str) -> Result<Snapshot, VolumeError> {
+        let pos = self
+            .snapshots
+            .iter()
+            .position(|s| s.name == name)
+            .ok_or_else(|| VolumeError::NoSuchSnapshot(name.to_owned()))?;
+        Ok(self.snapshots.remove(pos))
+    }
+
+    // Changes the state as `command` says. Commands that fail here failed
+    // the same way on the client, which then never sent them.
+    fn run(&mut self, command: &Command) {
+        match command {
+            Command::Init(uuid) => {
+                if self.uuid == 0 {
+                    self.uuid = *uuid;
+                }
+            }
+            Command::JournalBegin(id, chunks) => self.journal = Some((*id, chunks.clone())),
+            Command::JournalEnd => self.journal = None,
+            Command::SaveFile(file) => {
+                self.files.insert(file.id, file.clone());
+            }
+            Command::FreeFile(id) => {
+                self.files.remove(id);
+            }
+            Command::SetVersioning(true) => self.flags |= VERSIONING,
+            Command::SetVersioning(false) => self.flags &= !VERSIONING,
+            Command::IndexPut(entry) => {
+                self.index_put(entry.into());
+            }
+            Command::IndexRestore(entry) => self.index_restore(entry.into()),
+            Command::IndexRemoveAll(name) => {
+                self.index.remove_all(&name[..], &mut |_| {});
+            }
+            Command::IndexRemoveVersion(name, id) => {
+                self.index.remove_version(&name[..], *id, &mut |_| {});
+            }
+            Command::CreateSnapshot(name, created) => {
+                let _ = self.create_snapshot(name, *created);
+            }
+            Command::DeleteSnapshot(name) => {
+                let _ = self.delete_snapshot(name);
+            }
+            Command::Tag(tag) => {
+                self.recent.push_back(*tag);
+                if self.recent.len() > RECENT {
+                    self.recent.pop_front();
+                }
+            }
+        }
+    }
+}
+
+// On a replica, every entry of the log is a batch of commands.
+impl StateMachine for MetadataState {
+    fn apply(&mut self, entry: &[u8]) -> Result<(), VolumeError> {
+        let commands: Vec<Command> =
+            bincode::deserialize(entry).map_err(|_| VolumeError::BadLogEntry)?;
+        commands.iter().for_each(|c| self.run(c));
+        Ok(())
+    }
+
+    fn image(&self) -> Vec<u8> {
+        let image = Image {
+            uuid: self.uuid,
+            flags: self.flags,
+            sequence: self.sequence,
+            index: encode_index(&self.index),
+            snapshots: self
+                .snapshots
+                .iter()
+                .map(|s| (s.name.clone(), s.created, encode_index(&s.index)))
+                .collect(),
+            files: self.files.values().cloned().collect(),
+            journal: self.journal.clone(),
+            recent: self.recent.iter().cloned().collect(),
+        };
+        bincode::serialize(&image).unwrap()
+    }
+
+    fn restore(&mut self, image: &[u8]) -> Result<(), VolumeError> {
+        *self = MetadataState::decode(image)?;
+        Ok(())
+    }
+}
+
+fn versions(state: &MetadataState, name: &[u8]) -> Vec<(UUID, u64, u64)> {
+    state
+        .index
+        .versions(name)
+        .map(|e| (e.id, e.version, e.flags))
+        .collect()
+}
+
+fn snapshot_names(state: &MetadataState) -> Vec<String> {
+    state.snapshots.iter().map(|s| s.name.clone()).collect()
+}
+
+// What the commands not yet committed were made against: the versions of
+// every name they touch, the files they free, and the journal, snapshots
+// and flags as they were.
+#[derive(Default)]
+struct Seen {
+    names: HashMap<Vec<u8>, Vec<(UUID, u64, u64)>>,
+    saved: HashSet<UUID>,
+    freed: HashSet<UUID>,
+    journal: Option<Option<(UUID, Vec<UUID>)>>,
+    snapshots: Option<Vec<String>>,
+    flags: Option<u64>,
+}
+
+impl Seen {
+    // Takes note of what `command` depends on, before it runs on `state`.
+    fn note(&mut self, state: &MetadataState, command: &Command) {
+        self.flags.get_or_insert(state.flags);
+        match command {
+            Command::IndexPut(entry) | Command::IndexRestore(entry) => {
+                self.name(state, &entry.name[..])
+            }
+            Command::IndexRemoveAll(name) | Command::IndexRemoveVersion(name, _) => {
+                self.name(state, &name[..])
+            }
+            Command::SaveFile(file) => {
+                self.saved.insert(file.id);
+            }
+            Command::FreeFile(id) if !self.saved.contains(id) => {
+                self.freed.insert(*id);
+            }
+            Command::JournalBegin(..) | Command::JournalEnd => {
+                self.journal.get_or_insert_with(|| state.journal.clone());
+            }
+            Command::CreateSnapshot(..) | Command::DeleteSnapshot(_) => {
+                self.snapshots.get_or_insert_with(|| snapshot_names(state));
+            }
+            _ => {}
+        }
+    }
+
+    fn name(&mut self, state: &MetadataState, name: &[u8]) {
+        if !self.names.contains_key(name) {
+            self.names.insert(name.to_vec(), versions(state, name));
+        }
+    }
+
+    // Whether `state` is still what the commands were made against, as far
+    // as they go.
+    fn holds(&self, state: &MetadataState) -> bool {
+        self.names
+            .iter()
+            .all(|(name, seen)| versions(state, name) == *seen)
+            && self.freed.iter().all(|id| state.files.contains_key(id))
+            && self.journal.as_ref().is_none_or(|j| *j == state.journal)
+            && self
+                .snapshots
+                .as_ref()
+                .is_none_or(|s| *s == snapshot_names(state))
+            && self.flags.is_none_or(|f| f == state.flags)
+    }
+}
+
+// The metadata of a volume kept by a service of three or five replicas,
+// that goes on as long as a majority of them is up. The client keeps a copy
+// of its own, changes it as the volume asks and sends the commands that did
+// so as one entry when they are synced, or when a write commits. The entry
+// is only taken if nothing changed on the service since the copy was taken.
+// A client that finds another committed first makes its commands again on
+// top of what the other did, unless the other changed what they were made
+// against; then it fails with StaleMetadata until it reloads.
+pub struct ReplicatedMetadataStore {
+    replicas: Vec<String>,
+    leader: usize,
+    state: MetadataState,
+    base: u64,
+    pending: Vec<Command>,
+    // The tag the pending commands are proposed under, once they are.
+    tag: Option<UUID>,
+    seen: Seen,
+    rollback: Vec<UUID>,
+}
+
+impl ReplicatedMetadataStore {
+    pub fn open(replicas: &[String]) -> Result<ReplicatedMetadataStore, VolumeError> {
+        if replicas.is_empty() {
+            return Err(VolumeError::TooFewStores(0));
+        }
+        let mut meta_data = ReplicatedMetadataStore {
+            replicas: replicas.to_vec(),
+            leader: 0,
+            state: MetadataState::default(),
+            base: 0,
+            pending: Vec::new(),
+            tag: None,
+            seen: Seen::default(),
+            rollback: Vec::new(),
+        };
+        meta_data.fetch()?;
+        Ok(meta_data)
+    }
+
+    pub fn replicas(&self) -> &[String] {
+        &self.replicas[..]
+    }
+
+    // Sends `message` to the leader, following the replicas that say which
+    // one it is and trying each in turn while there is none.
+    fn request(&mut self, message: &Message) -> Result<Message, VolumeError> {
+        let until = Instant::now() + LEADER_TIMEOUT;
+        let mut n = self.leader;
+        loop {
+            match consensus::call(&self.replicas[n], message, CALL_TIMEOUT) {
+                Ok(Message::NotLeader {
+                    leader: Some(leader),
+                }) if leader < self.replicas.len() && leader != n => {
+                    n = leader;
+                    if Instant::now() < until {
+                        continue;
+                    }
+                }
+                Ok(Message::NotLeader { .. }) | Err(_) => n = (n + 1) % self.replicas.len(),
+                Ok(reply) => {
+                    self.leader = n;
+                    return Ok(reply);
+                }
+            }
+            if Instant::now() >= until {
+                return Err(VolumeError::NoLeader);
+            }
+            std::thread::sleep(RETRY);
+        }
+    }
+
+    // The metadata as the service has it, and the last entry applied to it.
+    fn image(&mut self) -> Result<(MetadataState, u64), VolumeError> {
+        match self.request(&Message::Fetch)? {
+            Message::Image { applied, image } => Ok((MetadataState::decode(&image[..])?, applied)),
+            Message::Failed(_) => Err(VolumeError::NoLeader),
+            _ => Err(VolumeError::BadFrame),
+        }
+    }
+
+    // Takes `state` as of entry `applied`, with nothing left to commit.
+    fn take(&mut self, state: MetadataState, applied: u64) {
+        self.state = state;
+        self.base = applied;
+        self.pending.clear();
+        self.tag = None;
+        self.seen = Seen::default();
+    }
+
+    // Takes a fresh copy of the metadata, dropping what wasn't committed.
+    // A write left open by a client that went away names the chunks it
+    // stored, to be freed.
+    fn fetch(&mut self) -> Result<(), VolumeError> {
+        let (state, applied) = self.image()?;
+        self.take(state, applied);
+        self.rollback = match &self.state.journal {
+            Some((file, chunks)) if !self.state.index.iter().any(|e| e.id == *file) => {
+                chunks.clone()
+            }
+            _ => Vec::new(),
+        };
+        if self.rollback.is_empty() && self.state.journal.is_some() {
+            self.run(Command::JournalEnd);
+            self.commit()?;
+        }
+        Ok(())
+    }
+
+    fn run(&mut self, command: Command) {
+        self.seen.note(&self.state, &command);
+        self.state.run(&command);
+        self.pending.push(command);
+    }
+
+    // Has the service take every change made since the last commit, all
+    // or none of them. A leader that goes away before it answers leaves it
+    // unknown whether they went in, so they are proposed again under the
+    // same tag, for the state to tell once another entry is in the way.
+    fn commit(&mut self) -> Result<(), VolumeError> {
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+        let tag = *self
+            .tag
+            .get_or_insert_with(|| uuid::Uuid::new_v4().as_u128());
+        let until = Instant::now() + COMMIT_TIMEOUT;
+        loop {
+            let tagged = Command::Tag(tag);
+            let entry: Vec<&Command> = std::iter::once(&tagged).chain(&self.pending).collect();
+            let message = Message::Propose {
+                base: self.base,
+                entry: bincode::serialize(&entry).unwrap(),
+            };
+            match self.request(&message)? {
+                Message::Proposed { index } => {
+                    self.base = index;
+                    self.pending.clear();
+                    self.tag = None;
+                    self.seen = Seen::default();
+                    return Ok(());
+                }
+                Message::Conflict { .. } => {
+                    let (mut state, applied) = self.image()?;
+                    if state.recent.contains(&tag) {
+                        self.take(state, applied);
+                        return Ok(());
+                    }
+                    if !self.seen.holds(&state) {
+                        return Err(VolumeError::StaleMetadata);
+                    }
+                    self.pending.iter().for_each(|c| state.run(c));
+                    self.state = state;
+                    self.base = applied;
+                }
+                Message::Failed(_) => {}
+                _ => return Err(VolumeError::BadFrame),
+            }
+            if Instant::now() >= until {
+                return Err(VolumeError::NoLeader);
+            }
+            std::thread::sleep(RETRY);
+        }
+    }
+}
+
+impl MetadataStore for ReplicatedMetadataStore {
+    fn uuid(&self) -> UUID {
+        self.state.uuid
+    }
+
+    fn journal_begin(&mut self, id: UUID, chunks: &[UUID]) -> Result<(), VolumeError> {
+        if self.state.journal.is_some() {
+            return Err(VolumeError::PendingJournal);
+        }
+        self.run(Command::JournalBegin(id, chunks.to_vec()));
+        self.commit()
+    }
+
+    fn journal_extend(&mut self, id: UUID, chunks: &[UUID]) -> Result<(), VolumeError> {
+        let (_, mut journal) = self.state.journal.clone().ok_or(VolumeError::NoJournal)?;
+        journal.extend_from_slice(chunks);
+        self.run(Command::JournalBegin(id, journal));
+        self.commit()
+    }
+
+    fn journal_commit(&mut self) -> Result<(), VolumeError> {
+        if self.state.journal.is_none() {
+            return Err(VolumeError::NoJournal);
+        }
+        self.run(Command::JournalEnd);
+        self.commit()?;
+        self.finish_rollback()
+    }
+
+    fn rollback(&self) -> &[UUID] {
+        &self.rollback[..]
+    }
+
+    fn finish_rollback(&mut self) -> Result<(), VolumeError> {
+        if self.state.journal.is_some() {
+            self.run(Command::JournalEnd);
+            self.commit()?;
+        }
+        self.rollback.clear();
+        Ok(())
+    }
+
+    fn reload(&mut self) -> Result<(), VolumeError> {
+        self.fetch()
+    }
+
+    fn sync_metadata(&mut self) -> Result<(), VolumeError> {
+        match self.state.journal {
+            Some(_) => Ok(()),
+            None => self.commit(),
+        }
+    }
+
+    fn save_file(&mut self, file: RedundantFile) -> Result<(), VolumeError> {
+        self.run(Command::SaveFile(file));
+        Ok(())
+    }
+
+    fn load_file(&self, id: UUID) -> Result<RedundantFile, VolumeError> {
+        self.state
+            .files
+            .get(&id)
+            .cloned()
+            .ok_or(VolumeError::NoDataFound)
+    }
+
+    fn contains_file(&self, id: UUID) -> bool {
+        self.state.files.contains_key(&id)
+    }
+
+    fn free_file(&mut self, id: UUID) -> Result<(), VolumeError> {
+        if !self.contains_file(id) {
+            return Err(VolumeError::NoDataFound);
+        }
+        self.run(Command::FreeFile(id));
+        Ok(())
+    }
+
+    fn versioning(&self) -> bool {
+        self.state.versioning()
+    }
+
+    fn set_versioning(&mut self, enabled: bool) -> Result<(), VolumeError> {
+        self.run(Command::SetVersioning(enabled));
+        Ok(())
+    }
+
+    fn index_put(&mut self, entry: IndexEntry) -> Result<Vec<IndexEntry>, VolumeError> {
+        let command = Command::IndexPut((&entry).into());
+        self.seen.note(&self.state, &command);
+        let displaced = self.state.index_put(entry);
+        self.pending.push(command);
+        Ok(displaced)
+    }
+
+    fn index_restore(&mut self, entry: IndexEntry) -> Result<(), VolumeError> {
+        self.run(Command::IndexRestore((&entry).into()));
+        Ok(())
+    }
+
+    fn index_get(&self, name: &str) -> Option<IndexEntry> {
+        self.state.index.get(name.as_bytes()).cloned()
+    }
+
+    fn index_get_version(&self, name: &str, id: UUID) -> Option<IndexEntry> {
+        self.state.index.get_version(name.as_bytes(), id).cloned()
+    }
+
+    fn index_entries(&self) -> Vec<IndexEntry> {
+        self.state.index.iter().cloned().collect()
+    }
+
+    fn index_remove_all(&mut self, name: &str) -> Result<Vec<IndexEntry>, VolumeError> {
+        let command = Command::IndexRemoveAll(name.as_bytes().to_vec());
+        self.seen.note(&self.state, &command);
+        let removed = self.state.index.remove_all(name.as_bytes(), &mut |_| {});
+        self.pending.push(command);
+        Ok(removed)
+    }
+
+    fn index_remove_version(
+        &mut self,
+        name: &str,
+        id: UUID,
+    ) -> Result<Option<IndexEntry>, VolumeError> {
+        let command = Command::IndexRemoveVersion(name.as_bytes().to_vec(), id);
+        self.seen.note(&self.state, &command);
+        let removed = self
+            .state
+            .index
+            .remove_version(name.as_bytes(), id, &mut |_| {});
+        self.pending.push(command);
+        Ok(removed)
+    }
+
+    fn list(
+        &self,
+        prefix: &str,
+        delimiter: Option<&str>,
+        start_after: Option<&str>,
+        limit: usize,
+    ) -> Result<ObjectList, VolumeError> {
+        Ok(self.state.index.list(prefix, delimiter, start_after, limit))
+    }
+
+    fn list_versions(
+        &self,
+        prefix: &str,
+        start_after: Option<&str>,
+        limit: usize,
+    ) -> Result<ObjectList, VolumeError> {
+        Ok(self.state.index.list_versions(prefix, start_after, limit))
+    }
+
+    fn create_snapshot(&mut self, name: &str, created: u64) -> Result<(), VolumeError> {
+        let command = Command::CreateSnapshot(name.to_owned(), created);
+        self.seen.note(&self.state, &command);
+        self.state.create_snapshot(name, created)?;
+        self.pending.push(command);
+        Ok(())
+    }
+
+    fn delete_snapshot(&mut self, name: &str) -> Result<Snapshot, VolumeError> {
+        let command = Command::DeleteSnapshot(name.to_owned());
+        self.seen.note(&self.state, &command);
+        let snapshot = self.state.delete_snapshot(name)?;
+        self.pending.push(command);
+        Ok(snapshot)
+    }
+
+    fn snapshots(&self) -> Vec<SnapshotSummary> {
+        self.state.snapshots.iter().map(|s| s.summary()).collect()
+    }
+
+    fn snapshot(&self, name: &str) -> Result<&Snapshot, VolumeError> {
+        self.state
+            .snapshots
+            .iter()
+            .find(|s| s.name == name)
+            .ok_or_else(|| VolumeError::NoSuchSnapshot(name.to_owned()))
+    }
+
+    fn referenced(&self, id: UUID) -> bool {
+        self.state.index.iter().any(|e| e.id == id)
+            || self.state.snapshots.iter().any(|s| s.references(id))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{serve, Command, ReplicatedMetadataStore};
+    use crate::backend::MetadataStore;
+    use crate::consensus::{self, Message};
+    use crate::error::VolumeError;
+    use crate::index::IndexEntry;
+    use crate::test_util::Scratch;
+    use std::net::TcpListener;
+    use std::path::PathBuf;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    // Starts three replicas of the service, each on a thread of its own.
+    fn service(scratch: &Scratch) -> Vec<String> {
+        let listeners: Vec<TcpListener> = (0..3)
+            .map(|_| TcpListener::bind("127.0.0.1:0").unwrap())
+            .collect();
+        let peers: Vec<String> = listeners
+            .iter()
+            .map(|l| l.local_addr().unwrap().to_string())
+            .collect();
+        for (n, listener) in listeners.into_iter().enumerate() {
+            let peers = peers.clone();
+            let dir = PathBuf::from(scratch.path(&format!("replica{}", n)));
+            std::thread::spawn(move || {
+                let errors = Arc::new(|_: &str, _: &VolumeError| {});
+                serve(n, peers, &dir, listener, errors)
+            });
+        }
+        peers
+    }
+
+    fn put(meta_data: &mut ReplicatedMetadataStore, name: &str, id: u128) {
+        meta_data
+            .index_put(IndexEntry::new(name.as_bytes(), id, 0))
+            .unwrap();
+    }
+
+    #[test]
+    fn a_write_is_seen_by_another_client() {
+        let scratch = Scratch::new();
+        let replicas = service(&scratch);
+        let mut a = ReplicatedMetadataStore::open(&replicas).unwrap();
+        put(&mut a, "a", 1);
+        a.sync_metadata().unwrap();
+        let b = ReplicatedMetadataStore::open(&replicas).unwrap();
+        assert_eq!(b.index_get("a").map(|e| e.id), Some(1));
+    }
+
+    #[test]
+    fn a_write_to_another_name_goes_in_on_top_of_what_went_in_first() {
+        let scratch = Scratch::new();
+        let replicas = service(&scratch);
+        let mut a = ReplicatedMetadataStore::open(&replicas).unwrap();
+        let mut b = ReplicatedMetadataStore::open(&replicas).unwrap();
+        put(&mut a, "a", 1);
+        a.sync_metadata().unwrap();
+        put(&mut b, "b", 2);
+        b.sync_metadata().unwrap();
+        assert_eq!(b.index_get("a").map(|e| e.id), Some(1));
+        let c = ReplicatedMetadataStore::open(&replicas).unwrap();
+        assert_eq!(c.index_get("a").map(|e| e.id), Some(1));
+        assert_eq!(c.index_get("b").map(|e| e.id), Some(2));
+    }
+
+    #[test]
+    fn a_write_to_a_name_another_client_changed_is_stale() {
+        let scratch = Scratch::new();
+        let replicas = service(&scratch);
+        let mut a = ReplicatedMetadataStore::open(&replicas).unwrap();
+        let mut b = ReplicatedMetadataStore::open(&replicas).unwrap();
+        put(&mut a, "a", 1);
+        a.sync_metadata().unwrap();
+        put(&mut b, "a", 2);
+        assert!(matches!(b.sync_metadata(), Err(VolumeError::StaleMetadata)));
+        b.reload().unwrap();
+        assert_eq!(b.index_get("a").map(|e| e.id), Some(1));
+    }
+
+    #[test]
+    fn a_proposal_that_went_in_unanswered_is_not_made_again() {
+        let scratch = Scratch::new();
+        let replicas = service(&scratch);
+        let mut a = ReplicatedMetadataStore::open(&replicas).unwrap();
+        a.set_versioning(true).unwrap();
+        a.sync_metadata().unwrap();
+        put(&mut a, "a", 1);
+        // The proposal goes in, but its answer is lost on the way.
+        let tag = uuid::Uuid::new_v4().as_u128();
+        a.tag = Some(tag);
+        let tagged = Command::Tag(tag);
+        let entry: Vec<&Command> = std::iter::once(&tagged).chain(&a.pending).collect();
+        let message = Message::Propose {
+            base: a.base,
+            entry: bincode::serialize(&entry).unwrap(),
+        };
+        let reply = consensus::call(&replicas[a.leader], &message, Duration::from_secs(6));
+        assert!(matches!(reply, Ok(Message::Proposed { .. })));
+        a.sync_metadata().unwrap();
+        assert_eq!(a.list_versions("", None, 10).unwrap().objects.len(), 1);
+        let b = ReplicatedMetadataStore::open(&replicas).unwrap();
+        assert_eq!(b.list_versions("", None, 10).unwrap().objects.len(), 1);
+    }
+}
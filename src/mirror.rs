@@ -0,0 +1,358 @@
+use crate::crc32c::crc32c;
+use crate::error::VolumeError;
+use crate::volume_manager::SUPER_BLOCK_AREA;
+use std::fs::File;
+use std::io::Write;
+use std::os::unix::fs::FileExt;
+
+pub const SECTOR_BYTES: u64 = 4096;
+pub const SECTOR_PAYLOAD: u64 = SECTOR_BYTES - 4;
+pub const GROUP_SECTORS: u64 = 64;
+
+// A volume file as seen through its offsets. A plain file is read and written
+// as it is. In a mirrored one, which is what the metadata file is, everything
+// past the superblock area is cut into sectors of 4092 bytes, each stored
+// with its crc in two places: sectors go in groups of 64, and every group is
+// followed by its copy, so damage to a stretch of the disk rarely takes out
+// both. A read that finds a copy damaged uses the other and rewrites the
+// damaged one; the superblock has its own copies and is left as it is.
+// Reads and writes go by offset, never through the file's own position, so
+// threads sharing the file may read and write it side by side.
+#[derive(Debug)]
+pub struct VolumeFile {
+    file: File,
+    mirrored: bool,
+}
+
+// What one copy of a sector holds. A sector that was never written reads as
+// zeroes in both copies.
+enum Copy {
+    Intact(Vec<u8>),
+    Blank,
+    Damaged,
+}
+
+impl VolumeFile {
+    pub fn plain(file: File) -> VolumeFile {
+        VolumeFile {
+            file,
+            mirrored: false,
+        }
+    }
+
+    pub fn mirrored(file: File) -> VolumeFile {
+        VolumeFile {
+            file,
+            mirrored: true,
+        }
+    }
+
+    // How far into the file can be read, which for a mirrored file ends with
+    // the last sector that has both its copies.
+    pub fn end(&self) -> Result<u64, VolumeError> {
+        let len = self.file.metadata().map_err(VolumeError::IoError)?.len();
+        if !self.mirrored || len <= SUPER_BLOCK_AREA {
+            return Ok(len);
+        }
+        let group = 2 * GROUP_SECTORS * SECTOR_BYTES;
+        let rest = (len - SUPER_BLOCK_AREA) % group;
+        let sectors = (len - SUPER_BLOCK_AREA) / group * GROUP_SECTORS
+            + rest.saturating_sub(GROUP_SECTORS * SECTOR_BYTES) / SECTOR_BYTES;
+        Ok(SUPER_BLOCK_AREA + sectors * SECTOR_PAYLOAD)
+    }
+
+    // Bytes the file takes up, copies and all.
+    pub fn size(&self) -> Result<u64, VolumeError> {
+        Ok(self.file.metadata().map_err(VolumeError::IoError)?.len())
+    }
+
+    // How big the file has to be to hold everything below `end`. Mirrored
+    // files grow by whole groups, so that every sector has both its copies.
+    pub fn size_for(&self, end: u64) -> u64 {
+        if !self.mirrored || end <= SUPER_BLOCK_AREA {
+            return end;
+        }
+        let sectors = (end - SUPER_BLOCK_AREA).div_ceil(SECTOR_PAYLOAD);
+        SUPER_BLOCK_AREA + sectors.div_ceil(GROUP_SECTORS) * 2 * GROUP_SECTORS * SECTOR_BYTES
+    }
+
+    pub fn set_size(&mut self, size: u64) -> Result<(), VolumeError> {
+        self.file.set_len(size).map_err(VolumeError::IoError)
+    }
+
+    pub fn sync_data(&self) -> Result<(), VolumeError> {
+        self.file.sync_data().map_err(VolumeError::IoError)
+    }
+
+    pub fn read_at(&self, pos: u64, buf: &mut [u8]) -> Result<(), VolumeError> {
+        let raw = match self.mirrored {
+            true => std::cmp::min(buf.len() as u64, SUPER_BLOCK_AREA.saturating_sub(pos)) as usize,
+            false => buf.len(),
+        };
+        if raw > 0 {
+            self.file
+                .read_exact_at(&mut buf[..raw], pos)
+                .map_err(VolumeError::IoError)?;
+        }
+        let mut done = raw;
+        while done < buf.len() {
+            let (sector, offset) = locate(pos + done as u64);
+            let len = std::cmp::min(buf.len() - done, (SECTOR_PAYLOAD - offset) as usize);
+            let payload = self.read_sector(sector)?;
+            buf[done..done + len].copy_from_slice(&payload[offset as usize..offset as usize + len]);
+            done += len;
+        }
+        Ok(())
+    }
+
+    // Sectors only partly covered by `bytes` keep the rest of what they hold.
+    pub fn write_at(&self, pos: u64, bytes: &[u8]) -> Result<(), VolumeError> {
+        let raw = match self.mirrored {
+            true => {
+                std::cmp::min(bytes.len() as u64, SUPER_BLOCK_AREA.saturating_sub(pos)) as usize
+            }
+            false => bytes.len(),
+        };
+        if raw > 0 {
+            self.file
+                .write_all_at(&bytes[..raw], pos)
+                .map_err(VolumeError::IoError)?;
+        }
+        let mut done = raw;
+        while done < bytes.len() {
+            let (sector, offset) = locate(pos + done as u64);
+            let len = std::cmp::min(bytes.len() - done, (SECTOR_PAYLOAD - offset) as usize);
+            let mut payload = match len as u64 == SECTOR_PAYLOAD {
+                true => vec![0u8; SECTOR_PAYLOAD as usize],
+                false => self.read_sector(sector)?,
+            };
+            payload[offset as usize..offset as usize + len]
+                .copy_from_slice(&bytes[done..done + len]);
+            write_sector(&self.file, sector, &payload)?;
+            done += len;
+        }
+        Ok(())
+    }
+
+    // Offsets of the mirrored sectors below `end` whose copies aren't both
+    // intact and the same, but of which one still is.
+    pub fn damaged(&self, end: u64) -> Result<Vec<u64>, VolumeError> {
+        let mut damaged = Vec::new();
+        if !self.mirrored || end <= SUPER_BLOCK_AREA {
+            return Ok(damaged);
+        }
+        for sector in 0..locate(end - 1).0 + 1 {
+            let copies = (
+                read_copy(&self.file, sector, primary(sector))?,
+                read_copy(
+                    &self.file,
+                    sector,
+                    primary(sector) + GROUP_SECTORS * SECTOR_BYTES,
+                )?,
+            );
+            let good = match copies {
+                (Copy::Intact(a), Copy::Intact(b)) => a == b,
+                (Copy::Blank, Copy::Blank) => true,
+                (Copy::Damaged, Copy::Damaged) => true,
+                _ => false,
+            };
+            if !good {
+                damaged.push(SUPER_BLOCK_AREA + sector * SECTOR_PAYLOAD);
+            }
+        }
+        Ok(damaged)
+    }
+
+    // Writes both copies of the sector at `pos` from the one a read uses.
+    pub fn rewrite(&mut self, pos: u64) -> Result<(), VolumeError> {
+        if !self.mirrored || pos < SUPER_BLOCK_AREA {
+            return Ok(());
+        }
+        let (sector, _) = locate(pos);
+        let payload = self.read_sector(sector)?;
+        write_sector(&self.file, sector, &payload)?;
+        self.file.flush().map_err(VolumeError::IoError)
+    }
+
+    // The primary copy unless it is damaged or was never written while the
+    // other one was. A repair that fails, as on a file opened read-only, is
+    // left for the next read or fsck.
+    fn read_sector(&self, sector: u64) -> Result<Vec<u8>, VolumeError> {
+        let mirror = primary(sector) + GROUP_SECTORS * SECTOR_BYTES;
+        match read_copy(&self.file, sector, primary(sector))? {
+            Copy::Intact(payload) => Ok(payload),
+            first => match (first, read_copy(&self.file, sector, mirror)?) {
+                (_, Copy::Intact(payload)) => {
+                    let _ = write_copy(&self.file, sector, primary(sector), &payload);
+                    Ok(payload)
+                }
+                (Copy::Blank, _) | (_, Copy::Blank) => Ok(vec![0u8; SECTOR_PAYLOAD as usize]),
+                _ => Err(VolumeError::CorruptMetadata(
+                    SUPER_BLOCK_AREA + sector * SECTOR_PAYLOAD,
+                )),
+            },
+        }
+    }
+}
+
+// The sector holding offset `pos` and where in it `pos` is.
+fn locate(pos: u64) -> (u64, u64) {
+    let pos = pos - SUPER_BLOCK_AREA;
+    (pos / SECTOR_PAYLOAD, pos % SECTOR_PAYLOAD)
+}
+
+fn primary(sector: u64) -> u64 {
+    let group = sector / GROUP_SECTORS;
+    SUPER_BLOCK_AREA + (group * 2 * GROUP_SECTORS + sector % GROUP_SECTORS) * SECTOR_BYTES
+}
+
+// The crc covers the sector number too, so a sector that landed in the wrong
+// place doesn't check out.
+fn sector_crc(sector: u64, payload: &[u8]) -> u32 {
+    let mut buf = payload.to_vec();
+    buf.extend_from_slice(&sector.to_le_bytes());
+    crc32c(&buf[..])
+}
+
+fn read_copy(file: &File, sector: u64, at: u64) -> Result<Copy, VolumeError> {
+    let len = file.metadata().map_err(VolumeError::IoError)?.len();
+    let mut buf = vec![0u8; SECTOR_BYTES as usize];
+    if at < len {
+        let end = std::cmp::min(len - at, SECTOR_BYTES) as usize;
+        if file.read_exact_at(&mut buf[..end], at).is_err() {
+            return Ok(Copy::Damaged);
+        }
+    }
+    if buf.iter().all(|b| *b == 0) {
+        return Ok(Copy::Blank);
+    }
+    let mut crc = [0u8; 4];
+    crc.copy_from_slice(&buf[SECTOR_PAYLOAD as usize..]);
+    buf.truncate(SECTOR_PAYLOAD as usize);
+    match sector_crc(sector, &buf[..]) == u32::from_le_bytes(crc) {
+        true => Ok(Copy::Intact(buf)),
+        false => Ok(Copy::Damaged),
+    }
+}
+
+fn write_copy(file: &File, sector: u64, at: u64, payload: &[u8]) -> Result<(), VolumeError> {
+    let mut buf = payload.to_vec();
+    buf.extend_from_slice(&sector_crc(sector, payload).to_le_bytes());
+    file.write_all_at(&buf[..], at)
+        .map_err(VolumeError::IoError)
+}
+
+// The primary copy goes first: until the other one is written too, a read
+// still finds an intact sector, old or new.
+fn write_sector(file: &File, sector: u64, payload: &[u8]) -> Result<(), VolumeError> {
+    write_copy(file, sector, primary(sector), payload)?;
+    write_copy(
+        file,
+        sector,
+        primary(sector) + GROUP_SECTORS * SECTOR_BYTES,
+        payload,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{primary, VolumeFile, GROUP_SECTORS, SECTOR_BYTES, SECTOR_PAYLOAD};
+    use crate::error::VolumeError;
+    use crate::test_util::Scratch;
+    use crate::volume_manager::SUPER_BLOCK_AREA;
+    use std::fs::{File, OpenOptions};
+    use std::os::unix::fs::FileExt;
+
+    fn open(path: &str) -> VolumeFile {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)
+            .unwrap();
+        VolumeFile::mirrored(file)
+    }
+
+    // Flips a byte of the sector copy at `at`, behind the mirror's back.
+    fn damage(path: &str, at: u64) {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(path)
+            .unwrap();
+        let mut byte = [0u8];
+        file.read_exact_at(&mut byte, at + 10).unwrap();
+        file.write_all_at(&[byte[0] ^ 0xff], at + 10).unwrap();
+    }
+
+    fn mirror(sector: u64) -> u64 {
+        primary(sector) + GROUP_SECTORS * SECTOR_BYTES
+    }
+
+    fn written(path: &str) -> (VolumeFile, Vec<u8>) {
+        let mut file = open(path);
+        let data: Vec<u8> = (0..SECTOR_PAYLOAD as usize * 2).map(|n| n as u8).collect();
+        file.set_size(file.size_for(SUPER_BLOCK_AREA + data.len() as u64))
+            .unwrap();
+        file.write_at(SUPER_BLOCK_AREA, &data).unwrap();
+        (file, data)
+    }
+
+    fn read(file: &VolumeFile, len: usize) -> Result<Vec<u8>, VolumeError> {
+        let mut buf = vec![0u8; len];
+        file.read_at(SUPER_BLOCK_AREA, &mut buf).map(|_| buf)
+    }
+
+    #[test]
+    fn damaged_primary_falls_back_to_the_mirror_and_is_repaired() {
+        let scratch = Scratch::new();
+        let path = scratch.path("volume.bin");
+        let (file, data) = written(&path);
+        damage(&path, primary(1));
+        let end = SUPER_BLOCK_AREA + data.len() as u64;
+        assert_eq!(
+            file.damaged(end).unwrap(),
+            vec![SUPER_BLOCK_AREA + SECTOR_PAYLOAD]
+        );
+
+        assert_eq!(read(&file, data.len()).unwrap(), data);
+        assert!(file.damaged(end).unwrap().is_empty());
+    }
+
+    #[test]
+    fn damaged_mirror_is_found_and_rewritten() {
+        let scratch = Scratch::new();
+        let path = scratch.path("volume.bin");
+        let (mut file, data) = written(&path);
+        damage(&path, mirror(0));
+        let end = SUPER_BLOCK_AREA + data.len() as u64;
+        assert_eq!(read(&file, data.len()).unwrap(), data);
+        assert_eq!(file.damaged(end).unwrap(), vec![SUPER_BLOCK_AREA]);
+
+        file.rewrite(SUPER_BLOCK_AREA).unwrap();
+        assert!(file.damaged(end).unwrap().is_empty());
+    }
+
+    #[test]
+    fn both_copies_damaged_is_an_error() {
+        let scratch = Scratch::new();
+        let path = scratch.path("volume.bin");
+        let (file, data) = written(&path);
+        damage(&path, primary(0));
+        damage(&path, mirror(0));
+        match read(&file, data.len()) {
+            Err(VolumeError::CorruptMetadata(pos)) => assert_eq!(pos, SUPER_BLOCK_AREA),
+            other => panic!("read {:?}", other.map(|b| b.len())),
+        }
+    }
+
+    #[test]
+    fn plain_file_is_read_as_it_is() {
+        let scratch = Scratch::new();
+        let path = scratch.path("plain.bin");
+        let file = VolumeFile::plain(File::create(&path).unwrap());
+        file.write_at(5, &[1, 2, 3]).unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), vec![0, 0, 0, 0, 0, 1, 2, 3]);
+    }
+}
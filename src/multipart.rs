@@ -0,0 +1,136 @@
+use crate::block::Block;
+use crate::chunk::Chunk;
+use crate::error::RedundantFileError;
+use crate::redundant_file::RedundantFile;
+use crate::UUID;
+use std::collections::BTreeMap;
+
+// Parts are numbered from 1 to this.
+pub const MAX_PARTS: u32 = 10000;
+
+// A part encoded into chunks but not yet stored. Encoding needs no access to
+// the volume, so parts can be built on as many threads as the caller likes
+// and handed to the volume in any order.
+pub struct EncodedPart {
+    pub number: u32,
+    pub chunks: Vec<Chunk>,
+    pub blocks: Vec<Block>,
+}
+
+#[derive(Debug, Clone)]
+pub struct UploadedPart {
+    pub number: u32,
+    pub chunks: Vec<UUID>,
+    pub size: u64,
+}
+
+#[derive(Debug, Clone)]
+pub struct MultipartUpload {
+    pub id: UUID,
+    pub name: String,
+    parts: BTreeMap<u32, UploadedPart>,
+}
+
+impl EncodedPart {
+    pub fn encode<T>(number: u32, reader: &mut T) -> Result<EncodedPart, RedundantFileError>
+    where
+        T: std::io::Read + Send,
+    {
+        let (chunks, blocks) = RedundantFile::encode_chunks(reader)?;
+        Ok(EncodedPart {
+            number,
+            chunks,
+            blocks,
+        })
+    }
+
+    pub fn size(&self) -> u64 {
+        self.chunks.iter().map(|c| c.chunk_size as u64).sum()
+    }
+
+    pub fn chunk_blocks(&self, chunk: &Chunk) -> Vec<Block> {
+        self.blocks
+            .iter()
+            .filter(|b| chunk.blocks.contains(&b.id))
+            .cloned()
+            .collect()
+    }
+}
+
+impl MultipartUpload {
+    pub fn new(name: &str) -> MultipartUpload {
+        MultipartUpload::resume(uuid::Uuid::new_v4().as_u128(), name)
+    }
+
+    // An upload begun earlier, whose parts the caller adds back.
+    pub fn resume(id: UUID, name: &str) -> MultipartUpload {
+        MultipartUpload {
+            id,
+            name: name.to_owned(),
+            parts: BTreeMap::new(),
+        }
+    }
+
+    // The id of the record kept for part `number`, which no other part of
+    // this upload, nor the upload itself, shares.
+    pub fn part_id(&self, number: u32) -> UUID {
+        self.id ^ number as UUID
+    }
+
+    pub fn parts(&self) -> impl Iterator<Item = &UploadedPart> {
+        self.parts.values()
+    }
+
+    // Returns the part previously stored under the same number, whose chunks
+    // the caller has to free.
+    pub fn add_part(&mut self, part: UploadedPart) -> Option<UploadedPart> {
+        self.parts.insert(part.number, part)
+    }
+
+    pub fn size(&self) -> u64 {
+        self.parts.values().map(|p| p.size).sum()
+    }
+
+    // Chunk ids of every part in part-number order, ready to become the chunk
+    // list of the completed file.
+    pub fn chunk_ids(&self) -> Vec<UUID> {
+        self.parts
+            .values()
+            .flat_map(|p| p.chunks.iter().cloned())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{MultipartUpload, UploadedPart};
+
+    fn part(number: u32, chunks: &[u128], size: u64) -> UploadedPart {
+        UploadedPart {
+            number,
+            chunks: chunks.to_vec(),
+            size,
+        }
+    }
+
+    #[test]
+    fn chunks_come_in_part_number_order() {
+        let mut upload = MultipartUpload::new("object");
+        assert!(upload.add_part(part(3, &[5], 10)).is_none());
+        assert!(upload.add_part(part(1, &[1, 2], 20)).is_none());
+        assert!(upload.add_part(part(2, &[3, 4], 30)).is_none());
+        assert_eq!(upload.chunk_ids(), vec![1, 2, 3, 4, 5]);
+        assert_eq!(upload.size(), 60);
+    }
+
+    #[test]
+    fn part_added_again_replaces_the_old_one() {
+        let mut upload = MultipartUpload::new("object");
+        upload.add_part(part(1, &[1, 2], 20));
+        upload.add_part(part(2, &[3], 5));
+        let replaced = upload.add_part(part(1, &[7], 8)).unwrap();
+        assert_eq!(replaced.chunks, vec![1, 2]);
+        assert_eq!(upload.chunk_ids(), vec![7, 3]);
+        assert_eq!(upload.size(), 13);
+    }
+}
@@ -0,0 +1,1382 @@
+use crate::backend::BlockStore;
+use crate::block::Block;
+use crate::chunk::Chunk;
+use crate::constants::{BLOCKS, PARITY};
+use crate::crc32c::crc32c;
+use crate::directory::{
+    hashed, hashed_ids, read_file, remove_file, seal, sync_dirs, u128_at, unseal, write_file,
+};
+use crate::domain::FailureDomain;
+use crate::error::{ErrorLog, RedundantFileError, VolumeError};
+use crate::hedge::{self, Cancel};
+use crate::label::{ChunkLabel, LABEL_BYTES};
+use crate::placement::{Placement, Spread, StoreInfo};
+use crate::reed_solomon_erasure::galois_8::ReedSolomon;
+use crate::volume::BigFileVolume;
+use crate::volume_manager::FileVolumeManager;
+use crate::UUID;
+use std::collections::HashSet;
+use std::io::{ErrorKind, Read, Write};
+use std::net::{Shutdown, TcpListener, TcpStream, ToSocketAddrs};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+// The protocol spoken between a volume and `oggetto blockd`. Every request
+// is a frame of an op, a namespace, an id and a payload; every reply one of
+// a status and a payload. Numbers are little endian.
+//
+//   request: op u8, namespace u8, id u128, length u32, payload
+//   reply:   status u8, length u32, payload
+pub const OP_PUT: u8 = 1;
+pub const OP_GET: u8 = 2;
+pub const OP_DELETE: u8 = 3;
+pub const OP_STAT: u8 = 4;
+pub const OP_LIST: u8 = 5;
+pub const OP_SYNC: u8 = 6;
+
+// Blocks, chunk records and the record of which node of which volume the
+// daemon is. The last has no id.
+pub const NS_BLOCK: u8 = 0;
+pub const NS_CHUNK: u8 = 1;
+pub const NS_NODE: u8 = 2;
+
+pub const STATUS_OK: u8 = 0;
+pub const STATUS_NOT_FOUND: u8 = 1;
+pub const STATUS_ERROR: u8 = 2;
+
+// No frame carries more than a block and then some.
+pub const MAX_FRAME_BYTES: usize = 16 << 20;
+
+pub const NODE_MAGIC: [u8; 8] = *b"OGGNETND";
+pub const RECORD_MAGIC: [u8; 8] = *b"OGGNETCH";
+pub const ADDRS_MAGIC: [u8; 8] = *b"OGGNETAD";
+
+// Blocks of a chunk that have to be on their nodes for a write to succeed,
+// unless the volume is told otherwise.
+pub const DEFAULT_QUORUM: usize = BLOCKS + PARITY / 2;
+
+// Blocks are big and passed by value, more so in debug builds, so a thread
+// handling them needs more stack than the default.
+const REPAIR_STACK_BYTES: usize = 32 << 20;
+
+// How long a read waits on a block before asking another node for one more
+// of the chunk's blocks.
+pub const DEFAULT_HEDGE: Duration = Duration::from_millis(20);
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+// A fetch that connects checks this often whether it is still wanted.
+const CONNECT_STEP: Duration = Duration::from_millis(100);
+const IO_TIMEOUT: Duration = Duration::from_secs(60);
+
+// Opens the volume with its metadata in `meta_data` and its blocks on the
+// block daemons at `nodes`, creating it if neither is there yet. Blocks
+// owed to nodes that are down, and the addresses the nodes were last found
+// at, are kept next to the metadata, under the same name with a .hints
+// extension. Repairs that fail are reported to `log`.
+pub fn open(
+    meta_data: &str,
+    nodes: &[String],
+    domains: &[FailureDomain],
+    quorum: usize,
+    log: ErrorLog,
+) -> Result<BigFileVolume, VolumeError> {
+    let hints = Path::new(meta_data).with_extension("hints");
+    let fvm = match Path::new(meta_data).exists() {
+        true => Some(FileVolumeManager::open_metadata(meta_data)?),
+        false => None,
+    };
+    let blocks = match NetBlockStores::open(nodes, &hints) {
+        Ok(blocks) => Some(blocks),
+        Err(VolumeError::NoDataFound) => None,
+        Err(err) => return Err(err),
+    };
+    let uuid = fvm
+        .as_ref()
+        .map(|f| f.super_block().uuid())
+        .or(blocks.as_ref().map(BlockStore::uuid))
+        .unwrap_or_else(|| uuid::Uuid::new_v4().as_u128());
+    let fvm = match fvm {
+        Some(fvm) => fvm,
+        None => FileVolumeManager::init_metadata(meta_data, uuid)?,
+    };
+    let mut blocks = match blocks {
+        Some(blocks) => blocks,
+        None => NetBlockStores::init(nodes, domains, uuid, &hints)?,
+    };
+    blocks.set_quorum(quorum)?;
+    blocks.set_log(log);
+    BigFileVolume::with_backends(Box::new(fvm), Box::new(blocks))
+}
+
+fn frame(op: u8, ns: u8, id: UUID, payload: &[u8]) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(22 + payload.len());
+    buf.push(op);
+    buf.push(ns);
+    buf.extend_from_slice(&id.to_le_bytes());
+    buf.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    buf.extend_from_slice(payload);
+    buf
+}
+
+fn read_payload(stream: &mut dyn Read, len: [u8; 4]) -> Result<Vec<u8>, VolumeError> {
+    let len = u32::from_le_bytes(len) as usize;
+    if len > MAX_FRAME_BYTES {
+        return Err(VolumeError::BadFrame);
+    }
+    let mut payload = vec![0u8; len];
+    stream
+        .read_exact(&mut payload[..])
+        .map_err(VolumeError::IoError)?;
+    Ok(payload)
+}
+
+// Sends one request and waits for its reply.
+fn request(
+    mut stream: &TcpStream,
+    op: u8,
+    ns: u8,
+    id: UUID,
+    payload: &[u8],
+) -> Result<Vec<u8>, VolumeError> {
+    stream
+        .write_all(&frame(op, ns, id, payload)[..])
+        .map_err(VolumeError::IoError)?;
+    let mut head = [0u8; 5];
+    stream.read_exact(&mut head).map_err(VolumeError::IoError)?;
+    let payload = read_payload(&mut stream, [head[1], head[2], head[3], head[4]])?;
+    match head[0] {
+        STATUS_OK => Ok(payload),
+        STATUS_NOT_FOUND => Err(VolumeError::NoDataFound),
+        STATUS_ERROR => Err(VolumeError::IoError(std::io::Error::other(
+            String::from_utf8_lossy(&payload[..]).into_owned(),
+        ))),
+        _ => Err(VolumeError::BadFrame),
+    }
+}
+
+fn connect(addr: &str) -> Result<TcpStream, VolumeError> {
+    connect_unless(addr, &Cancel::default())
+}
+
+// Connects to `addr`, giving up as soon as `cancel` says the connection
+// is no longer wanted: the connect timeout is waited out a step at a time.
+fn connect_unless(addr: &str, cancel: &Cancel) -> Result<TcpStream, VolumeError> {
+    let mut err = VolumeError::NoDataFound;
+    for addr in addr.to_socket_addrs().map_err(VolumeError::IoError)? {
+        let deadline = Instant::now() + CONNECT_TIMEOUT;
+        loop {
+            if cancel.cancelled() {
+                return Err(VolumeError::IoError(ErrorKind::Interrupted.into()));
+            }
+            let left = deadline.saturating_duration_since(Instant::now());
+            match TcpStream::connect_timeout(&addr, left.min(CONNECT_STEP)) {
+                Ok(stream) => {
+                    stream.set_nodelay(true).map_err(VolumeError::IoError)?;
+                    stream
+                        .set_read_timeout(Some(IO_TIMEOUT))
+                        .map_err(VolumeError::IoError)?;
+                    stream
+                        .set_write_timeout(Some(IO_TIMEOUT))
+                        .map_err(VolumeError::IoError)?;
+                    return Ok(stream);
+                }
+                Err(e) if e.kind() == ErrorKind::TimedOut && left > CONNECT_STEP => {}
+                Err(e) => {
+                    err = VolumeError::IoError(e);
+                    break;
+                }
+            }
+        }
+    }
+    Err(err)
+}
+
+// What `oggetto blockd` keeps under its directory: blocks and chunk records
+// as files in hashed trees, laid out as a DirBlockStore lays them out, and
+// the record of the node it is. It knows nothing of what it stores.
+pub struct BlockServer {
+    root: PathBuf,
+    dirty: HashSet<PathBuf>,
+}
+
+impl BlockServer {
+    pub fn new(root: &Path) -> Result<BlockServer, VolumeError> {
+        std::fs::create_dir_all(root).map_err(VolumeError::IoError)?;
+        Ok(BlockServer {
+            root: root.to_owned(),
+            dirty: HashSet::new(),
+        })
+    }
+
+    fn dir(&self, ns: u8) -> Result<PathBuf, VolumeError> {
+        match ns {
+            NS_BLOCK => Ok(self.root.join("blocks")),
+            NS_CHUNK => Ok(self.root.join("chunks")),
+            _ => Err(VolumeError::BadFrame),
+        }
+    }
+
+    fn path(&self, ns: u8, id: UUID) -> Result<PathBuf, VolumeError> {
+        match ns {
+            NS_NODE => Ok(self.root.join("node")),
+            ns => Ok(hashed(&self.dir(ns)?, id)),
+        }
+    }
+
+    pub fn handle(
+        &mut self,
+        op: u8,
+        ns: u8,
+        id: UUID,
+        payload: &[u8],
+    ) -> Result<Vec<u8>, VolumeError> {
+        match op {
+            OP_PUT => write_file(&self.path(ns, id)?, payload, &mut self.dirty).map(|_| Vec::new()),
+            OP_GET => read_file(&self.path(ns, id)?),
+            OP_DELETE => remove_file(&self.path(ns, id)?, &mut self.dirty).map(|_| Vec::new()),
+            OP_STAT => match std::fs::metadata(self.path(ns, id)?) {
+                Ok(meta) => Ok(meta.len().to_le_bytes().to_vec()),
+                Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+                    Err(VolumeError::NoDataFound)
+                }
+                Err(err) => Err(VolumeError::IoError(err)),
+            },
+            OP_LIST => Ok(hashed_ids(&self.dir(ns)?)
+                .iter()
+                .flat_map(|id| id.to_le_bytes())
+                .collect()),
+            OP_SYNC => sync_dirs(&mut self.dirty).map(|_| Vec::new()),
+            _ => Err(VolumeError::BadFrame),
+        }
+    }
+}
+
+// Serves the blocks under `root` to whoever connects, each connection in a
+// thread of its own, until the listener fails. Connections that fail are
+// reported to `log` under the peer's address.
+pub fn serve(listener: TcpListener, root: &Path, log: ErrorLog) -> Result<(), VolumeError> {
+    let server = Arc::new(Mutex::new(BlockServer::new(root)?));
+    for stream in listener.incoming() {
+        let stream = stream.map_err(VolumeError::IoError)?;
+        let server = server.clone();
+        let log = log.clone();
+        std::thread::spawn(move || {
+            let peer = stream.peer_addr().map(|a| a.to_string()).ok();
+            if let Err(err) = serve_connection(stream, &server) {
+                log(&peer.unwrap_or_default(), &err);
+            }
+        });
+    }
+    Ok(())
+}
+
+// Answers requests until the client hangs up. A frame that can't be
+// understood ends the connection, as nothing after it can be trusted.
+fn serve_connection(mut stream: TcpStream, server: &Mutex<BlockServer>) -> Result<(), VolumeError> {
+    stream.set_nodelay(true).map_err(VolumeError::IoError)?;
+    loop {
+        let mut head = [0u8; 22];
+        match stream.read_exact(&mut head) {
+            Ok(()) => {}
+            Err(err) if err.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(()),
+            Err(err) => return Err(VolumeError::IoError(err)),
+        }
+        let id = u128_at(&head[..], 2);
+        let payload = read_payload(&mut stream, [head[18], head[19], head[20], head[21]])?;
+        let reply = server
+            .lock()
+            .unwrap()
+            .handle(head[0], head[1], id, &payload[..]);
+        let (status, payload) = match reply {
+            Ok(payload) => (STATUS_OK, payload),
+            Err(VolumeError::NoDataFound) => (STATUS_NOT_FOUND, Vec::new()),
+            Err(VolumeError::BadFrame) => return Err(VolumeError::BadFrame),
+            Err(err) => (STATUS_ERROR, format!("{:?}", err).into_bytes()),
+        };
+        let mut buf = vec![status];
+        buf.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+        buf.extend(payload);
+        stream.write_all(&buf[..]).map_err(VolumeError::IoError)?;
+    }
+}
+
+// Whether `block` is the one the label of its chunk expects as block `n`,
+// as it was written. One that isn't is missing as far as reads go.
+fn sound(label: &ChunkLabel, n: usize, block: &Block, id: UUID) -> bool {
+    block.id == id
+        && crc32c(&block.data) == block.crc
+        && (label.crcs[n] == 0 || label.crcs[n] == block.crc)
+}
+
+// The addresses the nodes of the volume with its hints under `hints` were
+// last found at, by node. None if they were never written down.
+fn read_addrs(hints: &Path) -> Result<Option<Vec<Option<String>>>, VolumeError> {
+    let bytes = match read_file(&hints.join("addrs")) {
+        Ok(bytes) => bytes,
+        Err(VolumeError::NoDataFound) => return Ok(None),
+        Err(err) => return Err(err),
+    };
+    let addrs = unseal(&ADDRS_MAGIC, &bytes[..]).ok_or(VolumeError::CorruptMetadata(0))?;
+    Ok(Some(
+        String::from_utf8_lossy(addrs)
+            .split('\n')
+            .map(|a| Some(a.to_owned()).filter(|a| !a.is_empty()))
+            .collect(),
+    ))
+}
+
+fn write_addrs(hints: &Path, addrs: &[Option<String>]) -> Result<(), VolumeError> {
+    let addrs: Vec<&str> = addrs.iter().map(|a| a.as_deref().unwrap_or("")).collect();
+    let mut dirty = HashSet::new();
+    write_file(
+        &hints.join("addrs"),
+        &seal(&ADDRS_MAGIC, addrs.join("\n").as_bytes())[..],
+        &mut dirty,
+    )?;
+    sync_dirs(&mut dirty)
+}
+
+// The connections to the nodes of a volume, and the shards owed to the
+// nodes that couldn't take them. Those are kept under `hints` until the
+// node is back, one directory per node laid out as the node would keep
+// them; an empty one stands for a delete.
+struct Cluster {
+    addrs: Vec<Option<String>>,
+    nodes: Vec<Option<TcpStream>>,
+    // Nodes that took something they haven't synced yet.
+    dirty: HashSet<usize>,
+    hints: PathBuf,
+}
+
+impl Cluster {
+    fn call(
+        &self,
+        n: usize,
+        op: u8,
+        ns: u8,
+        id: UUID,
+        payload: &[u8],
+    ) -> Result<Vec<u8>, VolumeError> {
+        let node = self.nodes[n]
+            .as_ref()
+            .ok_or(VolumeError::StoreUnavailable(n))?;
+        request(node, op, ns, id, payload)
+    }
+
+    fn available(&self) -> impl Iterator<Item = usize> + '_ {
+        let nodes = &self.nodes;
+        (0..nodes.len()).filter(move |n| nodes[*n].is_some())
+    }
+
+    fn hints(&self, n: usize) -> PathBuf {
+        self.hints.join(n.to_string())
+    }
+
+    fn hint(&self, n: usize, ns: u8, id: UUID, payload: &[u8]) -> Result<(), VolumeError> {
+        let mut hints = BlockServer::new(&self.hints(n))?;
+        hints.handle(OP_PUT, ns, id, payload)?;
+        hints.handle(OP_SYNC, ns, 0, &[])?;
+        Ok(())
+    }
+
+    // Sends `payload` to node `n`, or queues it for the node if it can't be
+    // reached. A node that fails is taken to be gone until the volume is
+    // opened again. Returns whether the node has it, not yet synced; it is
+    // up to the caller to sync it.
+    fn send(&mut self, n: usize, ns: u8, id: UUID, payload: &[u8]) -> Result<bool, VolumeError> {
+        if self.nodes[n].is_some() {
+            match self.call(n, OP_PUT, ns, id, payload) {
+                Ok(_) => return Ok(true),
+                Err(VolumeError::IoError(_)) => self.nodes[n] = None,
+                Err(err) => return Err(err),
+            }
+        }
+        self.hint(n, ns, id, payload)?;
+        Ok(false)
+    }
+
+    // The same, left for the next sync.
+    fn put(&mut self, n: usize, ns: u8, id: UUID, payload: &[u8]) -> Result<bool, VolumeError> {
+        let sent = self.send(n, ns, id, payload)?;
+        if sent {
+            self.dirty.insert(n);
+        }
+        Ok(sent)
+    }
+
+    fn record(label: Vec<u8>, chunk: &Chunk) -> Vec<u8> {
+        let mut record = label;
+        record.extend(bincode::serialize(chunk).unwrap());
+        seal(&RECORD_MAGIC, &record[..])
+    }
+
+    fn put_record(&mut self, n: usize, label: Vec<u8>, chunk: &Chunk) -> Result<bool, VolumeError> {
+        self.put(n, NS_CHUNK, chunk.id, &Cluster::record(label, chunk)[..])
+    }
+
+    fn delete(&mut self, n: usize, ns: u8, id: UUID) -> Result<(), VolumeError> {
+        if self.nodes[n].is_some() {
+            match self.call(n, OP_DELETE, ns, id, &[]) {
+                Ok(_) | Err(VolumeError::NoDataFound) => {
+                    self.dirty.insert(n);
+                    return Ok(());
+                }
+                Err(VolumeError::IoError(_)) => self.nodes[n] = None,
+                Err(err) => return Err(err),
+            }
+        }
+        self.hint(n, ns, id, &[])
+    }
+
+    // Hands node `n` what it is owed, blocks before the records of their
+    // chunks. Returns how many shards it was given or told to delete.
+    fn deliver(&mut self, n: usize) -> Result<usize, VolumeError> {
+        if !self.hints(n).exists() {
+            return Ok(0);
+        }
+        let mut hints = BlockServer::new(&self.hints(n))?;
+        let mut delivered = 0;
+        for ns in [NS_BLOCK, NS_CHUNK] {
+            let list = hints.handle(OP_LIST, ns, 0, &[])?;
+            for id in (0..list.len() / 16).map(|at| u128_at(&list[..], at * 16)) {
+                let payload = hints.handle(OP_GET, ns, id, &[])?;
+                match payload.is_empty() {
+                    true => match self.call(n, OP_DELETE, ns, id, &[]) {
+                        Ok(_) | Err(VolumeError::NoDataFound) => {}
+                        Err(err) => return Err(err),
+                    },
+                    false => {
+                        self.call(n, OP_PUT, ns, id, &payload[..])?;
+                    }
+                }
+                self.call(n, OP_SYNC, ns, 0, &[])?;
+                hints.handle(OP_DELETE, ns, id, &[])?;
+                delivered += 1;
+            }
+        }
+        hints.handle(OP_SYNC, NS_BLOCK, 0, &[])?;
+        Ok(delivered)
+    }
+
+    // Has each of `nodes` make what it took durable, and returns those that
+    // did. One that fails is taken to be gone, and whatever it took since
+    // it last synced with it.
+    fn sync_nodes(&mut self, nodes: &[usize]) -> Result<Vec<usize>, VolumeError> {
+        let mut synced = Vec::new();
+        for n in nodes.iter().cloned() {
+            if self.nodes[n].is_none() {
+                continue;
+            }
+            match self.call(n, OP_SYNC, NS_CHUNK, 0, &[]) {
+                Ok(_) => {
+                    self.dirty.remove(&n);
+                    synced.push(n);
+                }
+                Err(VolumeError::IoError(_)) => self.nodes[n] = None,
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(synced)
+    }
+
+    // Fails with StoreUnavailable if a node that took something went before
+    // it could sync it. That is reported once; what the node is sent from
+    // then on is kept for it as hints.
+    fn sync(&mut self) -> Result<(), VolumeError> {
+        let mut dirty: Vec<usize> = self.dirty.drain().collect();
+        dirty.sort_unstable();
+        let synced = self.sync_nodes(&dirty)?;
+        match dirty.into_iter().find(|n| !synced.contains(n)) {
+            Some(n) => Err(VolumeError::StoreUnavailable(n)),
+            None => Ok(()),
+        }
+    }
+
+    // The label and header of chunk `id`, as the first node that has a
+    // record of it that checks out has them.
+    fn load_record(&self, id: UUID) -> Result<(Vec<u8>, Chunk), VolumeError> {
+        let start = (id % self.nodes.len() as u128) as usize;
+        let mut err = VolumeError::NoDataFound;
+        for n in (0..self.nodes.len()).map(|n| (start + n) % self.nodes.len()) {
+            if self.nodes[n].is_none() {
+                continue;
+            }
+            let bytes = match self.call(n, OP_GET, NS_CHUNK, id, &[]) {
+                Ok(bytes) => bytes,
+                Err(VolumeError::NoDataFound) => continue,
+                Err(e) => {
+                    err = e;
+                    continue;
+                }
+            };
+            let record = match unseal(&RECORD_MAGIC, &bytes[..]) {
+                Some(record) if record.len() >= LABEL_BYTES => record,
+                _ => {
+                    err = VolumeError::CorruptChunk(id);
+                    continue;
+                }
+            };
+            match bincode::deserialize::<Chunk>(&record[LABEL_BYTES..]) {
+                Ok(chunk) if chunk.is_sound(id) => {
+                    return Ok((record[..LABEL_BYTES].to_vec(), chunk));
+                }
+                _ => err = VolumeError::CorruptChunk(id),
+            }
+        }
+        Err(err)
+    }
+
+    // The label of chunk `id` and its header.
+    fn chunk(&self, id: UUID) -> Result<(ChunkLabel, Chunk), VolumeError> {
+        let (label, chunk) = self.load_record(id)?;
+        let label = ChunkLabel::parse(&label[..])
+            .filter(|l| l.chunk == id && l.stores.iter().all(|n| (*n as usize) < self.nodes.len()))
+            .ok_or(VolumeError::NoDataFound)?;
+        Ok((label, chunk))
+    }
+
+    // A block that doesn't decode reads as missing, like a stale one.
+    fn block(&self, n: usize, id: UUID) -> Result<Block, VolumeError> {
+        let bytes = self.call(n, OP_GET, NS_BLOCK, id, &[])?;
+        bincode::deserialize(&bytes[..]).map_err(|_| VolumeError::NoDataFound)
+    }
+
+    // Rebuilds the blocks of chunk `id` that nodes that are up have lost or
+    // hold stale, from the others, and puts them back. Returns how many.
+    fn repair(&mut self, id: UUID) -> Result<usize, VolumeError> {
+        let (label, chunk) = self.chunk(id)?;
+        let mut shards = Vec::new();
+        let mut lost = Vec::new();
+        for n in 0..chunk.blocks.len() {
+            match self.block(label.stores[n] as usize, chunk.blocks[n]) {
+                Ok(block) if sound(&label, n, &block, chunk.blocks[n]) => {
+                    shards.push(block.inner_data_as_vec())
+                }
+                Ok(_) | Err(VolumeError::NoDataFound) => {
+                    lost.push(n);
+                    shards.push(None);
+                }
+                Err(_) => shards.push(None),
+            }
+        }
+        if lost.is_empty() {
+            return Ok(0);
+        }
+        ReedSolomon::new(chunk.chunk_n, chunk.parity_n)
+            .and_then(|r| r.reconstruct(&mut shards))
+            .map_err(|e| VolumeError::FileError(RedundantFileError::RecostructError(e)))?;
+        for n in lost.iter().cloned() {
+            let data = shards[n].take().ok_or(VolumeError::NoDataFound)?;
+            let mut block = Block::empty();
+            block.id = chunk.blocks[n];
+            block.position = n;
+            block.data.copy_from_slice(&data[..]);
+            block.crc = crc32c(&block.data);
+            let node = label.stores[n] as usize;
+            self.put(
+                node,
+                NS_BLOCK,
+                block.id,
+                &bincode::serialize(&block).unwrap()[..],
+            )?;
+            if let Err(VolumeError::NoDataFound) = self.call(node, OP_STAT, NS_CHUNK, id, &[]) {
+                self.put_record(node, label.into(), &chunk)?;
+            }
+        }
+        self.sync()?;
+        Ok(lost.len())
+    }
+}
+
+// Repairs the chunks it is sent, one at a time, over connections of its
+// own, until the volume that sends them goes away. Repairs that fail are
+// reported to `log`, if there is one.
+fn repairs(
+    addrs: Vec<Option<String>>,
+    hints: PathBuf,
+    jobs: Receiver<UUID>,
+    log: Option<ErrorLog>,
+) {
+    let mut cluster = Cluster {
+        nodes: addrs
+            .iter()
+            .map(|a| a.as_deref().and_then(|a| connect(a).ok()))
+            .collect(),
+        addrs,
+        dirty: HashSet::new(),
+        hints,
+    };
+    let mut done = HashSet::new();
+    for id in jobs {
+        if !done.insert(id) {
+            continue;
+        }
+        if let (Err(err), Some(log)) = (cluster.repair(id), log.as_ref()) {
+            log(&format!("repair of chunk {:032x}", id), &err);
+        }
+    }
+}
+
+struct Repairer {
+    jobs: Sender<UUID>,
+    thread: JoinHandle<()>,
+}
+
+// The blocks of a volume spread over block daemons, as BlockStores spreads
+// them over store files: each block of a chunk on a different node, placed
+// by the placement policy, and every node holding a block also holding a
+// record of the chunk's label and header. A chunk reads as long as no more
+// than PARITY of its nodes are missing, and is written once `quorum` of
+// its blocks are synced on their nodes; those owed to nodes that are down are
+// handed to them when the volume is next opened. Reads that find a block
+// lost or stale have it rebuilt in the background.
+pub struct NetBlockStores {
+    cluster: Cluster,
+    domains: Vec<FailureDomain>,
+    uuid: UUID,
+    policy: Box<dyn Placement>,
+    quorum: usize,
+    hedge: Duration,
+    repairer: Mutex<Option<Repairer>>,
+    log: Option<ErrorLog>,
+}
+
+impl NetBlockStores {
+    // Tells the daemons at `addrs` which nodes of a new volume they are.
+    // Every one of them has to be up and hold no volume yet.
+    pub fn init(
+        addrs: &[String],
+        domains: &[FailureDomain],
+        uuid: UUID,
+        hints: &Path,
+    ) -> Result<NetBlockStores, VolumeError> {
+        if addrs.len() < BLOCKS + PARITY || addrs.len() > u16::MAX as usize {
+            return Err(VolumeError::TooFewStores(addrs.len()));
+        }
+        let domains: Vec<FailureDomain> = (0..addrs.len())
+            .map(|n| domains.get(n).cloned().unwrap_or_default())
+            .collect();
+        let mut nodes = Vec::new();
+        for (n, addr) in addrs.iter().enumerate() {
+            let node = connect(addr)?;
+            match request(&node, OP_STAT, NS_NODE, 0, &[]) {
+                Err(VolumeError::NoDataFound) => {}
+                Ok(_) => return Err(VolumeError::StoreMismatch(addr.clone())),
+                Err(err) => return Err(err),
+            }
+            let mut record = uuid.to_le_bytes().to_vec();
+            record.extend_from_slice(&(n as u16).to_le_bytes());
+            record.extend_from_slice(&(addrs.len() as u16).to_le_bytes());
+            record.extend_from_slice(domains[n].to_string().as_bytes());
+            request(
+                &node,
+                OP_PUT,
+                NS_NODE,
+                0,
+                &seal(&NODE_MAGIC, &record[..])[..],
+            )?;
+            request(&node, OP_SYNC, NS_NODE, 0, &[])?;
+            nodes.push(Some(node));
+        }
+        let addrs: Vec<Option<String>> = addrs.iter().cloned().map(Some).collect();
+        write_addrs(hints, &addrs)?;
+        Ok(NetBlockStores {
+            cluster: Cluster {
+                addrs,
+                nodes,
+                dirty: HashSet::new(),
+                hints: hints.to_owned(),
+            },
+            domains,
+            uuid,
+            policy: Box::new(Spread),
+            quorum: DEFAULT_QUORUM,
+            hedge: DEFAULT_HEDGE,
+            repairer: Mutex::new(None),
+            log: None,
+        })
+    }
+
+    // Connects to every daemon that is up and hands it what it is owed. A
+    // node none of them is keeps the address it was last found at, kept
+    // with the hints, for repairs to reach it once it is back. Fails with
+    // NoDataFound if none of them holds a node.
+    pub fn open(addrs: &[String], hints: &Path) -> Result<NetBlockStores, VolumeError> {
+        let mut ordered: Vec<Option<String>> = vec![None; addrs.len()];
+        let mut nodes: Vec<Option<TcpStream>> = addrs.iter().map(|_| None).collect();
+        let mut domains = vec![FailureDomain::default(); addrs.len()];
+        let mut uuid = None;
+        let mut missing = 0;
+        for addr in addrs.iter() {
+            let node = match connect(addr) {
+                Ok(node) => node,
+                Err(_) => {
+                    missing += 1;
+                    continue;
+                }
+            };
+            let record = match request(&node, OP_GET, NS_NODE, 0, &[]) {
+                Ok(record) => record,
+                Err(VolumeError::NoDataFound) => {
+                    missing += 1;
+                    continue;
+                }
+                Err(err) => return Err(err),
+            };
+            let record = unseal(&NODE_MAGIC, &record[..])
+                .filter(|r| r.len() >= 20)
+                .ok_or(VolumeError::CorruptSuperBlock)?;
+            let n = u16::from_le_bytes([record[16], record[17]]) as usize;
+            let count = u16::from_le_bytes([record[18], record[19]]) as usize;
+            if count != addrs.len() || n >= addrs.len() || nodes[n].is_some() {
+                return Err(VolumeError::StoreMismatch(addr.clone()));
+            }
+            let id = u128_at(record, 0);
+            if *uuid.get_or_insert(id) != id {
+                return Err(VolumeError::VolumeMismatch(uuid.unwrap(), id));
+            }
+            domains[n] = FailureDomain::parse(&String::from_utf8_lossy(&record[20..]));
+            ordered[n] = Some(addr.clone());
+            nodes[n] = Some(node);
+        }
+        let uuid = uuid.ok_or(VolumeError::NoDataFound)?;
+        if missing > PARITY {
+            return Err(VolumeError::TooManyMissing(missing));
+        }
+        let known = read_addrs(hints)?;
+        let addrs: Vec<Option<String>> = ordered
+            .into_iter()
+            .enumerate()
+            .map(|(n, a)| a.or_else(|| known.as_ref().and_then(|k| k.get(n).cloned().flatten())))
+            .collect();
+        if known.as_ref() != Some(&addrs) {
+            write_addrs(hints, &addrs)?;
+        }
+        let mut cluster = Cluster {
+            addrs,
+            nodes,
+            dirty: HashSet::new(),
+            hints: hints.to_owned(),
+        };
+        // A node that fails to take what it is owed is as good as down.
+        for n in cluster.available().collect::<Vec<usize>>() {
+            if cluster.deliver(n).is_err() {
+                cluster.nodes[n] = None;
+            }
+        }
+        Ok(NetBlockStores {
+            cluster,
+            domains,
+            uuid,
+            policy: Box::new(Spread),
+            quorum: DEFAULT_QUORUM,
+            hedge: DEFAULT_HEDGE,
+            repairer: Mutex::new(None),
+            log: None,
+        })
+    }
+
+    // How many blocks of a chunk have to be on their nodes for a write to
+    // succeed: all the data blocks and at least one more.
+    pub fn set_quorum(&mut self, quorum: usize) -> Result<(), VolumeError> {
+        if quorum <= BLOCKS || quorum > BLOCKS + PARITY {
+            return Err(VolumeError::BadQuorum(quorum));
+        }
+        self.quorum = quorum;
+        Ok(())
+    }
+
+    // Where background repairs that fail are reported.
+    pub fn set_log(&mut self, log: ErrorLog) {
+        self.log = Some(log);
+    }
+
+    // The addresses of the nodes, in the order the volume numbers them. A
+    // node that has never been found has none.
+    pub fn addrs(&self) -> &[Option<String>] {
+        &self.cluster.addrs[..]
+    }
+
+    // The nodes that couldn't be reached.
+    pub fn missing(&self) -> Vec<usize> {
+        (0..self.cluster.nodes.len())
+            .filter(|n| self.cluster.nodes[*n].is_none())
+            .collect()
+    }
+
+    // The node of each block of chunk `id`, as its label has it.
+    pub fn placement(&self, id: UUID) -> Result<Vec<usize>, VolumeError> {
+        let (label, _) = self.cluster.chunk(id)?;
+        Ok(label.stores.iter().map(|s| *s as usize).collect())
+    }
+
+    // What the policy is told about the nodes: only those that are up, or
+    // every one of them when new blocks can't go elsewhere.
+    fn infos(&self, all: bool) -> Vec<StoreInfo> {
+        (0..self.cluster.nodes.len())
+            .map(|n| StoreInfo {
+                domain: self.domains[n].clone(),
+                available: all || self.cluster.nodes[n].is_some(),
+            })
+            .collect()
+    }
+
+    // Where the blocks of a new chunk go: on nodes that are up, one each,
+    // as long as there are enough of them. Otherwise the chunk is placed
+    // as if every node were up, and then each block that landed on a node
+    // that is down is moved to one that is up, if the policy finds one;
+    // the rest are handed to their nodes once they are back. Fails with
+    // NoPlacement if too few nodes are up to make the quorum.
+    fn place(&self, chunk: &Chunk) -> Result<Vec<usize>, VolumeError> {
+        let shards = chunk.blocks.len();
+        let live = self.cluster.nodes.iter().filter(|n| n.is_some()).count();
+        if live < self.quorum.min(shards) {
+            return Err(VolumeError::NoPlacement);
+        }
+        let up = self.infos(false);
+        match self.policy.place(chunk.id, &up, shards, chunk.parity_n) {
+            Err(VolumeError::NoPlacement) => {}
+            placement => return placement,
+        }
+        let mut placement =
+            self.policy
+                .place(chunk.id, &self.infos(true), shards, chunk.parity_n)?;
+        for n in 0..shards {
+            if up[placement[n]].available {
+                continue;
+            }
+            let mut current: Vec<Option<usize>> = placement.iter().cloned().map(Some).collect();
+            current[n] = None;
+            if let Ok(moved) = self.policy.replace(chunk.id, &up, &current, chunk.parity_n) {
+                placement = moved;
+            }
+        }
+        Ok(placement)
+    }
+
+    // Has chunk `id` repaired in the background.
+    fn repair(&self, id: UUID) {
+        let mut repairer = self.repairer.lock().unwrap();
+        if repairer.is_none() {
+            let (jobs, queue) = channel();
+            let addrs = self.cluster.addrs.clone();
+            let hints = self.cluster.hints.clone();
+            let log = self.log.clone();
+            let thread = std::thread::Builder::new()
+                .stack_size(REPAIR_STACK_BYTES)
+                .spawn(move || repairs(addrs, hints, queue, log));
+            *repairer = thread.ok().map(|thread| Repairer { jobs, thread });
+        }
+        if let Some(repairer) = repairer.as_ref() {
+            let _ = repairer.jobs.send(id);
+        }
+    }
+}
+
+// Repairs already asked for are finished before the volume goes.
+impl Drop for NetBlockStores {
+    fn drop(&mut self) {
+        if let Some(repairer) = self.repairer.lock().unwrap().take() {
+            drop(repairer.jobs);
+            let _ = repairer.thread.join();
+        }
+    }
+}
+
+impl BlockStore for NetBlockStores {
+    fn uuid(&self) -> UUID {
+        self.uuid
+    }
+
+    // The blocks go first, so that a node only has a record of a chunk
+    // once it has its block. Blocks placed on nodes that are down wait for
+    // them. Only blocks their nodes have synced count toward the quorum.
+    fn save_chunk(
+        &mut self,
+        mut label: ChunkLabel,
+        chunk: Chunk,
+        blocks: Vec<Block>,
+    ) -> Result<(), VolumeError> {
+        let shards = chunk.blocks.len();
+        let placement = self.place(&chunk)?;
+        for (n, node) in placement.iter().enumerate() {
+            label.stores[n] = *node as u16;
+        }
+        let mut stored = vec![false; shards];
+        for (n, id) in chunk.blocks.iter().enumerate() {
+            if let Some(block) = blocks.iter().find(|b| b.id == *id) {
+                let bytes = bincode::serialize(block).unwrap();
+                stored[n] = self.cluster.send(placement[n], NS_BLOCK, *id, &bytes[..])?;
+                let record = Cluster::record(label.into(), &chunk);
+                let recorded = self
+                    .cluster
+                    .send(placement[n], NS_CHUNK, chunk.id, &record[..])?;
+                stored[n] &= recorded;
+            }
+        }
+        let mut nodes = placement.clone();
+        nodes.sort_unstable();
+        nodes.dedup();
+        let synced = self.cluster.sync_nodes(&nodes)?;
+        let stored = (0..shards)
+            .filter(|n| stored[*n] && synced.contains(&placement[*n]))
+            .count();
+        let quorum = self.quorum.min(shards);
+        match stored >= quorum {
+            true => Ok(()),
+            false => Err(VolumeError::NoQuorum(stored, quorum)),
+        }
+    }
+
+    fn save_chunk_header(&mut self, chunk: Chunk) -> Result<(), VolumeError> {
+        let (label, _) = self.cluster.load_record(chunk.id)?;
+        let mut placement = self.placement(chunk.id)?;
+        placement.sort_unstable();
+        placement.dedup();
+        for n in placement {
+            self.cluster.put_record(n, label.clone(), &chunk)?;
+        }
+        Ok(())
+    }
+
+    fn load_chunk(&self, id: UUID) -> Result<Chunk, VolumeError> {
+        Ok(self.cluster.load_record(id)?.1)
+    }
+
+    fn load_label(&self, id: UUID) -> Result<Option<ChunkLabel>, VolumeError> {
+        Ok(ChunkLabel::parse(&self.cluster.load_record(id)?.0[..]))
+    }
+
+    // Writes the label to every node the chunk is on, keeping the placement
+    // it already has.
+    fn save_label(&mut self, mut label: ChunkLabel) -> Result<(), VolumeError> {
+        let (current, chunk) = self.cluster.chunk(label.chunk)?;
+        label.stores = current.stores;
+        let mut placement: Vec<usize> = current.stores.iter().map(|s| *s as usize).collect();
+        placement.sort_unstable();
+        placement.dedup();
+        for n in placement {
+            self.cluster.put_record(n, label.into(), &chunk)?;
+        }
+        Ok(())
+    }
+
+    // A block its node has lost or holds stale reads as missing, and the
+    // chunk is repaired in the background.
+    fn load_block(&self, chunk: UUID, n: usize) -> Result<Block, VolumeError> {
+        let (label, header) = self.cluster.chunk(chunk)?;
+        let id = *header.blocks.get(n).ok_or(VolumeError::NoDataFound)?;
+        match self.cluster.block(label.stores[n] as usize, id) {
+            Ok(block) if sound(&label, n, &block, id) => Ok(block),
+            Ok(_) | Err(VolumeError::NoDataFound) => {
+                self.repair(chunk);
+                Err(VolumeError::NoDataFound)
+            }
+            Err(err) => Err(err),
+        }
+    }
+
+    // Asks the nodes for the chunk's blocks all at once, each over a
+    // connection of its own that is shut down once enough blocks are in.
+    fn load_blocks(&self, chunk: UUID) -> Result<Vec<Option<Block>>, VolumeError> {
+        let (label, header) = self.cluster.chunk(chunk)?;
+        let addrs: Vec<Option<String>> = (0..header.blocks.len())
+            .map(|n| label.stores[n] as usize)
+            .map(|node| {
+                self.cluster.nodes[node]
+                    .as_ref()
+                    .and(self.cluster.addrs[node].clone())
+            })
+            .collect();
+        let ids = header.blocks;
+        let shards = hedge::fetch(ids.len(), header.chunk_n, self.hedge, move |n, cancel| {
+            let addr = addrs[n]
+                .as_ref()
+                .ok_or(VolumeError::StoreUnavailable(label.stores[n] as usize))?;
+            let node = connect_unless(addr, cancel)?;
+            let stream = node.try_clone().map_err(VolumeError::IoError)?;
+            cancel.on_cancel(Box::new(move || {
+                let _ = stream.shutdown(Shutdown::Both);
+            }));
+            let bytes = request(&node, OP_GET, NS_BLOCK, ids[n], &[])?;
+            let block: Block =
+                bincode::deserialize(&bytes[..]).map_err(|_| VolumeError::NoDataFound)?;
+            match sound(&label, n, &block, ids[n]) {
+                true => Ok(block),
+                false => Err(VolumeError::NoDataFound),
+            }
+        });
+        if shards
+            .iter()
+            .any(|s| matches!(s, Some(Err(VolumeError::NoDataFound))))
+        {
+            self.repair(chunk);
+        }
+        Ok(shards
+            .into_iter()
+            .map(|s| s.and_then(Result::ok).map(|b| *b))
+            .collect())
+    }
+
+    // Overwrites block `n` of `chunk`. A node that lost its part of the
+    // chunk gets the chunk's record back along with the block.
+    fn save_block(&mut self, chunk: UUID, n: usize, block: Block) -> Result<(), VolumeError> {
+        let (label, header) = self.cluster.load_record(chunk)?;
+        if header.blocks.get(n) != Some(&block.id) {
+            return Err(VolumeError::BlockMismatch(chunk, n));
+        }
+        let node = *self
+            .placement(chunk)?
+            .get(n)
+            .ok_or(VolumeError::NoDataFound)?;
+        let bytes = bincode::serialize(&block).unwrap();
+        if !self.cluster.put(node, NS_BLOCK, block.id, &bytes[..])? {
+            return self.cluster.put_record(node, label, &header).map(|_| ());
+        }
+        match self.cluster.call(node, OP_STAT, NS_CHUNK, chunk, &[]) {
+            Ok(_) => Ok(()),
+            Err(VolumeError::NoDataFound) => {
+                self.cluster.put_record(node, label, &header).map(|_| ())
+            }
+            Err(err) => Err(err),
+        }
+    }
+
+    fn contains(&self, id: UUID) -> bool {
+        self.cluster
+            .available()
+            .any(|n| self.cluster.call(n, OP_STAT, NS_CHUNK, id, &[]).is_ok())
+    }
+
+    fn ids(&self) -> Vec<UUID> {
+        let mut ids: Vec<UUID> = self
+            .cluster
+            .available()
+            .filter_map(|n| self.cluster.call(n, OP_LIST, NS_CHUNK, 0, &[]).ok())
+            .flat_map(|list| {
+                (0..list.len() / 16)
+                    .map(|at| u128_at(&list[..], at * 16))
+                    .collect::<Vec<UUID>>()
+            })
+            .collect();
+        ids.sort_unstable();
+        ids.dedup();
+        ids
+    }
+
+    // The records go first, so that a chunk is gone once they are. Nodes
+    // that are down are told when they are back.
+    fn free_chunk(&mut self, id: UUID) -> Result<(), VolumeError> {
+        let (label, chunk) = self.cluster.chunk(id)?;
+        let mut placement: Vec<usize> = label.stores.iter().map(|s| *s as usize).collect();
+        placement.sort_unstable();
+        placement.dedup();
+        for n in placement {
+            self.cluster.delete(n, NS_CHUNK, id)?;
+        }
+        for (n, block) in chunk.blocks.iter().enumerate() {
+            self.cluster
+                .delete(label.stores[n] as usize, NS_BLOCK, *block)?;
+        }
+        Ok(())
+    }
+
+    fn sync_metadata(&mut self) -> Result<(), VolumeError> {
+        self.cluster.sync()
+    }
+
+    fn sync_data(&mut self) -> Result<(), VolumeError> {
+        self.cluster.sync()
+    }
+
+    fn set_policy(&mut self, policy: Box<dyn Placement>) {
+        self.policy = policy;
+    }
+
+    fn set_hedge(&mut self, delay: Duration) {
+        self.hedge = delay;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        read_payload, BlockServer, NetBlockStores, DEFAULT_QUORUM, NS_BLOCK, NS_CHUNK, OP_DELETE,
+        OP_LIST, OP_PUT, OP_STAT, OP_SYNC, STATUS_ERROR, STATUS_NOT_FOUND, STATUS_OK,
+    };
+    use crate::backend::BlockStore;
+    use crate::block::Block;
+    use crate::chunk::Chunk;
+    use crate::directory::u128_at;
+    use crate::error::VolumeError;
+    use crate::label::ChunkLabel;
+    use crate::redundant_file::RedundantFile;
+    use crate::test_util::{with_stack, Scratch};
+    use crate::UUID;
+    use std::io::{ErrorKind, Read, Write};
+    use std::net::{Shutdown, TcpListener, TcpStream};
+    use std::path::{Path, PathBuf};
+    use std::sync::atomic::{AtomicU8, Ordering};
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    const UP: u8 = 0;
+    const DOWN: u8 = 1;
+    const NO_SYNC: u8 = 2;
+
+    // A block daemon that can be taken down, made to fail every sync, and
+    // brought back at the same address.
+    struct Node {
+        addr: String,
+        root: PathBuf,
+        mode: Arc<AtomicU8>,
+        streams: Arc<Mutex<Vec<TcpStream>>>,
+    }
+
+    impl Node {
+        fn start(root: &str) -> Node {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let addr = listener.local_addr().unwrap().to_string();
+            let mode = Arc::new(AtomicU8::new(UP));
+            let streams = Arc::new(Mutex::new(Vec::new()));
+            let server = Arc::new(Mutex::new(BlockServer::new(Path::new(root)).unwrap()));
+            let (bound, watched, open) = (addr.clone(), mode.clone(), streams.clone());
+            std::thread::spawn(move || listen(listener, &bound, &watched, &open, &server));
+            Node {
+                addr,
+                root: PathBuf::from(root),
+                mode,
+                streams,
+            }
+        }
+
+        fn set(&self, mode: u8) {
+            self.mode.store(mode, Ordering::SeqCst);
+            if mode == DOWN {
+                for stream in self.streams.lock().unwrap().drain(..) {
+                    let _ = stream.shutdown(Shutdown::Both);
+                }
+            }
+        }
+
+        fn holds(&self, ns: u8, id: UUID) -> bool {
+            let mut server = BlockServer::new(&self.root).unwrap();
+            server.handle(OP_STAT, ns, id, &[]).is_ok()
+        }
+    }
+
+    // A node that is down doesn't listen at all, so connecting is refused.
+    fn listen(
+        mut listener: TcpListener,
+        addr: &str,
+        mode: &Arc<AtomicU8>,
+        streams: &Mutex<Vec<TcpStream>>,
+        server: &Arc<Mutex<BlockServer>>,
+    ) {
+        listener.set_nonblocking(true).unwrap();
+        loop {
+            match listener.accept() {
+                Ok((stream, _)) => {
+                    stream.set_nonblocking(false).unwrap();
+                    streams.lock().unwrap().push(stream.try_clone().unwrap());
+                    let (server, mode) = (server.clone(), mode.clone());
+                    std::thread::spawn(move || serve(stream, &server, &mode));
+                }
+                Err(err) if err.kind() == ErrorKind::WouldBlock => {
+                    if mode.load(Ordering::SeqCst) == DOWN {
+                        drop(listener);
+                        while mode.load(Ordering::SeqCst) == DOWN {
+                            std::thread::sleep(Duration::from_millis(5));
+                        }
+                        listener = TcpListener::bind(addr).unwrap();
+                        listener.set_nonblocking(true).unwrap();
+                    }
+                    std::thread::sleep(Duration::from_millis(1));
+                }
+                Err(err) => panic!("{}", err),
+            }
+        }
+    }
+
+    // As serve_connection answers, except that a node told not to sync
+    // hangs up on a sync instead.
+    fn serve(mut stream: TcpStream, server: &Mutex<BlockServer>, mode: &AtomicU8) {
+        loop {
+            let mut head = [0u8; 22];
+            if stream.read_exact(&mut head).is_err() {
+                return;
+            }
+            let payload = match read_payload(&mut stream, [head[18], head[19], head[20], head[21]])
+            {
+                Ok(payload) => payload,
+                Err(_) => return,
+            };
+            if head[0] == OP_SYNC && mode.load(Ordering::SeqCst) != UP {
+                let _ = stream.shutdown(Shutdown::Both);
+                return;
+            }
+            let reply = server.lock().unwrap().handle(
+                head[0],
+                head[1],
+                u128_at(&head[..], 2),
+                &payload[..],
+            );
+            let (status, payload) = match reply {
+                Ok(payload) => (STATUS_OK, payload),
+                Err(VolumeError::NoDataFound) => (STATUS_NOT_FOUND, Vec::new()),
+                Err(err) => (STATUS_ERROR, format!("{:?}", err).into_bytes()),
+            };
+            let mut buf = vec![status];
+            buf.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+            buf.extend(payload);
+            if stream.write_all(&buf[..]).is_err() {
+                return;
+            }
+        }
+    }
+
+    fn cluster(scratch: &Scratch) -> (Vec<Node>, Vec<String>, NetBlockStores) {
+        let nodes: Vec<Node> = (0..8)
+            .map(|n| Node::start(&scratch.path(&format!("node{}", n))))
+            .collect();
+        let addrs: Vec<String> = nodes.iter().map(|n| n.addr.clone()).collect();
+        let hints = scratch.path("volume.hints");
+        let stores = NetBlockStores::init(&addrs, &[], 1, Path::new(&hints)).unwrap();
+        (nodes, addrs, stores)
+    }
+
+    fn chunk(fill: u8) -> (Chunk, Vec<Block>) {
+        let (chunks, blocks) = RedundantFile::encode_chunks(&mut &[fill; 1000][..]).unwrap();
+        (chunks[0], blocks)
+    }
+
+    fn save(
+        stores: &mut NetBlockStores,
+        chunk: &Chunk,
+        blocks: &[Block],
+    ) -> Result<(), VolumeError> {
+        let label = ChunkLabel::new(chunk, blocks);
+        stores.save_chunk(label, *chunk, blocks.to_vec())
+    }
+
+    // The node block `n` of `chunk` is on, and the block's id.
+    fn block_on(stores: &NetBlockStores, chunk: &Chunk, n: usize) -> (usize, UUID) {
+        (stores.placement(chunk.id).unwrap()[n], chunk.blocks[n])
+    }
+
+    #[test]
+    fn a_write_counts_only_blocks_whose_nodes_synced() {
+        with_stack(|| {
+            let scratch = Scratch::new();
+            let (nodes, _, mut stores) = cluster(&scratch);
+
+            // Two nodes taking their blocks and losing them still leaves
+            // the default quorum of six.
+            nodes[0].set(NO_SYNC);
+            nodes[1].set(NO_SYNC);
+            let (first, blocks) = chunk(1);
+            save(&mut stores, &first, &blocks).unwrap();
+            assert_eq!(stores.missing(), vec![0, 1]);
+
+            // A third one doesn't.
+            nodes[2].set(NO_SYNC);
+            let (second, blocks) = chunk(2);
+            assert!(matches!(
+                save(&mut stores, &second, &blocks),
+                Err(VolumeError::NoQuorum(5, DEFAULT_QUORUM))
+            ));
+
+            // Nor does a sync that a node which took a delete fails.
+            stores.sync_data().unwrap();
+            nodes[3].set(NO_SYNC);
+            stores.free_chunk(first.id).unwrap();
+            assert!(matches!(
+                stores.sync_data(),
+                Err(VolumeError::StoreUnavailable(3))
+            ));
+            stores.sync_data().unwrap();
+        });
+    }
+
+    #[test]
+    fn blocks_owed_to_a_node_that_is_down_are_handed_over_when_it_is_back() {
+        with_stack(|| {
+            let scratch = Scratch::new();
+            let (nodes, addrs, mut stores) = cluster(&scratch);
+            nodes[7].set(DOWN);
+            let (chunk, blocks) = chunk(3);
+            save(&mut stores, &chunk, &blocks).unwrap();
+            stores.sync_data().unwrap();
+
+            let n = (0..8)
+                .find(|n| block_on(&stores, &chunk, *n).0 == 7)
+                .unwrap();
+            let (_, block) = block_on(&stores, &chunk, n);
+            assert!(!nodes[7].holds(NS_BLOCK, block));
+            drop(stores);
+
+            nodes[7].set(UP);
+            let hints = scratch.path("volume.hints");
+            let stores = NetBlockStores::open(&addrs, Path::new(&hints)).unwrap();
+            assert!(stores.missing().is_empty());
+            assert!(nodes[7].holds(NS_BLOCK, block));
+            assert!(nodes[7].holds(NS_CHUNK, chunk.id));
+            assert_eq!(stores.load_block(chunk.id, n).unwrap().id, block);
+            let mut owed = BlockServer::new(&Path::new(&hints).join("7")).unwrap();
+            assert!(owed.handle(OP_LIST, NS_BLOCK, 0, &[]).unwrap().is_empty());
+            assert!(owed.handle(OP_LIST, NS_CHUNK, 0, &[]).unwrap().is_empty());
+        });
+    }
+
+    #[test]
+    fn a_block_read_lost_or_stale_is_rebuilt() {
+        with_stack(|| {
+            let scratch = Scratch::new();
+            let (nodes, _, mut stores) = cluster(&scratch);
+            let (chunk, blocks) = chunk(4);
+            save(&mut stores, &chunk, &blocks).unwrap();
+
+            let (lost, lost_id) = block_on(&stores, &chunk, 1);
+            let mut server = BlockServer::new(&nodes[lost].root).unwrap();
+            server.handle(OP_DELETE, NS_BLOCK, lost_id, &[]).unwrap();
+            let (stale, stale_id) = block_on(&stores, &chunk, 6);
+            let other = bincode::serialize(&blocks[0]).unwrap();
+            let mut server = BlockServer::new(&nodes[stale].root).unwrap();
+            server
+                .handle(OP_PUT, NS_BLOCK, stale_id, &other[..])
+                .unwrap();
+
+            assert!(stores.load_block(chunk.id, 1).is_err());
+            assert!(stores.load_block(chunk.id, 6).is_err());
+            // Repairs asked for are done by the time the volume is gone.
+            drop(stores);
+
+            let hints = scratch.path("volume.hints");
+            let addrs: Vec<String> = nodes.iter().map(|n| n.addr.clone()).collect();
+            let stores = NetBlockStores::open(&addrs, Path::new(&hints)).unwrap();
+            assert_eq!(stores.load_block(chunk.id, 1).unwrap().id, lost_id);
+            assert_eq!(stores.load_block(chunk.id, 6).unwrap().id, stale_id);
+        });
+    }
+
+    #[test]
+    fn nodes_that_are_down_keep_the_addresses_they_were_found_at() {
+        with_stack(|| {
+            let scratch = Scratch::new();
+            let (nodes, addrs, stores) = cluster(&scratch);
+            drop(stores);
+
+            nodes[2].set(DOWN);
+            nodes[5].set(DOWN);
+            let reversed: Vec<String> = addrs.iter().rev().cloned().collect();
+            let hints = scratch.path("volume.hints");
+            let stores = NetBlockStores::open(&reversed, Path::new(&hints)).unwrap();
+            assert_eq!(stores.missing(), vec![2, 5]);
+            let expected: Vec<Option<String>> = addrs.iter().cloned().map(Some).collect();
+            assert_eq!(stores.addrs(), &expected[..]);
+        });
+    }
+}
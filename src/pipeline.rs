@@ -0,0 +1,335 @@
+use crate::block::Block;
+use crate::chunk::Chunk;
+use crate::constants::{BLOCKS, READ_STEP};
+use crate::error::{RedundantFileError, VolumeError};
+use crate::volume::Volume;
+use crate::UUID;
+use std::collections::BTreeMap;
+use std::sync::mpsc::{channel, sync_channel, Receiver, Sender, SyncSender};
+use std::sync::{Arc, Mutex};
+
+// Workers build and rebuild whole chunks on their stacks.
+const WORKER_STACK_BYTES: usize = 16 << 20;
+
+const MAX_WORKERS: usize = 8;
+
+// How chunks are encoded and decoded: on `workers` threads at once, with at
+// most `depth` chunks read and not yet stored, or fetched and not yet
+// written, at any time. Either way chunks come out in the order they went
+// in.
+#[derive(Debug, Clone, Copy)]
+pub struct Pipeline {
+    pub workers: usize,
+    pub depth: usize,
+}
+
+impl Default for Pipeline {
+    fn default() -> Self {
+        let workers = std::thread::available_parallelism()
+            .map(|n| n.get())
+            .unwrap_or(1)
+            .min(MAX_WORKERS);
+        Pipeline::new(workers, 2 * workers)
+    }
+}
+
+type Encoded = Result<(Box<Chunk>, Box<Vec<Block>>), RedundantFileError>;
+
+// What the reader tells the stage storing the chunks.
+enum Read {
+    Chunk(u32, Encoded),
+    End(u32),
+    Failed(std::io::Error),
+}
+
+impl Pipeline {
+    pub fn new(workers: usize, depth: usize) -> Pipeline {
+        Pipeline {
+            workers: workers.max(1),
+            depth: depth.max(1),
+        }
+    }
+
+    // Cuts what `reader` holds into chunks, encodes them on the workers
+    // while the next ones are read, and hands them to `store` in order, as
+    // many at a time as are ready. Stops at the first error, from any
+    // stage. Returns how many chunks there were.
+    pub fn encode<R, S>(&self, reader: &mut R, mut store: S) -> Result<usize, VolumeError>
+    where
+        R: std::io::Read + Send,
+        S: FnMut(Vec<(Chunk, Vec<Block>)>) -> Result<(), VolumeError>,
+    {
+        std::thread::scope(|s| {
+            let (tokens, permits) = window(self.depth);
+            let (jobs, queue) = channel::<(u32, Box<[u8; READ_STEP]>, usize)>();
+            let (built, results) = channel::<Read>();
+            let queue = Arc::new(Mutex::new(queue));
+            for _ in 0..self.workers {
+                let queue = queue.clone();
+                let built = built.clone();
+                spawn(s, move || loop {
+                    let job = queue.lock().unwrap().recv();
+                    let (position, buf, n) = match job {
+                        Ok(job) => job,
+                        Err(_) => return,
+                    };
+                    let chunk = Chunk::build(&buf, n, position, BLOCKS);
+                    if built.send(Read::Chunk(position, chunk)).is_err() {
+                        return;
+                    }
+                })?;
+            }
+            let done = built.clone();
+            spawn(s, move || read_chunks(reader, permits, jobs, done))?;
+            drop(built);
+            collect(results, tokens, &mut store)
+        })
+    }
+
+    // Writes the data of `chunks` to `writer` in order. Their blocks are
+    // fetched here, through `data_manager`, the next ones while the workers
+    // rebuild and check those before them and the writer writes what they
+    // give back.
+    pub fn decode<T, W>(
+        &self,
+        chunks: &[UUID],
+        data_manager: &T,
+        writer: &mut W,
+    ) -> Result<(), VolumeError>
+    where
+        T: Volume,
+        W: std::io::Write + Send,
+    {
+        std::thread::scope(|s| {
+            let (tokens, permits) = window(self.depth);
+            let (jobs, queue) = channel::<(usize, Box<Chunk>, Vec<Box<Block>>)>();
+            let (decoded, results) = channel::<(usize, Result<Vec<u8>, VolumeError>)>();
+            let queue = Arc::new(Mutex::new(queue));
+            let mut workers = Vec::new();
+            for _ in 0..self.workers {
+                let queue = queue.clone();
+                let decoded = decoded.clone();
+                workers.push(spawn(s, move || loop {
+                    let job = queue.lock().unwrap().recv();
+                    let (n, chunk, blocks) = match job {
+                        Ok(job) => job,
+                        Err(_) => return,
+                    };
+                    if decoded.send((n, chunk.decode(blocks))).is_err() {
+                        return;
+                    }
+                })?);
+            }
+            let total = chunks.len();
+            let written = spawn(s, move || write_chunks(writer, total, results, tokens))?;
+
+            // A failed fetch goes to the writer in its place, to be reported
+            // in order. Workers that are gone leave the writer short of
+            // chunks, which it reports.
+            for (n, id) in chunks.iter().enumerate() {
+                if permits.recv().is_err() {
+                    break;
+                }
+                let job = data_manager
+                    .get_chunk(*id)
+                    .map(|chunk| (chunk.load_blocks(data_manager), chunk));
+                match job {
+                    Ok((blocks, chunk)) => {
+                        if jobs.send((n, chunk, blocks)).is_err() {
+                            break;
+                        }
+                    }
+                    Err(err) => {
+                        let _ = decoded.send((n, Err(err)));
+                        break;
+                    }
+                }
+            }
+            drop(jobs);
+            drop(decoded);
+            let written = written.join().map_err(|_| VolumeError::WorkerFailed);
+            // Every worker is joined, or a panic in one would take the
+            // caller's thread down at the end of the scope.
+            let mut failed = false;
+            for worker in workers {
+                failed |= worker.join().is_err();
+            }
+            match failed {
+                true => Err(VolumeError::WorkerFailed),
+                false => written?,
+            }
+        })
+    }
+}
+
+// A stage takes a permit for every chunk it starts on, and the last stage
+// gives one back for every chunk it is done with.
+fn window(depth: usize) -> (SyncSender<()>, Receiver<()>) {
+    let (tokens, permits) = sync_channel(depth);
+    for _ in 0..depth {
+        tokens.send(()).unwrap();
+    }
+    (tokens, permits)
+}
+
+fn spawn<'scope, 'env, F, T>(
+    s: &'scope std::thread::Scope<'scope, 'env>,
+    f: F,
+) -> Result<std::thread::ScopedJoinHandle<'scope, T>, VolumeError>
+where
+    F: FnOnce() -> T + Send + 'scope,
+    T: Send + 'scope,
+{
+    std::thread::Builder::new()
+        .stack_size(WORKER_STACK_BYTES)
+        .spawn_scoped(s, f)
+        .map_err(VolumeError::IoError)
+}
+
+// Reads a chunk's worth at a time for as long as there are permits, and
+// tells `done` how many chunks there were. An empty reader still makes one,
+// empty, chunk.
+fn read_chunks<R>(
+    reader: &mut R,
+    permits: Receiver<()>,
+    jobs: Sender<(u32, Box<[u8; READ_STEP]>, usize)>,
+    done: Sender<Read>,
+) where
+    R: std::io::Read,
+{
+    let mut position = 0;
+    loop {
+        if permits.recv().is_err() {
+            return;
+        }
+        let mut buf = Box::new([0; READ_STEP]);
+        let n = match read_step(reader, &mut buf[..]) {
+            Ok(n) => n,
+            Err(err) => {
+                let _ = done.send(Read::Failed(err));
+                return;
+            }
+        };
+        if n == 0 && position > 0 {
+            let _ = done.send(Read::End(position));
+            return;
+        }
+        if jobs.send((position, buf, n)).is_err() {
+            return;
+        }
+        position += 1;
+        if n < READ_STEP {
+            let _ = done.send(Read::End(position));
+            return;
+        }
+    }
+}
+
+// Hands the encoded chunks to `store` in order, and a permit back to the
+// reader for every chunk stored.
+fn collect<S>(
+    results: Receiver<Read>,
+    tokens: SyncSender<()>,
+    mut store: S,
+) -> Result<usize, VolumeError>
+where
+    S: FnMut(Vec<(Chunk, Vec<Block>)>) -> Result<(), VolumeError>,
+{
+    let mut pending = BTreeMap::new();
+    let mut next = 0;
+    let mut total = None;
+    while total != Some(next) {
+        match results.recv() {
+            Ok(Read::Chunk(position, chunk)) => {
+                let (chunk, blocks) = chunk.map_err(VolumeError::FileError)?;
+                pending.insert(position, (*chunk, *blocks));
+            }
+            Ok(Read::End(count)) => total = Some(count),
+            Ok(Read::Failed(err)) => return Err(VolumeError::IoError(err)),
+            Err(_) => return Err(VolumeError::WorkerFailed),
+        }
+        let mut ready = Vec::new();
+        while let Some(chunk) = pending.remove(&next) {
+            ready.push(chunk);
+            next += 1;
+        }
+        if ready.is_empty() {
+            continue;
+        }
+        let count = ready.len();
+        store(ready)?;
+        for _ in 0..count {
+            let _ = tokens.send(());
+        }
+    }
+    Ok(next as usize)
+}
+
+// Writes the rebuilt chunks in order, and gives a permit back to the stage
+// fetching them for every chunk written.
+fn write_chunks<W>(
+    writer: &mut W,
+    total: usize,
+    results: Receiver<(usize, Result<Vec<u8>, VolumeError>)>,
+    tokens: SyncSender<()>,
+) -> Result<(), VolumeError>
+where
+    W: std::io::Write,
+{
+    let mut pending = BTreeMap::new();
+    let mut next = 0;
+    while next < total {
+        let (n, data) = results.recv().map_err(|_| VolumeError::WorkerFailed)?;
+        pending.insert(n, data);
+        while let Some(data) = pending.remove(&next) {
+            writer.write_all(&data?[..]).map_err(VolumeError::IoError)?;
+            next += 1;
+            let _ = tokens.send(());
+        }
+    }
+    Ok(())
+}
+
+// Unlike a single `read`, keeps reading until the buffer is full or the
+// reader is exhausted, so short reads from pipes or sockets don't end a file.
+pub(crate) fn read_step<T>(reader: &mut T, buf: &mut [u8]) -> std::io::Result<usize>
+where
+    T: std::io::Read,
+{
+    let mut filled = 0;
+    while filled < buf.len() {
+        match reader.read(&mut buf[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(ref e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(filled)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Pipeline;
+    use crate::constants::READ_STEP;
+    use crate::memory::MemoryVolume;
+    use crate::test_util::with_stack;
+    use crate::volume::Volume;
+
+    #[test]
+    fn decodes_chunks_in_order_on_several_workers() {
+        with_stack(|| {
+            let data: Vec<u8> = (0..READ_STEP * 9 + 123).map(|n| (n / 1000) as u8).collect();
+            let mut volume = MemoryVolume::new();
+            let id = volume.destruct("object", &mut &data[..]).unwrap();
+            let chunks = volume.get_redundant_file(id).unwrap().chunk_ids();
+            assert_eq!(chunks.len(), 10);
+            let mut out = Vec::new();
+            Pipeline::new(4, 3)
+                .decode(&chunks, &volume, &mut out)
+                .unwrap();
+            assert_eq!(out.len(), data.len());
+            assert!(out == data);
+        });
+    }
+}
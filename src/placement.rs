@@ -0,0 +1,149 @@
+use crate::domain::FailureDomain;
+use crate::error::VolumeError;
+use crate::UUID;
+
+// What a placement policy is told about one block store.
+#[derive(Debug, Clone)]
+pub struct StoreInfo {
+    pub domain: FailureDomain,
+    pub available: bool,
+}
+
+// Decides which block stores the blocks of a new chunk go to. Whatever it
+// picks is recorded in the chunk's label, so reads never have to ask it.
+pub trait Placement: Send + Sync {
+    // A different available store for each of the `shards` blocks of chunk
+    // `id`, of which up to `tolerance` may be lost.
+    fn place(
+        &self,
+        id: UUID,
+        stores: &[StoreInfo],
+        shards: usize,
+        tolerance: usize,
+    ) -> Result<Vec<usize>, VolumeError>;
+
+    // Where the blocks of chunk `id`, now on `placement`, should be. Those
+    // that have to move are None. A policy may move others too, to meet its
+    // rules, but should leave what it can where it is. By default a chunk
+    // that has to change is placed afresh.
+    fn replace(
+        &self,
+        id: UUID,
+        stores: &[StoreInfo],
+        placement: &[Option<usize>],
+        tolerance: usize,
+    ) -> Result<Vec<usize>, VolumeError> {
+        let domains: Vec<&FailureDomain> = stores.iter().map(|s| &s.domain).collect();
+        let kept: Option<Vec<usize>> = placement.iter().cloned().collect();
+        match kept {
+            Some(kept) if fits(&domains, &kept, tolerance) => Ok(kept),
+            _ => self.place(id, stores, placement.len(), tolerance),
+        }
+    }
+}
+
+// Spreads the blocks of a chunk as evenly as it can over the widest
+// domains first, then over the ones below them. Chunks start looking at
+// different stores, so that they even out across the stores too. It fails
+// rather than putting more blocks in one domain than the chunk can lose.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Spread;
+
+impl Placement for Spread {
+    fn place(
+        &self,
+        id: UUID,
+        stores: &[StoreInfo],
+        shards: usize,
+        tolerance: usize,
+    ) -> Result<Vec<usize>, VolumeError> {
+        spread(id, stores, vec![None; shards], tolerance)
+    }
+
+    // Blocks are taken out of the domains holding too many of them, the
+    // most crowded first, and then put back wherever a new chunk's would go.
+    fn replace(
+        &self,
+        id: UUID,
+        stores: &[StoreInfo],
+        placement: &[Option<usize>],
+        tolerance: usize,
+    ) -> Result<Vec<usize>, VolumeError> {
+        let domains: Vec<&FailureDomain> = stores.iter().map(|s| &s.domain).collect();
+        let depth = domains.iter().map(|d| d.depth()).max().unwrap_or(0);
+        let mut kept = placement.to_vec();
+        loop {
+            let placed: Vec<usize> = kept.iter().flatten().cloned().collect();
+            if fits(&domains, &placed, tolerance) {
+                break;
+            }
+            let crowded = (0..kept.len())
+                .filter(|n| kept[*n].is_some_and(|s| s < stores.len() && stores[s].available))
+                .max_by_key(|n| {
+                    (1..=depth)
+                        .map(|d| shared(&domains, &placed, kept[*n].unwrap(), d))
+                        .collect::<Vec<usize>>()
+                })
+                .ok_or(VolumeError::NoPlacement)?;
+            kept[crowded] = None;
+        }
+        spread(id, stores, kept, tolerance)
+    }
+}
+
+// Fills in the blocks of `placement` that have no store yet, each in the
+// available store sharing the fewest domains with the blocks already placed.
+fn spread(
+    id: UUID,
+    stores: &[StoreInfo],
+    mut placement: Vec<Option<usize>>,
+    tolerance: usize,
+) -> Result<Vec<usize>, VolumeError> {
+    let domains: Vec<&FailureDomain> = stores.iter().map(|s| &s.domain).collect();
+    let depth = domains.iter().map(|d| d.depth()).max().unwrap_or(0);
+    let start = (id % stores.len().max(1) as u128) as usize;
+    for n in 0..placement.len() {
+        if placement[n].is_some() {
+            continue;
+        }
+        let placed: Vec<usize> = placement.iter().flatten().cloned().collect();
+        let best = (0..stores.len())
+            .map(|n| (start + n) % stores.len())
+            .filter(|n| stores[*n].available && !placed.contains(n))
+            .min_by_key(|n| {
+                (1..=depth)
+                    .map(|d| shared(&domains, &placed, *n, d))
+                    .collect::<Vec<usize>>()
+            })
+            .ok_or(VolumeError::NoPlacement)?;
+        placement[n] = Some(best);
+    }
+    let placed: Vec<usize> = placement.into_iter().flatten().collect();
+    match fits(&domains, &placed, tolerance) {
+        true => Ok(placed),
+        false => Err(VolumeError::NoPlacement),
+    }
+}
+
+// How many of the `placed` blocks are in the same domain as store `n`,
+// `depth` levels down.
+fn shared(domains: &[&FailureDomain], placed: &[usize], n: usize, depth: usize) -> usize {
+    match domains[n].prefix(depth) {
+        Some(prefix) => placed
+            .iter()
+            .filter(|p| domains[**p].prefix(depth) == Some(prefix))
+            .count(),
+        None => 0,
+    }
+}
+
+// Whether losing any one domain, or any one store, loses at most
+// `tolerance` of the blocks placed on `placement`.
+pub fn fits(domains: &[&FailureDomain], placement: &[usize], tolerance: usize) -> bool {
+    let depth = domains.iter().map(|d| d.depth()).max().unwrap_or(0);
+    placement.iter().all(|n| {
+        *n < domains.len()
+            && placement.iter().filter(|p| *p == n).count() <= tolerance
+            && (1..=depth).all(|d| shared(domains, placement, *n, d) <= tolerance)
+    })
+}
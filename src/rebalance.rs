@@ -0,0 +1,131 @@
+use crate::block::Block;
+use crate::constants::{BLOCKS, PARITY};
+use crate::domain::FailureDomain;
+use crate::error::VolumeError;
+use crate::store::BlockStores;
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Progress {
+    pub chunks: usize,
+    pub checked: usize,
+    pub moved: usize,
+    pub rebuilt: usize,
+    pub skipped: usize,
+    pub failed: usize,
+    pub bytes: u64,
+}
+
+// Holds the blocks moved down to `rate` bytes a second, if there is one.
+struct Throttle {
+    rate: Option<u64>,
+    start: Instant,
+}
+
+impl Throttle {
+    fn pace(&self, bytes: u64) {
+        if let Some(rate) = self.rate.filter(|r| *r > 0) {
+            let due = Duration::from_secs_f64(bytes as f64 / rate as f64);
+            let elapsed = self.start.elapsed();
+            if due > elapsed {
+                std::thread::sleep(due - elapsed);
+            }
+        }
+    }
+}
+
+// Moves or rebuilds blocks until every chunk in `stores` is where the
+// placement policy wants it, as after failure domains change. Blocks in
+// stores that are missing are left for fsck to put back once the store is
+// replaced, and counted as skipped; a store that is gone for good is
+// decommissioned instead. `progress` is told after every chunk. A chunk
+// that can't be moved is counted as failed and left as it was.
+pub fn rebalance(
+    stores: &[String],
+    rate: Option<u64>,
+    progress: &mut dyn FnMut(&Progress),
+) -> Result<Progress, VolumeError> {
+    let mut blocks = BlockStores::open(stores)?;
+    run(&mut blocks, &[], rate, progress)
+}
+
+// Adds a new store at `path` in `domain` and rebalances onto it.
+pub fn attach(
+    stores: &[String],
+    path: &str,
+    domain: &FailureDomain,
+    rate: Option<u64>,
+    progress: &mut dyn FnMut(&Progress),
+) -> Result<Progress, VolumeError> {
+    let mut blocks = BlockStores::open(stores)?;
+    blocks.attach(path, domain)?;
+    run(&mut blocks, &[], rate, progress)
+}
+
+// Drains the store at `path`, failing or missing as it may be, and then
+// detaches it from the volume. Blocks that can't be read from it are
+// rebuilt from the rest of their chunk. The store is only detached if every
+// chunk could be moved off it; otherwise it can be drained again.
+pub fn decommission(
+    stores: &[String],
+    path: &str,
+    rate: Option<u64>,
+    progress: &mut dyn FnMut(&Progress),
+) -> Result<Progress, VolumeError> {
+    if stores.len() <= BLOCKS + PARITY {
+        return Err(VolumeError::TooFewStores(stores.len().saturating_sub(1)));
+    }
+    let mut blocks = BlockStores::open(stores)?;
+    let n = blocks
+        .paths()
+        .iter()
+        .position(|p| p == path)
+        .ok_or_else(|| VolumeError::StoreMismatch(path.to_owned()))?;
+    let done = run(&mut blocks, &[n], rate, progress)?;
+    if done.failed > 0 {
+        return Ok(done);
+    }
+    if !blocks.missing().contains(&n) {
+        blocks.release_leftovers(n)?;
+    }
+    blocks.detach(n)?;
+    Ok(done)
+}
+
+fn run(
+    blocks: &mut BlockStores,
+    draining: &[usize],
+    rate: Option<u64>,
+    progress: &mut dyn FnMut(&Progress),
+) -> Result<Progress, VolumeError> {
+    let ids = blocks.ids();
+    let missing = blocks.missing();
+    let throttle = Throttle {
+        rate,
+        start: Instant::now(),
+    };
+    let mut done = Progress {
+        chunks: ids.len(),
+        ..Progress::default()
+    };
+    for id in ids {
+        match blocks.rebalance(id, draining) {
+            Ok((moved, rebuilt)) => {
+                done.moved += moved;
+                done.rebuilt += rebuilt;
+                done.skipped += blocks
+                    .placement(id)
+                    .unwrap_or_default()
+                    .iter()
+                    .filter(|s| missing.contains(s) && !draining.contains(s))
+                    .count();
+                done.bytes += moved as u64 * Block::size();
+                throttle.pace(done.bytes);
+            }
+            Err(_) => done.failed += 1,
+        }
+        done.checked += 1;
+        progress(&done);
+    }
+    Ok(done)
+}
@@ -0,0 +1,94 @@
+use crate::error::VolumeError;
+use crate::index::IndexEntry;
+use crate::label::ChunkLabel;
+use crate::redundant_file::RedundantFile;
+use crate::store::BlockStores;
+use crate::volume_manager::FileVolumeManager;
+use crate::UUID;
+use std::collections::HashMap;
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Recovered {
+    pub objects: usize,
+    pub chunks: usize,
+    pub unlabeled: usize,
+    pub unowned: usize,
+    pub incomplete: Vec<(UUID, String)>,
+}
+
+// Rebuilds a lost metadata file from the chunk labels in `stores`. Every
+// object whose chunks are all there gets its file record back under its
+// original id and name; when several objects share a name the last one
+// written is the current version. Versioning, delete markers and snapshots
+// only ever lived in the metadata file and are not recovered.
+pub fn recover(stores: &[String], meta_data: &str) -> Result<Recovered, VolumeError> {
+    if std::path::Path::new(meta_data).exists() {
+        return Err(VolumeError::IoError(std::io::Error::new(
+            std::io::ErrorKind::AlreadyExists,
+            meta_data.to_string(),
+        )));
+    }
+    let blocks = BlockStores::open(stores)?;
+    let mut recovered = Recovered::default();
+
+    let mut objects: HashMap<UUID, Vec<ChunkLabel>> = HashMap::new();
+    for id in blocks.ids() {
+        match blocks.load_label(id)? {
+            Some(label) if label.chunk == id => objects.entry(label.file).or_default().push(label),
+            _ => recovered.unlabeled += 1,
+        }
+    }
+    // Chunks stored ahead of their object, like the parts of an upload that
+    // never completed, have no object or no chunk count.
+    recovered.unowned = objects.remove(&0).map(|l| l.len()).unwrap_or(0);
+
+    let mut complete = Vec::new();
+    for (file, labels) in objects {
+        let count = labels[0].count as usize;
+        let mut chunks: Vec<Option<&ChunkLabel>> = vec![None; count];
+        for label in labels.iter().filter(|l| l.count as usize == count) {
+            if let Some(slot) = chunks.get_mut(label.position as usize) {
+                *slot = Some(label);
+            }
+        }
+        if count == 0 {
+            recovered.unowned += labels.len();
+            continue;
+        }
+        let chunks: Option<Vec<&ChunkLabel>> = chunks.into_iter().collect();
+        match chunks {
+            Some(chunks) => {
+                let written = chunks.iter().map(|l| l.written).max().unwrap_or(0);
+                complete.push((
+                    written,
+                    file,
+                    chunks.iter().map(|l| **l).collect::<Vec<_>>(),
+                ));
+            }
+            None => recovered
+                .incomplete
+                .push((file, String::from_utf8_lossy(labels[0].key()).into_owned())),
+        }
+    }
+    complete.sort_by_key(|c| (c.0, c.1));
+    recovered.incomplete.sort();
+
+    let mut meta = FileVolumeManager::init_metadata(meta_data, blocks.super_block().uuid())?;
+    for (n, (_, file, labels)) in complete.iter().enumerate() {
+        let name = String::from_utf8_lossy(labels[0].key()).into_owned();
+        let ids: Vec<UUID> = labels.iter().map(|l| l.chunk).collect();
+        let rf = RedundantFile::from_chunks(*file, &name, &ids).map_err(VolumeError::FileError)?;
+        let size: u64 = labels.iter().map(|l| l.chunk_size as u64).sum();
+        let mut entry = IndexEntry::new(&rf.name, rf.id, size);
+        entry.version = n as u64 + 1;
+
+        let pos = meta.allocate_file(rf.id)?;
+        meta.save_file(pos, rf)?;
+        meta.index_import(entry)?;
+        recovered.objects += 1;
+        recovered.chunks += ids.len();
+    }
+    meta.sync_metadata()?;
+    meta.sync_data()?;
+    Ok(recovered)
+}
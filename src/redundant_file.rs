@@ -1,9 +1,10 @@
 use crate::block::Block;
 use crate::chunk::Chunk;
+use crate::constants::FILENAME_SIZE;
 use crate::constants::FIRST_INDIRECTION_SIZE;
-use crate::constants::{BLOCKS, FILENAME_SIZE, READ_STEP};
 use crate::error::RedundantFileError;
 use crate::error::VolumeError;
+use crate::pipeline::Pipeline;
 use crate::serde::{Deserialize, Serialize};
 use crate::volume::Volume;
 use crate::UUID;
@@ -107,7 +108,7 @@ impl RedundantFile {
     pub fn rebuild<T, W>(id: UUID, data_manager: &T, writer: &mut W) -> Result<(), VolumeError>
     where
         T: Volume,
-        W: std::io::Write,
+        W: std::io::Write + Send,
     {
         let file: Box<RedundantFile> = data_manager.get_redundant_file(id)?;
         file.inner_rebuild(data_manager, writer)?;
@@ -117,23 +118,9 @@ impl RedundantFile {
     pub fn inner_rebuild<T, W>(&self, data_manager: &T, writer: &mut W) -> Result<(), VolumeError>
     where
         T: Volume,
-        W: std::io::Write,
+        W: std::io::Write + Send,
     {
-        for c in self.chunks_fi.chunks.iter() {
-            if *c != 0 {
-                Chunk::rebuild(*c, data_manager, writer)?;
-            }
-        }
-
-        for cs in self.chunks_si.iter() {
-            for c in cs.chunks.iter() {
-                if *c != 0 {
-                    Chunk::rebuild(*c, data_manager, writer)?;
-                }
-            }
-        }
-
-        Ok(())
+        Pipeline::default().decode(&self.chunk_ids(), data_manager, writer)
     }
 
     pub fn destruct<T>(
@@ -141,32 +128,64 @@ impl RedundantFile {
         reader: &mut T,
     ) -> Result<(Box<RedundantFile>, Box<Vec<Chunk>>, Box<Vec<Block>>), RedundantFileError>
     where
-        T: std::io::Read,
+        T: std::io::Read + Send,
+    {
+        let (chunks, blocks) = RedundantFile::encode_chunks(reader)?;
+        let ids: Vec<UUID> = chunks.iter().map(|c| c.id).collect();
+        let rf = RedundantFile::from_chunks(uuid::Uuid::new_v4().as_u128(), file, &ids)?;
+
+        Ok((Box::new(rf), Box::new(chunks), Box::new(blocks)))
+    }
+
+    pub fn encode_chunks<T>(reader: &mut T) -> Result<(Vec<Chunk>, Vec<Block>), RedundantFileError>
+    where
+        T: std::io::Read + Send,
     {
         let mut chunks = Vec::<Chunk>::new();
         let mut blocks = Vec::<Block>::new();
-        let mut position = 0;
-        loop {
-            let mut buf = Box::new([0; READ_STEP]);
-            let n = reader.read(&mut buf[..]).map_err(RedundantFileError::Io)?;
-
-            let (chunk, mut c_blocks) = Chunk::build(&buf, n, position, BLOCKS).unwrap();
-            chunks.push(*chunk);
-            blocks.append(&mut c_blocks);
-            if n < READ_STEP {
-                break;
+        let encoded = Pipeline::default().encode(reader, |batch| {
+            for (chunk, mut c_blocks) in batch {
+                chunks.push(chunk);
+                blocks.append(&mut c_blocks);
             }
-            position += 1;
+            Ok(())
+        });
+        match encoded {
+            Ok(_) => Ok((chunks, blocks)),
+            Err(VolumeError::FileError(err)) => Err(err),
+            Err(VolumeError::IoError(err)) => Err(RedundantFileError::Io(err)),
+            Err(err) => Err(RedundantFileError::Io(std::io::Error::other(format!(
+                "{:?}",
+                err
+            )))),
         }
-        let mut chunks_fi: [u128; FIRST_INDIRECTION_SIZE] = [0; FIRST_INDIRECTION_SIZE];
-        for n in 0..std::cmp::min(FIRST_INDIRECTION_SIZE, chunks.len()) {
-            chunks_fi[n] = chunks[n].id;
+    }
+
+    pub fn from_chunks(
+        id: UUID,
+        file: &str,
+        chunks: &[UUID],
+    ) -> Result<RedundantFile, RedundantFileError> {
+        if chunks.len() > FIRST_INDIRECTION_SIZE * (FIRST_INDIRECTION_SIZE + 1) {
+            return Err(RedundantFileError::FileTooLarge(chunks.len()));
         }
 
-        let mut name_u8 = Box::new([0u8; FILENAME_SIZE]);
-        for (n, x) in file.chars().enumerate() {
-            name_u8[n] = x as u8;
+        let mut chunks_fi: [u128; FIRST_INDIRECTION_SIZE] = [0; FIRST_INDIRECTION_SIZE];
+        let first = std::cmp::min(FIRST_INDIRECTION_SIZE, chunks.len());
+        chunks_fi[..first].copy_from_slice(&chunks[..first]);
+
+        // The index is keyed on these same bytes, so a name that doesn't fit
+        // can't be cut short, nor hold the zero that ends it, without clashing
+        // with another.
+        let name = file.as_bytes();
+        if name.len() > FILENAME_SIZE {
+            return Err(RedundantFileError::NameTooLong(name.len()));
+        }
+        if name.contains(&0) {
+            return Err(RedundantFileError::NulInName);
         }
+        let mut name_u8 = Box::new([0u8; FILENAME_SIZE]);
+        name_u8[..name.len()].copy_from_slice(name);
 
         let mut chunks_si: [ChunkIndirection; FIRST_INDIRECTION_SIZE] =
             [ChunkIndirection::default(); FIRST_INDIRECTION_SIZE];
@@ -176,7 +195,7 @@ impl RedundantFile {
             let mut i = 0;
             let mut k = 0;
             for j in 0..missing {
-                chunks_si[i].chunks[k] = chunks[FIRST_INDIRECTION_SIZE + j].id;
+                chunks_si[i].chunks[k] = chunks[FIRST_INDIRECTION_SIZE + j];
 
                 k += 1;
                 if k == FIRST_INDIRECTION_SIZE {
@@ -185,18 +204,25 @@ impl RedundantFile {
                 }
             }
         }
-        Ok((
-            Box::new(RedundantFile {
-                id: uuid::Uuid::new_v4().as_u128(),
-                name: name_u8,
-                chunks_fi: Box::new(ChunkIndirection { chunks: chunks_fi }),
-                chunks_si: Box::new(chunks_si),
-            }),
-            Box::new(chunks),
-            Box::new(blocks),
-        ))
+        Ok(RedundantFile {
+            id,
+            name: name_u8,
+            chunks_fi: Box::new(ChunkIndirection { chunks: chunks_fi }),
+            chunks_si: Box::new(chunks_si),
+        })
+    }
+
+    pub fn chunk_ids(&self) -> Vec<UUID> {
+        self.chunks_fi
+            .chunks
+            .iter()
+            .chain(self.chunks_si.iter().flat_map(|cs| cs.chunks.iter()))
+            .filter(|c| **c != 0)
+            .cloned()
+            .collect()
     }
 }
+
 /*
 impl Into<Vec<u8>> for RedundantFile {
     fn into(self) -> Vec<u8> {
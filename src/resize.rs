@@ -0,0 +1,35 @@
+use crate::error::VolumeError;
+use crate::volume_manager::{FileVolumeManager, METADATA_VOLUME};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Usage {
+    pub size: u64,
+    pub used: u64,
+    pub max_size: u64,
+}
+
+// Resizes the metadata or block file at `path`, whichever it is, while the
+// volume isn't open. Either size left out stays as it is; without both it
+// only reports how the file is used. A metadata file whose journal still
+// holds an interrupted write has to be opened with its block file first.
+pub fn resize(path: &str, size: Option<u64>, max_size: Option<u64>) -> Result<Usage, VolumeError> {
+    let mut file = match FileVolumeManager::open_blockdata(path) {
+        Err(VolumeError::WrongVolumeKind(METADATA_VOLUME)) => {
+            FileVolumeManager::open_metadata(path)?
+        }
+        opened => opened?,
+    };
+    if !file.rollback().is_empty() {
+        return Err(VolumeError::PendingJournal);
+    }
+    if size.is_some() || max_size.is_some() {
+        file.resize(size, max_size)?;
+        file.sync_metadata()?;
+        file.sync_data()?;
+    }
+    Ok(Usage {
+        size: file.super_block().file_size(),
+        used: file.used(),
+        max_size: file.super_block().max_size(),
+    })
+}
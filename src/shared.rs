@@ -0,0 +1,820 @@
+use crate::backend::{BlockStore, MetadataStore};
+use crate::block::Block;
+use crate::chunk::Chunk;
+use crate::constants::FIRST_INDIRECTION_SIZE;
+use crate::error::{RedundantFileError, VolumeError};
+use crate::index::{IndexEntry, ObjectList};
+use crate::label::ChunkLabel;
+use crate::pipeline::Pipeline;
+use crate::redundant_file::RedundantFile;
+use crate::volume::{self, chunk_blocks, free_file, label_chunk, roll_back, BigFileVolume, Volume};
+use crate::UUID;
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, RwLock};
+
+// A handle on a volume that any number of threads can use at once; clones
+// share the volume. Readers lock the metadata only to look an object up
+// and the block store only to fetch a chunk, so they run side by side, and
+// alongside writers. Writers share the open journal, each batch of chunks
+// put in it along with those of the other writers waiting for it, and
+// have the block store to themselves only to take room for a chunk, which
+// they then write alongside the others. A write that is done commits,
+// along with those done while it waited for the commit before, and
+// returns; the writes still under way carry on in a new journal.
+#[derive(Clone)]
+pub struct SharedVolume {
+    shared: Arc<Shared>,
+}
+
+struct Shared {
+    meta_data: RwLock<Box<dyn MetadataStore>>,
+    stores: RwLock<Box<dyn BlockStore>>,
+    pipeline: Pipeline,
+    // Writers hold it from taking room for a chunk to writing it, and a
+    // rollback to itself, so the room isn't freed in between.
+    storing: RwLock<()>,
+    group: Mutex<Group>,
+    journaled: Condvar,
+    committed: Condvar,
+    readers: Mutex<Readers>,
+}
+
+// What a write in a group changes once the group commits.
+enum Change {
+    Put(Box<RedundantFile>, u64),
+    Delete(String),
+    Free(Box<IndexEntry>),
+}
+
+// The writes sharing the open journal, each known by a ticket, with the
+// chunks they added to it, and what those done change, until committed.
+#[derive(Default)]
+struct Group {
+    open: bool,
+    // The object the journal is named after.
+    journal: UUID,
+    writing: HashMap<UUID, Vec<UUID>>,
+    // Chunks handed in to go in the journal, whether a write is putting
+    // them there, and how it went for writes another write put them in for.
+    queued: Vec<(UUID, Vec<UUID>)>,
+    extending: bool,
+    extended: HashMap<UUID, bool>,
+    changes: Vec<(UUID, Change)>,
+    committing: bool,
+    // How the commit went for writes another write committed.
+    outcome: HashMap<UUID, bool>,
+    // Writes under way whose chunks are in no journal any more, after a
+    // failed commit rolled them back or a new journal couldn't be begun.
+    lost: HashSet<UUID>,
+}
+
+// How many readers each object has, and those of them dropped from the
+// index since, to be freed after the last reader.
+#[derive(Default)]
+struct Readers {
+    reading: HashMap<UUID, usize>,
+    dropped: HashMap<UUID, IndexEntry>,
+}
+
+// Keeps an object from being freed while it is read.
+struct Pin<'a> {
+    volume: &'a SharedVolume,
+    id: UUID,
+}
+
+impl SharedVolume {
+    // Shares `volume`. The multipart uploads it has open stay in the
+    // metadata, for a volume opened later to take up again.
+    pub fn new(volume: BigFileVolume) -> SharedVolume {
+        let (meta_data, stores, pipeline) = volume.into_parts();
+        SharedVolume {
+            shared: Arc::new(Shared {
+                meta_data: RwLock::new(meta_data),
+                stores: RwLock::new(stores),
+                pipeline,
+                storing: RwLock::new(()),
+                group: Mutex::new(Group::default()),
+                journaled: Condvar::new(),
+                committed: Condvar::new(),
+                readers: Mutex::new(Readers::default()),
+            }),
+        }
+    }
+
+    pub fn lookup(&self, name: &str) -> Result<UUID, VolumeError> {
+        current(&**self.shared.meta_data.read().unwrap(), name)
+    }
+
+    // Stores what `reader` holds as the current version of `name`.
+    pub fn put<R>(&self, name: &str, reader: &mut R) -> Result<UUID, VolumeError>
+    where
+        R: std::io::Read + Send,
+    {
+        let named = RedundantFile::from_chunks(uuid::Uuid::new_v4().as_u128(), name, &[])
+            .map_err(VolumeError::FileError)?;
+        self.join(named.id)?;
+        let mut chunks = Vec::new();
+        let stored = self.store(&named, reader, &mut chunks).and_then(|size| {
+            let file = RedundantFile::from_chunks(named.id, name, &chunks)
+                .map_err(VolumeError::FileError)?;
+            let ids = file.chunk_ids();
+            for n in 0..ids.len() {
+                label_chunk(&mut **self.shared.stores.write().unwrap(), &file, &ids, n)?;
+            }
+            Ok(Change::Put(Box::new(file), size))
+        });
+        self.finish(named.id, stored, &chunks)?;
+        Ok(named.id)
+    }
+
+    // Writes the current version of `name` to `writer`.
+    pub fn get<W>(&self, name: &str, writer: &mut W) -> Result<(), VolumeError>
+    where
+        W: std::io::Write + Send,
+    {
+        let pin = self.pin(|meta_data| current(meta_data, name))?;
+        let file = self.get_redundant_file(pin.id)?;
+        self.shared
+            .pipeline
+            .decode(&file.chunk_ids(), self, writer)?;
+        writer.flush().map_err(VolumeError::IoError)
+    }
+
+    // Writes the `len` bytes of the current version of `name` from `offset`
+    // on, or as many of them as it holds.
+    pub fn read_range<W>(
+        &self,
+        name: &str,
+        offset: u64,
+        len: u64,
+        writer: &mut W,
+    ) -> Result<(), VolumeError>
+    where
+        W: std::io::Write + Send,
+    {
+        let pin = self.pin(|meta_data| current(meta_data, name))?;
+        volume::read_range(self, &self.shared.pipeline, pin.id, offset, len, writer)
+    }
+
+    // With versioning on the name keeps its history behind a delete marker;
+    // otherwise every version is removed and its chunks freed, once no one
+    // reads them any more.
+    pub fn delete(&self, name: &str) -> Result<(), VolumeError> {
+        self.lookup(name)?;
+        let ticket = uuid::Uuid::new_v4().as_u128();
+        self.join(ticket)?;
+        self.finish(ticket, Ok(Change::Delete(name.to_owned())), &[])
+    }
+
+    pub fn list(
+        &self,
+        prefix: &str,
+        delimiter: Option<&str>,
+        start_after: Option<&str>,
+        limit: usize,
+    ) -> Result<ObjectList, VolumeError> {
+        let meta_data = self.shared.meta_data.read().unwrap();
+        meta_data.list(prefix, delimiter, start_after, limit)
+    }
+
+    // Encodes what `reader` holds into chunks of `named` and stores them,
+    // each batch put in the journal first. Returns how many bytes there
+    // were; `chunks` gets the ids of all the chunks, stored or not.
+    fn store<R>(
+        &self,
+        named: &RedundantFile,
+        reader: &mut R,
+        chunks: &mut Vec<UUID>,
+    ) -> Result<u64, VolumeError>
+    where
+        R: std::io::Read + Send,
+    {
+        let mut size = 0;
+        self.shared.pipeline.encode(reader, |batch| {
+            let ids: Vec<UUID> = batch.iter().map(|(c, _)| c.id).collect();
+            chunks.extend_from_slice(&ids);
+            if chunks.len() > FIRST_INDIRECTION_SIZE * (FIRST_INDIRECTION_SIZE + 1) {
+                let err = RedundantFileError::FileTooLarge(chunks.len());
+                return Err(VolumeError::FileError(err));
+            }
+            self.journal(named.id, &ids)?;
+            for (chunk, blocks) in batch {
+                size += chunk.chunk_size as u64;
+                let label = ChunkLabel::new(&chunk, &blocks).owned(
+                    named.id,
+                    &named.name,
+                    chunk.position,
+                    0,
+                );
+                let _storing = self.shared.storing.read().unwrap();
+                let reserved = self
+                    .shared
+                    .stores
+                    .write()
+                    .unwrap()
+                    .reserve_chunk(label, chunk, blocks)?;
+                if let Some(reserved) = reserved {
+                    self.shared
+                        .stores
+                        .read()
+                        .unwrap()
+                        .write_reserved(reserved)?;
+                }
+            }
+            Ok(())
+        })?;
+        Ok(size)
+    }
+
+    // Puts the chunks `ids` of write `ticket` in the open journal, before
+    // they are stored. Writes that hand theirs in meanwhile wait for one of
+    // them to put them all in at once, locking the metadata once for all.
+    fn journal(&self, ticket: UUID, ids: &[UUID]) -> Result<(), VolumeError> {
+        let shared = &*self.shared;
+        let mut group = shared.group.lock().unwrap();
+        group.queued.push((ticket, ids.to_vec()));
+        loop {
+            if let Some(extended) = group.extended.remove(&ticket) {
+                return match extended {
+                    true => Ok(()),
+                    false => Err(VolumeError::CommitFailed),
+                };
+            }
+            if !group.extending {
+                break;
+            }
+            group = shared.journaled.wait(group).unwrap();
+        }
+        group.extending = true;
+        let queued = std::mem::take(&mut group.queued);
+        drop(group);
+
+        let ids: Vec<UUID> = queued.iter().flat_map(|(_, ids)| ids.clone()).collect();
+        let mut meta_data = shared.meta_data.write().unwrap();
+        let extended = meta_data.journal_extend(ticket, &ids);
+        let mut group = shared.group.lock().unwrap();
+        if extended.is_ok() {
+            group.journal = ticket;
+            for (t, ids) in queued.iter() {
+                if let Some(journaled) = group.writing.get_mut(t) {
+                    journaled.extend_from_slice(ids);
+                }
+            }
+        }
+        for (t, _) in queued.iter().filter(|(t, _)| *t != ticket) {
+            group.extended.insert(*t, extended.is_ok());
+        }
+        group.extending = false;
+        shared.journaled.notify_all();
+        extended
+    }
+
+    // Joins the open journal as write `ticket`, or begins one named after
+    // object `ticket`.
+    fn join(&self, ticket: UUID) -> Result<(), VolumeError> {
+        let shared = &*self.shared;
+        {
+            let mut group = shared.group.lock().unwrap();
+            if group.open {
+                group.writing.insert(ticket, Vec::new());
+                return Ok(());
+            }
+        }
+        let mut meta_data = shared.meta_data.write().unwrap();
+        let mut group = shared.group.lock().unwrap();
+        if !group.open {
+            meta_data.journal_begin(ticket, &[])?;
+            group.open = true;
+            group.journal = ticket;
+        }
+        group.writing.insert(ticket, Vec::new());
+        Ok(())
+    }
+
+    // Hands in what write `ticket` changes, and returns once that is
+    // durable: committed by this write along with the others done by then,
+    // or by the commit under way when it was handed in. A write that failed
+    // frees the `chunks` it stored and leaves straight away.
+    fn finish(
+        &self,
+        ticket: UUID,
+        change: Result<Change, VolumeError>,
+        chunks: &[UUID],
+    ) -> Result<(), VolumeError> {
+        let shared = &*self.shared;
+        let mut group = shared.group.lock().unwrap();
+        let change = match group.lost.remove(&ticket) {
+            true => change.and(Err(VolumeError::CommitFailed)),
+            false => change,
+        };
+        let err = match change {
+            Ok(change) => {
+                group.changes.push((ticket, change));
+                None
+            }
+            Err(err) => Some(err),
+        };
+        if let Some(err) = err {
+            group.writing.remove(&ticket);
+            drop(group);
+            // Chunks that can't be freed now are only lost space: the
+            // journal still names them until it commits.
+            for c in chunks {
+                let _ = shared.stores.write().unwrap().free_chunk(*c);
+            }
+            let _ = shared.stores.write().unwrap().sync_metadata();
+            // The last write to leave the journal closes it.
+            let group = shared.group.lock().unwrap();
+            if group.writing.is_empty() && group.open && !group.committing {
+                if let Ok(dropped) = self.lead(group, ticket) {
+                    let _ = self.free(dropped);
+                }
+            }
+            return Err(err);
+        }
+
+        loop {
+            if let Some(committed) = group.outcome.remove(&ticket) {
+                return match committed {
+                    true => Ok(()),
+                    false => Err(VolumeError::CommitFailed),
+                };
+            }
+            if !group.committing {
+                break;
+            }
+            group = shared.committed.wait(group).unwrap();
+        }
+        self.lead(group, ticket)
+            .and_then(|dropped| self.free(dropped))
+    }
+
+    // Commits the writes done so far, on behalf of write `ticket`, and lets
+    // the others know how it went. Returns the entries the commit dropped
+    // from the index.
+    fn lead(
+        &self,
+        mut group: MutexGuard<'_, Group>,
+        ticket: UUID,
+    ) -> Result<Vec<IndexEntry>, VolumeError> {
+        group.committing = true;
+        drop(group);
+        let (tickets, committed) = self.commit();
+        let mut group = self.shared.group.lock().unwrap();
+        group.committing = false;
+        for t in tickets.into_iter().filter(|t| *t != ticket) {
+            group.outcome.insert(t, committed.is_ok());
+        }
+        self.shared.committed.notify_all();
+        committed
+    }
+
+    // Makes what the writes done changed current and durable, all of it or
+    // none, and moves the writes left into a new journal. Returns the
+    // tickets of the writes it committed, and the entries it dropped from
+    // the index.
+    fn commit(&self) -> (Vec<UUID>, Result<Vec<IndexEntry>, VolumeError>) {
+        let shared = &*self.shared;
+        let (tickets, changes) = {
+            let mut group = shared.group.lock().unwrap();
+            let (tickets, changes): (Vec<UUID>, Vec<Change>) =
+                std::mem::take(&mut group.changes).into_iter().unzip();
+            for t in tickets.iter() {
+                group.writing.remove(t);
+            }
+            (tickets, changes)
+        };
+        // The chunks are made durable before the metadata is locked, for
+        // readers to go on looking objects up meanwhile.
+        let synced = shared.stores.write().unwrap().sync_data();
+        let mut meta_data = shared.meta_data.write().unwrap();
+        let (open, journal) = {
+            let group = shared.group.lock().unwrap();
+            (group.open, group.journal)
+        };
+        let mut dropped = Vec::new();
+
+        // The journal has to be named after an object the commit puts in
+        // the index, or a crash half way through it would take the writes
+        // for ones that never committed and free their chunks.
+        let files: Vec<UUID> = changes
+            .iter()
+            .filter_map(|c| match c {
+                Change::Put(file, _) => Some(file.id),
+                _ => None,
+            })
+            .collect();
+        let done = synced
+            .and_then(|_| match open {
+                true => Ok(()),
+                false => meta_data.journal_begin(*files.first().unwrap_or(&journal), &[]),
+            })
+            .and_then(|_| apply(&mut **meta_data, changes, &mut dropped))
+            .and_then(|_| match files.first() {
+                Some(id) if !files.contains(&journal) => meta_data.journal_extend(*id, &[]),
+                _ => Ok(()),
+            })
+            .and_then(|_| meta_data.journal_commit());
+
+        let mut group = shared.group.lock().unwrap();
+        group.open = false;
+        if let Err(err) = done {
+            let _storing = shared.storing.write().unwrap();
+            let mut stores = shared.stores.write().unwrap();
+            let rolled = meta_data
+                .reload()
+                .and_then(|_| roll_back(&mut **meta_data, &mut **stores))
+                .and_then(|_| meta_data.finish_rollback());
+            // The rollback freed the chunks of every write in the journal.
+            for (t, _) in std::mem::take(&mut group.changes) {
+                group.writing.remove(&t);
+                group.outcome.insert(t, false);
+            }
+            let lost: Vec<UUID> = group.writing.drain().map(|(t, _)| t).collect();
+            group.lost.extend(lost);
+            return (tickets, rolled.and(Err(err)));
+        }
+
+        // A crash before the new journal is begun leaves the chunks of the
+        // writes left as lost space, for fsck to find.
+        if let Some(name) = group.writing.keys().next().copied() {
+            let chunks: Vec<UUID> = group.writing.values().flatten().copied().collect();
+            match meta_data.journal_begin(name, &chunks) {
+                Ok(()) => {
+                    group.open = true;
+                    group.journal = name;
+                }
+                Err(_) => {
+                    let done: HashSet<UUID> = group.changes.iter().map(|(t, _)| *t).collect();
+                    let lost: Vec<UUID> = group
+                        .writing
+                        .keys()
+                        .filter(|t| !done.contains(t))
+                        .copied()
+                        .collect();
+                    group.lost.extend(lost);
+                }
+            }
+        }
+        (tickets, Ok(dropped))
+    }
+
+    // Frees the files behind entries dropped from the index, unless a
+    // snapshot still needs them. Those still being read are freed after
+    // their last reader.
+    fn free(&self, entries: Vec<IndexEntry>) -> Result<(), VolumeError> {
+        let mut meta_data = self.shared.meta_data.write().unwrap();
+        let mut stores = self.shared.stores.write().unwrap();
+        let mut readers = self.shared.readers.lock().unwrap();
+        for entry in entries.into_iter().filter(|e| !e.is_delete_marker()) {
+            if meta_data.referenced(entry.id) {
+                continue;
+            }
+            if readers.reading.contains_key(&entry.id) {
+                readers.dropped.insert(entry.id, entry);
+                continue;
+            }
+            free_file(&mut **meta_data, &mut **stores, entry.id)?;
+        }
+        Ok(())
+    }
+
+    // Pins the object `find` picks, while the metadata can't change.
+    fn pin<F>(&self, find: F) -> Result<Pin<'_>, VolumeError>
+    where
+        F: FnOnce(&dyn MetadataStore) -> Result<UUID, VolumeError>,
+    {
+        let meta_data = self.shared.meta_data.read().unwrap();
+        let id = find(&**meta_data)?;
+        let mut readers = self.shared.readers.lock().unwrap();
+        *readers.reading.entry(id).or_insert(0) += 1;
+        Ok(Pin { volume: self, id })
+    }
+}
+
+impl Drop for Pin<'_> {
+    fn drop(&mut self) {
+        let dropped = {
+            let mut readers = self.volume.shared.readers.lock().unwrap();
+            let count = readers.reading.get_mut(&self.id).unwrap();
+            *count -= 1;
+            if *count > 0 {
+                return;
+            }
+            readers.reading.remove(&self.id);
+            readers.dropped.remove(&self.id)
+        };
+        // The read went through either way; a file that can't be freed now
+        // is only lost space.
+        if let Some(entry) = dropped {
+            let ticket = uuid::Uuid::new_v4().as_u128();
+            if self.volume.join(ticket).is_ok() {
+                let _ = self
+                    .volume
+                    .finish(ticket, Ok(Change::Free(Box::new(entry))), &[]);
+            }
+        }
+    }
+}
+
+// The id of the current version of `name`.
+fn current(meta_data: &dyn MetadataStore, name: &str) -> Result<UUID, VolumeError> {
+    match meta_data.index_get(name) {
+        Some(entry) if !entry.is_delete_marker() => Ok(entry.id),
+        _ => Err(VolumeError::NoDataFound),
+    }
+}
+
+// Applies the changes of the writes done in the order its writes were done in,
+// adding the entries they drop from the index to `dropped`.
+fn apply(
+    meta_data: &mut dyn MetadataStore,
+    changes: Vec<Change>,
+    dropped: &mut Vec<IndexEntry>,
+) -> Result<(), VolumeError> {
+    for change in changes {
+        match change {
+            Change::Put(file, size) => {
+                let entry = IndexEntry::new(&file.name, file.id, size);
+                meta_data.save_file(*file)?;
+                dropped.extend(meta_data.index_put(entry)?);
+            }
+            Change::Delete(name) => {
+                if current(meta_data, &name).is_err() {
+                    continue;
+                }
+                if meta_data.versioning() {
+                    meta_data.index_put(IndexEntry::delete_marker(name.as_bytes()))?;
+                } else {
+                    dropped.extend(meta_data.index_remove_all(&name)?);
+                }
+            }
+            Change::Free(entry) => dropped.push(*entry),
+        }
+    }
+    Ok(())
+}
+
+impl Volume for SharedVolume {
+    fn get_redundant_file(&self, id: UUID) -> Result<Box<RedundantFile>, VolumeError> {
+        Ok(Box::new(
+            self.shared.meta_data.read().unwrap().load_file(id)?,
+        ))
+    }
+    fn get_chunk(&self, id: UUID) -> Result<Box<Chunk>, VolumeError> {
+        Ok(Box::new(volume::load_chunk(
+            &**self.shared.stores.read().unwrap(),
+            id,
+        )?))
+    }
+    fn get_block(&self, id: UUID) -> Result<Box<Block>, VolumeError> {
+        let stores = self.shared.stores.read().unwrap();
+        for chunk in stores.ids() {
+            let chunk = stores.load_chunk(chunk)?;
+            if let Some(n) = chunk.blocks.iter().position(|b| *b == id) {
+                return Ok(Box::new(stores.load_block(chunk.id, n)?));
+            }
+        }
+        Err(VolumeError::NoDataFound)
+    }
+    fn get_chunk_block(&self, chunk: &Chunk, n: usize) -> Result<Box<Block>, VolumeError> {
+        let block = self.shared.stores.read().unwrap().load_block(chunk.id, n)?;
+        if block.id != chunk.blocks[n] {
+            return Err(VolumeError::NoDataFound);
+        }
+        Ok(Box::new(block))
+    }
+    fn get_chunk_blocks(&self, chunk: &Chunk) -> Vec<Option<Box<Block>>> {
+        chunk_blocks(&**self.shared.stores.read().unwrap(), chunk)
+    }
+
+    fn destruct_from_file(&mut self, file_name: &str) -> Result<UUID, VolumeError> {
+        let mut file = std::fs::File::open(file_name).map_err(VolumeError::IoError)?;
+
+        self.put(file_name, &mut file)
+    }
+
+    fn restruct_to_file(&mut self, id: UUID, file_name: &str) -> Result<(), VolumeError> {
+        let pin = self.pin(|meta_data| meta_data.load_file(id).map(|f| f.id))?;
+        let file = self.get_redundant_file(pin.id)?;
+        let mut out = std::fs::File::create(file_name).map_err(VolumeError::IoError)?;
+        self.shared
+            .pipeline
+            .decode(&file.chunk_ids(), self, &mut out)
+    }
+
+    fn delete(&mut self, name: &str) -> Result<(), VolumeError> {
+        SharedVolume::delete(self, name)
+    }
+
+    fn list(
+        &self,
+        prefix: &str,
+        delimiter: Option<&str>,
+        start_after: Option<&str>,
+        limit: usize,
+    ) -> Result<ObjectList, VolumeError> {
+        SharedVolume::list(self, prefix, delimiter, start_after, limit)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{current, SharedVolume};
+    use crate::constants::READ_STEP;
+    use crate::error::VolumeError;
+    use crate::test_util::{with_stack, Scratch};
+    use crate::volume::{BigFileVolume, Volume};
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+    use std::thread::JoinHandle;
+
+    fn open(scratch: &Scratch) -> SharedVolume {
+        let volume =
+            BigFileVolume::try_init(&scratch.path("volume.bin"), &scratch.path("block.bin"))
+                .unwrap();
+        SharedVolume::new(volume)
+    }
+
+    fn read(volume: &SharedVolume, name: &str) -> Result<Vec<u8>, VolumeError> {
+        let mut out = Vec::new();
+        volume.get(name, &mut out)?;
+        Ok(out)
+    }
+
+    fn contents(n: usize) -> Vec<u8> {
+        (0..READ_STEP + 1000 * n)
+            .map(|b| (b * 7 + n) as u8)
+            .collect()
+    }
+
+    fn spawn<T, F>(f: F) -> JoinHandle<T>
+    where
+        T: Send + 'static,
+        F: FnOnce() -> T + Send + 'static,
+    {
+        std::thread::Builder::new()
+            .stack_size(16 * 1024 * 1024)
+            .spawn(f)
+            .unwrap()
+    }
+
+    // Hands out `good` bytes, then fails.
+    struct Failing {
+        good: usize,
+    }
+
+    impl std::io::Read for Failing {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            if self.good == 0 {
+                return Err(std::io::Error::other("gone"));
+            }
+            let n = std::cmp::min(buf.len(), self.good);
+            buf[..n].fill(7);
+            self.good -= n;
+            Ok(n)
+        }
+    }
+
+    // Readers that read `name` over and over until told to stop, checking
+    // it is `expected` every time.
+    fn readers(
+        volume: &SharedVolume,
+        name: &str,
+        expected: &[u8],
+        stop: &Arc<AtomicBool>,
+    ) -> Vec<JoinHandle<usize>> {
+        (0..2)
+            .map(|_| {
+                let (volume, name, expected, stop) = (
+                    volume.clone(),
+                    name.to_owned(),
+                    expected.to_vec(),
+                    stop.clone(),
+                );
+                spawn(move || {
+                    let mut reads = 0;
+                    while !stop.load(Ordering::SeqCst) {
+                        assert_eq!(read(&volume, &name).unwrap(), expected);
+                        reads += 1;
+                    }
+                    reads
+                })
+            })
+            .collect()
+    }
+
+    #[test]
+    fn writers_on_different_objects_each_get_their_own_result() {
+        with_stack(|| {
+            let scratch = Scratch::new();
+            let volume = open(&scratch);
+            let base = contents(0);
+            volume.put("base", &mut &base[..]).unwrap();
+            let stop = Arc::new(AtomicBool::new(false));
+            let readers = readers(&volume, "base", &base, &stop);
+
+            // Every other writer's reader fails half way through, after some
+            // of its chunks are stored.
+            let writers: Vec<_> = (1..9)
+                .map(|n| {
+                    let volume = volume.clone();
+                    spawn(move || match n % 2 {
+                        0 => volume.put(&format!("object{}", n), &mut &contents(n)[..]),
+                        _ => {
+                            let mut failing = Failing {
+                                good: READ_STEP + 10,
+                            };
+                            volume.put(&format!("object{}", n), &mut failing)
+                        }
+                    })
+                })
+                .collect();
+            let results: Vec<_> = writers.into_iter().map(|w| w.join().unwrap()).collect();
+            stop.store(true, Ordering::SeqCst);
+            for reader in readers {
+                assert!(reader.join().unwrap() > 0);
+            }
+
+            for (n, result) in (1..9).zip(results) {
+                let name = format!("object{}", n);
+                match n % 2 {
+                    0 => {
+                        assert_eq!(volume.lookup(&name).unwrap(), result.unwrap());
+                        assert_eq!(read(&volume, &name).unwrap(), contents(n));
+                    }
+                    _ => {
+                        assert!(matches!(result, Err(VolumeError::IoError(_))));
+                        assert!(matches!(
+                            volume.lookup(&name),
+                            Err(VolumeError::NoDataFound)
+                        ));
+                    }
+                }
+            }
+            assert_eq!(read(&volume, "base").unwrap(), base);
+        });
+    }
+
+    #[test]
+    fn writes_that_commit_together_each_find_their_object_current() {
+        with_stack(|| {
+            let scratch = Scratch::new();
+            let volume = open(&scratch);
+            // Writers to the same name and to names of their own, all at
+            // once; each returns once its own object is in.
+            let writers: Vec<_> = (0..8)
+                .map(|n| {
+                    let volume = volume.clone();
+                    spawn(move || {
+                        let name = match n % 2 {
+                            0 => "shared".to_owned(),
+                            _ => format!("own{}", n),
+                        };
+                        let id = volume.put(&name, &mut &contents(n)[..]).unwrap();
+                        if n % 2 == 1 {
+                            assert_eq!(volume.lookup(&name).unwrap(), id);
+                            assert_eq!(read(&volume, &name).unwrap(), contents(n));
+                        }
+                        id
+                    })
+                })
+                .collect();
+            let ids: Vec<_> = writers.into_iter().map(|w| w.join().unwrap()).collect();
+
+            let shared = volume.lookup("shared").unwrap();
+            let n = (0..8).step_by(2).find(|n| ids[*n] == shared).unwrap();
+            assert_eq!(read(&volume, "shared").unwrap(), contents(n));
+            let listed = volume.list("", None, None, 100).unwrap();
+            assert_eq!(listed.objects.len(), 5);
+        });
+    }
+
+    #[test]
+    fn a_reader_keeps_the_version_it_found_while_it_is_replaced() {
+        with_stack(|| {
+            let scratch = Scratch::new();
+            let volume = open(&scratch);
+            let old = contents(1);
+            volume.put("object", &mut &old[..]).unwrap();
+            let pin = volume
+                .pin(|meta_data| current(meta_data, "object"))
+                .unwrap();
+            let new = contents(2);
+            volume.put("object", &mut &new[..]).unwrap();
+
+            let file = volume.get_redundant_file(pin.id).unwrap();
+            let mut out = Vec::new();
+            volume
+                .shared
+                .pipeline
+                .decode(&file.chunk_ids(), &volume, &mut out)
+                .unwrap();
+            assert_eq!(out, old);
+            let id = pin.id;
+            drop(pin);
+            assert!(volume.get_redundant_file(id).is_err());
+            assert_eq!(read(&volume, "object").unwrap(), new);
+        });
+    }
+}
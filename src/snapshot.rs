@@ -0,0 +1,142 @@
+use crate::index::{ObjectIndex, INDEX_PAGE_BYTES};
+use crate::UUID;
+
+pub const SNAPSHOT_NAME_SIZE: usize = 64;
+pub const SNAPSHOT_PAGE_ENTRIES: usize = 16;
+
+// Snapshot table pages share the size of index pages so both can be handed
+// out by, and returned to, the same page allocator.
+pub const SNAPSHOT_PAGE_BYTES: usize = INDEX_PAGE_BYTES;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SnapshotSummary {
+    pub name: String,
+    pub created: u64,
+    pub objects: usize,
+}
+
+// A read-only copy of the object index. File records are never modified in
+// place, so the copied index is enough to read every object as it was.
+#[derive(Debug, Clone)]
+pub struct Snapshot {
+    pub name: String,
+    pub created: u64,
+    pub index_start: u64,
+    pub index: ObjectIndex,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct SnapshotEntry {
+    pub name: [u8; SNAPSHOT_NAME_SIZE],
+    pub created: u64,
+    pub index_start: u64,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct SnapshotPage {
+    pub entries: Vec<SnapshotEntry>,
+    pub next_snapshot_page: u64,
+}
+
+impl SnapshotEntry {
+    pub fn name(&self) -> String {
+        let len = self
+            .name
+            .iter()
+            .position(|b| *b == 0)
+            .unwrap_or(SNAPSHOT_NAME_SIZE);
+        String::from_utf8_lossy(&self.name[..len]).into_owned()
+    }
+}
+
+impl Snapshot {
+    pub fn entry(&self) -> SnapshotEntry {
+        let mut name = [0u8; SNAPSHOT_NAME_SIZE];
+        let bytes = self.name.as_bytes();
+        let len = std::cmp::min(bytes.len(), SNAPSHOT_NAME_SIZE);
+        name[..len].copy_from_slice(&bytes[..len]);
+        SnapshotEntry {
+            name,
+            created: self.created,
+            index_start: self.index_start,
+        }
+    }
+
+    pub fn summary(&self) -> SnapshotSummary {
+        SnapshotSummary {
+            name: self.name.clone(),
+            created: self.created,
+            objects: self.index.len(),
+        }
+    }
+
+    pub fn references(&self, id: UUID) -> bool {
+        self.index.iter().any(|e| e.id == id)
+    }
+}
+
+impl From<SnapshotPage> for Vec<u8> {
+    fn from(page: SnapshotPage) -> Vec<u8> {
+        let mut buf: Vec<u8> = Vec::with_capacity(SNAPSHOT_PAGE_BYTES);
+
+        buf.extend_from_slice(&(page.entries.len() as u64).to_le_bytes());
+        for e in page.entries.iter() {
+            buf.extend_from_slice(&e.name);
+            buf.extend_from_slice(&e.created.to_le_bytes());
+            buf.extend_from_slice(&e.index_start.to_le_bytes());
+        }
+        buf.resize(SNAPSHOT_PAGE_BYTES - 8, 0);
+        buf.extend_from_slice(&page.next_snapshot_page.to_le_bytes());
+
+        buf
+    }
+}
+
+impl From<[u8; SNAPSHOT_PAGE_BYTES]> for SnapshotPage {
+    fn from(bytes: [u8; SNAPSHOT_PAGE_BYTES]) -> Self {
+        let mut buf = [0u8; 8];
+        buf.copy_from_slice(&bytes[0..8]);
+        let count = std::cmp::min(u64::from_le_bytes(buf) as usize, SNAPSHOT_PAGE_ENTRIES);
+
+        let mut entries = Vec::with_capacity(count);
+        let mut i = 8;
+        for _ in 0..count {
+            let mut name = [0u8; SNAPSHOT_NAME_SIZE];
+            name.copy_from_slice(&bytes[i..i + SNAPSHOT_NAME_SIZE]);
+            i += SNAPSHOT_NAME_SIZE;
+
+            buf.copy_from_slice(&bytes[i..i + 8]);
+            let created = u64::from_le_bytes(buf);
+            buf.copy_from_slice(&bytes[i + 8..i + 16]);
+            let index_start = u64::from_le_bytes(buf);
+            i += 16;
+
+            entries.push(SnapshotEntry {
+                name,
+                created,
+                index_start,
+            });
+        }
+
+        buf.copy_from_slice(&bytes[SNAPSHOT_PAGE_BYTES - 8..SNAPSHOT_PAGE_BYTES]);
+        SnapshotPage {
+            entries,
+            next_snapshot_page: u64::from_le_bytes(buf),
+        }
+    }
+}
+
+// Splits the snapshot table into pages; an empty table still has one page.
+pub fn snapshot_pages(snapshots: &[Snapshot]) -> Vec<SnapshotPage> {
+    let mut pages: Vec<SnapshotPage> = snapshots
+        .chunks(SNAPSHOT_PAGE_ENTRIES)
+        .map(|c| SnapshotPage {
+            entries: c.iter().map(|s| s.entry()).collect(),
+            next_snapshot_page: 0,
+        })
+        .collect();
+    if pages.is_empty() {
+        pages.push(SnapshotPage::default());
+    }
+    pages
+}
@@ -0,0 +1,632 @@
+use crate::backend::Reserved;
+use crate::block::Block;
+use crate::chunk::Chunk;
+use crate::constants::{BLOCKS, PARITY};
+use crate::crc32c::crc32c;
+use crate::domain::FailureDomain;
+use crate::error::{RedundantFileError, VolumeError};
+use crate::label::ChunkLabel;
+use crate::placement::{Placement, Spread, StoreInfo};
+use crate::reed_solomon_erasure::galois_8::ReedSolomon;
+use crate::volume_manager::{FileVolumeManager, SuperBlock};
+use crate::UUID;
+use std::path::Path;
+
+// Where the blocks of a volume are kept. A single store is one block file
+// holding whole chunks. A volume spread over several stores, meant to be on
+// different disks, keeps each block of a chunk in a different store along
+// with a copy of the chunk's label and header, so that a chunk can still be
+// read with up to PARITY of its stores gone. Which stores a new chunk goes
+// to is up to the placement policy; the label records it. Every store
+// records which one it is, so their paths may be given in any order.
+pub struct BlockStores {
+    paths: Vec<String>,
+    stores: Vec<Option<FileVolumeManager>>,
+    domains: Vec<FailureDomain>,
+    policy: Box<dyn Placement>,
+}
+
+impl BlockStores {
+    // Creates the stores, each in the failure domain given for it, if any.
+    pub fn init(
+        paths: &[String],
+        domains: &[FailureDomain],
+        uuid: UUID,
+    ) -> Result<BlockStores, VolumeError> {
+        let domains: Vec<FailureDomain> = (0..paths.len())
+            .map(|n| domains.get(n).cloned().unwrap_or_default())
+            .collect();
+        if paths.len() == 1 {
+            return Ok(BlockStores {
+                paths: paths.to_vec(),
+                stores: vec![Some(FileVolumeManager::init_blockdata(&paths[0], uuid)?)],
+                domains,
+                policy: Box::new(Spread),
+            });
+        }
+        if paths.len() < BLOCKS + PARITY || paths.len() > u16::MAX as usize {
+            return Err(VolumeError::TooFewStores(paths.len()));
+        }
+        let mut stores = Vec::new();
+        for (n, path) in paths.iter().enumerate() {
+            stores.push(Some(FileVolumeManager::init_shards(
+                path,
+                uuid,
+                n,
+                paths.len(),
+                &domains[n],
+            )?));
+        }
+        Ok(BlockStores {
+            paths: paths.to_vec(),
+            stores,
+            domains,
+            policy: Box::new(Spread),
+        })
+    }
+
+    // Opens every store there is. Paths that don't exist are taken to be
+    // the stores none of the others say they are, in the order given.
+    pub fn open(paths: &[String]) -> Result<BlockStores, VolumeError> {
+        if paths.len() == 1 {
+            let store = FileVolumeManager::open_blockdata(&paths[0])?;
+            if store.super_block().stores() != 0 {
+                return Err(VolumeError::StoreMismatch(paths[0].clone()));
+            }
+            return Ok(BlockStores {
+                paths: paths.to_vec(),
+                stores: vec![Some(store)],
+                domains: vec![FailureDomain::default()],
+                policy: Box::new(Spread),
+            });
+        }
+        let mut ordered: Vec<Option<String>> = vec![None; paths.len()];
+        let mut stores: Vec<Option<FileVolumeManager>> = paths.iter().map(|_| None).collect();
+        let mut domains = vec![FailureDomain::default(); paths.len()];
+        let mut uuid = None;
+        let mut missing = Vec::new();
+        for path in paths.iter() {
+            if !Path::new(path).exists() {
+                missing.push(path.clone());
+                continue;
+            }
+            let store = FileVolumeManager::open_blockdata(path)?;
+            let sb = store.super_block();
+            let n = sb.store();
+            if sb.stores() != paths.len() || n >= paths.len() || stores[n].is_some() {
+                return Err(VolumeError::StoreMismatch(path.clone()));
+            }
+            if *uuid.get_or_insert(sb.uuid()) != sb.uuid() {
+                return Err(VolumeError::VolumeMismatch(uuid.unwrap(), sb.uuid()));
+            }
+            // A domain that can't be read only matters to new placements,
+            // and fsck reports it.
+            domains[n] = store.load_domain().ok().flatten().unwrap_or_default();
+            ordered[n] = Some(path.clone());
+            stores[n] = Some(store);
+        }
+        if missing.len() > PARITY {
+            return Err(VolumeError::TooManyMissing(missing.len()));
+        }
+        let mut missing = missing.into_iter();
+        let paths = ordered
+            .into_iter()
+            .map(|p| p.or_else(|| missing.next()).unwrap())
+            .collect();
+        Ok(BlockStores {
+            paths,
+            stores,
+            domains,
+            policy: Box::new(Spread),
+        })
+    }
+
+    pub fn exists(paths: &[String]) -> bool {
+        paths.iter().any(|p| Path::new(p).exists())
+    }
+
+    pub fn set_policy(&mut self, policy: Box<dyn Placement>) {
+        self.policy = policy;
+    }
+
+    // The paths of the stores, in the order the volume numbers them.
+    pub fn paths(&self) -> &[String] {
+        &self.paths[..]
+    }
+
+    pub fn domains(&self) -> &[FailureDomain] {
+        &self.domains[..]
+    }
+
+    // The stores that couldn't be opened.
+    pub fn missing(&self) -> Vec<usize> {
+        (0..self.stores.len())
+            .filter(|n| self.stores[*n].is_none())
+            .collect()
+    }
+
+    // Creates store `n` afresh in place of one that is missing. It holds
+    // nothing until the blocks it should have are put back.
+    pub fn replace(&mut self, n: usize, domain: &FailureDomain) -> Result<(), VolumeError> {
+        let uuid = self.super_block().uuid();
+        self.stores[n] = Some(FileVolumeManager::init_shards(
+            &self.paths[n],
+            uuid,
+            n,
+            self.stores.len(),
+            domain,
+        )?);
+        self.domains[n] = domain.clone();
+        Ok(())
+    }
+
+    pub fn super_block(&self) -> &SuperBlock {
+        self.stores.iter().flatten().next().unwrap().super_block()
+    }
+
+    pub fn store_mut(&mut self, path: &str) -> Option<&mut FileVolumeManager> {
+        let n = self.paths.iter().position(|p| p == path)?;
+        self.stores[n].as_mut()
+    }
+
+    fn sharded(&self) -> bool {
+        self.stores.len() > 1
+    }
+
+    // The stores there are that hold a slot for chunk `id`.
+    fn holders(&self, id: UUID) -> Vec<usize> {
+        (0..self.stores.len())
+            .filter(|n| {
+                self.stores[*n]
+                    .as_ref()
+                    .is_some_and(|s| s.find(id).is_some())
+            })
+            .collect()
+    }
+
+    // The store of each block of chunk `id`, as its label has it.
+    pub fn placement(&self, id: UUID) -> Option<Vec<usize>> {
+        if !self.sharded() {
+            return Some(vec![0; BLOCKS + PARITY]);
+        }
+        self.holders(id).into_iter().find_map(|n| {
+            let label = self.get(n).ok()?.load_label(id).ok()??;
+            let placement: Vec<usize> = label.stores.iter().map(|s| *s as usize).collect();
+            match label.chunk == id && placement.iter().all(|s| *s < self.stores.len()) {
+                true => Some(placement),
+                false => None,
+            }
+        })
+    }
+
+    // The store holding block `n` of chunk `id` and where that block is in
+    // the store's slot for the chunk.
+    fn locate(&self, id: UUID, n: usize) -> Result<(usize, usize), VolumeError> {
+        match self.sharded() {
+            true => {
+                let placement = self.placement(id).ok_or(VolumeError::NoDataFound)?;
+                Ok((placement[n], 0))
+            }
+            false => Ok((0, n)),
+        }
+    }
+
+    // What the policy is told about the stores. New blocks only go to the
+    // stores there are, and not to the `draining` ones.
+    fn infos(&self, draining: &[usize]) -> Vec<StoreInfo> {
+        (0..self.stores.len())
+            .map(|n| StoreInfo {
+                domain: self.domains[n].clone(),
+                available: self.stores[n].is_some() && !draining.contains(&n),
+            })
+            .collect()
+    }
+
+    fn get(&self, n: usize) -> Result<&FileVolumeManager, VolumeError> {
+        self.stores[n]
+            .as_ref()
+            .ok_or(VolumeError::StoreUnavailable(n))
+    }
+
+    fn get_mut(&mut self, n: usize) -> Result<&mut FileVolumeManager, VolumeError> {
+        self.stores[n]
+            .as_mut()
+            .ok_or(VolumeError::StoreUnavailable(n))
+    }
+
+    pub fn save_chunk(
+        &mut self,
+        label: ChunkLabel,
+        chunk: Chunk,
+        blocks: Vec<Block>,
+    ) -> Result<(), VolumeError> {
+        let reserved = self.reserve_chunk(label, chunk, blocks)?;
+        self.write_reserved(reserved)
+    }
+
+    // Places a new chunk and takes room for its blocks in each store they
+    // go to.
+    pub fn reserve_chunk(
+        &mut self,
+        mut label: ChunkLabel,
+        chunk: Chunk,
+        blocks: Vec<Block>,
+    ) -> Result<Reserved, VolumeError> {
+        if !self.sharded() {
+            let store = self.get_mut(0)?;
+            let pos = store.allocate_file(chunk.id)?;
+            store.sync_metadata()?;
+            return Ok(Reserved {
+                label,
+                chunk,
+                slots: vec![(0, pos, blocks)],
+            });
+        }
+        let placement = self.policy.place(
+            chunk.id,
+            &self.infos(&[]),
+            chunk.blocks.len(),
+            chunk.parity_n,
+        )?;
+        for (n, store) in placement.iter().enumerate() {
+            label.stores[n] = *store as u16;
+        }
+        let mut slots = Vec::new();
+        for (n, id) in chunk.blocks.iter().enumerate() {
+            let block = match blocks.iter().find(|b| b.id == *id) {
+                Some(block) => *block,
+                None => continue,
+            };
+            let store = self.get_mut(placement[n])?;
+            let pos = store.allocate_file(chunk.id)?;
+            store.sync_metadata()?;
+            slots.push((placement[n], pos, vec![block]));
+        }
+        Ok(Reserved {
+            label,
+            chunk,
+            slots,
+        })
+    }
+
+    pub fn write_reserved(&self, reserved: Reserved) -> Result<(), VolumeError> {
+        for (n, pos, blocks) in reserved.slots {
+            self.get(n)?
+                .save_chunk(pos, reserved.label, reserved.chunk, blocks)?;
+        }
+        Ok(())
+    }
+
+    // Changing a chunk needs every store it is placed on.
+    fn placed(&self, id: UUID) -> Result<Vec<usize>, VolumeError> {
+        let mut placement = self.placement(id).ok_or(VolumeError::NoDataFound)?;
+        placement.dedup();
+        for n in placement.iter() {
+            self.get(*n)?;
+        }
+        Ok(placement)
+    }
+
+    pub fn save_chunk_header(&mut self, chunk: Chunk) -> Result<(), VolumeError> {
+        for n in self.placed(chunk.id)? {
+            self.get_mut(n)?.save_chunk_header(chunk)?;
+        }
+        Ok(())
+    }
+
+    // The header as the first store holding the chunk has it.
+    pub fn load_chunk(&self, id: UUID) -> Result<Chunk, VolumeError> {
+        let mut err = VolumeError::NoDataFound;
+        for holder in self.holders(id) {
+            match self.get(holder).and_then(|s| s.load_chunk(id)) {
+                Ok(chunk) if chunk.id == id => return Ok(chunk),
+                Ok(_) => err = VolumeError::CorruptChunk(id),
+                Err(e) => err = e,
+            }
+        }
+        Err(err)
+    }
+
+    pub fn load_label(&self, id: UUID) -> Result<Option<ChunkLabel>, VolumeError> {
+        let mut found = Err(VolumeError::NoDataFound);
+        for holder in self.holders(id) {
+            match self.get(holder)?.load_label(id)? {
+                Some(label) => return Ok(Some(label)),
+                None => found = Ok(None),
+            }
+        }
+        found
+    }
+
+    // Writes the label to every store the chunk is on, keeping the
+    // placement it already has.
+    pub fn save_label(&mut self, mut label: ChunkLabel) -> Result<(), VolumeError> {
+        let placement = self.placement(label.chunk);
+        if let (true, Some(placement)) = (self.sharded(), placement) {
+            for (n, store) in placement.iter().enumerate() {
+                label.stores[n] = *store as u16;
+            }
+        }
+        for n in self
+            .placed(label.chunk)
+            .unwrap_or_else(|_| self.holders(label.chunk))
+        {
+            let store = self.get_mut(n)?;
+            if store.find(label.chunk).is_some() {
+                store.save_label(label)?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn load_block(&self, chunk: UUID, n: usize) -> Result<Block, VolumeError> {
+        let (store, at) = self.locate(chunk, n)?;
+        self.get(store)?.load_block(chunk, at)
+    }
+
+    // Overwrites block `n` of `chunk`. A store that lost its part of the
+    // chunk, as one that replaced a failed disk, gets it back along with
+    // the chunk's label and header.
+    pub fn save_block(&mut self, chunk: UUID, n: usize, block: Block) -> Result<(), VolumeError> {
+        let (store, at) = self.locate(chunk, n)?;
+        if self.get(store)?.find(chunk).is_some() {
+            return self.get_mut(store)?.save_block(chunk, at, block);
+        }
+        let header = self.load_chunk(chunk)?;
+        let label = self.load_label(chunk)?.ok_or(VolumeError::NoDataFound)?;
+        let store = self.get_mut(store)?;
+        let pos = store.allocate_file(chunk)?;
+        store.sync_metadata()?;
+        store.save_chunk(pos, label, header, vec![block])
+    }
+
+    // Moves the blocks of chunk `id` to where the policy wants them, off the
+    // `draining` stores. Blocks on stores that are missing stay where they
+    // are unless the policy moves them. Returns how many blocks were moved
+    // and how many of those had to be rebuilt from the others.
+    pub fn rebalance(
+        &mut self,
+        id: UUID,
+        draining: &[usize],
+    ) -> Result<(usize, usize), VolumeError> {
+        if !self.sharded() {
+            return Ok((0, 0));
+        }
+        let placement = self.placement(id).ok_or(VolumeError::NoDataFound)?;
+        let chunk = self.load_chunk(id)?;
+        let current: Vec<Option<usize>> = placement
+            .iter()
+            .map(|s| Some(*s).filter(|s| !draining.contains(s)))
+            .collect();
+        let mut target =
+            self.policy
+                .replace(id, &self.infos(draining), &current, chunk.parity_n)?;
+        // A block going to a store another block is leaving takes that
+        // block's place instead, so no store ever holds two.
+        for n in 0..target.len() {
+            while target[n] != placement[n] {
+                match placement.iter().position(|s| *s == target[n]) {
+                    Some(m) if target[m] != placement[m] => target.swap(n, m),
+                    _ => break,
+                }
+            }
+        }
+        let mut moved = (0, 0);
+        for n in (0..target.len()).filter(|n| target[*n] != placement[*n]) {
+            moved.0 += 1;
+            if self.move_block(&chunk, n, target[n])? {
+                moved.1 += 1;
+            }
+        }
+        Ok(moved)
+    }
+
+    // Copies block `n` of `chunk` to store `to` and then lets go of the old
+    // copy, once every label says where the new one is. Returns whether the
+    // block had to be rebuilt.
+    fn move_block(&mut self, chunk: &Chunk, n: usize, to: usize) -> Result<bool, VolumeError> {
+        let id = chunk.id;
+        let mut placement = self.placement(id).ok_or(VolumeError::NoDataFound)?;
+        let from = placement[n];
+        let loaded = self
+            .get(from)
+            .and_then(|s| s.load_block(id, 0))
+            .ok()
+            .filter(|b| b.id == chunk.blocks[n] && b.inner_data_as_vec().is_some());
+        let rebuilt = loaded.is_none();
+        let block = match loaded {
+            Some(block) => block,
+            None => self.rebuild_block(chunk, n)?,
+        };
+        let mut label = self.load_label(id)?.ok_or(VolumeError::NoDataFound)?;
+        placement[n] = to;
+        for (n, store) in placement.iter().enumerate() {
+            label.stores[n] = *store as u16;
+        }
+
+        let store = self.get_mut(to)?;
+        let pos = match store.find(id) {
+            Some(pos) => pos,
+            None => store.allocate_file(id)?,
+        };
+        store.sync_metadata()?;
+        store.save_chunk(pos, label, *chunk, vec![block])?;
+        store.sync_data()?;
+        for n in self.holders(id).into_iter().filter(|n| *n != to) {
+            if placement.contains(&n) {
+                let store = self.get_mut(n)?;
+                store.save_label(label)?;
+                store.sync_data()?;
+            }
+        }
+        if let Some(store) = self.stores[from].as_mut() {
+            if store.find(id).is_some() {
+                store.free_file(id)?;
+                store.sync_metadata()?;
+            }
+        }
+        Ok(rebuilt)
+    }
+
+    // Block `n` of `chunk` made again from the others.
+    fn rebuild_block(&self, chunk: &Chunk, n: usize) -> Result<Block, VolumeError> {
+        let mut shards: Vec<Option<Vec<u8>>> = (0..chunk.blocks.len())
+            .map(|i| match i == n {
+                true => None,
+                false => self
+                    .load_block(chunk.id, i)
+                    .ok()
+                    .filter(|b| b.id == chunk.blocks[i])
+                    .and_then(|b| b.inner_data_as_vec()),
+            })
+            .collect();
+        ReedSolomon::new(chunk.chunk_n, chunk.parity_n)
+            .and_then(|r| r.reconstruct(&mut shards))
+            .map_err(|e| VolumeError::FileError(RedundantFileError::RecostructError(e)))?;
+        let data = shards[n].take().ok_or(VolumeError::NoDataFound)?;
+        let mut block = Block::empty();
+        block.id = chunk.blocks[n];
+        block.position = n;
+        block.data.copy_from_slice(&data[..]);
+        block.crc = crc32c(&block.data);
+        Ok(block)
+    }
+
+    // Frees the slots store `n` has for chunks that aren't placed on it, left
+    // behind by frees made while it was missing. Returns how many it freed.
+    pub fn release_leftovers(&mut self, n: usize) -> Result<usize, VolumeError> {
+        let ids = self.get(n)?.ids();
+        let leftovers: Vec<UUID> = ids
+            .into_iter()
+            .filter(|id| self.placement(*id).is_some_and(|p| !p.contains(&n)))
+            .collect();
+        let store = self.get_mut(n)?;
+        for id in leftovers.iter() {
+            store.free_file(*id)?;
+        }
+        store.sync_metadata()?;
+        Ok(leftovers.len())
+    }
+
+    // Adds a new, empty store at `path` in failure domain `domain`. It takes
+    // the next number, which every other store has to learn, so they all
+    // have to be there. An attach cut short leaves the new store missing,
+    // for fsck to create.
+    pub fn attach(&mut self, path: &str, domain: &FailureDomain) -> Result<(), VolumeError> {
+        if !self.sharded() {
+            return Err(VolumeError::TooFewStores(1));
+        }
+        if let Some(n) = self.missing().first() {
+            return Err(VolumeError::StoreUnavailable(*n));
+        }
+        let n = self.stores.len();
+        if n >= u16::MAX as usize {
+            return Err(VolumeError::StoreMismatch(path.to_owned()));
+        }
+        if Path::new(path).exists() || self.paths.iter().any(|p| p == path) {
+            return Err(VolumeError::IoError(std::io::Error::new(
+                std::io::ErrorKind::AlreadyExists,
+                path.to_owned(),
+            )));
+        }
+        for store in self.stores.iter_mut().flatten() {
+            let at = store.super_block().store();
+            store.renumber(at, n + 1)?;
+            store.sync_metadata()?;
+            store.sync_data()?;
+        }
+        self.paths.push(path.to_owned());
+        self.stores.push(None);
+        self.domains.push(FailureDomain::default());
+        self.replace(n, domain)
+    }
+
+    // Takes store `n`, which must hold nothing, out of the volume and
+    // returns its path. The last store takes its number, so every other
+    // store has to be there. The chunks in the last store are relabeled
+    // first; until it is renumbered those blocks are read as missing.
+    pub fn detach(&mut self, n: usize) -> Result<String, VolumeError> {
+        let last = self.stores.len() - 1;
+        if !self.sharded() || last < BLOCKS + PARITY {
+            return Err(VolumeError::TooFewStores(last));
+        }
+        if let Some(m) = self.missing().into_iter().find(|m| *m != n) {
+            return Err(VolumeError::StoreUnavailable(m));
+        }
+        let ids = self.ids();
+        let held = self.stores[n].as_ref().is_some_and(|s| !s.ids().is_empty())
+            || ids
+                .iter()
+                .any(|id| self.placement(*id).is_some_and(|p| p.contains(&n)));
+        if held {
+            return Err(VolumeError::StoreNotEmpty(n));
+        }
+        if n != last {
+            for id in ids {
+                let placement = match self.placement(id) {
+                    Some(placement) if placement.contains(&last) => placement,
+                    _ => continue,
+                };
+                let mut label = self.load_label(id)?.ok_or(VolumeError::NoDataFound)?;
+                for (i, store) in placement.iter().enumerate() {
+                    label.stores[i] = if *store == last { n } else { *store } as u16;
+                }
+                for holder in self.holders(id) {
+                    let store = self.get_mut(holder)?;
+                    store.save_label(label)?;
+                    store.sync_data()?;
+                }
+            }
+        }
+        for s in (0..=last).filter(|s| *s != n) {
+            let store = self.get_mut(s)?;
+            store.renumber(if s == last { n } else { s }, last)?;
+            store.sync_metadata()?;
+            store.sync_data()?;
+        }
+        self.stores.swap_remove(n);
+        self.domains.swap_remove(n);
+        Ok(self.paths.swap_remove(n))
+    }
+
+    pub fn contains(&self, id: UUID) -> bool {
+        !self.holders(id).is_empty()
+    }
+
+    // Every chunk held by any of the stores there are.
+    pub fn ids(&self) -> Vec<UUID> {
+        let mut ids: Vec<UUID> = self.stores.iter().flatten().flat_map(|s| s.ids()).collect();
+        if self.sharded() {
+            ids.sort();
+            ids.dedup();
+        }
+        ids
+    }
+
+    // Releases the chunk in the stores there are. The slots it has in stores
+    // that are missing are left for fsck once they are back.
+    pub fn free_file(&mut self, id: UUID) -> Result<(), VolumeError> {
+        let holders = self.holders(id);
+        if holders.is_empty() {
+            return Err(VolumeError::NoDataFound);
+        }
+        for n in holders {
+            self.get_mut(n)?.free_file(id)?;
+        }
+        Ok(())
+    }
+
+    pub fn sync_metadata(&mut self) -> Result<(), VolumeError> {
+        for store in self.stores.iter_mut().flatten() {
+            store.sync_metadata()?;
+        }
+        Ok(())
+    }
+
+    pub fn sync_data(&mut self) -> Result<(), VolumeError> {
+        for store in self.stores.iter_mut().flatten() {
+            store.sync_data()?;
+        }
+        Ok(())
+    }
+}
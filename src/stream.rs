@@ -0,0 +1,279 @@
+use crate::block::Block;
+use crate::chunk::Chunk;
+use crate::error::VolumeError;
+use crate::index::IndexEntry;
+use crate::label::ChunkLabel;
+use crate::redundant_file::RedundantFile;
+use crate::volume::{BigFileVolume, Volume};
+use crate::UUID;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::io::{Read, Write};
+
+pub const STREAM_MAGIC: [u8; 8] = *b"OGGSTRM\0";
+pub const STREAM_VERSION: u32 = 1;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct StreamEntry {
+    pub name: String,
+    pub id: UUID,
+    pub size: u64,
+    pub flags: u64,
+}
+
+// A stream is the magic followed by length-prefixed records: one `Begin`,
+// then for every object its chunks followed by the object itself, then any
+// `Delete`s and a final `End`. Chunks come first so an interrupted receive
+// never indexes an object whose data is missing.
+#[derive(Serialize, Deserialize)]
+pub enum StreamRecord {
+    Begin {
+        version: u32,
+        from: Option<String>,
+        snapshot: Option<String>,
+    },
+    Chunk {
+        chunk: Chunk,
+        blocks: Vec<Block>,
+    },
+    Object {
+        entry: StreamEntry,
+        file: Option<RedundantFile>,
+    },
+    Delete {
+        name: String,
+        id: UUID,
+    },
+    End {
+        objects: u64,
+        deletes: u64,
+    },
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct StreamStats {
+    pub objects: u64,
+    pub chunks: u64,
+    pub deletes: u64,
+    pub skipped: u64,
+}
+
+impl From<&IndexEntry> for StreamEntry {
+    fn from(entry: &IndexEntry) -> StreamEntry {
+        StreamEntry {
+            name: String::from_utf8_lossy(entry.key()).into_owned(),
+            id: entry.id,
+            size: entry.size,
+            flags: entry.flags,
+        }
+    }
+}
+
+impl From<&StreamEntry> for IndexEntry {
+    fn from(entry: &StreamEntry) -> IndexEntry {
+        let mut ret = IndexEntry::new(entry.name.as_bytes(), entry.id, entry.size);
+        ret.flags = entry.flags;
+        ret
+    }
+}
+
+fn write_record<W: Write>(writer: &mut W, record: &StreamRecord) -> Result<(), VolumeError> {
+    let bytes = bincode::serialize(record)
+        .map_err(|err| VolumeError::IoError(std::io::Error::other(err)))?;
+    writer
+        .write_all(&(bytes.len() as u64).to_le_bytes())
+        .map_err(VolumeError::IoError)?;
+    writer.write_all(&bytes[..]).map_err(VolumeError::IoError)
+}
+
+fn read_record<R: Read>(reader: &mut R) -> Result<StreamRecord, VolumeError> {
+    let mut len = [0u8; 8];
+    reader.read_exact(&mut len).map_err(VolumeError::IoError)?;
+    let mut bytes = vec![0u8; u64::from_le_bytes(len) as usize];
+    reader
+        .read_exact(&mut bytes[..])
+        .map_err(VolumeError::IoError)?;
+    bincode::deserialize(&bytes[..]).map_err(|_| VolumeError::BadStream)
+}
+
+// Writes a stream of `snapshot`, or of the live volume when it is `None`.
+// With `from` set only the difference between the two snapshots is sent.
+pub fn send<W: Write>(
+    volume: &BigFileVolume,
+    from: Option<&str>,
+    snapshot: Option<&str>,
+    writer: &mut W,
+) -> Result<StreamStats, VolumeError> {
+    if from.is_some() && snapshot.is_none() {
+        return Err(VolumeError::BadStream);
+    }
+    let mut stats = StreamStats::default();
+
+    let mut entries = volume.entries(snapshot)?;
+    let base = match from {
+        Some(from) => volume.entries(Some(from))?,
+        None => Vec::new(),
+    };
+    let base_ids: HashSet<UUID> = base.iter().map(|e| e.id).collect();
+    let ids: HashSet<UUID> = entries.iter().map(|e| e.id).collect();
+
+    // Oldest first, so the receiver rebuilds the same version order.
+    entries.retain(|e| !base_ids.contains(&e.id));
+    entries.sort_by_key(|e| e.version);
+
+    writer
+        .write_all(&STREAM_MAGIC)
+        .map_err(VolumeError::IoError)?;
+    write_record(
+        writer,
+        &StreamRecord::Begin {
+            version: STREAM_VERSION,
+            from: from.map(|s| s.to_owned()),
+            snapshot: snapshot.map(|s| s.to_owned()),
+        },
+    )?;
+
+    for entry in entries.iter() {
+        let file = if entry.is_delete_marker() {
+            None
+        } else {
+            let file = volume.get_redundant_file(entry.id)?;
+            for id in file.chunk_ids() {
+                let chunk = volume.get_chunk(id)?;
+                let blocks = (0..chunk.blocks.len())
+                    .map(|n| match volume.get_chunk_block(&chunk, n) {
+                        Ok(block) => *block,
+                        Err(_) => Block::empty(),
+                    })
+                    .collect();
+                write_record(
+                    writer,
+                    &StreamRecord::Chunk {
+                        chunk: *chunk,
+                        blocks,
+                    },
+                )?;
+                stats.chunks += 1;
+            }
+            Some(*file)
+        };
+        write_record(
+            writer,
+            &StreamRecord::Object {
+                entry: entry.into(),
+                file,
+            },
+        )?;
+        stats.objects += 1;
+    }
+
+    for entry in base.iter().filter(|e| !ids.contains(&e.id)) {
+        write_record(
+            writer,
+            &StreamRecord::Delete {
+                name: String::from_utf8_lossy(entry.key()).into_owned(),
+                id: entry.id,
+            },
+        )?;
+        stats.deletes += 1;
+    }
+
+    write_record(
+        writer,
+        &StreamRecord::End {
+            objects: stats.objects,
+            deletes: stats.deletes,
+        },
+    )?;
+    writer.flush().map_err(VolumeError::IoError)?;
+    Ok(stats)
+}
+
+// Applies a stream to `volume`. An incremental stream needs the snapshot it
+// was taken from to exist on the receiving side; the snapshot it was taken
+// of is created once the stream has been applied.
+pub fn receive<R: Read>(
+    volume: &mut BigFileVolume,
+    reader: &mut R,
+) -> Result<StreamStats, VolumeError> {
+    let mut stats = StreamStats::default();
+
+    let mut magic = [0u8; 8];
+    reader
+        .read_exact(&mut magic)
+        .map_err(VolumeError::IoError)?;
+    if magic != STREAM_MAGIC {
+        return Err(VolumeError::BadStream);
+    }
+    let snapshot = match read_record(reader)? {
+        StreamRecord::Begin {
+            version,
+            from,
+            snapshot,
+        } if version == STREAM_VERSION => {
+            let snapshots = volume.list_snapshots();
+            if let Some(from) = from {
+                if !snapshots.iter().any(|s| s.name == from) {
+                    return Err(VolumeError::NoSuchSnapshot(from));
+                }
+            }
+            if let Some(snapshot) = snapshot.as_ref() {
+                if snapshots.iter().any(|s| &s.name == snapshot) {
+                    return Err(VolumeError::SnapshotExists(snapshot.clone()));
+                }
+            }
+            snapshot
+        }
+        _ => return Err(VolumeError::BadStream),
+    };
+
+    let mut chunks: HashSet<UUID> = HashSet::new();
+    let mut seen_objects = 0;
+    let mut seen_deletes = 0;
+    loop {
+        match read_record(reader)? {
+            StreamRecord::Begin { .. } => return Err(VolumeError::BadStream),
+            StreamRecord::Chunk { chunk, blocks } => {
+                if volume.get_chunk(chunk.id).is_err() {
+                    // Labelled for its object once that arrives.
+                    volume.save_chunk(ChunkLabel::new(&chunk, &blocks), chunk, blocks)?;
+                    stats.chunks += 1;
+                }
+                chunks.insert(chunk.id);
+            }
+            StreamRecord::Object { entry, file } => {
+                seen_objects += 1;
+                if volume.contains_version(&entry.name, entry.id) {
+                    stats.skipped += 1;
+                    continue;
+                }
+                if let Some(file) = file.as_ref() {
+                    if file.chunk_ids().iter().any(|c| !chunks.contains(c)) {
+                        return Err(VolumeError::BadStream);
+                    }
+                }
+                volume.restore(file.as_ref(), (&entry).into())?;
+                stats.objects += 1;
+            }
+            StreamRecord::Delete { name, id } => {
+                seen_deletes += 1;
+                match volume.delete_version(&name, id) {
+                    Ok(()) => stats.deletes += 1,
+                    Err(VolumeError::NoDataFound) => stats.skipped += 1,
+                    Err(err) => return Err(err),
+                }
+            }
+            StreamRecord::End { objects, deletes } => {
+                if objects != seen_objects || deletes != seen_deletes {
+                    return Err(VolumeError::BadStream);
+                }
+                break;
+            }
+        }
+    }
+
+    if let Some(snapshot) = snapshot {
+        volume.create_snapshot(&snapshot)?;
+    }
+    Ok(stats)
+}
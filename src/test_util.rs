@@ -0,0 +1,37 @@
+use std::path::PathBuf;
+
+// Chunks are big enough to overflow the stack tests get by default, so
+// tests that encode or decode them run on a thread of their own.
+pub(crate) fn with_stack<F: FnOnce() + Send + 'static>(f: F) {
+    std::thread::Builder::new()
+        .stack_size(16 * 1024 * 1024)
+        .spawn(f)
+        .unwrap()
+        .join()
+        .unwrap();
+}
+
+// A directory of its own for a test's volume files, removed when the test
+// is done with it.
+pub(crate) struct Scratch(PathBuf);
+
+impl Scratch {
+    pub(crate) fn new() -> Scratch {
+        let dir = std::env::temp_dir().join(format!(
+            "oggetto-test-{:032x}",
+            uuid::Uuid::new_v4().as_u128()
+        ));
+        std::fs::create_dir_all(&dir).unwrap();
+        Scratch(dir)
+    }
+
+    pub(crate) fn path(&self, name: &str) -> String {
+        self.0.join(name).to_str().unwrap().to_owned()
+    }
+}
+
+impl Drop for Scratch {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
@@ -0,0 +1,273 @@
+use crate::block::Block;
+use crate::chunk::Chunk;
+use crate::constants::{BLOCKS, PARITY};
+use crate::error::VolumeError;
+use crate::index::IndexEntry;
+use crate::label::ChunkLabel;
+use crate::mirror::VolumeFile;
+use crate::redundant_file::RedundantFile;
+use crate::volume_manager::{
+    FileVector, FileVolumeManager, SuperBlock, FILE_VECTOR_BYTES, FORMAT_VERSION,
+    SUPER_BLOCK_BYTES, SUPER_BLOCK_COPIES,
+};
+use crate::UUID;
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+
+// Volumes written before the superblock had a magic and a version: the
+// superblock was just the file size and where the first file vector starts,
+// right after it, and there was no index.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Layout {
+    Current,
+    Unindexed,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UpgradeStats {
+    pub layout: Layout,
+    pub objects: usize,
+    pub files: usize,
+    pub chunks: usize,
+    pub missing_files: usize,
+    pub missing_chunks: usize,
+}
+
+const UNINDEXED_VECTOR_START: u64 = 16;
+
+impl std::fmt::Display for Layout {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            Layout::Current => "current",
+            Layout::Unindexed => "unindexed",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+// Any superblock copy with the magic makes the file a versioned one, so that
+// one damaged copy doesn't get it mistaken for a legacy volume.
+pub fn detect(path: &str) -> Result<Layout, VolumeError> {
+    let file = VolumeFile::plain(File::open(path).map_err(VolumeError::IoError)?);
+    if let Some(sb) = super_block(&file)? {
+        return match sb.version {
+            FORMAT_VERSION => Ok(Layout::Current),
+            version => Err(VolumeError::UnsupportedVersion(version)),
+        };
+    }
+    let mut head = [0u8; 16];
+    file.read_at(0, &mut head)?;
+    match le_u64(&head[8..16]) {
+        UNINDEXED_VECTOR_START => Ok(Layout::Unindexed),
+        _ => Err(VolumeError::BadMagic),
+    }
+}
+
+// Whether any superblock copy has the magic. Copies that have it but are
+// damaged make it an error.
+fn super_block(file: &VolumeFile) -> Result<Option<SuperBlock>, VolumeError> {
+    let mut damaged = false;
+    for pos in SUPER_BLOCK_COPIES.iter() {
+        let mut buf = [0u8; SUPER_BLOCK_BYTES];
+        if file.read_at(*pos, &mut buf).is_err() {
+            continue;
+        }
+        match SuperBlock::decode(buf) {
+            Ok(sb) => return Ok(Some(sb)),
+            Err(VolumeError::BadMagic) => {}
+            Err(_) => damaged = true,
+        }
+    }
+    match damaged {
+        true => Err(VolumeError::CorruptSuperBlock),
+        false => Ok(None),
+    }
+}
+
+// Read-only view of a legacy file.
+struct Legacy {
+    file: VolumeFile,
+}
+
+impl Legacy {
+    fn open(path: &str) -> Result<Legacy, VolumeError> {
+        Ok(Legacy {
+            file: VolumeFile::plain(File::open(path).map_err(VolumeError::IoError)?),
+        })
+    }
+
+    fn read(&mut self, pos: u64, len: usize) -> Result<Vec<u8>, VolumeError> {
+        let mut buf = vec![0u8; len];
+        self.file.read_at(pos, &mut buf[..])?;
+        Ok(buf)
+    }
+
+    // Offsets and ids of every used slot. File and chunk vectors share their
+    // format, and it hasn't changed since.
+    fn slots(&mut self) -> Result<Vec<(u64, UUID)>, VolumeError> {
+        let mut slots = Vec::new();
+        let mut seen = HashSet::new();
+        let mut seek = UNINDEXED_VECTOR_START;
+        while seek != 0 {
+            if !seen.insert(seek) {
+                return Err(VolumeError::CorruptMetadata(seek));
+            }
+            let mut buf = [0u8; FILE_VECTOR_BYTES];
+            buf.copy_from_slice(&self.read(seek, FILE_VECTOR_BYTES)?[..]);
+            let vector: FileVector = buf.into();
+            slots.extend(vector.entries.iter().filter(|e| e.1 != 0).cloned());
+            seek = vector.next_file_vector;
+        }
+        Ok(slots)
+    }
+}
+
+fn le_u64(bytes: &[u8]) -> u64 {
+    let mut buf = [0u8; 8];
+    buf.copy_from_slice(bytes);
+    u64::from_le_bytes(buf)
+}
+
+fn name_of(rf: &RedundantFile) -> Vec<u8> {
+    let len = rf
+        .name
+        .iter()
+        .position(|b| *b == 0)
+        .unwrap_or(rf.name.len());
+    rf.name[..len].to_vec()
+}
+
+// Copies a legacy volume into a new one at `out_meta` and `out_blocks`, which
+// must not exist yet. Every file record was an object, named by the record
+// itself and sized by its chunks; objects keep their ids, and those sharing
+// a name become its versions, the newest slot current. A current volume is
+// left alone and reported as such.
+pub fn upgrade(
+    meta: &str,
+    blocks: &str,
+    out_meta: &str,
+    out_blocks: &str,
+) -> Result<UpgradeStats, VolumeError> {
+    let layout = detect(meta)?;
+    let block_layout = detect(blocks)?;
+    let mut stats = UpgradeStats {
+        layout,
+        objects: 0,
+        files: 0,
+        chunks: 0,
+        missing_files: 0,
+        missing_chunks: 0,
+    };
+    if layout == Layout::Current && block_layout == Layout::Current {
+        return Ok(stats);
+    }
+    if layout == Layout::Current || block_layout == Layout::Current {
+        return Err(VolumeError::MixedLayouts);
+    }
+    for path in [out_meta, out_blocks].iter() {
+        if std::path::Path::new(path).exists() {
+            return Err(VolumeError::IoError(std::io::Error::new(
+                std::io::ErrorKind::AlreadyExists,
+                path.to_string(),
+            )));
+        }
+    }
+
+    let mut old_meta = Legacy::open(meta)?;
+    let mut old_blocks = Legacy::open(blocks)?;
+    let chunks: HashMap<UUID, u64> = old_blocks
+        .slots()?
+        .into_iter()
+        .map(|(p, i)| (i, p))
+        .collect();
+
+    let uuid = uuid::Uuid::new_v4().as_u128();
+    let mut new_meta = FileVolumeManager::init_metadata(out_meta, uuid)?;
+    let mut new_blocks = FileVolumeManager::init_blockdata(out_blocks, uuid)?;
+
+    // In slot order, so the chunk labels tell the versions of a name apart
+    // in the order they were written.
+    let mut live = Vec::new();
+    for (pos, _) in old_meta.slots()? {
+        let rf = load_file(&mut old_meta, pos)?;
+        let ids = rf.chunk_ids();
+        let count = ids.len() as u32;
+        let mut size = 0;
+        for (n, c) in ids.into_iter().enumerate() {
+            let pos = match chunks.get(&c) {
+                Some(pos) => *pos,
+                None => {
+                    stats.missing_chunks += 1;
+                    continue;
+                }
+            };
+            let chunk = load_chunk(&mut old_blocks, pos)?;
+            size += chunk.chunk_size as u64;
+            if new_blocks.find(c).is_some() {
+                continue;
+            }
+            // A block that no longer decodes is carried over as a missing
+            // one, which reads rebuild from the others.
+            let mut data = Vec::with_capacity(BLOCKS + PARITY);
+            for n in 0..BLOCKS + PARITY {
+                let at = pos + Chunk::header_size() as u64 + n as u64 * Block::size();
+                let bytes = old_blocks.read(at, Block::size() as usize)?;
+                data.push(bincode::deserialize(&bytes[..]).unwrap_or_else(|_| Block::empty()));
+            }
+            let label = ChunkLabel::new(&chunk, &data).owned(rf.id, &rf.name, n as u32, count);
+            let slot = new_blocks.allocate_file(c)?;
+            new_blocks.save_chunk(slot, label, chunk, data)?;
+            stats.chunks += 1;
+        }
+        let mut entry = IndexEntry::new(&name_of(&rf), rf.id, size);
+        entry.version = live.len() as u64 + 1;
+        live.push(entry);
+        let slot = new_meta.allocate_file(rf.id)?;
+        new_meta.save_file(slot, rf)?;
+        stats.files += 1;
+    }
+
+    for entry in live {
+        new_meta.index_import(entry)?;
+        stats.objects += 1;
+    }
+
+    new_blocks.sync_metadata()?;
+    new_blocks.sync_data()?;
+    new_meta.sync_metadata()?;
+    new_meta.sync_data()?;
+    Ok(stats)
+}
+
+// Upgrades the volume where it is. The new files are built next to the old
+// ones and renamed over them, the metadata file first; if that is all that
+// happened before an interruption, running it again finishes the job.
+pub fn upgrade_in_place(meta: &str, blocks: &str) -> Result<UpgradeStats, VolumeError> {
+    let new_meta = format!("{}.upgrade", meta);
+    let new_blocks = format!("{}.upgrade", blocks);
+    if detect(meta)? == Layout::Current && std::path::Path::new(&new_blocks).exists() {
+        std::fs::rename(&new_blocks, blocks).map_err(VolumeError::IoError)?;
+    }
+    for path in [&new_meta, &new_blocks].iter() {
+        if std::path::Path::new(path).exists() {
+            std::fs::remove_file(path).map_err(VolumeError::IoError)?;
+        }
+    }
+
+    let stats = upgrade(meta, blocks, &new_meta, &new_blocks)?;
+    if stats.layout != Layout::Current {
+        std::fs::rename(&new_meta, meta).map_err(VolumeError::IoError)?;
+        std::fs::rename(&new_blocks, blocks).map_err(VolumeError::IoError)?;
+    }
+    Ok(stats)
+}
+
+fn load_file(legacy: &mut Legacy, pos: u64) -> Result<RedundantFile, VolumeError> {
+    let bytes = legacy.read(pos, RedundantFile::size())?;
+    bincode::deserialize(&bytes[..]).map_err(|_| VolumeError::CorruptMetadata(pos))
+}
+
+fn load_chunk(legacy: &mut Legacy, pos: u64) -> Result<Chunk, VolumeError> {
+    let bytes = legacy.read(pos, Chunk::header_size())?;
+    bincode::deserialize(&bytes[..]).map_err(|_| VolumeError::CorruptMetadata(pos))
+}
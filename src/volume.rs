@@ -1,22 +1,52 @@
+use crate::backend::{BlockStore, MetadataStore};
 use crate::block::Block;
 use crate::chunk::Chunk;
+use crate::constants::{BLOCKS, FIRST_INDIRECTION_SIZE, PARITY, READ_STEP};
+use crate::domain::FailureDomain;
+use crate::error::RedundantFileError;
 use crate::error::VolumeError;
+use crate::index::{IndexEntry, ObjectList};
+use crate::label::ChunkLabel;
+use crate::multipart::{EncodedPart, MultipartUpload, UploadedPart, MAX_PARTS};
+use crate::pipeline::Pipeline;
+use crate::placement::Placement;
 use crate::redundant_file::RedundantFile;
+use crate::snapshot::SnapshotSummary;
+use crate::store::BlockStores;
 use crate::volume_manager::FileVolumeManager;
 use crate::UUID;
 use std::collections::HashMap;
+use std::time::Duration;
 
 pub trait Volume {
     fn get_redundant_file(&self, id: UUID) -> Result<Box<RedundantFile>, VolumeError>;
     fn get_chunk(&self, id: UUID) -> Result<Box<Chunk>, VolumeError>;
     fn get_block(&self, id: UUID) -> Result<Box<Block>, VolumeError>;
+    fn get_chunk_block(&self, chunk: &Chunk, n: usize) -> Result<Box<Block>, VolumeError> {
+        self.get_block(chunk.blocks[n])
+    }
+    fn get_chunk_blocks(&self, chunk: &Chunk) -> Vec<Option<Box<Block>>> {
+        (0..chunk.blocks.len())
+            .map(|n| self.get_chunk_block(chunk, n).ok())
+            .collect()
+    }
     fn destruct_from_file(&mut self, file_name: &str) -> Result<UUID, VolumeError>;
     fn restruct_to_file(&mut self, id: UUID, file_name: &str) -> Result<(), VolumeError>;
+    fn delete(&mut self, name: &str) -> Result<(), VolumeError>;
+    fn list(
+        &self,
+        prefix: &str,
+        delimiter: Option<&str>,
+        start_after: Option<&str>,
+        limit: usize,
+    ) -> Result<ObjectList, VolumeError>;
 }
 
 pub struct BigFileVolume {
-    meta_data: Option<FileVolumeManager>,
-    block_file: Option<FileVolumeManager>,
+    meta_data: Option<Box<dyn MetadataStore>>,
+    stores: Option<Box<dyn BlockStore>>,
+    uploads: HashMap<UUID, MultipartUpload>,
+    pipeline: Pipeline,
 }
 
 pub struct BigFileVolumeHashMap<T> {
@@ -37,102 +67,1087 @@ impl<T> BigFileVolumeHashMap<T> {
     pub fn get(&self, id: &UUID) -> Option<&T> {
         self.hashmap.get(&id)
     }
+
+    pub fn get_mut(&mut self, id: &UUID) -> Option<&mut T> {
+        self.hashmap.get_mut(id)
+    }
+
+    pub fn remove(&mut self, id: &UUID) -> Option<T> {
+        self.hashmap.remove(id)
+    }
 }
 
 impl BigFileVolume {
     pub fn default() -> BigFileVolume {
         return BigFileVolume {
             meta_data: None,
-            block_file: None,
+            stores: None,
+            uploads: HashMap::new(),
+            pipeline: Pipeline::default(),
         };
     }
 
     pub fn init(meta_data: &str, block_file: &str) -> BigFileVolume {
-        let fvm = match FileVolumeManager::open_metadata(meta_data) {
-            Ok(fvm) => fvm,
-            Err(_) => FileVolumeManager::init_metadata(meta_data).unwrap(),
-        };
+        match BigFileVolume::try_init(meta_data, block_file) {
+            Ok(bfv) => bfv,
+            Err(err) => panic!("cannot open {} {}: {:?}", meta_data, block_file, err),
+        }
+    }
+
+    pub fn try_init(meta_data: &str, block_file: &str) -> Result<BigFileVolume, VolumeError> {
+        BigFileVolume::try_init_stores(meta_data, &[block_file.to_owned()], &[])
+    }
 
-        let block = match FileVolumeManager::open_blockdata(block_file) {
-            Ok(fvm) => fvm,
-            Err(_) => FileVolumeManager::init_blockdata(block_file).unwrap(),
+    // Opens the volume, creating its metadata file or its block stores if
+    // they don't exist yet. All of them carry the volume's UUID and have to
+    // agree on it. Several stores spread every chunk over them; there have
+    // to be at least as many as a chunk has blocks. New stores are put in
+    // the failure domains given for them.
+    pub fn try_init_stores(
+        meta_data: &str,
+        stores: &[String],
+        domains: &[FailureDomain],
+    ) -> Result<BigFileVolume, VolumeError> {
+        let fvm = match std::path::Path::new(meta_data).exists() {
+            true => Some(FileVolumeManager::open_metadata(meta_data)?),
+            false => None,
+        };
+        let block = match BlockStores::exists(stores) {
+            true => Some(BlockStores::open(stores)?),
+            false => None,
         };
+        let uuid = fvm
+            .as_ref()
+            .map(|f| f.super_block())
+            .or(block.as_ref().map(|b| b.super_block()))
+            .map(|sb| sb.uuid())
+            .unwrap_or_else(|| uuid::Uuid::new_v4().as_u128());
+        let fvm = match fvm {
+            Some(fvm) => fvm,
+            None => FileVolumeManager::init_metadata(meta_data, uuid)?,
+        };
+        let block = match block {
+            Some(block) => block,
+            None => BlockStores::init(stores, domains, uuid)?,
+        };
+        BigFileVolume::with_backends(Box::new(fvm), Box::new(block))
+    }
+
+    // Opens a volume kept in any metadata and block backends, which have to
+    // belong to the same volume.
+    pub fn with_backends(
+        meta_data: Box<dyn MetadataStore>,
+        stores: Box<dyn BlockStore>,
+    ) -> Result<BigFileVolume, VolumeError> {
+        if meta_data.uuid() != stores.uuid() {
+            return Err(VolumeError::VolumeMismatch(meta_data.uuid(), stores.uuid()));
+        }
 
         let mut bfv = BigFileVolume::default();
 
-        bfv.meta_data = Some(fvm);
-        bfv.block_file = Some(block);
+        bfv.meta_data = Some(meta_data);
+        bfv.stores = Some(stores);
+        bfv.roll_back()?;
+
+        Ok(bfv)
+    }
+
+    // Replaces the policy deciding which stores new chunks go to.
+    pub fn set_placement(&mut self, policy: Box<dyn Placement>) {
+        self.stores.as_mut().unwrap().set_policy(policy);
+    }
+
+    // Sets how long a read waits on a slow block before racing it with
+    // another, on backends that read blocks in parallel.
+    pub fn set_hedge(&mut self, delay: Duration) {
+        self.stores.as_mut().unwrap().set_hedge(delay);
+    }
+
+    // Sets how many threads encode and decode chunks, and how many chunks
+    // can be in flight between reading and storing them.
+    pub fn set_pipeline(&mut self, pipeline: Pipeline) {
+        self.pipeline = pipeline;
+    }
 
-        bfv
+    fn roll_back(&mut self) -> Result<(), VolumeError> {
+        roll_back(
+            self.meta_data.as_deref_mut().unwrap(),
+            self.stores.as_deref_mut().unwrap(),
+        )
+    }
+
+    // Runs `write` as one journal transaction for object `file`, whose new
+    // `chunks` are freed again if it doesn't commit. Chunk data is made
+    // durable before the metadata pointing at it. On failure the metadata is
+    // reloaded, dropping whatever `write` changed in memory.
+    fn transaction<F>(&mut self, file: UUID, chunks: &[UUID], write: F) -> Result<(), VolumeError>
+    where
+        F: FnOnce(&mut BigFileVolume) -> Result<(), VolumeError>,
+    {
+        self.meta_data
+            .as_mut()
+            .unwrap()
+            .journal_begin(file, chunks)?;
+        let done = write(self)
+            .and_then(|_| self.stores.as_mut().unwrap().sync_data())
+            .and_then(|_| self.meta_data.as_mut().unwrap().journal_commit());
+        if let Err(err) = done {
+            // A write that failed before journaling any chunk leaves nothing
+            // to roll back, but its journal still has to be closed.
+            self.meta_data.as_mut().unwrap().reload()?;
+            self.roll_back()?;
+            self.meta_data.as_mut().unwrap().finish_rollback()?;
+            return Err(err);
+        }
+        Ok(())
     }
 
     pub fn destruct<T>(&mut self, file: &str, reader: &mut T) -> Result<UUID, VolumeError>
     where
-        T: std::io::Read,
+        T: std::io::Read + Send,
     {
-        let (file, chunks, blocks) = RedundantFile::destruct(file, reader).unwrap();
+        self.destruct_sized(file, reader, 0)
+    }
 
-        let id = file.id;
-        let pos = self.meta_data.as_mut().unwrap().allocate_file(id)?;
-        self.meta_data.as_mut().unwrap().sync_metadata()?;
+    // Stores chunks as the pipeline hands them over, each added to the
+    // journal before it is stored. `expected` is how many chunks the object
+    // is likely to have, for their labels; if it turns out wrong, or is 0
+    // for unknown, they are labeled again once all of them are in.
+    fn destruct_sized<T>(
+        &mut self,
+        file: &str,
+        reader: &mut T,
+        expected: u32,
+    ) -> Result<UUID, VolumeError>
+    where
+        T: std::io::Read + Send,
+    {
+        let named = RedundantFile::from_chunks(uuid::Uuid::new_v4().as_u128(), file, &[])
+            .map_err(VolumeError::FileError)?;
+        let pipeline = self.pipeline;
+
+        let mut displaced = Vec::new();
+        self.transaction(named.id, &[], |volume| {
+            let mut ids = Vec::new();
+            let mut size = 0u64;
+            pipeline.encode(reader, |batch| {
+                let batch_ids: Vec<UUID> = batch.iter().map(|(c, _)| c.id).collect();
+                ids.extend_from_slice(&batch_ids);
+                if ids.len() > FIRST_INDIRECTION_SIZE * (FIRST_INDIRECTION_SIZE + 1) {
+                    let err = RedundantFileError::FileTooLarge(ids.len());
+                    return Err(VolumeError::FileError(err));
+                }
+                volume
+                    .meta_data
+                    .as_mut()
+                    .unwrap()
+                    .journal_extend(named.id, &batch_ids)?;
+                for (chunk, blocks) in batch {
+                    size += chunk.chunk_size as u64;
+                    let label = ChunkLabel::new(&chunk, &blocks).owned(
+                        named.id,
+                        &named.name,
+                        chunk.position,
+                        expected,
+                    );
+                    volume.save_chunk(label, chunk, blocks)?;
+                }
+                Ok(())
+            })?;
+
+            let file =
+                RedundantFile::from_chunks(named.id, file, &ids).map_err(VolumeError::FileError)?;
+            if ids.len() != expected as usize {
+                volume.label_chunks(&file)?;
+            }
+            displaced = volume.commit_file(&file, size)?;
+            Ok(())
+        })?;
+
+        self.free_entries(&displaced)?;
+        Ok(named.id)
+    }
+
+    // Saves the file record and makes it the current version of its name.
+    // Returns the versions it replaces, to be freed once the write commits.
+    fn commit_file(
+        &mut self,
+        file: &RedundantFile,
+        size: u64,
+    ) -> Result<Vec<IndexEntry>, VolumeError> {
+        self.meta_data.as_mut().unwrap().save_file(file.clone())?;
 
         self.meta_data
             .as_mut()
             .unwrap()
-            .save_file(pos, *file.clone())?;
+            .index_put(IndexEntry::new(&file.name, file.id, size))
+    }
+
+    // Frees the files behind entries that were dropped from the index, unless
+    // a snapshot still needs them.
+    fn free_entries(&mut self, entries: &[IndexEntry]) -> Result<(), VolumeError> {
+        for entry in entries.iter().filter(|e| !e.is_delete_marker()) {
+            if !self.meta_data.as_ref().unwrap().referenced(entry.id) {
+                self.free_file(entry.id)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn free_file(&mut self, id: UUID) -> Result<(), VolumeError> {
+        free_file(
+            self.meta_data.as_deref_mut().unwrap(),
+            self.stores.as_deref_mut().unwrap(),
+            id,
+        )
+    }
+
+    pub fn save_chunk(
+        &mut self,
+        label: ChunkLabel,
+        chunk: Chunk,
+        blocks: Vec<Block>,
+    ) -> Result<(), VolumeError> {
+        self.stores
+            .as_mut()
+            .unwrap()
+            .save_chunk(label, chunk, blocks)
+    }
 
-        for c in chunks.iter() {
-            let mut tmp = Vec::new();
-            for b in blocks.iter() {
-                if c.blocks.contains(&b.id) {
-                    tmp.push(*b);
+    fn label_chunks(&mut self, file: &RedundantFile) -> Result<(), VolumeError> {
+        label_chunks(self.stores.as_deref_mut().unwrap(), file)
+    }
+
+    fn free_chunks(&mut self, chunks: &[UUID]) -> Result<(), VolumeError> {
+        for c in chunks {
+            self.stores.as_mut().unwrap().free_chunk(*c)?;
+        }
+        self.stores.as_mut().unwrap().sync_metadata()
+    }
+
+    pub fn lookup(&self, name: &str) -> Result<UUID, VolumeError> {
+        match self.meta_data.as_ref().and_then(|m| m.index_get(name)) {
+            Some(entry) if !entry.is_delete_marker() => Ok(entry.id),
+            _ => Err(VolumeError::NoDataFound),
+        }
+    }
+
+    pub fn lookup_version(&self, name: &str, version: UUID) -> Result<UUID, VolumeError> {
+        match self
+            .meta_data
+            .as_ref()
+            .and_then(|m| m.index_get_version(name, version))
+        {
+            Some(entry) if !entry.is_delete_marker() => Ok(entry.id),
+            _ => Err(VolumeError::NoDataFound),
+        }
+    }
+
+    pub fn versioning(&self) -> bool {
+        self.meta_data
+            .as_ref()
+            .map(|m| m.versioning())
+            .unwrap_or(false)
+    }
+
+    pub fn set_versioning(&mut self, enabled: bool) -> Result<(), VolumeError> {
+        self.meta_data.as_mut().unwrap().set_versioning(enabled)?;
+        self.meta_data.as_mut().unwrap().sync_metadata()
+    }
+
+    pub fn list_versions(
+        &self,
+        prefix: &str,
+        start_after: Option<&str>,
+        limit: usize,
+    ) -> Result<ObjectList, VolumeError> {
+        match self.meta_data.as_ref() {
+            Some(meta_data) => meta_data.list_versions(prefix, start_after, limit),
+            None => Err(VolumeError::NoDataFound),
+        }
+    }
+
+    // Every entry, versions and delete markers included, of the live index or
+    // of a snapshot.
+    pub fn entries(&self, snapshot: Option<&str>) -> Result<Vec<IndexEntry>, VolumeError> {
+        let meta_data = self.meta_data.as_ref().ok_or(VolumeError::NoDataFound)?;
+        match snapshot {
+            Some(snapshot) => Ok(meta_data
+                .snapshot(snapshot)?
+                .index
+                .iter()
+                .cloned()
+                .collect()),
+            None => Ok(meta_data.index_entries()),
+        }
+    }
+
+    pub fn contains_version(&self, name: &str, version: UUID) -> bool {
+        self.meta_data
+            .as_ref()
+            .and_then(|m| m.index_get_version(name, version))
+            .is_some()
+    }
+
+    // Records an object received from another volume under its original id.
+    // Its chunks must already have been saved.
+    pub fn restore(
+        &mut self,
+        file: Option<&RedundantFile>,
+        entry: IndexEntry,
+    ) -> Result<(), VolumeError> {
+        let file = match file {
+            Some(file) => file,
+            None => {
+                self.meta_data.as_mut().unwrap().index_restore(entry)?;
+                return self.meta_data.as_mut().unwrap().sync_metadata();
+            }
+        };
+        // The chunks may be shared with objects already on the volume, so
+        // they are not handed to the journal for rollback.
+        self.transaction(file.id, &[], |volume| {
+            volume.label_chunks(file)?;
+            let meta_data = volume.meta_data.as_mut().unwrap();
+            if !meta_data.contains_file(file.id) {
+                meta_data.save_file(file.clone())?;
+            }
+            meta_data.index_restore(entry)
+        })
+    }
+
+    pub fn create_snapshot(&mut self, name: &str) -> Result<(), VolumeError> {
+        let created = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        self.meta_data
+            .as_mut()
+            .unwrap()
+            .create_snapshot(name, created)?;
+        self.meta_data.as_mut().unwrap().sync_metadata()
+    }
+
+    // Drops the snapshot and frees the files that only it was keeping alive.
+    pub fn delete_snapshot(&mut self, name: &str) -> Result<(), VolumeError> {
+        let snapshot = self.meta_data.as_mut().unwrap().delete_snapshot(name)?;
+        self.meta_data.as_mut().unwrap().sync_metadata()?;
+        let entries: Vec<IndexEntry> = snapshot.index.iter().cloned().collect();
+        self.free_entries(&entries)
+    }
+
+    pub fn list_snapshots(&self) -> Vec<SnapshotSummary> {
+        match self.meta_data.as_ref() {
+            Some(meta_data) => meta_data.snapshots(),
+            None => Vec::new(),
+        }
+    }
+
+    pub fn lookup_snapshot(&self, snapshot: &str, name: &str) -> Result<UUID, VolumeError> {
+        let snapshot = self
+            .meta_data
+            .as_ref()
+            .ok_or(VolumeError::NoDataFound)?
+            .snapshot(snapshot)?;
+        match snapshot.index.get(name.as_bytes()) {
+            Some(entry) if !entry.is_delete_marker() => Ok(entry.id),
+            _ => Err(VolumeError::NoDataFound),
+        }
+    }
+
+    pub fn list_snapshot(
+        &self,
+        snapshot: &str,
+        prefix: &str,
+        delimiter: Option<&str>,
+        start_after: Option<&str>,
+        limit: usize,
+    ) -> Result<ObjectList, VolumeError> {
+        let snapshot = self
+            .meta_data
+            .as_ref()
+            .ok_or(VolumeError::NoDataFound)?
+            .snapshot(snapshot)?;
+        Ok(snapshot.index.list(prefix, delimiter, start_after, limit))
+    }
+
+    // Permanently removes one version, or one delete marker, of `name`.
+    pub fn delete_version(&mut self, name: &str, version: UUID) -> Result<(), VolumeError> {
+        let removed = self
+            .meta_data
+            .as_mut()
+            .unwrap()
+            .index_remove_version(name, version)?
+            .ok_or(VolumeError::NoDataFound)?;
+        self.meta_data.as_mut().unwrap().sync_metadata()?;
+        self.free_entries(&[removed])
+    }
+
+    // An upload is kept in the metadata as a file record under its own id,
+    // with no chunks, and a record for each part holding the part's chunks.
+    // Neither is in the index, so a volume reopened before the upload
+    // completes or is aborted picks it up again from them.
+    pub fn initiate_multipart(&mut self, name: &str) -> Result<UUID, VolumeError> {
+        let upload = MultipartUpload::new(name);
+        let record =
+            RedundantFile::from_chunks(upload.id, name, &[]).map_err(VolumeError::FileError)?;
+        let meta_data = self.meta_data.as_mut().unwrap();
+        meta_data.save_file(record)?;
+        meta_data.sync_metadata()?;
+        let id = upload.id;
+        self.uploads.insert(id, upload);
+        Ok(id)
+    }
+
+    // The upload `id`, read back from its records if it began before the
+    // volume was opened. A part whose chunks didn't all survive is dropped.
+    fn upload(&mut self, id: UUID) -> Result<&mut MultipartUpload, VolumeError> {
+        if !self.uploads.contains_key(&id) {
+            let meta_data = self.meta_data.as_mut().unwrap();
+            // A completed upload's record became its file.
+            if !meta_data.contains_file(id) || meta_data.referenced(id) {
+                return Err(VolumeError::NoSuchUpload(id));
+            }
+            let record = meta_data.load_file(id)?;
+            let len = record
+                .name
+                .iter()
+                .position(|b| *b == 0)
+                .unwrap_or(record.name.len());
+            let mut upload =
+                MultipartUpload::resume(id, &String::from_utf8_lossy(&record.name[..len]));
+            let stores = self.stores.as_ref().unwrap();
+            for number in 1..=MAX_PARTS {
+                let part = upload.part_id(number);
+                if !meta_data.contains_file(part) {
+                    continue;
                 }
+                let chunks = meta_data.load_file(part)?.chunk_ids();
+                let sizes: Result<Vec<u64>, VolumeError> = chunks
+                    .iter()
+                    .map(|c| stores.load_chunk(*c).map(|c| c.chunk_size as u64))
+                    .collect();
+                match sizes {
+                    Ok(sizes) => {
+                        upload.add_part(UploadedPart {
+                            number,
+                            chunks,
+                            size: sizes.iter().sum(),
+                        });
+                    }
+                    Err(VolumeError::NoDataFound) => meta_data.free_file(part)?,
+                    Err(err) => return Err(err),
+                }
+            }
+            meta_data.sync_metadata()?;
+            self.uploads.insert(id, upload);
+        }
+        Ok(self.uploads.get_mut(&id).unwrap())
+    }
+
+    pub fn upload_part<T>(
+        &mut self,
+        upload: UUID,
+        number: u32,
+        reader: &mut T,
+    ) -> Result<(), VolumeError>
+    where
+        T: std::io::Read + Send,
+    {
+        self.upload(upload)?;
+        let part = EncodedPart::encode(number, reader).map_err(VolumeError::FileError)?;
+        self.put_part(upload, part)
+    }
+
+    // Stores a part encoded with `EncodedPart::encode`, replacing any part
+    // previously uploaded with the same number. The part's chunks and its
+    // record go in one transaction, so a part that doesn't commit leaves
+    // nothing behind.
+    pub fn put_part(&mut self, upload: UUID, part: EncodedPart) -> Result<(), VolumeError> {
+        if part.number == 0 || part.number > MAX_PARTS {
+            return Err(VolumeError::BadPartNumber(part.number));
+        }
+        let multipart = self.upload(upload)?;
+        let name = multipart.name.clone();
+        let id = multipart.part_id(part.number);
+        let chunks: Vec<UUID> = part.chunks.iter().map(|c| c.id).collect();
+        let record =
+            RedundantFile::from_chunks(id, &name, &chunks).map_err(VolumeError::FileError)?;
+        self.transaction(id, &chunks, |volume| {
+            for c in part.chunks.iter() {
+                let blocks = part.chunk_blocks(c);
+                let label = ChunkLabel::new(c, &blocks).owned(
+                    id,
+                    name.as_bytes(),
+                    c.position,
+                    chunks.len() as u32,
+                );
+                volume.save_chunk(label, *c, blocks)?;
             }
-            let pos = self.block_file.as_mut().unwrap().allocate_file(c.id)?;
-            self.block_file.as_mut().unwrap().sync_metadata()?;
-            self.block_file.as_mut().unwrap().save_chunk(pos, *c, tmp)?;
+            volume.meta_data.as_mut().unwrap().save_file(record)
+        })?;
+
+        let uploaded = UploadedPart {
+            number: part.number,
+            size: part.size(),
+            chunks,
+        };
+        let replaced = self.uploads.get_mut(&upload).unwrap().add_part(uploaded);
+        if let Some(replaced) = replaced {
+            self.free_chunks(&replaced.chunks)?;
         }
+        Ok(())
+    }
+
+    // Stitches the chunks of every part, in part-number order, into a single
+    // file. Chunks are only renumbered, never re-encoded.
+    pub fn complete_multipart(&mut self, upload: UUID) -> Result<UUID, VolumeError> {
+        let multipart = self.upload(upload)?.clone();
+        let chunks = multipart.chunk_ids();
+        let file = RedundantFile::from_chunks(multipart.id, &multipart.name, &chunks)
+            .map_err(VolumeError::FileError)?;
+        let size = multipart.size();
 
+        // The parts stay the upload's until the file commits, so a failed
+        // commit leaves them for a retry rather than rolling them back. The
+        // file takes the place of the upload's own record.
+        let mut displaced = Vec::new();
+        self.transaction(file.id, &[], |volume| {
+            for (position, id) in chunks.iter().enumerate() {
+                let mut chunk = volume.stores.as_ref().unwrap().load_chunk(*id)?;
+                if chunk.position != position as u32 {
+                    chunk.position = position as u32;
+                    volume.stores.as_mut().unwrap().save_chunk_header(chunk)?;
+                }
+            }
+            volume.label_chunks(&file)?;
+            for part in multipart.parts() {
+                let id = multipart.part_id(part.number);
+                volume.meta_data.as_mut().unwrap().free_file(id)?;
+            }
+            displaced = volume.commit_file(&file, size)?;
+            Ok(())
+        })?;
+
+        self.uploads.remove(&upload);
+        self.free_entries(&displaced)?;
         Ok(file.id)
     }
 
+    // The chunks go first, so an abort cut short leaves records that
+    // another abort finds, rather than chunks nothing points at.
+    pub fn abort_multipart(&mut self, upload: UUID) -> Result<(), VolumeError> {
+        let multipart = self.upload(upload)?.clone();
+        self.free_chunks(&multipart.chunk_ids())?;
+        let meta_data = self.meta_data.as_mut().unwrap();
+        for part in multipart.parts() {
+            meta_data.free_file(multipart.part_id(part.number))?;
+        }
+        meta_data.free_file(upload)?;
+        meta_data.sync_metadata()?;
+        self.uploads.remove(&upload);
+        Ok(())
+    }
+
     pub fn restruct<T>(&mut self, id: UUID, writer: &mut T) -> Result<(), VolumeError>
     where
-        T: std::io::Write,
+        T: std::io::Write + Send,
     {
-        let file = RedundantFile::rebuild(id, self, writer);
-        writer.flush();
-        Ok(())
+        let file = self.get_redundant_file(id)?;
+        self.pipeline.decode(&file.chunk_ids(), self, writer)?;
+        writer.flush().map_err(VolumeError::IoError)
+    }
+
+    // Writes the `len` bytes of object `id` from `offset` on, or as many of
+    // them as it holds. Only the chunks they are in are fetched.
+    pub fn read_range<T>(
+        &self,
+        id: UUID,
+        offset: u64,
+        len: u64,
+        writer: &mut T,
+    ) -> Result<(), VolumeError>
+    where
+        T: std::io::Write + Send,
+    {
+        read_range(self, &self.pipeline, id, offset, len, writer)
+    }
+
+    // Takes the volume apart, for a handle that locks its parts one by one.
+    pub(crate) fn into_parts(self) -> (Box<dyn MetadataStore>, Box<dyn BlockStore>, Pipeline) {
+        (self.meta_data.unwrap(), self.stores.unwrap(), self.pipeline)
+    }
+}
+
+// Writes the `len` bytes of object `id` of `volume` from `offset` on,
+// decoding only the chunks they are in on `pipeline`.
+pub(crate) fn read_range<V, T>(
+    volume: &V,
+    pipeline: &Pipeline,
+    id: UUID,
+    offset: u64,
+    len: u64,
+    writer: &mut T,
+) -> Result<(), VolumeError>
+where
+    V: Volume,
+    T: std::io::Write + Send,
+{
+    let file = volume.get_redundant_file(id)?;
+    let end = offset.saturating_add(len);
+    let mut chunks = Vec::new();
+    let mut skip = 0;
+    let mut at = 0;
+    for chunk in file.chunk_ids() {
+        if at >= end {
+            break;
+        }
+        let size = volume.get_chunk(chunk)?.chunk_size as u64;
+        if at + size > offset {
+            if chunks.is_empty() {
+                skip = offset - at;
+            }
+            chunks.push(chunk);
+        }
+        at += size;
+    }
+    let mut range = Range {
+        writer: &mut *writer,
+        skip,
+        left: len,
+    };
+    pipeline.decode(&chunks, volume, &mut range)?;
+    writer.flush().map_err(VolumeError::IoError)
+}
+
+// Frees the chunks of a write the metadata journal says never committed.
+pub(crate) fn roll_back(
+    meta_data: &mut dyn MetadataStore,
+    stores: &mut dyn BlockStore,
+) -> Result<(), VolumeError> {
+    let chunks = meta_data.rollback().to_vec();
+    if chunks.is_empty() {
+        return Ok(());
+    }
+    for c in chunks.iter() {
+        match stores.free_chunk(*c) {
+            Ok(()) | Err(VolumeError::NoDataFound) => {}
+            Err(err) => return Err(err),
+        }
+    }
+    stores.sync_metadata()?;
+    stores.sync_data()?;
+    meta_data.finish_rollback()
+}
+
+pub(crate) fn free_file(
+    meta_data: &mut dyn MetadataStore,
+    stores: &mut dyn BlockStore,
+    id: UUID,
+) -> Result<(), VolumeError> {
+    let file = meta_data.load_file(id)?;
+    for c in file.chunk_ids() {
+        stores.free_chunk(c)?;
+    }
+    stores.sync_metadata()?;
+    meta_data.free_file(id)?;
+    meta_data.sync_metadata()
+}
+
+// Labels the chunks of `file` as its own, leaving alone those already
+// labelled for another object they are shared with.
+pub(crate) fn label_chunks(
+    stores: &mut dyn BlockStore,
+    file: &RedundantFile,
+) -> Result<(), VolumeError> {
+    let chunks = file.chunk_ids();
+    for n in 0..chunks.len() {
+        label_chunk(stores, file, &chunks, n)?;
+    }
+    Ok(())
+}
+
+// The same for chunk `n` of `chunks`, those of `file`.
+pub(crate) fn label_chunk(
+    stores: &mut dyn BlockStore,
+    file: &RedundantFile,
+    chunks: &[UUID],
+    n: usize,
+) -> Result<(), VolumeError> {
+    let label = match stores.load_label(chunks[n])? {
+        Some(label) => label,
+        None => ChunkLabel::new(&stores.load_chunk(chunks[n])?, &[]),
+    };
+    if label.file != 0 && label.file != file.id {
+        return Ok(());
+    }
+    stores.save_label(label.owned(file.id, &file.name, n as u32, chunks.len() as u32))
+}
+
+// The header of chunk `id`. A damaged one is pieced back together from the
+// chunk's label and from its blocks, which carry their own ids; a block
+// that can't be read is left out, to be rebuilt from the others.
+pub(crate) fn load_chunk(stores: &dyn BlockStore, id: UUID) -> Result<Chunk, VolumeError> {
+    match stores.load_chunk(id) {
+        Err(VolumeError::CorruptChunk(_)) => {}
+        loaded => return loaded,
+    }
+    let label = stores
+        .load_label(id)?
+        .filter(|l| l.chunk == id && l.blocks as usize == BLOCKS && l.parity as usize == PARITY)
+        .ok_or(VolumeError::CorruptChunk(id))?;
+    let mut blocks = [0; BLOCKS + PARITY];
+    for (n, slot) in blocks.iter_mut().enumerate() {
+        if let Ok(block) = stores.load_block(id, n) {
+            if block.position == n && block.crc == label.crcs[n] {
+                *slot = block.id;
+            }
+        }
+    }
+    Ok(Chunk {
+        id,
+        position: label.position,
+        chunk_n: BLOCKS,
+        parity_n: PARITY,
+        chunk_size: label.chunk_size as usize,
+        blocks,
+        hash: label.hash,
+    })
+}
+
+// The blocks of `chunk` that are where it says they are.
+pub(crate) fn chunk_blocks(stores: &dyn BlockStore, chunk: &Chunk) -> Vec<Option<Box<Block>>> {
+    let blocks = match stores.load_blocks(chunk.id) {
+        Ok(blocks) => blocks,
+        Err(_) => return (0..chunk.blocks.len()).map(|_| None).collect(),
+    };
+    (0..chunk.blocks.len())
+        .map(|n| match blocks.get(n) {
+            Some(Some(block)) if block.id == chunk.blocks[n] => Some(Box::new(*block)),
+            _ => None,
+        })
+        .collect()
+}
+
+// Passes on the `left` bytes that come after the first `skip`, and drops
+// the rest.
+struct Range<'a, W> {
+    writer: &'a mut W,
+    skip: u64,
+    left: u64,
+}
+
+impl<W: std::io::Write> std::io::Write for Range<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let from = self.skip.min(buf.len() as u64);
+        let to = from + self.left.min(buf.len() as u64 - from);
+        self.writer.write_all(&buf[from as usize..to as usize])?;
+        self.skip -= from;
+        self.left -= to - from;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.writer.flush()
     }
 }
 
 impl Volume for BigFileVolume {
     fn get_redundant_file(&self, id: UUID) -> Result<Box<RedundantFile>, VolumeError> {
-        //    let file = self.files.get(&id).unwrap();
-        //    Ok(file.clone())
-        Err(VolumeError::GeneralError)
+        let meta_data = self.meta_data.as_ref().ok_or(VolumeError::NoDataFound)?;
+        Ok(Box::new(meta_data.load_file(id)?))
     }
     fn get_chunk(&self, id: UUID) -> Result<Box<Chunk>, VolumeError> {
-        //let chunk = self.chunks.get(&id).unwrap();
-        //Ok(chunk.clone())
-        Err(VolumeError::GeneralError)
+        let stores = self.stores.as_deref().ok_or(VolumeError::NoDataFound)?;
+        Ok(Box::new(load_chunk(stores, id)?))
     }
     fn get_block(&self, id: UUID) -> Result<Box<Block>, VolumeError> {
-        //let block = self.blocks.get(&id).unwrap();
-        //Ok(block.clone())
-        Err(VolumeError::GeneralError)
+        // Blocks are only addressable through their chunk, so a bare block id
+        // needs a scan of every stored chunk header.
+        let stores = self.stores.as_ref().ok_or(VolumeError::NoDataFound)?;
+        for chunk in stores.ids() {
+            let chunk = stores.load_chunk(chunk)?;
+            if let Some(n) = chunk.blocks.iter().position(|b| *b == id) {
+                return Ok(Box::new(stores.load_block(chunk.id, n)?));
+            }
+        }
+        Err(VolumeError::NoDataFound)
+    }
+    fn get_chunk_block(&self, chunk: &Chunk, n: usize) -> Result<Box<Block>, VolumeError> {
+        let stores = self.stores.as_ref().ok_or(VolumeError::NoDataFound)?;
+        let block = stores.load_block(chunk.id, n)?;
+        if block.id != chunk.blocks[n] {
+            return Err(VolumeError::NoDataFound);
+        }
+        Ok(Box::new(block))
+    }
+    fn get_chunk_blocks(&self, chunk: &Chunk) -> Vec<Option<Box<Block>>> {
+        match self.stores.as_deref() {
+            Some(stores) => chunk_blocks(stores, chunk),
+            None => (0..chunk.blocks.len()).map(|_| None).collect(),
+        }
     }
 
     fn destruct_from_file(&mut self, file_name: &str) -> Result<UUID, VolumeError> {
-        let mut file = std::fs::File::open(file_name).unwrap();
+        let mut file = std::fs::File::open(file_name).map_err(VolumeError::IoError)?;
+        let len = file.metadata().map_err(VolumeError::IoError)?.len();
+        let chunks = len.div_ceil(READ_STEP as u64).max(1);
 
-        self.destruct(file_name, &mut file)
+        self.destruct_sized(file_name, &mut file, chunks as u32)
     }
 
     fn restruct_to_file(&mut self, id: UUID, file_name: &str) -> Result<(), VolumeError> {
-        let mut file = std::fs::File::create(file_name).unwrap();
+        let mut file = std::fs::File::create(file_name).map_err(VolumeError::IoError)?;
 
-        self.restruct(id, &mut file);
-        Ok(())
+        self.restruct(id, &mut file)
+    }
+
+    // With versioning on the name keeps its history behind a delete marker;
+    // otherwise every version is removed and its chunks freed.
+    fn delete(&mut self, name: &str) -> Result<(), VolumeError> {
+        let meta_data = self.meta_data.as_mut().ok_or(VolumeError::NoDataFound)?;
+        match meta_data.index_get(name) {
+            Some(entry) if !entry.is_delete_marker() => {}
+            _ => return Err(VolumeError::NoDataFound),
+        }
+        if meta_data.versioning() {
+            meta_data.index_put(IndexEntry::delete_marker(name.as_bytes()))?;
+            meta_data.sync_metadata()
+        } else {
+            let removed = meta_data.index_remove_all(name)?;
+            meta_data.sync_metadata()?;
+            self.free_entries(&removed)
+        }
+    }
+
+    fn list(
+        &self,
+        prefix: &str,
+        delimiter: Option<&str>,
+        start_after: Option<&str>,
+        limit: usize,
+    ) -> Result<ObjectList, VolumeError> {
+        match self.meta_data.as_ref() {
+            Some(meta_data) => meta_data.list(prefix, delimiter, start_after, limit),
+            None => Err(VolumeError::NoDataFound),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::BigFileVolume;
+    use crate::constants::READ_STEP;
+    use crate::error::VolumeError;
+    use crate::label::ChunkLabel;
+    use crate::multipart::{EncodedPart, MultipartUpload};
+    use crate::redundant_file::RedundantFile;
+    use crate::test_util::{with_stack, Scratch};
+
+    fn open(scratch: &Scratch) -> BigFileVolume {
+        BigFileVolume::try_init(&scratch.path("volume.bin"), &scratch.path("block.bin")).unwrap()
+    }
+
+    fn read(volume: &mut BigFileVolume, name: &str) -> Vec<u8> {
+        let id = volume.lookup(name).unwrap();
+        let mut out = Vec::new();
+        volume.restruct(id, &mut out).unwrap();
+        out
+    }
+
+    fn upload_chunks(volume: &mut BigFileVolume, upload: u128) -> Vec<u128> {
+        volume.upload(upload).unwrap().chunk_ids()
+    }
+
+    #[test]
+    fn multipart_parts_go_in_number_order() {
+        with_stack(|| {
+            let scratch = Scratch::new();
+            let mut volume = open(&scratch);
+            let first = vec![1u8; READ_STEP + 10];
+            let second = vec![2u8; 100];
+            let third = vec![3u8; 1000];
+
+            let upload = volume.initiate_multipart("object").unwrap();
+            volume.upload_part(upload, 3, &mut &third[..]).unwrap();
+            volume.upload_part(upload, 1, &mut &first[..]).unwrap();
+            volume.upload_part(upload, 2, &mut &second[..]).unwrap();
+            volume.complete_multipart(upload).unwrap();
+
+            let expected = [first, second, third].concat();
+            assert_eq!(read(&mut volume, "object"), expected);
+            assert!(volume.complete_multipart(upload).is_err());
+        });
+    }
+
+    #[test]
+    fn multipart_part_uploaded_again_replaces_the_old_one() {
+        with_stack(|| {
+            let scratch = Scratch::new();
+            let mut volume = open(&scratch);
+            let upload = volume.initiate_multipart("object").unwrap();
+            volume.upload_part(upload, 1, &mut &[9u8; 500][..]).unwrap();
+            volume.upload_part(upload, 2, &mut &[2u8; 100][..]).unwrap();
+            let replaced = upload_chunks(&mut volume, upload);
+            volume.upload_part(upload, 1, &mut &[1u8; 300][..]).unwrap();
+
+            // The old part's chunk is freed straight away.
+            let stores = volume.stores.as_ref().unwrap();
+            assert!(!stores.contains(replaced[0]));
+            assert!(stores.contains(replaced[1]));
+
+            volume.complete_multipart(upload).unwrap();
+            assert_eq!(
+                read(&mut volume, "object"),
+                [vec![1u8; 300], vec![2u8; 100]].concat()
+            );
+        });
+    }
+
+    #[test]
+    fn multipart_abort_frees_the_parts() {
+        with_stack(|| {
+            let scratch = Scratch::new();
+            let mut volume = open(&scratch);
+            let upload = volume.initiate_multipart("object").unwrap();
+            volume.upload_part(upload, 1, &mut &[1u8; 300][..]).unwrap();
+            let chunks = upload_chunks(&mut volume, upload);
+            volume.abort_multipart(upload).unwrap();
+
+            let stores = volume.stores.as_ref().unwrap();
+            assert!(chunks.iter().all(|c| !stores.contains(*c)));
+            assert!(volume.lookup("object").is_err());
+        });
+    }
+
+    #[test]
+    fn multipart_upload_carries_on_after_reopening() {
+        with_stack(|| {
+            let scratch = Scratch::new();
+            let mut volume = open(&scratch);
+            let upload = volume.initiate_multipart("object").unwrap();
+            volume.upload_part(upload, 2, &mut &[2u8; 100][..]).unwrap();
+            volume.upload_part(upload, 1, &mut &[1u8; 300][..]).unwrap();
+            drop(volume);
+
+            let mut volume = open(&scratch);
+            assert_eq!(upload_chunks(&mut volume, upload).len(), 2);
+            volume.upload_part(upload, 3, &mut &[3u8; 50][..]).unwrap();
+            drop(volume);
+
+            let mut volume = open(&scratch);
+            assert_eq!(volume.complete_multipart(upload).unwrap(), upload);
+            assert_eq!(
+                read(&mut volume, "object"),
+                [vec![1u8; 300], vec![2u8; 100], vec![3u8; 50]].concat()
+            );
+
+            // Nothing of the upload is left but the file.
+            drop(volume);
+            let mut volume = open(&scratch);
+            let meta_data = volume.meta_data.as_ref().unwrap();
+            let multipart = MultipartUpload::resume(upload, "object");
+            assert!((1..=3).all(|n| !meta_data.contains_file(multipart.part_id(n))));
+            assert!(volume.upload(upload).is_err());
+        });
+    }
+
+    #[test]
+    fn multipart_abort_after_reopening_frees_the_parts() {
+        with_stack(|| {
+            let scratch = Scratch::new();
+            let mut volume = open(&scratch);
+            let upload = volume.initiate_multipart("object").unwrap();
+            volume.upload_part(upload, 1, &mut &[1u8; 300][..]).unwrap();
+            volume.upload_part(upload, 2, &mut &[2u8; 300][..]).unwrap();
+            let chunks = upload_chunks(&mut volume, upload);
+            drop(volume);
+
+            let mut volume = open(&scratch);
+            volume.abort_multipart(upload).unwrap();
+            drop(volume);
+
+            let mut volume = open(&scratch);
+            let stores = volume.stores.as_ref().unwrap();
+            assert!(chunks.iter().all(|c| !stores.contains(*c)));
+            let meta_data = volume.meta_data.as_ref().unwrap();
+            let multipart = MultipartUpload::resume(upload, "object");
+            assert!(!meta_data.contains_file(upload));
+            assert!((1..=2).all(|n| !meta_data.contains_file(multipart.part_id(n))));
+            assert!(volume.abort_multipart(upload).is_err());
+        });
+    }
+
+    #[test]
+    fn multipart_part_that_never_committed_is_rolled_back() {
+        with_stack(|| {
+            let scratch = Scratch::new();
+            let mut volume = open(&scratch);
+            let upload = volume.initiate_multipart("object").unwrap();
+            volume.upload_part(upload, 1, &mut &[1u8; 300][..]).unwrap();
+
+            // Part 2 stores its chunks under its journal and stops there.
+            let part = EncodedPart::encode(2, &mut &[2u8; 300][..]).unwrap();
+            let ids: Vec<u128> = part.chunks.iter().map(|c| c.id).collect();
+            let id = volume.upload(upload).unwrap().part_id(2);
+            let meta_data = volume.meta_data.as_mut().unwrap();
+            meta_data.journal_begin(id, &ids).unwrap();
+            for c in part.chunks.iter() {
+                let blocks = part.chunk_blocks(c);
+                let label = ChunkLabel::new(c, &blocks);
+                volume.save_chunk(label, *c, blocks).unwrap();
+            }
+            volume.stores.as_mut().unwrap().sync_metadata().unwrap();
+            drop(volume);
+
+            let mut volume = open(&scratch);
+            let stores = volume.stores.as_ref().unwrap();
+            assert!(ids.iter().all(|c| !stores.contains(*c)));
+            assert_eq!(volume.upload(upload).unwrap().parts().count(), 1);
+
+            // Part 0 would share the upload's own record.
+            let part = EncodedPart::encode(0, &mut &[0u8; 10][..]).unwrap();
+            assert!(matches!(
+                volume.put_part(upload, part),
+                Err(VolumeError::BadPartNumber(0))
+            ));
+        });
+    }
+
+    #[test]
+    fn reopening_rolls_back_a_write_that_never_committed() {
+        with_stack(|| {
+            let scratch = Scratch::new();
+            let mut volume = open(&scratch);
+            volume.destruct("kept", &mut &[7u8; 1000][..]).unwrap();
+
+            // A write that stores its chunks and journals its intent, and
+            // then stops short of committing.
+            let (chunks, blocks) = RedundantFile::encode_chunks(&mut &[8u8; 1000][..]).unwrap();
+            let ids: Vec<u128> = chunks.iter().map(|c| c.id).collect();
+            let file = uuid::Uuid::new_v4().as_u128();
+            let meta_data = volume.meta_data.as_mut().unwrap();
+            meta_data.journal_begin(file, &ids).unwrap();
+            let stores = volume.stores.as_mut().unwrap();
+            for chunk in chunks.iter() {
+                let blocks: Vec<_> = blocks
+                    .iter()
+                    .filter(|b| chunk.blocks.contains(&b.id))
+                    .cloned()
+                    .collect();
+                let label = ChunkLabel::new(chunk, &blocks);
+                stores.save_chunk(label, *chunk, blocks).unwrap();
+            }
+            stores.sync_metadata().unwrap();
+            stores.sync_data().unwrap();
+            assert!(ids.iter().all(|c| stores.contains(*c)));
+            drop(volume);
+
+            let mut volume = open(&scratch);
+            let stores = volume.stores.as_ref().unwrap();
+            assert!(ids.iter().all(|c| !stores.contains(*c)));
+            assert!(volume.meta_data.as_ref().unwrap().rollback().is_empty());
+            assert!(!volume.meta_data.as_ref().unwrap().contains_file(file));
+            assert_eq!(read(&mut volume, "kept"), vec![7u8; 1000]);
+        });
     }
 }
@@ -1,42 +1,119 @@
 use crate::block::Block;
 use crate::chunk::{chunk_block_serialize, Chunk};
+use crate::constants::{BLOCKS, BLOCK_SIZE, PARITY, READ_STEP};
+use crate::crc32c::crc32c;
+use crate::domain::{FailureDomain, DOMAIN_PAGE_BYTES};
 use crate::error::VolumeError;
+use crate::index::{IndexEntry, IndexPage, ObjectIndex, ObjectList, INDEX_PAGE_BYTES};
+use crate::journal::{read_journal, replay_journal, write_journal, JournalRecord, JOURNAL_BYTES};
+use crate::label::{ChunkLabel, LABEL_BYTES};
+use crate::mirror::VolumeFile;
 use crate::redundant_file::RedundantFile;
+use crate::snapshot::{
+    snapshot_pages, Snapshot, SnapshotPage, SnapshotSummary, SNAPSHOT_PAGE_BYTES,
+};
 use crate::UUID;
+use std::collections::HashMap;
 use std::fs::{File, OpenOptions};
-use std::io::{Read, Seek, SeekFrom, Write};
 
-const file_vector_size: usize = 16;
+pub(crate) const file_vector_size: usize = 16;
+pub const VERSIONING: u64 = 1;
+pub const FILE_VECTOR_BYTES: usize = (8 + 16) * file_vector_size + 8;
 
 #[derive(Debug)]
 pub enum FileVolumeManager {
     MetaData {
         path: String,
-        file: Option<File>,
+        file: Option<VolumeFile>,
         super_block: SuperBlock,
         file_vector: Vec<FileVector>,
+        index: ObjectIndex,
+        snapshots: Vec<Snapshot>,
+        snapshot_table: Vec<u64>,
+        free_pages: Vec<u64>,
+        written: HashMap<u64, Vec<u8>>,
+        // The superblock as the copies on disk hold it, whatever became of
+        // any one copy.
+        durable: Box<SuperBlock>,
+        journal: Option<Box<JournalRecord>>,
+        rollback: Vec<UUID>,
     },
 
     BlockFile {
         path: String,
-        file: Option<File>,
+        file: Option<VolumeFile>,
         super_block: SuperBlock,
         chunk_vector: Vec<ChunkVector>,
     },
 }
 
-#[repr(C)]
+pub const SUPER_BLOCK_MAGIC: [u8; 8] = *b"OGGETTO\0";
+pub const FORMAT_VERSION: u32 = 3;
+pub const SUPER_BLOCK_BYTES: usize = 160;
+pub const METADATA_VOLUME: u32 = 1;
+pub const BLOCK_VOLUME: u32 = 2;
+pub const SHARD_VOLUME: u32 = 3;
+
+// The superblock and its backups. Each copy has a 4 KiB sector to itself, so
+// a torn write can't take out two of them; vector pages start after the last.
+pub const SUPER_BLOCK_COPIES: [u64; 3] = [0, 4096, 8192];
+pub const SUPER_BLOCK_AREA: u64 = 3 * 4096;
+
+const GROWTH_STEP: u64 = 1 << 30;
+const SIZE_ALIGN: u64 = 1 << 20;
+
 #[derive(Debug, Copy, Clone)]
 pub struct SuperBlock {
-    file_size: u64,
-    file_vector_start: u64,
+    pub(crate) version: u32,
+    pub(crate) kind: u32,
+    pub(crate) uuid: UUID,
+    pub(crate) created: u64,
+    pub(crate) blocks: u32,
+    pub(crate) parity: u32,
+    pub(crate) block_size: u32,
+    pub(crate) read_step: u32,
+    pub(crate) slot_size: u64,
+    pub(crate) file_size: u64,
+    pub(crate) file_vector_start: u64,
+    pub(crate) index_start: u64,
+    pub(crate) next_free: u64,
+    pub(crate) flags: u64,
+    pub(crate) sequence: u64,
+    pub(crate) snapshot_start: u64,
+    pub(crate) free_page_start: u64,
+    pub(crate) journal_start: u64,
+    pub(crate) journal_size: u64,
+    pub(crate) max_size: u64,
+    pub(crate) store: u16,
+    pub(crate) stores: u16,
 }
 
 impl Default for SuperBlock {
     fn default() -> SuperBlock {
+        let file_vector_start = SUPER_BLOCK_AREA;
         SuperBlock {
-            file_size: u64::pow(8, 9),
-            file_vector_start: std::mem::size_of::<SuperBlock>() as u64,
+            version: FORMAT_VERSION,
+            kind: 0,
+            uuid: 0,
+            created: 0,
+            blocks: BLOCKS as u32,
+            parity: PARITY as u32,
+            block_size: BLOCK_SIZE as u32,
+            read_step: READ_STEP as u32,
+            slot_size: 0,
+            file_size: 0,
+            file_vector_start,
+            index_start: 0,
+            next_free: file_vector_start,
+            flags: 0,
+            sequence: 0,
+            snapshot_start: 0,
+            free_page_start: 0,
+            journal_start: 0,
+            journal_size: 0,
+            max_size: 0,
+            store: 0,
+            stores: 0,
         }
     }
 }
@@ -46,45 +123,170 @@ impl Into<Vec<u8>> for SuperBlock {
         let mut buf: Vec<u8> = Vec::new();
 
         let values: Vec<Vec<u8>> = vec![
+            SUPER_BLOCK_MAGIC.to_vec(),
+            self.version.to_le_bytes().to_vec(),
+            self.kind.to_le_bytes().to_vec(),
+            self.uuid.to_le_bytes().to_vec(),
+            self.created.to_le_bytes().to_vec(),
+            self.blocks.to_le_bytes().to_vec(),
+            self.parity.to_le_bytes().to_vec(),
+            self.block_size.to_le_bytes().to_vec(),
+            self.read_step.to_le_bytes().to_vec(),
+            self.slot_size.to_le_bytes().to_vec(),
             self.file_size.to_le_bytes().to_vec(),
             self.file_vector_start.to_le_bytes().to_vec(),
+            self.index_start.to_le_bytes().to_vec(),
+            self.next_free.to_le_bytes().to_vec(),
+            self.flags.to_le_bytes().to_vec(),
+            self.sequence.to_le_bytes().to_vec(),
+            self.snapshot_start.to_le_bytes().to_vec(),
+            self.free_page_start.to_le_bytes().to_vec(),
+            self.journal_start.to_le_bytes().to_vec(),
+            self.journal_size.to_le_bytes().to_vec(),
+            self.max_size.to_le_bytes().to_vec(),
+            self.store.to_le_bytes().to_vec(),
+            self.stores.to_le_bytes().to_vec(),
         ];
         for v in values {
             for b in v.iter() {
                 buf.push(*b);
             }
         }
+        buf.resize(SUPER_BLOCK_BYTES - 4, 0);
+        let crc = crc32c(&buf[..]);
+        buf.extend_from_slice(&crc.to_le_bytes());
 
         buf
     }
 }
 
-impl From<[u8; std::mem::size_of::<SuperBlock>()]> for SuperBlock {
-    fn from(bytes: [u8; std::mem::size_of::<SuperBlock>()]) -> Self {
-        let mut buf = [0u8; 8];
-        buf.clone_from_slice(&bytes[0..8]);
-        let size: u64 = u64::from_le_bytes(buf);
-
-        buf.clone_from_slice(&bytes[8..16]);
-        let start: u64 = u64::from_le_bytes(buf);
+impl From<[u8; SUPER_BLOCK_BYTES]> for SuperBlock {
+    fn from(bytes: [u8; SUPER_BLOCK_BYTES]) -> Self {
+        let u16_at = |i: usize| {
+            let mut buf = [0u8; 2];
+            buf.clone_from_slice(&bytes[i..i + 2]);
+            u16::from_le_bytes(buf)
+        };
+        let u32_at = |i: usize| {
+            let mut buf = [0u8; 4];
+            buf.clone_from_slice(&bytes[i..i + 4]);
+            u32::from_le_bytes(buf)
+        };
+        let u64_at = |i: usize| {
+            let mut buf = [0u8; 8];
+            buf.clone_from_slice(&bytes[i..i + 8]);
+            u64::from_le_bytes(buf)
+        };
+        let mut buf = [0u8; 16];
+        buf.clone_from_slice(&bytes[16..32]);
 
         SuperBlock {
-            file_size: size,
-            file_vector_start: start,
+            version: u32_at(8),
+            kind: u32_at(12),
+            uuid: u128::from_le_bytes(buf),
+            created: u64_at(32),
+            blocks: u32_at(40),
+            parity: u32_at(44),
+            block_size: u32_at(48),
+            read_step: u32_at(52),
+            slot_size: u64_at(56),
+            file_size: u64_at(64),
+            file_vector_start: u64_at(72),
+            index_start: u64_at(80),
+            next_free: u64_at(88),
+            flags: u64_at(96),
+            sequence: u64_at(104),
+            snapshot_start: u64_at(112),
+            free_page_start: u64_at(120),
+            journal_start: u64_at(128),
+            journal_size: u64_at(136),
+            max_size: u64_at(144),
+            store: u16_at(152),
+            stores: u16_at(154),
+        }
+    }
+}
+
+impl SuperBlock {
+    // Parses one copy as read from disk, refusing anything that isn't an
+    // intact superblock of this format.
+    pub fn parse(bytes: [u8; SUPER_BLOCK_BYTES]) -> Result<SuperBlock, VolumeError> {
+        let sb = SuperBlock::decode(bytes)?;
+        if sb.version != FORMAT_VERSION {
+            return Err(VolumeError::UnsupportedVersion(sb.version));
+        }
+        Ok(sb)
+    }
+
+    // Like `parse`, but takes a superblock of any format version.
+    pub fn decode(bytes: [u8; SUPER_BLOCK_BYTES]) -> Result<SuperBlock, VolumeError> {
+        if bytes[0..8] != SUPER_BLOCK_MAGIC {
+            return Err(VolumeError::BadMagic);
+        }
+        let mut buf = [0u8; 4];
+        buf.copy_from_slice(&bytes[SUPER_BLOCK_BYTES - 4..]);
+        if crc32c(&bytes[..SUPER_BLOCK_BYTES - 4]) != u32::from_le_bytes(buf) {
+            return Err(VolumeError::CorruptSuperBlock);
         }
+        Ok(bytes.into())
+    }
+
+    // Checks that the volume is of the expected kind and was laid out with
+    // the geometry this build encodes with.
+    pub fn check(&self, kind: u32, slot_size: usize) -> Result<(), VolumeError> {
+        if self.kind != kind {
+            return Err(VolumeError::WrongVolumeKind(self.kind));
+        }
+        if self.blocks != BLOCKS as u32
+            || self.parity != PARITY as u32
+            || self.block_size != BLOCK_SIZE as u32
+            || self.read_step != READ_STEP as u32
+            || self.slot_size != slot_size as u64
+        {
+            return Err(VolumeError::GeometryMismatch);
+        }
+        Ok(())
+    }
+
+    pub fn uuid(&self) -> UUID {
+        self.uuid
+    }
+
+    pub fn created(&self) -> u64 {
+        self.created
+    }
+
+    pub fn file_size(&self) -> u64 {
+        self.file_size
+    }
+
+    // The size the file may grow to, or 0 if it may grow as long as there is
+    // room for it.
+    pub fn max_size(&self) -> u64 {
+        self.max_size
+    }
+
+    // Which of the stores of a sharded volume this is, counting from 0.
+    pub fn store(&self) -> usize {
+        self.store as usize
+    }
+
+    // How many stores the volume is spread over, or 0 if it isn't sharded.
+    pub fn stores(&self) -> usize {
+        self.stores as usize
     }
 }
 
 #[derive(Copy, Clone)]
 pub struct FileVector {
-    entries: [(u64, u128); file_vector_size],
-    next_file_vector: u64,
+    pub(crate) entries: [(u64, u128); file_vector_size],
+    pub(crate) next_file_vector: u64,
 }
 
 #[derive(Copy, Clone)]
 pub struct ChunkVector {
-    entries: [(u64, u128); file_vector_size],
-    next_chunk_vector: u64,
+    pub(crate) entries: [(u64, u128); file_vector_size],
+    pub(crate) next_chunk_vector: u64,
 }
 
 impl std::fmt::Debug for FileVector {
@@ -159,8 +361,8 @@ impl Into<Vec<u8>> for FileVector {
     }
 }
 
-impl From<[u8; std::mem::size_of::<FileVector>()]> for FileVector {
-    fn from(bytes: [u8; std::mem::size_of::<FileVector>()]) -> Self {
+impl From<[u8; FILE_VECTOR_BYTES]> for FileVector {
+    fn from(bytes: [u8; FILE_VECTOR_BYTES]) -> Self {
         let mut entries = [(0u64, 0u128); file_vector_size];
         let mut k = 0;
         let mut i = 0;
@@ -208,8 +410,8 @@ impl Into<Vec<u8>> for ChunkVector {
     }
 }
 
-impl From<[u8; std::mem::size_of::<ChunkVector>()]> for ChunkVector {
-    fn from(bytes: [u8; std::mem::size_of::<FileVector>()]) -> Self {
+impl From<[u8; FILE_VECTOR_BYTES]> for ChunkVector {
+    fn from(bytes: [u8; FILE_VECTOR_BYTES]) -> Self {
         let mut entries = [(0u64, 0u128); file_vector_size];
         let mut k = 0;
         let mut i = 0;
@@ -237,259 +439,1502 @@ impl From<[u8; std::mem::size_of::<ChunkVector>()]> for ChunkVector {
     }
 }
 
+fn write_at(file: &Option<VolumeFile>, pos: u64, bytes: &[u8]) -> Result<(), VolumeError> {
+    file.as_ref()
+        .ok_or(VolumeError::NotOpen)?
+        .write_at(pos, bytes)
+}
+
+fn read_at(file: &Option<VolumeFile>, pos: u64, buf: &mut [u8]) -> Result<(), VolumeError> {
+    file.as_ref().ok_or(VolumeError::NotOpen)?.read_at(pos, buf)
+}
+
+fn read_vectors<V>(
+    file: &VolumeFile,
+    start: u64,
+    next: fn(&V) -> u64,
+) -> Result<Vec<V>, VolumeError>
+where
+    V: From<[u8; FILE_VECTOR_BYTES]>,
+{
+    let mut vectors = Vec::new();
+    let mut seek = start;
+    while seek != 0 {
+        let mut buf = [0u8; FILE_VECTOR_BYTES];
+        file.read_at(seek, &mut buf)?;
+        let vector: V = buf.into();
+        seek = next(&vector);
+        vectors.push(vector);
+    }
+    Ok(vectors)
+}
+
+fn read_index(file: &VolumeFile, start: u64) -> Result<ObjectIndex, VolumeError> {
+    let mut pages = Vec::new();
+    let mut seek = start;
+    while seek != 0 {
+        let mut buf = [0u8; INDEX_PAGE_BYTES];
+        file.read_at(seek, &mut buf)?;
+        let page: IndexPage = buf.into();
+        seek = page.next_index_page();
+        pages.push(page);
+    }
+    if pages.is_empty() {
+        pages.push(IndexPage::default());
+    }
+    Ok(ObjectIndex::new(pages))
+}
+
+fn read_snapshots(file: &VolumeFile, start: u64) -> Result<(Vec<Snapshot>, Vec<u64>), VolumeError> {
+    let mut snapshots = Vec::new();
+    let mut table = Vec::new();
+    let mut seek = start;
+    while seek != 0 {
+        let mut buf = [0u8; SNAPSHOT_PAGE_BYTES];
+        file.read_at(seek, &mut buf)?;
+        let page: SnapshotPage = buf.into();
+        table.push(seek);
+        seek = page.next_snapshot_page;
+        for entry in page.entries {
+            snapshots.push(Snapshot {
+                name: entry.name(),
+                created: entry.created,
+                index_start: entry.index_start,
+                index: read_index(file, entry.index_start)?,
+            });
+        }
+    }
+    Ok((snapshots, table))
+}
+
+// Freed pages form a chain through their first eight bytes.
+fn read_free_pages(file: &VolumeFile, start: u64) -> Result<Vec<u64>, VolumeError> {
+    let mut pages = Vec::new();
+    let mut seek = start;
+    while seek != 0 {
+        let mut buf = [0u8; 8];
+        file.read_at(seek, &mut buf)?;
+        pages.push(seek);
+        seek = u64::from_le_bytes(buf);
+    }
+    Ok(pages)
+}
+
+// Index and snapshot table pages all have the same size, so they share one
+// allocator: reuse a freed page if there is one, else grow the high-water mark.
+fn allocate_page(next_free: &mut u64, free_pages: &mut Vec<u64>) -> u64 {
+    match free_pages.pop() {
+        Some(page) => page,
+        None => {
+            let page = *next_free;
+            *next_free += INDEX_PAGE_BYTES as u64;
+            page
+        }
+    }
+}
+
+// Offsets of every page of an index chain, first page first.
+fn index_page_offsets(index: &ObjectIndex, start: u64) -> Vec<u64> {
+    let mut offsets = vec![start];
+    for page in index.pages() {
+        if page.next_index_page() != 0 {
+            offsets.push(page.next_index_page());
+        }
+    }
+    offsets
+}
+
+// Makes the file big enough for everything below the high-water mark. It at
+// least doubles, by up to a gigabyte at a time, so that a growing volume isn't
+// resized on every allocation, but never goes past the volume's size limit.
+fn grow(file: &mut VolumeFile, super_block: &mut SuperBlock) -> Result<(), VolumeError> {
+    let needed = file.size_for(super_block.next_free);
+    if needed <= super_block.file_size {
+        return Ok(());
+    }
+    let limit = match super_block.max_size {
+        0 => u64::MAX,
+        max => max,
+    };
+    if needed > limit {
+        return Err(VolumeError::VolumeFull);
+    }
+    let step = std::cmp::min(super_block.file_size, GROWTH_STEP);
+    let size = std::cmp::max(needed, super_block.file_size + step);
+    let size = std::cmp::min(size.div_ceil(SIZE_ALIGN) * SIZE_ALIGN, limit);
+    file.set_size(size)?;
+    super_block.file_size = size;
+    Ok(())
+}
+
+pub(crate) fn has_domain(super_block: &SuperBlock) -> bool {
+    super_block.kind == SHARD_VOLUME
+        && super_block.file_vector_start >= SUPER_BLOCK_AREA + DOMAIN_PAGE_BYTES as u64
+}
+
+// Where block `n` of a chunk starts in its slot. A shard store only holds the
+// one block of each chunk placed on it.
+fn block_offset(super_block: &SuperBlock, n: usize) -> u64 {
+    match super_block.kind {
+        SHARD_VOLUME => Chunk::block_offset(0),
+        _ => Chunk::block_offset(n),
+    }
+}
+
+// Each vector page is immediately followed by the slots its entries point to.
+pub(crate) fn vector_region(slot_size: usize) -> u64 {
+    (FILE_VECTOR_BYTES + file_vector_size * slot_size) as u64
+}
+
+// Uses the primary copy unless it is damaged, in which case the first intact
+// backup is. When no copy is usable the error of the most telling one is
+// returned: a damaged superblock says more than a missing magic.
+fn read_super_block(
+    file: &VolumeFile,
+    kind: u32,
+    slot_size: usize,
+) -> Result<SuperBlock, VolumeError> {
+    let mut error = VolumeError::BadMagic;
+    for pos in SUPER_BLOCK_COPIES.iter() {
+        let mut buf_sb = [0u8; SUPER_BLOCK_BYTES];
+        if file.read_at(*pos, &mut buf_sb).is_err() {
+            continue;
+        }
+        match SuperBlock::parse(buf_sb) {
+            Ok(sb) => {
+                sb.check(kind, slot_size)?;
+                return Ok(sb);
+            }
+            Err(VolumeError::BadMagic) => {}
+            Err(err) => error = err,
+        }
+    }
+    Err(error)
+}
+
+fn super_block_images(super_block: &SuperBlock) -> Vec<(u64, Vec<u8>)> {
+    let sb_v: Vec<u8> = (*super_block).into();
+    SUPER_BLOCK_COPIES
+        .iter()
+        .map(|pos| (*pos, sb_v.clone()))
+        .collect()
+}
+
+fn now() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+// Images of every metadata page at its offset. Laying out the snapshot table
+// may allocate or free pages, so this runs before the superblock is imaged.
+fn metadata_pages(
+    super_block: &mut SuperBlock,
+    file_vector: &[FileVector],
+    index: &ObjectIndex,
+    snapshots: &[Snapshot],
+    snapshot_table: &mut Vec<u64>,
+    free_pages: &mut Vec<u64>,
+) -> Vec<(u64, Vec<u8>)> {
+    let mut images = Vec::new();
+
+    let mut pages = snapshot_pages(snapshots);
+    if snapshots.is_empty() {
+        pages.clear();
+    }
+    while snapshot_table.len() < pages.len() {
+        let page = allocate_page(&mut super_block.next_free, free_pages);
+        snapshot_table.push(page);
+    }
+    while snapshot_table.len() > pages.len() {
+        free_pages.push(snapshot_table.pop().unwrap());
+    }
+    for (n, page) in pages.into_iter().enumerate() {
+        let mut page = page;
+        page.next_snapshot_page = snapshot_table.get(n + 1).cloned().unwrap_or(0);
+        images.push((snapshot_table[n], page.into()));
+    }
+    super_block.snapshot_start = snapshot_table.first().cloned().unwrap_or(0);
+
+    for (n, page) in free_pages.iter().enumerate() {
+        let next = free_pages.get(n + 1).cloned().unwrap_or(0);
+        images.push((*page, next.to_le_bytes().to_vec()));
+    }
+    super_block.free_page_start = free_pages.first().cloned().unwrap_or(0);
+
+    images.append(&mut super_block_images(super_block));
+
+    let mut seek = super_block.file_vector_start;
+    for b_fv in file_vector.iter() {
+        images.push((seek, (*b_fv).into()));
+        seek = b_fv.next_file_vector;
+    }
+
+    let mut seek = super_block.index_start;
+    for page in index.pages() {
+        images.push((seek, page.clone().into()));
+        seek = page.next_index_page();
+    }
+    images
+}
+
 impl FileVolumeManager {
-    pub fn init_metadata(path: &str) -> Result<FileVolumeManager, VolumeError> {
-        let mut file = File::create(path).map_err(VolumeError::IoError)?;
-        file.set_len(u64::pow(2, 30))
-            .map_err(VolumeError::IoError)?;
-        let mut bytes_representation: Vec<u8> = SuperBlock::default().into();
-        file.write(&bytes_representation[..])
-            .map_err(VolumeError::IoError)?;
-        bytes_representation = FileVector::default().into();
-        file.write(&bytes_representation[..])
+    pub fn init_metadata(path: &str, uuid: UUID) -> Result<FileVolumeManager, VolumeError> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)
             .map_err(VolumeError::IoError)?;
+        let mut file = VolumeFile::mirrored(file);
+        let mut super_block = SuperBlock::default();
+        super_block.kind = METADATA_VOLUME;
+        super_block.uuid = uuid;
+        super_block.created = now();
+        super_block.slot_size = RedundantFile::size() as u64;
+        super_block.index_start =
+            super_block.file_vector_start + vector_region(RedundantFile::size());
+        super_block.journal_start = super_block.index_start + INDEX_PAGE_BYTES as u64;
+        super_block.journal_size = JOURNAL_BYTES;
+        super_block.next_free = super_block.journal_start + JOURNAL_BYTES;
+        grow(&mut file, &mut super_block)?;
+
+        let mut pages = super_block_images(&super_block);
+        pages.push((super_block.file_vector_start, FileVector::default().into()));
+        pages.push((super_block.index_start, IndexPage::default().into()));
+        for (pos, bytes) in pages {
+            file.write_at(pos, &bytes[..])?;
+        }
+        write_journal(
+            &mut file,
+            super_block.journal_start,
+            &JournalRecord::default(),
+        )?;
 
         FileVolumeManager::open_metadata(path)
     }
 
+    // Opening finishes whatever the journal says was in progress: a committed
+    // record is replayed, while the chunks of a write that never committed are
+    // left in `rollback` for the volume to free from its block file.
     pub fn open_metadata(path: &str) -> Result<FileVolumeManager, VolumeError> {
-        let mut file = OpenOptions::new()
+        let file = OpenOptions::new()
             .read(true)
             .write(true)
             .open(path)
             .map_err(VolumeError::IoError)?;
-        let mut buf_sb = [0u8; std::mem::size_of::<SuperBlock>()];
-        file.read_exact(&mut buf_sb).map_err(VolumeError::IoError)?;
-        let sb: SuperBlock = buf_sb.into();
-        let mut buf_fv = [0u8; std::mem::size_of::<FileVector>()];
-
-        file.read_exact(&mut buf_fv).map_err(VolumeError::IoError)?;
-        let fv: FileVector = buf_fv.into();
-        let mut v_fv = Vec::new();
-        v_fv.push(fv);
-        Ok(FileVolumeManager::MetaData {
+        let mut file = VolumeFile::mirrored(file);
+        let mut sb = read_super_block(&file, METADATA_VOLUME, RedundantFile::size())?;
+
+        let mut rollback = Vec::new();
+        let record = read_journal(&file, sb.journal_start)?;
+        if record.committed {
+            replay_journal(&mut file, &record)?;
+            write_journal(&mut file, sb.journal_start, &JournalRecord::default())?;
+            sb = read_super_block(&file, METADATA_VOLUME, RedundantFile::size())?;
+        } else if !record.is_empty() {
+            rollback = record.chunks;
+        }
+        let durable = Box::new(sb);
+        // The file may have grown without the superblock saying so yet.
+        sb.file_size = file.size()?;
+
+        let v_fv = read_vectors(&file, sb.file_vector_start, |fv: &FileVector| {
+            fv.next_file_vector
+        })?;
+        let index = read_index(&file, sb.index_start)?;
+        let (snapshots, snapshot_table) = read_snapshots(&file, sb.snapshot_start)?;
+        let free_pages = read_free_pages(&file, sb.free_page_start)?;
+        let mut copies = Vec::new();
+        for pos in SUPER_BLOCK_COPIES.iter() {
+            let mut buf = vec![0u8; SUPER_BLOCK_BYTES];
+            file.read_at(*pos, &mut buf[..])?;
+            copies.push((*pos, buf));
+        }
+        let mut meta_data = FileVolumeManager::MetaData {
             path: path.to_owned(),
             file: Some(file),
             super_block: sb,
             file_vector: v_fv,
-        })
+            index,
+            snapshots,
+            snapshot_table,
+            free_pages,
+            written: HashMap::new(),
+            durable,
+            journal: None,
+            rollback,
+        };
+        if let FileVolumeManager::MetaData {
+            super_block,
+            file_vector,
+            index,
+            snapshots,
+            snapshot_table,
+            free_pages,
+            written,
+            ..
+        } = &mut meta_data
+        {
+            *written = metadata_pages(
+                super_block,
+                file_vector,
+                index,
+                snapshots,
+                snapshot_table,
+                free_pages,
+            )
+            .into_iter()
+            .collect();
+            // A damaged superblock copy is rewritten by the next commit.
+            for (pos, bytes) in copies {
+                if written.get(&pos) != Some(&bytes) {
+                    written.remove(&pos);
+                }
+            }
+        }
+        Ok(meta_data)
     }
 
-    pub fn init_blockdata(path: &str) -> Result<FileVolumeManager, VolumeError> {
-        let mut file = File::create(path).map_err(VolumeError::IoError)?;
-        file.set_len(u64::pow(2, 30))
-            .map_err(VolumeError::IoError)?;
-        let mut bytes_representation: Vec<u8> = SuperBlock::default().into();
-        file.write(&bytes_representation[..])
-            .map_err(VolumeError::IoError)?;
-        bytes_representation = ChunkVector::default().into();
-        file.write(&bytes_representation[..])
-            .map_err(VolumeError::IoError)?;
+    pub fn init_blockdata(path: &str, uuid: UUID) -> Result<FileVolumeManager, VolumeError> {
+        FileVolumeManager::init_blocks(path, uuid, BLOCK_VOLUME, 0, 0)
+    }
 
-        FileVolumeManager::open_metadata(path)
+    // A store of a sharded volume, which holds one block of each of the
+    // chunks placed on it. Its failure domain follows the superblock.
+    pub fn init_shards(
+        path: &str,
+        uuid: UUID,
+        store: usize,
+        stores: usize,
+        domain: &FailureDomain,
+    ) -> Result<FileVolumeManager, VolumeError> {
+        let mut shards =
+            FileVolumeManager::init_blocks(path, uuid, SHARD_VOLUME, store as u16, stores as u16)?;
+        shards.save_domain(domain)?;
+        Ok(shards)
+    }
+
+    fn init_blocks(
+        path: &str,
+        uuid: UUID,
+        kind: u32,
+        store: u16,
+        stores: u16,
+    ) -> Result<FileVolumeManager, VolumeError> {
+        let file = File::create(path).map_err(VolumeError::IoError)?;
+        let mut file = VolumeFile::plain(file);
+        // A store of a sharded volume has its failure domain right after the
+        // superblock.
+        let (slot_size, file_vector_start) = match kind {
+            SHARD_VOLUME => (
+                Chunk::shard_size(),
+                SUPER_BLOCK_AREA + DOMAIN_PAGE_BYTES as u64,
+            ),
+            _ => (Chunk::size(), SUPER_BLOCK_AREA),
+        };
+        let mut super_block = SuperBlock::default();
+        super_block.kind = kind;
+        super_block.uuid = uuid;
+        super_block.created = now();
+        super_block.store = store;
+        super_block.stores = stores;
+        super_block.slot_size = slot_size as u64;
+        super_block.file_vector_start = file_vector_start;
+        super_block.next_free = super_block.file_vector_start + vector_region(slot_size);
+        grow(&mut file, &mut super_block)?;
+
+        let mut pages = super_block_images(&super_block);
+        pages.push((super_block.file_vector_start, ChunkVector::default().into()));
+        for (pos, bytes) in pages {
+            file.write_at(pos, &bytes[..])?;
+        }
+
+        FileVolumeManager::open_blockdata(path)
     }
 
     pub fn open_blockdata(path: &str) -> Result<FileVolumeManager, VolumeError> {
-        let mut file = OpenOptions::new()
+        let file = OpenOptions::new()
             .read(true)
             .write(true)
             .open(path)
             .map_err(VolumeError::IoError)?;
-        let mut buf_sb = [0u8; std::mem::size_of::<SuperBlock>()];
-        file.read_exact(&mut buf_sb).map_err(VolumeError::IoError)?;
-        let sb: SuperBlock = buf_sb.into();
-        let mut buf_fv = [0u8; std::mem::size_of::<FileVector>()];
-
-        file.read_exact(&mut buf_fv).map_err(VolumeError::IoError)?;
-        let fv: FileVector = buf_fv.into();
-        let mut v_fv = Vec::new();
-        v_fv.push(fv);
-        Ok(FileVolumeManager::MetaData {
+        let file = VolumeFile::plain(file);
+        let mut sb = match read_super_block(&file, BLOCK_VOLUME, Chunk::size()) {
+            Err(VolumeError::WrongVolumeKind(SHARD_VOLUME)) => {
+                read_super_block(&file, SHARD_VOLUME, Chunk::shard_size())?
+            }
+            sb => sb?,
+        };
+        sb.file_size = file.size()?;
+
+        let v_cv = read_vectors(&file, sb.file_vector_start, |cv: &ChunkVector| {
+            cv.next_chunk_vector
+        })?;
+        Ok(FileVolumeManager::BlockFile {
             path: path.to_owned(),
             file: Some(file),
             super_block: sb,
-            file_vector: v_fv,
+            chunk_vector: v_cv,
         })
     }
 
+    // Metadata pages are never written in place without going through the
+    // journal first. Inside a transaction nothing is written until
+    // `journal_commit`.
     pub fn sync_metadata(&mut self) -> Result<(), VolumeError> {
         match self {
-            FileVolumeManager::MetaData {
-                path,
+            FileVolumeManager::MetaData { journal, .. } => {
+                if journal.is_none() {
+                    self.commit_pages(JournalRecord::default())?;
+                }
+            }
+            FileVolumeManager::BlockFile {
                 file,
                 super_block,
-                file_vector,
+                chunk_vector,
+                ..
             } => {
+                for (pos, sb_v) in super_block_images(super_block) {
+                    write_at(file, pos, &sb_v[..])?;
+                }
+
                 let mut seek = super_block.file_vector_start;
-                for b_fv in file_vector {
-                    let mut buf_fv = [0u8; std::mem::size_of::<FileVector>()];
-                    let fv_v: Vec<u8> = b_fv.clone().into();
-                    buf_fv.clone_from_slice(&fv_v[..]);
-                    file.as_mut()
-                        .unwrap()
-                        .seek(SeekFrom::Start(seek))
-                        .map_err(VolumeError::IoError)?;
-                    file.as_mut()
-                        .unwrap()
-                        .write(&buf_fv[..])
-                        .map_err(VolumeError::IoError)?;
-                    file.as_mut()
-                        .unwrap()
-                        .flush()
-                        .map_err(VolumeError::IoError)?;
-                    seek = b_fv.next_file_vector;
+                for b_cv in chunk_vector.iter() {
+                    let cv_v: Vec<u8> = (*b_cv).into();
+                    write_at(file, seek, &cv_v[..])?;
+                    seek = b_cv.next_chunk_vector;
                 }
             }
-            FileVolumeManager::BlockFile {
-                path,
+        }
+        Ok(())
+    }
+
+    // Writes the pages that changed since the last commit, together with
+    // whatever `record` already holds, through the journal.
+    fn commit_pages(&mut self, mut record: JournalRecord) -> Result<(), VolumeError> {
+        if let FileVolumeManager::MetaData {
+            file,
+            super_block,
+            file_vector,
+            index,
+            snapshots,
+            snapshot_table,
+            free_pages,
+            written,
+            durable,
+            ..
+        } = self
+        {
+            let images = metadata_pages(
+                super_block,
+                file_vector,
+                index,
+                snapshots,
+                snapshot_table,
+                free_pages,
+            );
+            record.pages.extend(
+                images
+                    .iter()
+                    .filter(|(pos, bytes)| written.get(pos) != Some(bytes))
+                    .cloned(),
+            );
+            if record.is_empty() {
+                return Ok(());
+            }
+            record.committed = true;
+            let file = file.as_mut().ok_or(VolumeError::NotOpen)?;
+
+            // A record that doesn't fit moves the journal to a bigger region.
+            // The superblock on disk has to point there before the record is
+            // written, so it gets the new location straight away.
+            let relocate = record.size() > super_block.journal_size;
+            if relocate {
+                let size = std::cmp::max(record.size() * 2, JOURNAL_BYTES);
+                super_block.journal_start = super_block.next_free;
+                super_block.journal_size = size;
+                super_block.next_free += size;
+            }
+            // Whatever was allocated since the last commit has to fit before
+            // anything is written.
+            let file_size = super_block.file_size;
+            grow(file, super_block)?;
+            if relocate {
+                durable.journal_start = super_block.journal_start;
+                durable.journal_size = super_block.journal_size;
+                for (pos, sb_v) in super_block_images(durable) {
+                    file.write_at(pos, &sb_v[..])?;
+                }
+                file.sync_data()?;
+            }
+            if relocate || super_block.file_size != file_size {
+                record
+                    .pages
+                    .retain(|(pos, _)| !SUPER_BLOCK_COPIES.contains(pos));
+                record.pages.append(&mut super_block_images(super_block));
+            }
+
+            write_journal(file, super_block.journal_start, &record)?;
+            replay_journal(file, &record)?;
+            write_journal(file, super_block.journal_start, &JournalRecord::default())?;
+
+            *written = images.into_iter().collect();
+            written.extend(super_block_images(super_block));
+            **durable = *super_block;
+        }
+        Ok(())
+    }
+
+    // Starts writing object `file` made of `chunks`. Until `journal_commit`
+    // metadata changes stay in memory, and a crash leaves the chunks to be
+    // rolled back by the next open.
+    pub fn journal_begin(&mut self, id: UUID, chunks: &[UUID]) -> Result<(), VolumeError> {
+        match self {
+            FileVolumeManager::MetaData {
                 file,
                 super_block,
-                chunk_vector,
+                journal,
+                ..
             } => {
-                let mut seek = super_block.file_vector_start;
-                for b_fv in chunk_vector {
-                    let mut buf_fv = [0u8; std::mem::size_of::<FileVector>()];
-                    let fv_v: Vec<u8> = b_fv.clone().into();
-                    buf_fv.clone_from_slice(&fv_v[..]);
-                    file.as_mut()
-                        .unwrap()
-                        .seek(SeekFrom::Start(seek))
-                        .map_err(VolumeError::IoError)?;
-                    file.as_mut()
-                        .unwrap()
-                        .write(&buf_fv[..])
-                        .map_err(VolumeError::IoError)?;
-                    file.as_mut()
-                        .unwrap()
-                        .flush()
-                        .map_err(VolumeError::IoError)?;
-                    seek = b_fv.next_chunk_vector;
+                if journal.is_some() {
+                    return Err(VolumeError::PendingJournal);
                 }
+                let record = JournalRecord::intent(id, chunks);
+                let file = file.as_mut().ok_or(VolumeError::NotOpen)?;
+                write_journal(file, super_block.journal_start, &record)?;
+                *journal = Some(Box::new(record));
+                Ok(())
+            }
+            FileVolumeManager::BlockFile { super_block, .. } => {
+                Err(VolumeError::WrongVolumeKind(super_block.kind))
             }
         }
+    }
+
+    pub fn journal_extend(&mut self, id: UUID, chunks: &[UUID]) -> Result<(), VolumeError> {
+        match self {
+            FileVolumeManager::MetaData {
+                file,
+                super_block,
+                journal,
+                ..
+            } => {
+                let record = journal.as_mut().ok_or(VolumeError::NoJournal)?;
+                record.file = id;
+                record.chunks.extend_from_slice(chunks);
+                let file = file.as_mut().ok_or(VolumeError::NotOpen)?;
+                write_journal(file, super_block.journal_start, record)
+            }
+            FileVolumeManager::BlockFile { super_block, .. } => {
+                Err(VolumeError::WrongVolumeKind(super_block.kind))
+            }
+        }
+    }
+
+    pub fn journal_commit(&mut self) -> Result<(), VolumeError> {
+        let record = match self {
+            FileVolumeManager::MetaData { journal, .. } => {
+                *journal.take().ok_or(VolumeError::NoJournal)?
+            }
+            FileVolumeManager::BlockFile { super_block, .. } => {
+                return Err(VolumeError::WrongVolumeKind(super_block.kind))
+            }
+        };
+        self.commit_pages(record)
+    }
+
+    // Chunk ids of a write interrupted before it committed.
+    pub fn rollback(&self) -> &[UUID] {
+        match self {
+            FileVolumeManager::MetaData { rollback, .. } => &rollback[..],
+            FileVolumeManager::BlockFile { .. } => &[],
+        }
+    }
+
+    // Called once the chunks in `rollback` are gone from the block file.
+    pub fn finish_rollback(&mut self) -> Result<(), VolumeError> {
+        if let FileVolumeManager::MetaData {
+            file,
+            super_block,
+            rollback,
+            ..
+        } = self
+        {
+            let file = file.as_mut().ok_or(VolumeError::NotOpen)?;
+            write_journal(file, super_block.journal_start, &JournalRecord::default())?;
+            rollback.clear();
+        }
         Ok(())
     }
 
+    pub fn path(&self) -> &str {
+        match self {
+            FileVolumeManager::MetaData { path, .. } => path,
+            FileVolumeManager::BlockFile { path, .. } => path,
+        }
+    }
+
+    pub fn super_block(&self) -> &SuperBlock {
+        match self {
+            FileVolumeManager::MetaData { super_block, .. } => super_block,
+            FileVolumeManager::BlockFile { super_block, .. } => super_block,
+        }
+    }
+
+    // Rewrites every superblock copy from the one in use, repairing damaged
+    // backups. Only safe while no transaction is open.
+    pub fn write_super_blocks(&mut self) -> Result<(), VolumeError> {
+        let (file, super_block) = match self {
+            FileVolumeManager::MetaData {
+                file, super_block, ..
+            } => (file, super_block),
+            FileVolumeManager::BlockFile {
+                file, super_block, ..
+            } => (file, super_block),
+        };
+        for (pos, sb_v) in super_block_images(super_block) {
+            write_at(file, pos, &sb_v[..])?;
+        }
+        Ok(())
+    }
+
+    // Bytes of the file in use, up to and including the last allocation.
+    pub fn used(&self) -> u64 {
+        let (file, super_block) = match self {
+            FileVolumeManager::MetaData {
+                file, super_block, ..
+            } => (file, super_block),
+            FileVolumeManager::BlockFile {
+                file, super_block, ..
+            } => (file, super_block),
+        };
+        file.as_ref()
+            .map(|f| f.size_for(super_block.next_free))
+            .unwrap_or(super_block.next_free)
+    }
+
+    // Sets the size of the file and the size it may grow to, 0 for no limit.
+    // A file can't shrink below what is in use, and one bigger than a new
+    // limit is shrunk to it. The superblock is written by the next sync.
+    pub fn resize(&mut self, size: Option<u64>, max_size: Option<u64>) -> Result<(), VolumeError> {
+        let used = self.used();
+        let (file, super_block) = match self {
+            FileVolumeManager::MetaData {
+                file, super_block, ..
+            } => (file, super_block),
+            FileVolumeManager::BlockFile {
+                file, super_block, ..
+            } => (file, super_block),
+        };
+        let max_size = max_size.unwrap_or(super_block.max_size);
+        let mut size = size.unwrap_or(super_block.file_size);
+        if max_size != 0 {
+            size = std::cmp::min(size, max_size);
+        }
+        if size < used {
+            return Err(VolumeError::TooSmall(used));
+        }
+        file.as_mut().ok_or(VolumeError::NotOpen)?.set_size(size)?;
+        super_block.file_size = size;
+        super_block.max_size = max_size;
+        Ok(())
+    }
+
+    // Rewrites both copies of the metadata sector at `pos` from the intact
+    // one.
+    pub fn rewrite_sector(&mut self, pos: u64) -> Result<(), VolumeError> {
+        match self {
+            FileVolumeManager::MetaData { file, .. } => {
+                file.as_mut().ok_or(VolumeError::NotOpen)?.rewrite(pos)
+            }
+            FileVolumeManager::BlockFile { super_block, .. } => {
+                Err(VolumeError::WrongVolumeKind(super_block.kind))
+            }
+        }
+    }
+
+    // The failure domain of a store of a sharded volume, which older stores
+    // don't have room for.
+    pub fn load_domain(&self) -> Result<Option<FailureDomain>, VolumeError> {
+        match self {
+            FileVolumeManager::BlockFile {
+                file, super_block, ..
+            } if has_domain(super_block) => {
+                let mut buf = vec![0u8; DOMAIN_PAGE_BYTES];
+                read_at(file, SUPER_BLOCK_AREA, &mut buf[..])?;
+                match FailureDomain::decode(&buf[..]) {
+                    Some(domain) => Ok(Some(domain)),
+                    None => Err(VolumeError::CorruptMetadata(SUPER_BLOCK_AREA)),
+                }
+            }
+            _ => Ok(None),
+        }
+    }
+
+    pub fn save_domain(&mut self, domain: &FailureDomain) -> Result<(), VolumeError> {
+        match self {
+            FileVolumeManager::BlockFile {
+                file, super_block, ..
+            } if has_domain(super_block) => write_at(file, SUPER_BLOCK_AREA, &domain.encode()?[..]),
+            FileVolumeManager::BlockFile { super_block, .. } => {
+                Err(VolumeError::WrongVolumeKind(super_block.kind))
+            }
+            FileVolumeManager::MetaData { .. } => {
+                Err(VolumeError::WrongVolumeKind(METADATA_VOLUME))
+            }
+        }
+    }
+
+    // Gives a store of a sharded volume a new number out of `stores`, as
+    // when a store is added or one is detached. The superblock is written by
+    // the next sync.
+    pub fn renumber(&mut self, store: usize, stores: usize) -> Result<(), VolumeError> {
+        match self {
+            FileVolumeManager::BlockFile { super_block, .. }
+                if super_block.kind == SHARD_VOLUME =>
+            {
+                super_block.store = store as u16;
+                super_block.stores = stores as u16;
+                Ok(())
+            }
+            FileVolumeManager::BlockFile { super_block, .. } => {
+                Err(VolumeError::WrongVolumeKind(super_block.kind))
+            }
+            FileVolumeManager::MetaData { .. } => {
+                Err(VolumeError::WrongVolumeKind(METADATA_VOLUME))
+            }
+        }
+    }
+
+    pub fn sync_data(&mut self) -> Result<(), VolumeError> {
+        let file = match self {
+            FileVolumeManager::MetaData { file, .. } => file,
+            FileVolumeManager::BlockFile { file, .. } => file,
+        };
+        file.as_mut().ok_or(VolumeError::NotOpen)?.sync_data()
+    }
+
     pub fn allocate_file(&mut self, id: UUID) -> Result<u64, VolumeError> {
         match self {
             FileVolumeManager::MetaData {
-                path,
                 file,
                 super_block,
                 file_vector,
+                ..
             } => {
-                let mut pos = 0;
-                let mut pos_start =
-                    super_block.file_vector_start + (std::mem::size_of::<FileVector>() as u64);
+                let slot_size = RedundantFile::size();
+                let mut pos_start = super_block.file_vector_start;
 
                 for file_vector in file_vector.iter_mut() {
                     for (n, data) in file_vector.entries.iter_mut().enumerate() {
                         if *data == (0u64, 0u128) {
-                            pos = pos_start + ((n * RedundantFile::size()) as u64);
-                            data.0 = pos;
-                            data.1 = id;
-
-                            break;
+                            let pos = pos_start + (FILE_VECTOR_BYTES + n * slot_size) as u64;
+                            *data = (pos, id);
+                            return Ok(pos);
                         }
                     }
+                    if file_vector.next_file_vector == 0 {
+                        break;
+                    }
                     pos_start = file_vector.next_file_vector;
                 }
-                return Ok(pos);
+
+                let new_start = super_block.next_free;
+                super_block.next_free += vector_region(slot_size);
+                let file = file.as_mut().ok_or(VolumeError::NotOpen)?;
+                if let Err(err) = grow(file, super_block) {
+                    super_block.next_free = new_start;
+                    return Err(err);
+                }
+                if let Some(last) = file_vector.last_mut() {
+                    last.next_file_vector = new_start;
+                }
+                let mut new_vector = FileVector::default();
+                let pos = new_start + FILE_VECTOR_BYTES as u64;
+                new_vector.entries[0] = (pos, id);
+                file_vector.push(new_vector);
+                Ok(pos)
             }
             FileVolumeManager::BlockFile {
-                path,
                 file,
                 super_block,
                 chunk_vector,
+                ..
             } => {
-                let mut pos = 0;
-                let mut pos_start =
-                    super_block.file_vector_start + (std::mem::size_of::<FileVector>() as u64);
+                let slot_size = super_block.slot_size as usize;
+                let mut pos_start = super_block.file_vector_start;
 
                 for chunk_vector in chunk_vector.iter_mut() {
                     for (n, data) in chunk_vector.entries.iter_mut().enumerate() {
                         if *data == (0u64, 0u128) {
-                            pos = pos_start + ((n * Chunk::size()) as u64);
-                            data.0 = pos;
-                            data.1 = id;
-
-                            break;
+                            let pos = pos_start + (FILE_VECTOR_BYTES + n * slot_size) as u64;
+                            *data = (pos, id);
+                            return Ok(pos);
                         }
                     }
+                    if chunk_vector.next_chunk_vector == 0 {
+                        break;
+                    }
                     pos_start = chunk_vector.next_chunk_vector;
                 }
-                return Ok(pos);
+
+                let new_start = super_block.next_free;
+                super_block.next_free += vector_region(slot_size);
+                let file = file.as_mut().ok_or(VolumeError::NotOpen)?;
+                if let Err(err) = grow(file, super_block) {
+                    super_block.next_free = new_start;
+                    return Err(err);
+                }
+                if let Some(last) = chunk_vector.last_mut() {
+                    last.next_chunk_vector = new_start;
+                }
+                let mut new_vector = ChunkVector::default();
+                let pos = new_start + FILE_VECTOR_BYTES as u64;
+                new_vector.entries[0] = (pos, id);
+                chunk_vector.push(new_vector);
+                Ok(pos)
             }
         }
-        Ok(0u64)
     }
 
     pub fn save_file(&mut self, pos: u64, rf: RedundantFile) -> Result<(), VolumeError> {
         match self {
-            FileVolumeManager::MetaData {
-                path,
-                file,
-                super_block,
-                file_vector,
-            } => {
+            FileVolumeManager::MetaData { file, journal, .. } => {
                 let fv_v: Vec<u8> = bincode::serialize(&rf).unwrap();
-                file.as_mut()
-                    .unwrap()
-                    .seek(SeekFrom::Start(pos))
-                    .map_err(VolumeError::IoError)?;
-                file.as_mut()
-                    .unwrap()
-                    .write(&fv_v[..])
-                    .map_err(VolumeError::IoError)?;
-                file.as_mut()
-                    .unwrap()
-                    .flush()
-                    .map_err(VolumeError::IoError)?;
+                match journal {
+                    Some(record) => record.pages.push((pos, fv_v)),
+                    None => write_at(file, pos, &fv_v[..])?,
+                }
             }
-            FileVolumeManager::BlockFile {
-                path,
-                file,
-                super_block,
-                chunk_vector,
-            } => {}
+            FileVolumeManager::BlockFile { .. } => {}
         }
         Ok(())
     }
 
     pub fn save_chunk(
-        &mut self,
+        &self,
         pos: u64,
+        label: ChunkLabel,
         chunk: Chunk,
         blocks: Vec<Block>,
     ) -> Result<(), VolumeError> {
+        match self {
+            FileVolumeManager::MetaData { .. } => {}
+            FileVolumeManager::BlockFile { file, .. } => {
+                let mut fv_v: Vec<u8> = label.into();
+                fv_v.append(&mut chunk_block_serialize(&chunk, &blocks));
+                write_at(file, pos, &fv_v[..])?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn save_chunk_header(&mut self, chunk: Chunk) -> Result<(), VolumeError> {
+        let pos = self.find(chunk.id).ok_or(VolumeError::NoDataFound)?;
+        match self {
+            FileVolumeManager::MetaData { super_block, .. } => {
+                Err(VolumeError::WrongVolumeKind(super_block.kind))
+            }
+            FileVolumeManager::BlockFile { file, .. } => {
+                let header: Vec<u8> = bincode::serialize(&chunk).unwrap();
+                write_at(file, pos + LABEL_BYTES as u64, &header[..])
+            }
+        }
+    }
+
+    // The label in front of chunk `id`, or None if it doesn't parse.
+    pub fn load_label(&self, id: UUID) -> Result<Option<ChunkLabel>, VolumeError> {
+        let pos = self.find(id).ok_or(VolumeError::NoDataFound)?;
+        match self {
+            FileVolumeManager::BlockFile { file, .. } => {
+                let mut buf = vec![0u8; LABEL_BYTES];
+                read_at(file, pos, &mut buf[..])?;
+                Ok(ChunkLabel::parse(&buf[..]))
+            }
+            FileVolumeManager::MetaData { super_block, .. } => {
+                Err(VolumeError::WrongVolumeKind(super_block.kind))
+            }
+        }
+    }
+
+    pub fn save_label(&mut self, label: ChunkLabel) -> Result<(), VolumeError> {
+        let pos = self.find(label.chunk).ok_or(VolumeError::NoDataFound)?;
+        match self {
+            FileVolumeManager::MetaData { super_block, .. } => {
+                Err(VolumeError::WrongVolumeKind(super_block.kind))
+            }
+            FileVolumeManager::BlockFile { file, .. } => {
+                let label_v: Vec<u8> = label.into();
+                write_at(file, pos, &label_v[..])
+            }
+        }
+    }
+
+    pub fn versioning(&self) -> bool {
+        match self {
+            FileVolumeManager::MetaData { super_block, .. } => super_block.flags & VERSIONING != 0,
+            FileVolumeManager::BlockFile { .. } => false,
+        }
+    }
+
+    pub fn set_versioning(&mut self, enabled: bool) -> Result<(), VolumeError> {
+        match self {
+            FileVolumeManager::MetaData { super_block, .. } => {
+                if enabled {
+                    super_block.flags |= VERSIONING;
+                } else {
+                    super_block.flags &= !VERSIONING;
+                }
+                Ok(())
+            }
+            FileVolumeManager::BlockFile { super_block, .. } => {
+                Err(VolumeError::WrongVolumeKind(super_block.kind))
+            }
+        }
+    }
+
+    // Records `entry` as the newest version of its name and returns the
+    // entries it displaces: none with versioning on, otherwise the version
+    // that was current. History kept while versioning was on survives.
+    pub fn index_put(&mut self, mut entry: IndexEntry) -> Result<Vec<IndexEntry>, VolumeError> {
         match self {
             FileVolumeManager::MetaData {
-                path,
-                file,
                 super_block,
-                file_vector,
+                index,
+                free_pages,
+                ..
+            } => {
+                super_block.sequence += 1;
+                entry.version = super_block.sequence;
+                let current = index.get(entry.key()).map(|e| e.id);
+                let displaced = match current {
+                    Some(id) if super_block.flags & VERSIONING == 0 => {
+                        let free = &mut |page| free_pages.push(page);
+                        index
+                            .remove_version(entry.key(), id, free)
+                            .into_iter()
+                            .collect()
+                    }
+                    _ => Vec::new(),
+                };
+                let next_free = &mut super_block.next_free;
+                index.insert(entry, &mut || allocate_page(next_free, free_pages));
+                Ok(displaced)
+            }
+            FileVolumeManager::BlockFile { super_block, .. } => {
+                Err(VolumeError::WrongVolumeKind(super_block.kind))
+            }
+        }
+    }
+
+    // Adds `entry` as the newest version of its name without displacing
+    // anything, the way a replicated volume mirrors its source.
+    pub fn index_restore(&mut self, mut entry: IndexEntry) -> Result<(), VolumeError> {
+        match self {
+            FileVolumeManager::MetaData {
+                super_block,
+                index,
+                free_pages,
+                ..
+            } => {
+                super_block.sequence += 1;
+                entry.version = super_block.sequence;
+                let next_free = &mut super_block.next_free;
+                index.insert(entry, &mut || allocate_page(next_free, free_pages));
+                Ok(())
+            }
+            FileVolumeManager::BlockFile { super_block, .. } => {
+                Err(VolumeError::WrongVolumeKind(super_block.kind))
+            }
+        }
+    }
+
+    // Adds `entry` keeping the version it already has, as when importing the
+    // index of a volume written in an older layout.
+    pub fn index_import(&mut self, entry: IndexEntry) -> Result<(), VolumeError> {
+        match self {
+            FileVolumeManager::MetaData {
+                super_block,
+                index,
+                free_pages,
+                ..
+            } => {
+                super_block.sequence = std::cmp::max(super_block.sequence, entry.version);
+                let next_free = &mut super_block.next_free;
+                index.insert(entry, &mut || allocate_page(next_free, free_pages));
+                Ok(())
+            }
+            FileVolumeManager::BlockFile { super_block, .. } => {
+                Err(VolumeError::WrongVolumeKind(super_block.kind))
+            }
+        }
+    }
+
+    pub fn index_entries(&self) -> Vec<IndexEntry> {
+        match self {
+            FileVolumeManager::MetaData { index, .. } => index.iter().cloned().collect(),
+            FileVolumeManager::BlockFile { .. } => Vec::new(),
+        }
+    }
+
+    pub fn index_remove_all(&mut self, name: &str) -> Result<Vec<IndexEntry>, VolumeError> {
+        match self {
+            FileVolumeManager::MetaData {
+                index, free_pages, ..
+            } => Ok(index.remove_all(name.as_bytes(), &mut |page| free_pages.push(page))),
+            FileVolumeManager::BlockFile { super_block, .. } => {
+                Err(VolumeError::WrongVolumeKind(super_block.kind))
+            }
+        }
+    }
+
+    pub fn index_remove_version(
+        &mut self,
+        name: &str,
+        id: UUID,
+    ) -> Result<Option<IndexEntry>, VolumeError> {
+        match self {
+            FileVolumeManager::MetaData {
+                index, free_pages, ..
+            } => Ok(index.remove_version(name.as_bytes(), id, &mut |page| free_pages.push(page))),
+            FileVolumeManager::BlockFile { super_block, .. } => {
+                Err(VolumeError::WrongVolumeKind(super_block.kind))
+            }
+        }
+    }
+
+    pub fn index_get_version(&self, name: &str, id: UUID) -> Option<IndexEntry> {
+        match self {
+            FileVolumeManager::MetaData { index, .. } => {
+                index.get_version(name.as_bytes(), id).cloned()
+            }
+            FileVolumeManager::BlockFile { .. } => None,
+        }
+    }
+
+    pub fn list_versions(
+        &self,
+        prefix: &str,
+        start_after: Option<&str>,
+        limit: usize,
+    ) -> Result<ObjectList, VolumeError> {
+        match self {
+            FileVolumeManager::MetaData { index, .. } => {
+                Ok(index.list_versions(prefix, start_after, limit))
+            }
+            FileVolumeManager::BlockFile { super_block, .. } => {
+                Err(VolumeError::WrongVolumeKind(super_block.kind))
+            }
+        }
+    }
+
+    pub fn list(
+        &self,
+        prefix: &str,
+        delimiter: Option<&str>,
+        start_after: Option<&str>,
+        limit: usize,
+    ) -> Result<ObjectList, VolumeError> {
+        match self {
+            FileVolumeManager::MetaData { index, .. } => {
+                Ok(index.list(prefix, delimiter, start_after, limit))
+            }
+            FileVolumeManager::BlockFile { super_block, .. } => {
+                Err(VolumeError::WrongVolumeKind(super_block.kind))
+            }
+        }
+    }
+
+    pub fn find(&self, id: UUID) -> Option<u64> {
+        if id == 0 {
+            return None;
+        }
+        match self {
+            FileVolumeManager::MetaData { file_vector, .. } => file_vector
+                .iter()
+                .flat_map(|fv| fv.entries.iter())
+                .find(|e| e.1 == id)
+                .map(|e| e.0),
+            FileVolumeManager::BlockFile { chunk_vector, .. } => chunk_vector
+                .iter()
+                .flat_map(|cv| cv.entries.iter())
+                .find(|e| e.1 == id)
+                .map(|e| e.0),
+        }
+    }
+
+    pub fn ids(&self) -> Vec<UUID> {
+        match self {
+            FileVolumeManager::MetaData { file_vector, .. } => file_vector
+                .iter()
+                .flat_map(|fv| fv.entries.iter())
+                .filter(|e| e.1 != 0)
+                .map(|e| e.1)
+                .collect(),
+            FileVolumeManager::BlockFile { chunk_vector, .. } => chunk_vector
+                .iter()
+                .flat_map(|cv| cv.entries.iter())
+                .filter(|e| e.1 != 0)
+                .map(|e| e.1)
+                .collect(),
+        }
+    }
+
+    // Releases the slot held by `id`; its position is derived from the entry
+    // index, so the next allocation in that entry reuses the same space.
+    pub fn free_file(&mut self, id: UUID) -> Result<(), VolumeError> {
+        let entry = match self {
+            FileVolumeManager::MetaData { file_vector, .. } => file_vector
+                .iter_mut()
+                .flat_map(|fv| fv.entries.iter_mut())
+                .find(|e| e.1 == id),
+            FileVolumeManager::BlockFile { chunk_vector, .. } => chunk_vector
+                .iter_mut()
+                .flat_map(|cv| cv.entries.iter_mut())
+                .find(|e| e.1 == id),
+        };
+        match entry {
+            Some(entry) => {
+                *entry = (0u64, 0u128);
+                Ok(())
+            }
+            None => Err(VolumeError::NoDataFound),
+        }
+    }
+
+    pub fn load_file(&self, id: UUID) -> Result<RedundantFile, VolumeError> {
+        let pos = self.find(id).ok_or(VolumeError::NoDataFound)?;
+        match self {
+            FileVolumeManager::MetaData { file, .. } => {
+                let mut buf = vec![0u8; RedundantFile::size()];
+                read_at(file, pos, &mut buf[..])?;
+                bincode::deserialize(&buf[..]).map_err(|_| VolumeError::CorruptFile(id))
+            }
+            FileVolumeManager::BlockFile { super_block, .. } => {
+                Err(VolumeError::WrongVolumeKind(super_block.kind))
+            }
+        }
+    }
+
+    pub fn load_chunk(&self, id: UUID) -> Result<Chunk, VolumeError> {
+        let pos = self.find(id).ok_or(VolumeError::NoDataFound)?;
+        match self {
+            FileVolumeManager::BlockFile { file, .. } => {
+                let mut buf = vec![0u8; Chunk::header_size()];
+                read_at(file, pos + LABEL_BYTES as u64, &mut buf[..])?;
+                bincode::deserialize::<Chunk>(&buf[..])
+                    .ok()
+                    .filter(|chunk| chunk.is_sound(id))
+                    .ok_or(VolumeError::CorruptChunk(id))
+            }
+            FileVolumeManager::MetaData { super_block, .. } => {
+                Err(VolumeError::WrongVolumeKind(super_block.kind))
+            }
+        }
+    }
+
+    pub fn load_block(&self, chunk: UUID, n: usize) -> Result<Block, VolumeError> {
+        let pos = self.find(chunk).ok_or(VolumeError::NoDataFound)?;
+        match self {
+            FileVolumeManager::BlockFile {
+                file, super_block, ..
             } => {
-                let fv_v: Vec<u8> = chunk_block_serialize(&chunk, &blocks);
-                file.as_mut()
-                    .unwrap()
-                    .seek(SeekFrom::Start(pos))
-                    .map_err(VolumeError::IoError)?;
-                file.as_mut()
-                    .unwrap()
-                    .write(&fv_v[..])
-                    .map_err(VolumeError::IoError)?;
-                file.as_mut()
-                    .unwrap()
-                    .flush()
-                    .map_err(VolumeError::IoError)?;
+                let mut buf = vec![0u8; Block::size() as usize];
+                let block_pos = pos + block_offset(super_block, n);
+                read_at(file, block_pos, &mut buf[..])?;
+                bincode::deserialize(&buf[..]).map_err(|_| VolumeError::CorruptBlock(chunk, n))
             }
+            FileVolumeManager::MetaData { super_block, .. } => {
+                Err(VolumeError::WrongVolumeKind(super_block.kind))
+            }
+        }
+    }
+
+    // Overwrites block `n` of `chunk` in place, as when repairing it from the
+    // other blocks of the chunk.
+    pub fn save_block(&mut self, chunk: UUID, n: usize, block: Block) -> Result<(), VolumeError> {
+        let pos = self.find(chunk).ok_or(VolumeError::NoDataFound)?;
+        match self {
             FileVolumeManager::BlockFile {
-                path,
+                file, super_block, ..
+            } => {
+                let block_v: Vec<u8> = bincode::serialize(&block).unwrap();
+                let block_pos = pos + block_offset(super_block, n);
+                write_at(file, block_pos, &block_v[..])
+            }
+            FileVolumeManager::MetaData { super_block, .. } => {
+                Err(VolumeError::WrongVolumeKind(super_block.kind))
+            }
+        }
+    }
+
+    pub fn index_get(&self, name: &str) -> Option<IndexEntry> {
+        match self {
+            FileVolumeManager::MetaData { index, .. } => index.get(name.as_bytes()).cloned(),
+            FileVolumeManager::BlockFile { .. } => None,
+        }
+    }
+
+    pub fn create_snapshot(&mut self, name: &str, created: u64) -> Result<(), VolumeError> {
+        match self {
+            FileVolumeManager::MetaData {
                 file,
                 super_block,
-                chunk_vector,
-            } => {}
+                index,
+                snapshots,
+                free_pages,
+                ..
+            } => {
+                if snapshots.iter().any(|s| s.name == name) {
+                    return Err(VolumeError::SnapshotExists(name.to_owned()));
+                }
+                let offsets: Vec<u64> = index
+                    .pages()
+                    .iter()
+                    .map(|_| allocate_page(&mut super_block.next_free, free_pages))
+                    .collect();
+                grow(file.as_mut().ok_or(VolumeError::NotOpen)?, super_block)?;
+                let mut pages = Vec::new();
+                for (n, page) in index.pages().iter().enumerate() {
+                    let mut page = page.clone();
+                    page.set_next_index_page(offsets.get(n + 1).cloned().unwrap_or(0));
+                    let page_v: Vec<u8> = page.clone().into();
+                    write_at(file, offsets[n], &page_v[..])?;
+                    pages.push(page);
+                }
+                snapshots.push(Snapshot {
+                    name: name.to_owned(),
+                    created,
+                    index_start: offsets[0],
+                    index: ObjectIndex::new(pages),
+                });
+                Ok(())
+            }
+            FileVolumeManager::BlockFile { super_block, .. } => {
+                Err(VolumeError::WrongVolumeKind(super_block.kind))
+            }
         }
-        Ok(())
+    }
+
+    // Forgets the snapshot and returns its index pages to the free list. The
+    // caller decides which of its objects can now be freed.
+    pub fn delete_snapshot(&mut self, name: &str) -> Result<Snapshot, VolumeError> {
+        match self {
+            FileVolumeManager::MetaData {
+                snapshots,
+                free_pages,
+                ..
+            } => {
+                let pos = snapshots
+                    .iter()
+                    .position(|s| s.name == name)
+                    .ok_or_else(|| VolumeError::NoSuchSnapshot(name.to_owned()))?;
+                let snapshot = snapshots.remove(pos);
+                free_pages.extend(index_page_offsets(&snapshot.index, snapshot.index_start));
+                Ok(snapshot)
+            }
+            FileVolumeManager::BlockFile { super_block, .. } => {
+                Err(VolumeError::WrongVolumeKind(super_block.kind))
+            }
+        }
+    }
+
+    pub fn snapshots(&self) -> Vec<SnapshotSummary> {
+        match self {
+            FileVolumeManager::MetaData { snapshots, .. } => {
+                snapshots.iter().map(|s| s.summary()).collect()
+            }
+            FileVolumeManager::BlockFile { .. } => Vec::new(),
+        }
+    }
+
+    pub fn snapshot(&self, name: &str) -> Result<&Snapshot, VolumeError> {
+        match self {
+            FileVolumeManager::MetaData { snapshots, .. } => snapshots
+                .iter()
+                .find(|s| s.name == name)
+                .ok_or_else(|| VolumeError::NoSuchSnapshot(name.to_owned())),
+            FileVolumeManager::BlockFile { super_block, .. } => {
+                Err(VolumeError::WrongVolumeKind(super_block.kind))
+            }
+        }
+    }
+
+    // Whether any version in the live index or in a snapshot still points at
+    // the file `id`.
+    pub fn referenced(&self, id: UUID) -> bool {
+        match self {
+            FileVolumeManager::MetaData {
+                index, snapshots, ..
+            } => index.iter().any(|e| e.id == id) || snapshots.iter().any(|s| s.references(id)),
+            FileVolumeManager::BlockFile { .. } => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{super_block_images, FileVolumeManager, SuperBlock, SUPER_BLOCK_BYTES};
+    use crate::index::{IndexEntry, INDEX_PAGE_ENTRIES};
+    use crate::journal::{read_journal, write_journal, JournalRecord};
+    use crate::mirror::VolumeFile;
+    use crate::test_util::Scratch;
+    use crate::volume_manager::VERSIONING;
+    use std::fs::OpenOptions;
+
+    // Leaves `record` in the journal of the volume at `path`, as a crash
+    // between writing it and finishing with it would.
+    fn crash_with(path: &str, record: &JournalRecord) {
+        let fvm = FileVolumeManager::open_metadata(path).unwrap();
+        let start = fvm.super_block().journal_start;
+        drop(fvm);
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(path)
+            .unwrap();
+        write_journal(&mut VolumeFile::mirrored(file), start, record).unwrap();
+    }
+
+    fn journal(path: &str) -> JournalRecord {
+        let fvm = FileVolumeManager::open_metadata(path).unwrap();
+        let start = fvm.super_block().journal_start;
+        let file = OpenOptions::new().read(true).open(path).unwrap();
+        read_journal(&VolumeFile::mirrored(file), start).unwrap()
+    }
+
+    // A record holding the superblock with versioning turned on.
+    fn versioning_record(path: &str) -> JournalRecord {
+        let fvm = FileVolumeManager::open_metadata(path).unwrap();
+        let mut super_block = *fvm.super_block();
+        super_block.flags |= VERSIONING;
+        JournalRecord {
+            pages: super_block_images(&super_block),
+            ..JournalRecord::intent(1, &[2, 3])
+        }
+    }
+
+    #[test]
+    fn commit_lasts_and_leaves_the_journal_empty() {
+        let scratch = Scratch::new();
+        let path = scratch.path("volume.bin");
+        let mut fvm = FileVolumeManager::init_metadata(&path, 1).unwrap();
+        fvm.journal_begin(1, &[2, 3]).unwrap();
+        fvm.set_versioning(true).unwrap();
+        assert!(fvm.journal_begin(4, &[]).is_err());
+        fvm.journal_commit().unwrap();
+        assert!(fvm.journal_commit().is_err());
+        drop(fvm);
+
+        let fvm = FileVolumeManager::open_metadata(&path).unwrap();
+        assert!(fvm.versioning());
+        assert!(fvm.rollback().is_empty());
+        assert!(journal(&path).is_empty());
+    }
+
+    #[test]
+    fn opening_replays_a_committed_record() {
+        let scratch = Scratch::new();
+        let path = scratch.path("volume.bin");
+        drop(FileVolumeManager::init_metadata(&path, 1).unwrap());
+        let record = JournalRecord {
+            committed: true,
+            ..versioning_record(&path)
+        };
+        crash_with(&path, &record);
+
+        let fvm = FileVolumeManager::open_metadata(&path).unwrap();
+        assert!(fvm.versioning());
+        assert!(fvm.rollback().is_empty());
+        assert!(journal(&path).is_empty());
+    }
+
+    #[test]
+    fn opening_rolls_back_a_record_that_never_committed() {
+        let scratch = Scratch::new();
+        let path = scratch.path("volume.bin");
+        drop(FileVolumeManager::init_metadata(&path, 1).unwrap());
+        crash_with(&path, &versioning_record(&path));
+
+        let mut fvm = FileVolumeManager::open_metadata(&path).unwrap();
+        assert!(!fvm.versioning());
+        assert_eq!(fvm.rollback(), &[2, 3]);
+        fvm.finish_rollback().unwrap();
+        drop(fvm);
+
+        let fvm = FileVolumeManager::open_metadata(&path).unwrap();
+        assert!(!fvm.versioning());
+        assert!(fvm.rollback().is_empty());
+    }
+
+    #[test]
+    fn pages_freed_by_deletes_are_reused() {
+        let scratch = Scratch::new();
+        let path = scratch.path("volume.bin");
+        let mut fvm = FileVolumeManager::init_metadata(&path, 1).unwrap();
+        let mut high_water = Vec::new();
+        for round in 0..3 {
+            fvm.journal_begin(1, &[]).unwrap();
+            for n in 0..4 * INDEX_PAGE_ENTRIES {
+                let name = format!("round{}-{:03}", round, n);
+                fvm.index_put(IndexEntry::new(name.as_bytes(), n as u128 + 1, 1))
+                    .unwrap();
+            }
+            fvm.journal_commit().unwrap();
+            high_water.push(fvm.super_block().next_free);
+
+            fvm.journal_begin(1, &[]).unwrap();
+            for n in 0..4 * INDEX_PAGE_ENTRIES {
+                let removed = fvm
+                    .index_remove_all(&format!("round{}-{:03}", round, n))
+                    .unwrap();
+                assert_eq!(removed.len(), 1, "round {}", round);
+            }
+            fvm.journal_commit().unwrap();
+            drop(fvm);
+            fvm = FileVolumeManager::open_metadata(&path).unwrap();
+            assert!(fvm.index_entries().is_empty());
+        }
+        assert!(high_water.iter().all(|h| *h == high_water[0]));
+    }
+
+    #[test]
+    fn journal_moves_after_opening_with_a_damaged_superblock() {
+        let scratch = Scratch::new();
+        let path = scratch.path("volume.bin");
+        drop(FileVolumeManager::init_metadata(&path, 1).unwrap());
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&path)
+            .unwrap();
+        VolumeFile::mirrored(file)
+            .write_at(0, &[0xa5; SUPER_BLOCK_BYTES])
+            .unwrap();
+
+        let mut fvm = FileVolumeManager::open_metadata(&path).unwrap();
+        let journal_start = fvm.super_block().journal_start;
+        // More index pages than the journal holds.
+        let names = 4000;
+        fvm.journal_begin(1, &[]).unwrap();
+        for n in 0..names {
+            let name = format!("object{:05}", n);
+            fvm.index_put(IndexEntry::new(name.as_bytes(), n as u128 + 1, 1))
+                .unwrap();
+        }
+        fvm.journal_commit().unwrap();
+        assert_ne!(fvm.super_block().journal_start, journal_start);
+        drop(fvm);
+
+        let fvm = FileVolumeManager::open_metadata(&path).unwrap();
+        assert_eq!(fvm.index_entries().len(), names);
+        let file = OpenOptions::new().read(true).open(&path).unwrap();
+        let mut copy = [0u8; SUPER_BLOCK_BYTES];
+        VolumeFile::mirrored(file).read_at(0, &mut copy).unwrap();
+        let copy = SuperBlock::parse(copy).unwrap();
+        assert_eq!(copy.journal_start, fvm.super_block().journal_start);
     }
 }